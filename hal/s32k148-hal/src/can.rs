@@ -1,8 +1,7 @@
-#![no_std]
-
+use core::fmt;
 use vcell::VolatileCell;
-use core::marker::PhantomData;
-use embedded_can::blocking::Can;
+use embedded_can::{ErrorKind, ExtendedId, Frame, Id, StandardId};
+use crate::clock::Clock;
 
 #[derive(Debug)]
 pub enum CanError {
@@ -10,116 +9,1692 @@ pub enum CanError {
     RxFifoEmpty,
     InvalidId,
     InvalidLength,
+    InvalidBitTiming,
+    InvalidFilter,
+    Timeout,
     BusOff,
     Error,
 }
 
+impl embedded_can::Error for CanError {
+    // The controller's error flags are not broken down per frame
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+#[repr(C)]
 pub struct CanRegisters {
     mcr: VolatileCell<u32>,
     ctrl1: VolatileCell<u32>,
     timer: VolatileCell<u32>,
+    _reserved0: u32,
     rxmgmask: VolatileCell<u32>,
     rx14mask: VolatileCell<u32>,
     rx15mask: VolatileCell<u32>,
     ecr: VolatileCell<u32>,
     esr1: VolatileCell<u32>,
+    imask2: VolatileCell<u32>,
     imask1: VolatileCell<u32>,
+    iflag2: VolatileCell<u32>,
     iflag1: VolatileCell<u32>,
     ctrl2: VolatileCell<u32>,
     esr2: VolatileCell<u32>,
+    _reserved1: [u32; 2],
     crcr: VolatileCell<u32>,
     rxfgmask: VolatileCell<u32>,
     rxfir: VolatileCell<u32>,
-    _reserved: [u32; 1],
-    mb: [VolatileCell<u32>; 16],
-    rximr: [VolatileCell<u32>; 16],
+    cbt: VolatileCell<u32>,
+    _reserved2: [u32; 11],
+    // Message buffer RAM, 512 bytes
+    mb: [VolatileCell<u32>; MB_RAM_WORDS],
+    _reserved3: [u32; 384],
+    rximr: [VolatileCell<u32>; RXIMR_COUNT],
+    _reserved4: [u32; 192],
+    fdctrl: VolatileCell<u32>,
+    fdcbt: VolatileCell<u32>,
+    fdcrc: VolatileCell<u32>,
+}
+
+// Register offsets
+pub const MCR: usize = 0x000;
+pub const CTRL1: usize = 0x004;
+pub const TIMER: usize = 0x008;
+pub const RXMGMASK: usize = 0x010;
+pub const RX14MASK: usize = 0x014;
+pub const RX15MASK: usize = 0x018;
+pub const ECR: usize = 0x01C;
+pub const ESR1: usize = 0x020;
+pub const IMASK2: usize = 0x024;
+pub const IMASK1: usize = 0x028;
+pub const IFLAG2: usize = 0x02C;
+pub const IFLAG1: usize = 0x030;
+pub const CTRL2: usize = 0x034;
+pub const ESR2: usize = 0x038;
+pub const CRCR: usize = 0x044;
+pub const RXFGMASK: usize = 0x048;
+pub const RXFIR: usize = 0x04C;
+pub const CBT: usize = 0x050;
+pub const MB_RAM: usize = 0x080;
+pub const RXIMR: usize = 0x880;
+pub const FDCTRL: usize = 0xC00;
+pub const FDCBT: usize = 0xC04;
+pub const FDCRC: usize = 0xC08;
+
+const MB_RAM_WORDS: usize = 128;
+const RXIMR_COUNT: usize = 32;
+
+// MCR
+pub const MCR_MDIS: u32 = 1 << 31;
+pub const MCR_FRZ: u32 = 1 << 30;
+const MCR_RFEN: u32 = 1 << 29;
+pub const MCR_HALT: u32 = 1 << 28;
+pub const MCR_NOTRDY: u32 = 1 << 27;
+pub const MCR_SOFTRST: u32 = 1 << 25;
+pub const MCR_FRZACK: u32 = 1 << 24;
+pub const MCR_LPMACK: u32 = 1 << 20;
+const MCR_SRXDIS: u32 = 1 << 17;
+const MCR_IRMQ: u32 = 1 << 16;
+const MCR_FDEN: u32 = 1 << 11;
+const MCR_IDAM_SHIFT: u32 = 8;
+const MCR_IDAM_MASK: u32 = 0x3 << MCR_IDAM_SHIFT;
+const MCR_MAXMB_MASK: u32 = 0x7F;
+
+// CTRL1
+const CTRL1_CLKSRC: u32 = 1 << 13;
+const CTRL1_BOFFREC: u32 = 1 << 6;
+
+// CTRL2
+const CTRL2_RFFN_SHIFT: u32 = 24;
+const CTRL2_RFFN_MASK: u32 = 0xF << CTRL2_RFFN_SHIFT;
+const CTRL2_ISOCANFDEN: u32 = 1 << 12;
+
+// IFLAG1 bits of the legacy RX FIFO, which replace those of mailboxes 5 to 7
+const IFLAG_FIFO_AVAILABLE: u32 = 1 << 5;
+const IFLAG_FIFO_WARNING: u32 = 1 << 6;
+const IFLAG_FIFO_OVERFLOW: u32 = 1 << 7;
+
+// CBT: nominal (arbitration phase) bit timing with extended ranges
+const CBT_BTF: u32 = 1 << 31;
+
+// FDCTRL
+const FDCTRL_FDRATE: u32 = 1 << 31;
+const FDCTRL_MBDSR0_SHIFT: u32 = 16;
+const FDCTRL_TDCEN: u32 = 1 << 15;
+const FDCTRL_TDCOFF_SHIFT: u32 = 8;
+
+// ESR1 fault confinement state and ECR error counters. The interrupt
+// flags are cleared by writing 1.
+const ESR1_BOFFDONEINT: u32 = 1 << 19;
+const ESR1_FLTCONF_SHIFT: u32 = 4;
+const ESR1_FLTCONF_MASK: u32 = 0x3 << ESR1_FLTCONF_SHIFT;
+const ESR1_BOFFINT: u32 = 1 << 2;
+const ECR_RXERRCNT_SHIFT: u32 = 8;
+
+// Message buffer control and status word
+const CS_EDL: u32 = 1 << 31;
+const CS_BRS: u32 = 1 << 30;
+const CS_ESI: u32 = 1 << 29;
+const CS_SRR: u32 = 1 << 22;
+const CS_IDE: u32 = 1 << 21;
+const CS_RTR: u32 = 1 << 20;
+const CS_CODE_SHIFT: u32 = 24;
+const CS_CODE_MASK: u32 = 0xF << CS_CODE_SHIFT;
+const CS_DLC_SHIFT: u32 = 16;
+
+// Message buffer codes. A set lowest bit on a receive buffer means the
+// controller is still moving a frame into it.
+pub const CODE_RX_INACTIVE: u32 = 0x0;
+pub const CODE_RX_EMPTY: u32 = 0x4;
+pub const CODE_RX_FULL: u32 = 0x2;
+pub const CODE_RX_OVERRUN: u32 = 0x6;
+const CODE_RX_BUSY: u32 = 0x1;
+pub const CODE_TX_INACTIVE: u32 = 0x8;
+pub const CODE_TX_DATA: u32 = 0xC;
+
+// Standard identifiers sit in bits 28:18 of the ID word, extended ones
+// fill bits 28:0
+const ID_STD_SHIFT: u32 = 18;
+const ID_STD_MASK: u32 = 0x7FF;
+const ID_EXT_MASK: u32 = 0x1FFF_FFFF;
+
+// The legacy RX FIFO takes the first six message buffers, followed by its
+// ID filter table
+const FIFO_MB_COUNT: usize = 6;
+
+// Filter table elements: remote and extended frame bits of format A and B
+const FILTER_A_RTR: u32 = 1 << 31;
+const FILTER_A_IDE: u32 = 1 << 30;
+const FILTER_B_RTR: u32 = 1 << 15;
+const FILTER_B_IDE: u32 = 1 << 14;
+
+// Poll iterations before a mode change of the controller counts as failed
+const MODE_TIMEOUT_LOOPS: u32 = 100_000;
+
+// Payload length of every DLC value on CAN FD
+const DLC_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// Largest payload of a classic CAN frame
+pub const CAN_MAX_DATA_LEN: usize = 8;
+/// Largest payload of a CAN FD frame
+pub const CANFD_MAX_DATA_LEN: usize = 64;
+
+/// Payload length a DLC stands for. Classic frames carry 8 bytes for
+/// DLC 9 to 15.
+pub fn dlc_to_len(dlc: u8, fd: bool) -> usize {
+    let len = DLC_LENGTHS[(dlc & 0x0F) as usize];
+    if fd { len } else { len.min(CAN_MAX_DATA_LEN) }
+}
+
+/// Smallest DLC whose payload holds `len` bytes
+pub fn len_to_dlc(len: usize) -> Option<u8> {
+    DLC_LENGTHS.iter().position(|&dlc_len| dlc_len >= len).map(|dlc| dlc as u8)
+}
+
+/// Rounds `len` up to a payload length a CAN FD frame can carry. The
+/// remaining bytes must be padded.
+pub fn fd_frame_len(len: usize) -> Option<usize> {
+    len_to_dlc(len).map(|dlc| DLC_LENGTHS[dlc as usize])
+}
+
+/// Sample point used by `CanConfig::from_bitrate`, in per mille (CiA 601)
+pub const DEFAULT_SAMPLE_POINT: u16 = 875;
+
+// Field ranges of a bit timing register, in time quanta
+struct TimingLimits {
+    max_prescaler: u16,
+    prop_seg: (u8, u8),
+    phase_seg1: (u8, u8),
+    phase_seg2: (u8, u8),
+    max_sjw: u8,
+    quanta: (u32, u32),
+}
+
+// CBT, nominal timing of classic CAN and the FD arbitration phase
+const CBT_LIMITS: TimingLimits = TimingLimits {
+    max_prescaler: 1024,
+    prop_seg: (1, 64),
+    phase_seg1: (1, 32),
+    phase_seg2: (2, 32),
+    max_sjw: 32,
+    quanta: (8, 129),
+};
+
+// FDCBT, timing of the FD data phase
+const FDCBT_LIMITS: TimingLimits = TimingLimits {
+    max_prescaler: 1024,
+    prop_seg: (0, 31),
+    phase_seg1: (1, 8),
+    phase_seg2: (2, 8),
+    max_sjw: 8,
+    quanta: (5, 48),
+};
+
+/// Word access to a FlexCAN register block by byte offset. `CanRegisters`
+/// is the memory-mapped peripheral; a model of the controller can stand in
+/// for it to run the driver on the host.
+pub trait CanRegisterAccess {
+    fn read(&self, offset: usize) -> u32;
+    fn write(&mut self, offset: usize, value: u32);
+
+    fn modify<F: FnOnce(u32) -> u32>(&mut self, offset: usize, f: F) {
+        let value = self.read(offset);
+        self.write(offset, f(value));
+    }
+}
+
+impl CanRegisters {
+    fn cell(&self, offset: usize) -> &VolatileCell<u32> {
+        match offset {
+            MCR => &self.mcr,
+            CTRL1 => &self.ctrl1,
+            TIMER => &self.timer,
+            RXMGMASK => &self.rxmgmask,
+            RX14MASK => &self.rx14mask,
+            RX15MASK => &self.rx15mask,
+            ECR => &self.ecr,
+            ESR1 => &self.esr1,
+            IMASK2 => &self.imask2,
+            IMASK1 => &self.imask1,
+            IFLAG2 => &self.iflag2,
+            IFLAG1 => &self.iflag1,
+            CTRL2 => &self.ctrl2,
+            ESR2 => &self.esr2,
+            CRCR => &self.crcr,
+            RXFGMASK => &self.rxfgmask,
+            RXFIR => &self.rxfir,
+            CBT => &self.cbt,
+            FDCTRL => &self.fdctrl,
+            FDCBT => &self.fdcbt,
+            FDCRC => &self.fdcrc,
+            _ if (MB_RAM..MB_RAM + MB_RAM_WORDS * 4).contains(&offset) => {
+                &self.mb[(offset - MB_RAM) / 4]
+            }
+            _ if (RXIMR..RXIMR + RXIMR_COUNT * 4).contains(&offset) => {
+                &self.rximr[(offset - RXIMR) / 4]
+            }
+            _ => panic!("no FlexCAN register at offset {:#x}", offset),
+        }
+    }
+}
+
+impl CanRegisterAccess for CanRegisters {
+    fn read(&self, offset: usize) -> u32 {
+        self.cell(offset).get()
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        self.cell(offset).set(value)
+    }
+}
+
+/// Clock feeding the CAN protocol engine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    /// SOSCDIV2, the oscillator clock
+    Oscillator,
+    /// The peripheral (bus) clock
+    Peripheral,
+}
+
+/// Segment lengths of one bit, in time quanta, and the prescaler dividing
+/// the protocol engine clock into time quanta. All values are the actual
+/// counts, not the register encodings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitTiming {
+    pub prescaler: u16,
+    pub prop_seg: u8,
+    pub phase_seg1: u8,
+    pub phase_seg2: u8,
+    pub sjw: u8,
+}
+
+impl BitTiming {
+    /// Time quanta per bit, including the sync segment
+    pub fn get_quanta(&self) -> u32 {
+        1 + self.prop_seg as u32 + self.phase_seg1 as u32 + self.phase_seg2 as u32
+    }
+
+    // Sample point in 1/10 per mille
+    fn get_sample_point_fine(&self) -> u32 {
+        (1 + self.prop_seg as u32 + self.phase_seg1 as u32) * 10_000 / self.get_quanta()
+    }
+
+    // CBT: prescaler 1-1024, PROPSEG 1-64, PSEG1/PSEG2/RJW 1-32
+    fn to_cbt(self) -> Option<u32> {
+        if !in_range(self.prescaler as u32, 1024)
+            || !in_range(self.prop_seg as u32, 64)
+            || !in_range(self.phase_seg1 as u32, 32)
+            || !in_range(self.phase_seg2 as u32, 32)
+            || !in_range(self.sjw as u32, 32)
+        {
+            return None;
+        }
+
+        Some(
+            CBT_BTF
+                | ((self.prescaler as u32 - 1) << 21)
+                | ((self.sjw as u32 - 1) << 16)
+                | ((self.prop_seg as u32 - 1) << 10)
+                | ((self.phase_seg1 as u32 - 1) << 5)
+                | (self.phase_seg2 as u32 - 1),
+        )
+    }
+
+    // FDCBT: prescaler 1-1024, FPROPSEG 0-31, FPSEG1 1-8, FPSEG2 2-8,
+    // FRJW 1-8. Unlike the others, FPROPSEG is not stored minus one.
+    fn to_fdcbt(self) -> Option<u32> {
+        if !in_range(self.prescaler as u32, 1024)
+            || self.prop_seg > 31
+            || !in_range(self.phase_seg1 as u32, 8)
+            || !in_range(self.phase_seg2 as u32, 8)
+            || self.phase_seg2 < 2
+            || !in_range(self.sjw as u32, 8)
+        {
+            return None;
+        }
+
+        Some(
+            ((self.prescaler as u32 - 1) << 20)
+                | ((self.sjw as u32 - 1) << 16)
+                | ((self.prop_seg as u32) << 10)
+                | ((self.phase_seg1 as u32 - 1) << 5)
+                | (self.phase_seg2 as u32 - 1),
+        )
+    }
+}
+
+/// Why no bit timing fits the requested bitrate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitTimingError {
+    /// The bitrate is zero or above what the clock can produce
+    InvalidBitrate,
+    /// The sample point lies outside 50.0% to 95.0%
+    InvalidSamplePoint,
+    /// The clock cannot be divided into the bitrate exactly
+    NoExactMatch { clock_hz: u32, bitrate: u32 },
+}
+
+impl fmt::Display for BitTimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitTimingError::InvalidBitrate => write!(f, "Invalid bitrate"),
+            BitTimingError::InvalidSamplePoint => write!(f, "Sample point out of range"),
+            BitTimingError::NoExactMatch { clock_hz, bitrate } => write!(
+                f,
+                "No bit timing produces exactly {} bit/s from a {} Hz clock",
+                bitrate, clock_hz
+            ),
+        }
+    }
+}
+
+impl BitTiming {
+    /// Nominal timing (CBT) for `bitrate` from a protocol engine clock of
+    /// `clock_hz`, with the sample point, in per mille, as close to
+    /// `sample_point` as the segment ranges allow. Among equally close
+    /// solutions the one with the most time quanta wins.
+    pub fn calculate(clock_hz: u32, bitrate: u32, sample_point: u16) -> Result<Self, BitTimingError> {
+        solve(&CBT_LIMITS, clock_hz, bitrate, sample_point, None)
+    }
+
+    /// Timing of the CAN FD data phase (FDCBT), chosen like `calculate`
+    pub fn calculate_data(clock_hz: u32, bitrate: u32, sample_point: u16) -> Result<Self, BitTimingError> {
+        solve(&FDCBT_LIMITS, clock_hz, bitrate, sample_point, None)
+    }
+
+    pub fn get_bitrate(&self, clock_hz: u32) -> u32 {
+        clock_hz / (self.prescaler as u32 * self.get_quanta())
+    }
+
+    /// Sample point in per mille of the bit time
+    pub fn get_sample_point(&self) -> u16 {
+        let quanta = self.get_quanta();
+        let before_sample = 1 + self.prop_seg as u32 + self.phase_seg1 as u32;
+        (before_sample * 1000 / quanta) as u16
+    }
+}
+
+/// Bit timing of the arbitration (nominal) and data phases of CAN FD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FdBitTiming {
+    pub nominal: BitTiming,
+    pub data: BitTiming,
+    /// Send the data phase at the data bit rate (BRS)
+    pub bit_rate_switch: bool,
+}
+
+impl FdBitTiming {
+    /// Timing for both phases, switching bit rates when they differ. Both
+    /// phases share one prescaler where possible, which keeps the time
+    /// quanta of arbitration and data phase aligned.
+    pub fn calculate(
+        clock_hz: u32,
+        nominal_bitrate: u32,
+        data_bitrate: u32,
+        sample_point: u16,
+    ) -> Result<Self, BitTimingError> {
+        let bit_rate_switch = nominal_bitrate != data_bitrate;
+        for prescaler in 1..=FDCBT_LIMITS.max_prescaler {
+            let data = solve(&FDCBT_LIMITS, clock_hz, data_bitrate, sample_point, Some(prescaler));
+            let nominal = solve(&CBT_LIMITS, clock_hz, nominal_bitrate, sample_point, Some(prescaler));
+            if let (Ok(nominal), Ok(data)) = (nominal, data) {
+                return Ok(Self { nominal, data, bit_rate_switch });
+            }
+        }
+
+        Ok(Self {
+            nominal: BitTiming::calculate(clock_hz, nominal_bitrate, sample_point)?,
+            data: BitTiming::calculate_data(clock_hz, data_bitrate, sample_point)?,
+            bit_rate_switch,
+        })
+    }
+}
+
+// Searches the prescalers giving `bitrate` exactly, optionally only
+// `prescaler`, for the segments closest to the sample point
+fn solve(
+    limits: &TimingLimits,
+    clock_hz: u32,
+    bitrate: u32,
+    sample_point: u16,
+    prescaler: Option<u16>,
+) -> Result<BitTiming, BitTimingError> {
+    if bitrate == 0 || bitrate > clock_hz / limits.quanta.0 {
+        return Err(BitTimingError::InvalidBitrate);
+    }
+    if !(500..=950).contains(&sample_point) {
+        return Err(BitTimingError::InvalidSamplePoint);
+    }
+
+    let prescalers = match prescaler {
+        Some(prescaler) => prescaler..=prescaler,
+        None => 1..=limits.max_prescaler,
+    };
+
+    // Best timing so far with its sample point error in 1/10 per mille
+    let mut best: Option<(BitTiming, u32)> = None;
+    for prescaler in prescalers {
+        let divider = prescaler as u32 * bitrate;
+        if !clock_hz.is_multiple_of(divider) {
+            continue;
+        }
+        let quanta = clock_hz / divider;
+        if quanta < limits.quanta.0 || quanta > limits.quanta.1 {
+            continue;
+        }
+
+        if let Some(timing) = split_segments(limits, prescaler, quanta, sample_point) {
+            let error = (timing.get_sample_point_fine() as i32 - sample_point as i32 * 10).unsigned_abs();
+            if best.is_none_or(|(_, best_error)| error < best_error) {
+                best = Some((timing, error));
+            }
+        }
+    }
+
+    best.map(|(timing, _)| timing)
+        .ok_or(BitTimingError::NoExactMatch { clock_hz, bitrate })
+}
+
+// Divides `quanta` around the sample point. The propagation segment takes
+// whatever the phase segments leave of the time before the sample point.
+fn split_segments(limits: &TimingLimits, prescaler: u16, quanta: u32, sample_point: u16) -> Option<BitTiming> {
+    let (prop_min, prop_max) = (limits.prop_seg.0 as u32, limits.prop_seg.1 as u32);
+    let (ps1_min, ps1_max) = (limits.phase_seg1.0 as u32, limits.phase_seg1.1 as u32);
+    let (ps2_min, ps2_max) = (limits.phase_seg2.0 as u32, limits.phase_seg2.1 as u32);
+
+    // Quanta up to the sample point, the sync segment included
+    let before_sample = (quanta * sample_point as u32 + 500) / 1000;
+    let phase_seg2 = quanta.saturating_sub(before_sample).clamp(ps2_min, ps2_max);
+    let tseg1 = quanta.checked_sub(1 + phase_seg2)?;
+    if tseg1 < prop_min + ps1_min || tseg1 > prop_max + ps1_max {
+        return None;
+    }
+
+    let phase_seg1 = phase_seg2.clamp(ps1_min, ps1_max).min(tseg1 - prop_min);
+    let prop_seg = tseg1 - phase_seg1;
+    let (prop_seg, phase_seg1) = if prop_seg > prop_max {
+        (prop_max, tseg1 - prop_max)
+    } else {
+        (prop_seg, phase_seg1)
+    };
+
+    Some(BitTiming {
+        prescaler,
+        prop_seg: prop_seg as u8,
+        phase_seg1: phase_seg1 as u8,
+        phase_seg2: phase_seg2 as u8,
+        sjw: phase_seg1.min(phase_seg2).min(limits.max_sjw as u32) as u8,
+    })
 }
 
-pub struct CanDevice {
-    registers: &'static mut CanRegisters,
-    _phantom: PhantomData<()>,
+/// Controller settings applied by `CanDevice::init`
+#[derive(Debug, Clone, Copy)]
+pub struct CanConfig {
+    pub clock_source: ClockSource,
+    /// Bit timing of classic CAN operation
+    pub timing: BitTiming,
+    /// CAN FD operation, replacing `timing`
+    pub fd: Option<FdBitTiming>,
+    pub bus_off_recovery: BusOffRecovery,
 }
 
-impl CanDevice {
-    pub fn new(registers: &'static mut CanRegisters) -> Self {
+impl CanConfig {
+    pub fn new(clock_source: ClockSource, timing: BitTiming) -> Self {
+        Self {
+            clock_source,
+            timing,
+            fd: None,
+            bus_off_recovery: BusOffRecovery::Automatic,
+        }
+    }
+
+    /// Classic CAN at `bitrate` from the peripheral clock
+    pub fn from_bitrate(clock: &Clock, bitrate: u32) -> Result<Self, BitTimingError> {
+        let timing = BitTiming::calculate(clock.get_peripheral_clock(), bitrate, DEFAULT_SAMPLE_POINT)?;
+        Ok(Self::new(ClockSource::Peripheral, timing))
+    }
+
+    /// CAN FD with `data_bitrate` in the data phase, from the peripheral clock
+    pub fn from_fd_bitrate(clock: &Clock, nominal_bitrate: u32, data_bitrate: u32) -> Result<Self, BitTimingError> {
+        let timing = FdBitTiming::calculate(
+            clock.get_peripheral_clock(),
+            nominal_bitrate,
+            data_bitrate,
+            DEFAULT_SAMPLE_POINT,
+        )?;
+        Ok(Self::new(ClockSource::Peripheral, timing.nominal).with_fd(timing))
+    }
+
+    pub fn with_fd(mut self, timing: FdBitTiming) -> Self {
+        self.fd = Some(timing);
+        self
+    }
+
+    pub fn with_bus_off_recovery(mut self, recovery: BusOffRecovery) -> Self {
+        self.bus_off_recovery = recovery;
+        self
+    }
+}
+
+impl Default for CanConfig {
+    fn default() -> Self {
+        // 500 kbit/s from the 8 MHz oscillator, sample point at 81.25%
+        Self::new(
+            ClockSource::Oscillator,
+            BitTiming {
+                prescaler: 1,
+                prop_seg: 6,
+                phase_seg1: 6,
+                phase_seg2: 3,
+                sjw: 3,
+            },
+        )
+    }
+}
+
+/// How the controller returns to the bus after bus-off. Either way it
+/// first waits for 128 occurrences of 11 recessive bits, as ISO 11898-1
+/// demands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusOffRecovery {
+    /// The controller recovers on its own
+    Automatic,
+    /// The controller stays off the bus until `recover_bus_off` is called
+    Manual,
+}
+
+/// Fault confinement state of the controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorState {
+    Active,
+    Passive,
+    BusOff,
+}
+
+/// A classic or FD frame with an 11-bit or 29-bit identifier, as exchanged
+/// through the `embedded_can` traits of `CanDevice`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CanFrame {
+    id: Id,
+    data: [u8; CANFD_MAX_DATA_LEN],
+    // Payload length, or the requested length of a remote frame
+    len: u8,
+    remote: bool,
+    fd: bool,
+    bit_rate_switch: bool,
+    error_state_indicator: bool,
+}
+
+impl CanFrame {
+    fn empty(id: Id) -> Self {
+        Self {
+            id,
+            data: [0; CANFD_MAX_DATA_LEN],
+            len: 0,
+            remote: false,
+            fd: false,
+            bit_rate_switch: false,
+            error_state_indicator: false,
+        }
+    }
+
+    /// Creates a CAN FD data frame. `data` must have a length a DLC
+    /// expresses: up to 8, or 12, 16, 20, 24, 32, 48 or 64 bytes.
+    pub fn new_fd(id: impl Into<Id>, data: &[u8], bit_rate_switch: bool) -> Option<Self> {
+        if fd_frame_len(data.len()) != Some(data.len()) {
+            return None;
+        }
+
+        let mut frame = Self::empty(id.into());
+        frame.data[..data.len()].copy_from_slice(data);
+        frame.len = data.len() as u8;
+        frame.fd = true;
+        frame.bit_rate_switch = bit_rate_switch;
+        Some(frame)
+    }
+
+    pub fn is_fd(&self) -> bool {
+        self.fd
+    }
+
+    /// The data phase is sent with the data bit rate (BRS)
+    pub fn is_bit_rate_switch(&self) -> bool {
+        self.bit_rate_switch
+    }
+
+    /// The transmitter of a received FD frame was error passive (ESI)
+    pub fn is_error_state_indicator(&self) -> bool {
+        self.error_state_indicator
+    }
+}
+
+impl Frame for CanFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > CAN_MAX_DATA_LEN {
+            return None;
+        }
+
+        let mut frame = Self::empty(id.into());
+        frame.data[..data.len()].copy_from_slice(data);
+        frame.len = data.len() as u8;
+        Some(frame)
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > CAN_MAX_DATA_LEN {
+            return None;
+        }
+
+        let mut frame = Self::empty(id.into());
+        frame.len = dlc as u8;
+        frame.remote = true;
+        Some(frame)
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    // Classic frames use the length as DLC, FD frames encode it
+    fn dlc(&self) -> usize {
+        if self.fd {
+            len_to_dlc(self.len as usize).unwrap_or(0) as usize
+        } else {
+            self.len as usize
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        if self.remote {
+            return &[];
+        }
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Acceptance filter: a frame passes when the identifier bits selected by
+/// `mask` equal those of `id`. The mask is right-aligned like the
+/// identifier; a mask of 0 accepts every identifier of the format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdFilter {
+    pub id: Id,
+    pub mask: u32,
+}
+
+impl IdFilter {
+    pub fn new(id: impl Into<Id>, mask: u32) -> Self {
+        Self { id: id.into(), mask }
+    }
+
+    /// Passes `id` only
+    pub fn exact(id: impl Into<Id>) -> Self {
+        let id = id.into();
+        let mask = match id {
+            Id::Standard(_) => ID_STD_MASK,
+            Id::Extended(_) => ID_EXT_MASK,
+        };
+        Self { id, mask }
+    }
+
+    /// Passes every frame of the identifier format of `id`
+    pub fn accept_all(id: impl Into<Id>) -> Self {
+        Self::new(id, 0)
+    }
+}
+
+/// Layout of the legacy RX FIFO ID filter table elements
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FifoFormat {
+    /// One complete identifier per element
+    A,
+    /// Two filters per element: standard identifiers or the 14 most
+    /// significant bits of extended ones
+    B,
+    /// Four filters per element on the 8 most significant identifier bits.
+    /// The identifier format is not compared.
+    C,
+}
+
+impl FifoFormat {
+    fn filters_per_element(self) -> usize {
+        match self {
+            FifoFormat::A => 1,
+            FifoFormat::B => 2,
+            FifoFormat::C => 4,
+        }
+    }
+
+    fn idam(self) -> u32 {
+        match self {
+            FifoFormat::A => 0,
+            FifoFormat::B => 1,
+            FifoFormat::C => 2,
+        }
+    }
+}
+
+/// How received frames are filtered and buffered. The S32K148 FlexCAN has
+/// no enhanced RX FIFO; it first appears on the S32K3 family.
+#[derive(Debug, Clone, Copy)]
+pub enum RxConfig<'a> {
+    /// One receive mailbox per filter, each with its own mask. Holds up to
+    /// 31 filters, 6 with CAN FD.
+    Mailboxes(&'a [IdFilter]),
+    /// The legacy RX FIFO, six frames deep, with up to 96 filter table
+    /// elements. Classic CAN only.
+    Fifo {
+        format: FifoFormat,
+        filters: &'a [IdFilter],
+    },
+}
+
+impl Default for RxConfig<'_> {
+    fn default() -> Self {
+        // Everything, in one mailbox per identifier format
+        const ACCEPT_ALL: [IdFilter; 2] = [
+            IdFilter { id: Id::Standard(StandardId::ZERO), mask: 0 },
+            IdFilter { id: Id::Extended(ExtendedId::ZERO), mask: 0 },
+        ];
+        RxConfig::Mailboxes(&ACCEPT_ALL)
+    }
+}
+
+fn in_range(value: u32, max: u32) -> bool {
+    value >= 1 && value <= max
+}
+
+// Contents of a message buffer ID word
+fn encode_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => (id.as_raw() as u32) << ID_STD_SHIFT,
+        Id::Extended(id) => id.as_raw(),
+    }
+}
+
+fn decode_id(word: u32, extended: bool) -> Id {
+    if extended {
+        // Masking keeps the value within 29 bits
+        Id::Extended(ExtendedId::new(word & ID_EXT_MASK).unwrap())
+    } else {
+        Id::Standard(StandardId::new(((word >> ID_STD_SHIFT) & ID_STD_MASK) as u16).unwrap())
+    }
+}
+
+fn mb_code(cs: u32) -> u32 {
+    (cs & CS_CODE_MASK) >> CS_CODE_SHIFT
+}
+
+// Individual mask of a receive mailbox, laid out like its ID word
+fn mailbox_mask(filter: &IdFilter) -> u32 {
+    match filter.id {
+        Id::Standard(_) => (filter.mask & ID_STD_MASK) << ID_STD_SHIFT,
+        Id::Extended(_) => filter.mask & ID_EXT_MASK,
+    }
+}
+
+// Value and mask of one filter in a FIFO filter table element, right-aligned
+// within its share of the element. Data frames only, except with format C.
+fn fifo_filter(format: FifoFormat, filter: &IdFilter) -> (u32, u32) {
+    let (id, mask) = match filter.id {
+        Id::Standard(id) => (id.as_raw() as u32, filter.mask & ID_STD_MASK),
+        Id::Extended(id) => (id.as_raw(), filter.mask & ID_EXT_MASK),
+    };
+    let extended = matches!(filter.id, Id::Extended(_));
+    match (format, extended) {
+        (FifoFormat::A, false) => (id << 19, FILTER_A_RTR | FILTER_A_IDE | (mask << 19)),
+        (FifoFormat::A, true) => (FILTER_A_IDE | (id << 1), FILTER_A_RTR | FILTER_A_IDE | (mask << 1)),
+        (FifoFormat::B, false) => (id << 3, FILTER_B_RTR | FILTER_B_IDE | (mask << 3)),
+        (FifoFormat::B, true) => (FILTER_B_IDE | (id >> 15), FILTER_B_RTR | FILTER_B_IDE | (mask >> 15)),
+        (FifoFormat::C, false) => (id >> 3, mask >> 3),
+        (FifoFormat::C, true) => (id >> 21, mask >> 21),
+    }
+}
+
+// Value and mask of filter table element `index`. Slots beyond the given
+// filters repeat the last one, so they pass nothing new.
+fn fifo_element(format: FifoFormat, filters: &[IdFilter], index: usize) -> (u32, u32) {
+    let count = format.filters_per_element();
+    let width = 32 / count as u32;
+    let (mut value, mut mask) = (0, 0);
+    for slot in 0..count {
+        let filter = &filters[(index * count + slot).min(filters.len() - 1)];
+        let (slot_value, slot_mask) = fifo_filter(format, filter);
+        let shift = 32 - width * (slot as u32 + 1);
+        value |= slot_value << shift;
+        mask |= slot_mask << shift;
+    }
+    (value, mask)
+}
+
+// Filter control number: the table holds 8 * (RFFN + 1) elements
+fn fifo_rffn(format: FifoFormat, filters: &[IdFilter]) -> usize {
+    let elements = filters.len().div_ceil(format.filters_per_element());
+    elements.max(1).div_ceil(8) - 1
+}
+
+pub struct CanDevice<R: CanRegisterAccess + 'static = CanRegisters> {
+    registers: &'static mut R,
+    // Payload bytes per message buffer, 8 or 64 with CAN FD
+    mb_data_len: usize,
+    bit_rate_switch: bool,
+    // ESI flag of the last received frame
+    error_state_indicator: bool,
+    // Receive mailboxes come first, or the RX FIFO with its filter table;
+    // the transmit mailbox follows them
+    rx_fifo: bool,
+    tx_mb: usize,
+    bus_off_recovery: BusOffRecovery,
+}
+
+type ReceivedFrame = (Id, [u8; CANFD_MAX_DATA_LEN], u8);
+
+impl<R: CanRegisterAccess> CanDevice<R> {
+    pub fn new(registers: &'static mut R) -> Self {
         Self {
             registers,
-            _phantom: PhantomData,
+            mb_data_len: 8,
+            bit_rate_switch: false,
+            error_state_indicator: false,
+            rx_fifo: false,
+            tx_mb: 0,
+            bus_off_recovery: BusOffRecovery::Automatic,
         }
     }
 
-    pub fn init(&mut self) {
-        // Reset MCR register
-        self.registers.mcr.set(0x5000_0000);
+    /// Resets the controller and brings it onto the bus with the given
+    /// timing. Everything is configured in freeze mode, which is left last.
+    pub fn init(&mut self, config: &CanConfig) -> Result<(), CanError> {
+        let cbt = match &config.fd {
+            Some(fd) => fd.nominal.to_cbt(),
+            None => config.timing.to_cbt(),
+        }
+        .ok_or(CanError::InvalidBitTiming)?;
+        let fdcbt = match &config.fd {
+            Some(fd) => Some(fd.data.to_fdcbt().ok_or(CanError::InvalidBitTiming)?),
+            None => None,
+        };
+
+        // The clock source can only be selected while the module is disabled
+        self.registers.modify(MCR, |mcr| mcr | MCR_MDIS);
+        self.wait_mcr(MCR_LPMACK, true)?;
+        let clksrc = match config.clock_source {
+            ClockSource::Oscillator => 0,
+            ClockSource::Peripheral => CTRL1_CLKSRC,
+        };
+        self.registers.modify(CTRL1, |ctrl1| (ctrl1 & !CTRL1_CLKSRC) | clksrc);
+        self.registers.modify(MCR, |mcr| mcr & !MCR_MDIS);
+        self.wait_mcr(MCR_LPMACK, false)?;
+
+        // Soft reset returns MCR and the status registers to their defaults
+        self.registers.modify(MCR, |mcr| mcr | MCR_SOFTRST);
+        self.wait_mcr(MCR_SOFTRST, false)?;
+        self.enter_freeze()?;
 
-        // Configure CTRL1 register
-        self.registers.ctrl1.set(0x0000_0000);
+        let boffrec = match config.bus_off_recovery {
+            BusOffRecovery::Automatic => 0,
+            BusOffRecovery::Manual => CTRL1_BOFFREC,
+        };
+        self.registers.modify(CTRL1, |ctrl1| (ctrl1 & !CTRL1_BOFFREC) | boffrec);
+        self.registers.write(ESR1, ESR1_BOFFINT | ESR1_BOFFDONEINT);
+        self.bus_off_recovery = config.bus_off_recovery;
 
-        // Configure CTRL2 register
-        self.registers.ctrl2.set(0x0000_0000);
+        self.registers.write(CBT, cbt);
+        self.registers.write(CTRL2, 0);
+        match (&config.fd, fdcbt) {
+            (Some(fd), Some(fdcbt)) => {
+                self.registers.write(FDCBT, fdcbt);
 
-        // Clear all interrupt flags
-        self.registers.iflag1.set(0xFFFF_FFFF);
+                // 64 bytes per message buffer. The transceiver loop delay is
+                // compensated for the fast data phase.
+                let mut fdctrl = 3 << FDCTRL_MBDSR0_SHIFT;
+                if fd.bit_rate_switch {
+                    let data = &fd.data;
+                    let offset = (data.prop_seg as u32 + data.phase_seg1 as u32 + 2) * data.prescaler as u32;
+                    fdctrl |= FDCTRL_FDRATE | FDCTRL_TDCEN | (offset.min(31) << FDCTRL_TDCOFF_SHIFT);
+                }
+                self.registers.write(FDCTRL, fdctrl);
+                self.registers.modify(CTRL2, |ctrl2| ctrl2 | CTRL2_ISOCANFDEN);
+                self.registers.modify(MCR, |mcr| mcr | MCR_FDEN);
+                self.mb_data_len = CANFD_MAX_DATA_LEN;
+                self.bit_rate_switch = fd.bit_rate_switch;
+            }
+            _ => {
+                self.registers.write(FDCTRL, 0);
+                self.registers.modify(MCR, |mcr| mcr & !MCR_FDEN);
+                self.mb_data_len = 8;
+                self.bit_rate_switch = false;
+            }
+        }
 
-        // Disable all interrupts
-        self.registers.imask1.set(0x0000_0000);
+        // Polled operation
+        self.registers.write(IMASK1, 0);
+        self.apply_rx_config(&RxConfig::default())?;
+
+        self.exit_freeze()?;
+        self.wait_mcr(MCR_NOTRDY, false)
+    }
+
+    pub fn is_fd_enabled(&self) -> bool {
+        self.mb_data_len == CANFD_MAX_DATA_LEN
     }
 
-    pub fn send_frame(&mut self, id: u32, data: &[u8], len: u8) -> Result<(), CanError> {
-        if len > 8 {
+    /// Sends a frame with an 11-bit or 29-bit identifier. With CAN FD enabled
+    /// up to 64 bytes go out as an FD frame, padded with zeros to the next DLC.
+    pub fn send_frame(&mut self, id: impl Into<Id>, data: &[u8], len: u8) -> Result<(), CanError> {
+        let len = len as usize;
+        if len > self.mb_data_len || len > data.len() {
             return Err(CanError::InvalidLength);
         }
 
-        if id > 0x7FF {
-            return Err(CanError::InvalidId);
+        let dlc = len_to_dlc(len).ok_or(CanError::InvalidLength)?;
+        let mut flags = 0;
+        if self.is_fd_enabled() {
+            flags |= CS_EDL;
+            if self.bit_rate_switch {
+                flags |= CS_BRS;
+            }
         }
+        self.start_transmit(id.into(), &data[..len], dlc as u32, flags)
+    }
 
-        // Check if TX FIFO is full
-        if self.registers.esr1.get() & (1 << 4) != 0 {
+    // Hands a frame to the transmit mailbox. Data bytes up to the length
+    // of the DLC that `data` does not cover are sent as zeros.
+    fn start_transmit(&mut self, id: Id, data: &[u8], dlc: u32, flags: u32) -> Result<(), CanError> {
+        if self.get_error_state() == ErrorState::BusOff {
+            return Err(CanError::BusOff);
+        }
+        if self.is_transmit_pending() {
             return Err(CanError::TxFifoFull);
         }
 
-        // Write message buffer
-        let mb = &self.registers.mb[0];
-        mb.set(id << 18 | (len as u32) << 16);
+        let mut flags = flags | CS_SRR;
+        if let Id::Extended(_) = id {
+            flags |= CS_IDE;
+        }
+
+        // The completion flag of the previous frame is no longer of interest
+        self.registers.write(IFLAG1, 1 << self.tx_mb);
+        self.write_mb(self.tx_mb, 0, CODE_TX_INACTIVE << CS_CODE_SHIFT);
+        self.write_mb(self.tx_mb, 1, encode_id(id));
 
-        // Write data
-        let mut data_words = [0u32; 2];
-        for (i, byte) in data.iter().enumerate() {
-            let word_idx = i / 4;
-            let byte_idx = i % 4;
-            data_words[word_idx] |= (*byte as u32) << (24 - (byte_idx * 8));
+        // Data words are big-endian
+        let frame_len = dlc_to_len(dlc as u8, true);
+        for word in 0..frame_len.div_ceil(4) {
+            let mut bytes = [0u8; 4];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                if let Some(&value) = data.get(word * 4 + i) {
+                    *byte = value;
+                }
+            }
+            self.write_mb(self.tx_mb, 2 + word, u32::from_be_bytes(bytes));
         }
 
-        self.registers.mb[1].set(data_words[0]);
-        self.registers.mb[2].set(data_words[1]);
+        // Writing the code last hands the buffer to the controller
+        self.write_mb(self.tx_mb, 0, (CODE_TX_DATA << CS_CODE_SHIFT) | flags | (dlc << CS_DLC_SHIFT));
 
         Ok(())
     }
 
-    pub fn receive_frame(&mut self) -> Result<(u32, [u8; 8], u8), CanError> {
-        // Check if RX FIFO is empty
-        if self.registers.esr1.get() & (1 << 5) != 0 {
+    /// True while the transmit mailbox still holds a frame for the bus
+    pub fn is_transmit_pending(&self) -> bool {
+        mb_code(self.read_mb(self.tx_mb, 0)) == CODE_TX_DATA
+    }
+
+    /// Withdraws a frame that has not won arbitration yet
+    pub fn abort_transmit(&mut self) {
+        if self.is_transmit_pending() {
+            self.write_mb(self.tx_mb, 0, CODE_TX_INACTIVE << CS_CODE_SHIFT);
+        }
+        self.registers.write(IFLAG1, 1 << self.tx_mb);
+    }
+
+    /// Returns the identifier, payload and payload length of a received
+    /// frame. FD frames carry up to 64 bytes. Remote frames are dropped;
+    /// the `embedded_can` interface passes them on.
+    pub fn receive_frame(&mut self) -> Result<ReceivedFrame, CanError> {
+        loop {
+            let frame = self.take_frame()?;
+            if !frame.remote {
+                return Ok((frame.id, frame.data, frame.len));
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> Result<CanFrame, CanError> {
+        if self.rx_fifo {
+            self.receive_fifo()
+        } else {
+            self.receive_mailbox()
+        }
+    }
+
+    // Takes the frame of the lowest full receive mailbox
+    fn receive_mailbox(&mut self) -> Result<CanFrame, CanError> {
+        let iflag = self.registers.read(IFLAG1);
+        let mb = (0..self.tx_mb)
+            .find(|&mb| iflag & (1 << mb) != 0)
+            .ok_or(CanError::RxFifoEmpty)?;
+
+        // Reading the control word locks the mailbox until the timer is read
+        let cs = self.read_mb(mb, 0);
+        let code = mb_code(cs);
+        if code & CODE_RX_BUSY != 0 {
+            return Err(CanError::RxFifoEmpty);
+        }
+        if code != CODE_RX_FULL && code != CODE_RX_OVERRUN {
+            self.registers.write(IFLAG1, 1 << mb);
+            return Err(CanError::RxFifoEmpty);
+        }
+
+        let frame = self.read_frame(mb, cs);
+        self.write_mb(mb, 0, (CODE_RX_EMPTY << CS_CODE_SHIFT) | (cs & CS_IDE));
+        self.registers.write(IFLAG1, 1 << mb);
+        let _ = self.registers.read(TIMER);
+
+        Ok(frame)
+    }
+
+    // Takes the oldest frame of the RX FIFO, which message buffer 0 shows
+    fn receive_fifo(&mut self) -> Result<CanFrame, CanError> {
+        let iflag = self.registers.read(IFLAG1);
+        if iflag & IFLAG_FIFO_AVAILABLE == 0 {
             return Err(CanError::RxFifoEmpty);
         }
 
-        // Read message buffer
-        let mb = self.registers.mb[0].get();
-        let id = (mb >> 18) & 0x7FF;
-        let len = ((mb >> 16) & 0x0F) as u8;
+        let frame = self.read_frame(0, self.read_mb(0, 0));
+
+        // Clearing the available flag moves the FIFO on. Lost frames are
+        // not reported beyond that.
+        self.registers.write(
+            IFLAG1,
+            IFLAG_FIFO_AVAILABLE | (iflag & (IFLAG_FIFO_WARNING | IFLAG_FIFO_OVERFLOW)),
+        );
+
+        Ok(frame)
+    }
+
+    fn read_frame(&mut self, mb: usize, cs: u32) -> CanFrame {
+        let id = decode_id(self.read_mb(mb, 1), cs & CS_IDE != 0);
+        let dlc = ((cs >> CS_DLC_SHIFT) & 0xF) as u8;
+        let fd = cs & CS_EDL != 0;
+        if cs & CS_RTR != 0 {
+            // Remote frames carry the requested length in the DLC
+            return CanFrame {
+                len: dlc.min(CAN_MAX_DATA_LEN as u8),
+                remote: true,
+                ..CanFrame::empty(id)
+            };
+        }
+
+        let len = dlc_to_len(dlc, fd).min(self.mb_data_len);
+
+        let mut data = [0u8; CANFD_MAX_DATA_LEN];
+        for (word, chunk) in data[..len].chunks_mut(4).enumerate() {
+            let bytes = self.read_mb(mb, 2 + word).to_be_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+
+        self.error_state_indicator = cs & CS_ESI != 0;
+        CanFrame {
+            id,
+            data,
+            len: len as u8,
+            remote: false,
+            fd,
+            bit_rate_switch: cs & CS_BRS != 0,
+            error_state_indicator: cs & CS_ESI != 0,
+        }
+    }
+
+    /// True when the last received FD frame came from an error passive node
+    pub fn is_error_state_indicator(&self) -> bool {
+        self.error_state_indicator
+    }
+
+    /// Replaces the acceptance filters, and with them the receive mailboxes
+    /// or the RX FIFO. `init` sets up `RxConfig::default()`, which passes
+    /// every frame. Frames waiting to be received or sent are dropped.
+    pub fn configure_rx(&mut self, config: &RxConfig) -> Result<(), CanError> {
+        self.enter_freeze()?;
+        let result = self.apply_rx_config(config);
+        self.exit_freeze()?;
+        result
+    }
 
-        // Read data
-        let data_word0 = self.registers.mb[1].get();
-        let data_word1 = self.registers.mb[2].get();
+    pub fn is_rx_fifo_enabled(&self) -> bool {
+        self.rx_fifo
+    }
 
-        let mut data = [0u8; 8];
-        for i in 0..len as usize {
-            let word_idx = i / 4;
-            let byte_idx = i % 4;
-            let word = if word_idx == 0 { data_word0 } else { data_word1 };
-            data[i] = ((word >> (24 - (byte_idx * 8))) & 0xFF) as u8;
+    pub fn get_error_state(&self) -> ErrorState {
+        match (self.registers.read(ESR1) & ESR1_FLTCONF_MASK) >> ESR1_FLTCONF_SHIFT {
+            0 => ErrorState::Active,
+            1 => ErrorState::Passive,
+            _ => ErrorState::BusOff,
         }
+    }
+
+    /// Transmit and receive error counters (TEC, REC)
+    pub fn get_error_counters(&self) -> (u8, u8) {
+        let ecr = self.registers.read(ECR);
+        (ecr as u8, (ecr >> ECR_RXERRCNT_SHIFT) as u8)
+    }
+
+    /// True once after the controller went bus-off, even when it has
+    /// recovered since
+    pub fn take_bus_off_event(&mut self) -> bool {
+        let bus_off = self.registers.read(ESR1) & ESR1_BOFFINT != 0;
+        if bus_off {
+            self.registers.write(ESR1, ESR1_BOFFINT);
+        }
+        bus_off
+    }
+
+    /// Brings the controller back onto the bus after bus-off. Returns
+    /// `WouldBlock` until the recovery sequence has completed; a frame
+    /// waiting for transmission is dropped. With manual recovery the first
+    /// call starts the sequence.
+    pub fn recover_bus_off(&mut self) -> nb::Result<(), CanError> {
+        let bus_off = self.get_error_state() == ErrorState::BusOff;
+        if self.bus_off_recovery == BusOffRecovery::Automatic {
+            return if bus_off { Err(nb::Error::WouldBlock) } else { Ok(()) };
+        }
+
+        let recovering = self.registers.read(CTRL1) & CTRL1_BOFFREC == 0;
+        if bus_off && !recovering {
+            self.abort_transmit();
+            self.registers.modify(CTRL1, |ctrl1| ctrl1 & !CTRL1_BOFFREC);
+            return Err(nb::Error::WouldBlock);
+        }
+        if bus_off {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // Back on the bus: the next bus-off waits for another call again
+        if recovering {
+            self.registers.modify(CTRL1, |ctrl1| ctrl1 | CTRL1_BOFFREC);
+            self.registers.write(ESR1, ESR1_BOFFDONEINT);
+        }
+        Ok(())
+    }
+
+    pub fn get_bus_off_recovery(&self) -> BusOffRecovery {
+        self.bus_off_recovery
+    }
+
+    // Byte offset of word `index` of message buffer `mb`: control/status,
+    // ID, then data
+    fn mb_offset(&self, mb: usize, index: usize) -> usize {
+        let stride = 2 + self.mb_data_len / 4;
+        MB_RAM + (mb * stride + index) * 4
+    }
+
+    fn read_mb(&self, mb: usize, index: usize) -> u32 {
+        self.registers.read(self.mb_offset(mb, index))
+    }
+
+    fn write_mb(&mut self, mb: usize, index: usize, value: u32) {
+        let offset = self.mb_offset(mb, index);
+        self.registers.write(offset, value)
+    }
+
+    // Message buffers of 8 or 64 bytes in the RAM
+    fn mb_count(&self) -> usize {
+        MB_RAM_WORDS / (2 + self.mb_data_len / 4)
+    }
+
+    // Lays out the message buffers for `config`. Runs in freeze mode and
+    // leaves the hardware untouched when the filters do not fit.
+    fn apply_rx_config(&mut self, config: &RxConfig) -> Result<(), CanError> {
+        let tx_mb = match *config {
+            RxConfig::Mailboxes(filters) => {
+                if filters.is_empty() || filters.len() >= self.mb_count() {
+                    return Err(CanError::InvalidFilter);
+                }
+                filters.len()
+            }
+            RxConfig::Fifo { format, filters } => {
+                if filters.is_empty() || self.is_fd_enabled() {
+                    return Err(CanError::InvalidFilter);
+                }
+                let rffn = fifo_rffn(format, filters);
+                let tx_mb = FIFO_MB_COUNT + 2 * (rffn + 1);
+                if tx_mb >= self.mb_count() {
+                    return Err(CanError::InvalidFilter);
+                }
+
+                // Elements past those with an individual mask share RXFGMASK
+                let masked = tx_mb.min(RXIMR_COUNT);
+                let mut shared = (masked..8 * (rffn + 1)).map(|element| fifo_element(format, filters, element).1);
+                if let Some(first) = shared.next() {
+                    if shared.any(|mask| mask != first) {
+                        return Err(CanError::InvalidFilter);
+                    }
+                }
+                tx_mb
+            }
+        };
+
+        // Message buffer RAM and individual masks come out of reset undefined
+        for word in 0..MB_RAM_WORDS {
+            self.registers.write(MB_RAM + word * 4, 0);
+        }
+        for mb in 0..RXIMR_COUNT {
+            self.registers.write(RXIMR + mb * 4, 0);
+        }
+
+        match *config {
+            RxConfig::Mailboxes(filters) => {
+                // The IDE bit is always compared, the masks apply to the
+                // identifier alone
+                for (mb, filter) in filters.iter().enumerate() {
+                    let ide = if let Id::Extended(_) = filter.id { CS_IDE } else { 0 };
+                    self.registers.write(RXIMR + mb * 4, mailbox_mask(filter));
+                    self.write_mb(mb, 1, encode_id(filter.id));
+                    self.write_mb(mb, 0, (CODE_RX_EMPTY << CS_CODE_SHIFT) | ide);
+                }
+                self.registers.modify(MCR, |mcr| mcr & !MCR_RFEN);
+                self.registers.modify(CTRL2, |ctrl2| ctrl2 & !CTRL2_RFFN_MASK);
+            }
+            RxConfig::Fifo { format, filters } => {
+                let rffn = fifo_rffn(format, filters);
+                let masked = tx_mb.min(RXIMR_COUNT);
+                for element in 0..8 * (rffn + 1) {
+                    let (value, mask) = fifo_element(format, filters, element);
+                    self.registers.write(MB_RAM + FIFO_MB_COUNT * 16 + element * 4, value);
+                    if element < masked {
+                        self.registers.write(RXIMR + element * 4, mask);
+                    } else {
+                        self.registers.write(RXFGMASK, mask);
+                    }
+                }
+                self.registers.modify(MCR, |mcr| {
+                    (mcr & !MCR_IDAM_MASK) | MCR_RFEN | (format.idam() << MCR_IDAM_SHIFT)
+                });
+                self.registers.modify(CTRL2, |ctrl2| {
+                    (ctrl2 & !CTRL2_RFFN_MASK) | ((rffn as u32) << CTRL2_RFFN_SHIFT)
+                });
+            }
+        }
+
+        self.tx_mb = tx_mb;
+        self.rx_fifo = matches!(config, RxConfig::Fifo { .. });
+        self.write_mb(tx_mb, 0, CODE_TX_INACTIVE << CS_CODE_SHIFT);
+
+        // Individual masks throughout, and own frames are not received back
+        self.registers.modify(MCR, |mcr| {
+            (mcr & !MCR_MAXMB_MASK) | MCR_SRXDIS | MCR_IRMQ | tx_mb as u32
+        });
+        self.registers.write(IFLAG1, 0xFFFF_FFFF);
+        Ok(())
+    }
+
+    fn enter_freeze(&mut self) -> Result<(), CanError> {
+        self.registers.modify(MCR, |mcr| (mcr & !MCR_MDIS) | MCR_FRZ | MCR_HALT);
+        self.wait_mcr(MCR_FRZACK, true)
+    }
+
+    fn exit_freeze(&mut self) -> Result<(), CanError> {
+        self.registers.modify(MCR, |mcr| mcr & !(MCR_FRZ | MCR_HALT));
+        self.wait_mcr(MCR_FRZACK, false)
+    }
+
+    // Waits for MCR status bits to follow a mode change
+    fn wait_mcr(&self, mask: u32, set: bool) -> Result<(), CanError> {
+        let mut loops = 0;
+        while (self.registers.read(MCR) & mask != 0) != set {
+            loops += 1;
+            if loops > MODE_TIMEOUT_LOOPS {
+                return Err(CanError::Timeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: CanRegisterAccess> embedded_can::nb::Can for CanDevice<R> {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    // A frame waiting in the transmit mailbox is never replaced
+    fn transmit(&mut self, frame: &CanFrame) -> nb::Result<Option<CanFrame>, CanError> {
+        if frame.fd && !self.is_fd_enabled() {
+            return Err(nb::Error::Other(CanError::InvalidLength));
+        }
+
+        let mut flags = 0;
+        if frame.remote {
+            flags |= CS_RTR;
+        }
+        if frame.fd {
+            flags |= CS_EDL;
+            if frame.bit_rate_switch {
+                flags |= CS_BRS;
+            }
+        }
+        match self.start_transmit(frame.id, frame.data(), frame.dlc() as u32, flags) {
+            Ok(()) => Ok(None),
+            Err(CanError::TxFifoFull) => Err(nb::Error::WouldBlock),
+            Err(e) => Err(nb::Error::Other(e)),
+        }
+    }
+
+    fn receive(&mut self) -> nb::Result<CanFrame, CanError> {
+        match self.take_frame() {
+            Ok(frame) => Ok(frame),
+            Err(CanError::RxFifoEmpty) => Err(nb::Error::WouldBlock),
+            Err(e) => Err(nb::Error::Other(e)),
+        }
+    }
+}
+
+impl<R: CanRegisterAccess> embedded_can::blocking::Can for CanDevice<R> {
+    type Frame = CanFrame;
+    type Error = CanError;
+
+    // Waits for the transmit mailbox to take the frame, not for the frame
+    // to go out
+    fn transmit(&mut self, frame: &CanFrame) -> Result<(), CanError> {
+        nb::block!(embedded_can::nb::Can::transmit(self, frame)).map(|_| ())
+    }
+
+    fn receive(&mut self) -> Result<CanFrame, CanError> {
+        nb::block!(embedded_can::nb::Can::receive(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    // Clock, bitrate and sample point of setups seen on real buses
+    const TIMING_TABLE: [(u32, u32, u16); 8] = [
+        (8_000_000, 125_000, 875),
+        (8_000_000, 250_000, 875),
+        (8_000_000, 500_000, 875),
+        (8_000_000, 1_000_000, 750),
+        (40_000_000, 500_000, 800),
+        (48_000_000, 250_000, 875),
+        (80_000_000, 500_000, 875),
+        (80_000_000, 1_000_000, 800),
+    ];
+
+    #[test]
+    fn nominal_timings_are_exact() {
+        for (clock_hz, bitrate, sample_point) in TIMING_TABLE {
+            let timing = BitTiming::calculate(clock_hz, bitrate, sample_point).unwrap();
+            assert_eq!(timing.prescaler as u32 * timing.get_quanta() * bitrate, clock_hz);
+            assert_eq!(timing.get_bitrate(clock_hz), bitrate);
+            assert!(timing.get_sample_point().abs_diff(sample_point) <= 25, "{:?}", timing);
+            assert!(timing.sjw <= timing.phase_seg1.min(timing.phase_seg2));
+            assert!(timing.to_cbt().is_some(), "{:?}", timing);
+        }
+    }
+
+    #[test]
+    fn most_quanta_win_among_equal_sample_points() {
+        let timing = BitTiming::calculate(8_000_000, 500_000, 875).unwrap();
+        assert_eq!(
+            timing,
+            BitTiming { prescaler: 1, prop_seg: 11, phase_seg1: 2, phase_seg2: 2, sjw: 2 }
+        );
+        assert_eq!(timing.get_sample_point(), 875);
+    }
+
+    #[test]
+    fn impossible_timings_are_refused() {
+        assert_eq!(BitTiming::calculate(8_000_000, 0, 875), Err(BitTimingError::InvalidBitrate));
+        assert_eq!(BitTiming::calculate(8_000_000, 2_000_000, 875), Err(BitTimingError::InvalidBitrate));
+        assert_eq!(BitTiming::calculate(8_000_000, 500_000, 400), Err(BitTimingError::InvalidSamplePoint));
+        assert_eq!(BitTiming::calculate(8_000_000, 500_000, 960), Err(BitTimingError::InvalidSamplePoint));
+        assert_eq!(
+            BitTiming::calculate(48_000_000, 33_333, 875),
+            Err(BitTimingError::NoExactMatch { clock_hz: 48_000_000, bitrate: 33_333 })
+        );
+    }
+
+    #[test]
+    fn fd_phases_share_a_prescaler() {
+        let timing = FdBitTiming::calculate(80_000_000, 500_000, 2_000_000, 800).unwrap();
+        assert!(timing.bit_rate_switch);
+        assert_eq!(timing.nominal.prescaler, timing.data.prescaler);
+        assert_eq!(timing.nominal.get_bitrate(80_000_000), 500_000);
+        assert_eq!(timing.data.get_bitrate(80_000_000), 2_000_000);
+        assert!(timing.nominal.to_cbt().is_some());
+        assert!(timing.data.to_fdcbt().is_some());
+
+        let timing = FdBitTiming::calculate(80_000_000, 500_000, 500_000, 800).unwrap();
+        assert!(!timing.bit_rate_switch);
+    }
+
+    #[test]
+    fn timing_register_encodings() {
+        // CanConfig::default: 500 kbit/s from 8 MHz
+        let timing = CanConfig::default().timing;
+        assert_eq!(
+            timing.to_cbt(),
+            Some(CBT_BTF | (2 << 16) | (5 << 10) | (5 << 5) | 2)
+        );
+        assert_eq!(timing.get_bitrate(8_000_000), 500_000);
+
+        // FPROPSEG is stored as is, and may be zero
+        let data = BitTiming { prescaler: 2, prop_seg: 0, phase_seg1: 3, phase_seg2: 2, sjw: 2 };
+        assert_eq!(data.to_fdcbt(), Some((1 << 20) | (1 << 16) | (2 << 5) | 1));
+        assert_eq!(BitTiming { phase_seg2: 1, ..data }.to_fdcbt(), None);
+        assert_eq!(BitTiming { phase_seg1: 9, ..data }.to_fdcbt(), None);
+        assert_eq!(BitTiming { prop_seg: 0, ..timing }.to_cbt(), None);
+        assert_eq!(BitTiming { prescaler: 1025, ..timing }.to_cbt(), None);
+    }
+
+    // FlexCAN whose mode changes are acknowledged at once. Flags are
+    // cleared by writing ones, like on the hardware.
+    struct SimFlexcan {
+        words: [u32; (FDCRC + 4) / 4],
+    }
+
+    impl SimFlexcan {
+        fn new() -> &'static mut Self {
+            Box::leak(Box::new(Self { words: [0; (FDCRC + 4) / 4] }))
+        }
+    }
+
+    impl CanRegisterAccess for SimFlexcan {
+        fn read(&self, offset: usize) -> u32 {
+            self.words[offset / 4]
+        }
+
+        fn write(&mut self, offset: usize, value: u32) {
+            let value = match offset {
+                MCR => {
+                    let mut mcr = value & !(MCR_SOFTRST | MCR_LPMACK | MCR_FRZACK | MCR_NOTRDY);
+                    if mcr & MCR_MDIS != 0 {
+                        mcr |= MCR_LPMACK | MCR_NOTRDY;
+                    }
+                    if mcr & (MCR_FRZ | MCR_HALT) == MCR_FRZ | MCR_HALT {
+                        mcr |= MCR_FRZACK | MCR_NOTRDY;
+                    }
+                    mcr
+                }
+                IFLAG1 => self.read(IFLAG1) & !value,
+                ESR1 => self.read(ESR1) & !(value & (ESR1_BOFFINT | ESR1_BOFFDONEINT)),
+                _ => value,
+            };
+            self.words[offset / 4] = value;
+        }
+    }
+
+    fn device() -> CanDevice<SimFlexcan> {
+        let mut can = CanDevice::new(SimFlexcan::new());
+        can.init(&CanConfig::default()).unwrap();
+        can
+    }
+
+    fn element(can: &CanDevice<SimFlexcan>, index: usize) -> u32 {
+        can.registers.read(MB_RAM + FIFO_MB_COUNT * 16 + index * 4)
+    }
+
+    // Puts a received frame into message buffer `mb` and raises its flag
+    fn deliver(can: &mut CanDevice<SimFlexcan>, mb: usize, flag: u32, cs: u32, id: Id, data: [u8; 8]) {
+        can.write_mb(mb, 1, encode_id(id));
+        can.write_mb(mb, 2, u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+        can.write_mb(mb, 3, u32::from_be_bytes([data[4], data[5], data[6], data[7]]));
+        can.write_mb(mb, 0, cs);
+        let iflag = can.registers.read(IFLAG1) | flag;
+        can.registers.words[IFLAG1 / 4] = iflag;
+    }
+
+    #[test]
+    fn mailbox_filters_have_individual_masks() {
+        let mut can = device();
+        let filters = [
+            IdFilter::exact(StandardId::new(0x667).unwrap()),
+            IdFilter::new(ExtendedId::new(0x1234_5600).unwrap(), 0x1FFF_FF00),
+        ];
+        can.configure_rx(&RxConfig::Mailboxes(&filters)).unwrap();
+
+        assert!(!can.is_rx_fifo_enabled());
+        assert_eq!(can.registers.read(RXIMR), 0x7FF << ID_STD_SHIFT);
+        assert_eq!(can.read_mb(0, 1), 0x667 << ID_STD_SHIFT);
+        assert_eq!(can.read_mb(0, 0), CODE_RX_EMPTY << CS_CODE_SHIFT);
+        assert_eq!(can.registers.read(RXIMR + 4), 0x1FFF_FF00);
+        assert_eq!(can.read_mb(1, 1), 0x1234_5600);
+        assert_eq!(can.read_mb(1, 0), (CODE_RX_EMPTY << CS_CODE_SHIFT) | CS_IDE);
+        assert_eq!(mb_code(can.read_mb(2, 0)), CODE_TX_INACTIVE);
+
+        let mcr = can.registers.read(MCR);
+        assert_eq!(mcr & MCR_MAXMB_MASK, 2);
+        assert_eq!(mcr & (MCR_RFEN | MCR_FRZ | MCR_HALT), 0);
+        assert_ne!(mcr & MCR_IRMQ, 0);
+    }
+
+    #[test]
+    fn mailbox_frames_are_received() {
+        let mut can = device();
+        let id = Id::Extended(ExtendedId::new(0x18DA_00F1).unwrap());
+        let cs = (CODE_RX_FULL << CS_CODE_SHIFT) | CS_IDE | (3 << CS_DLC_SHIFT);
+        deliver(&mut can, 1, 1 << 1, cs, id, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let (received, data, len) = can.receive_frame().unwrap();
+        assert_eq!(received, id);
+        assert_eq!(&data[..len as usize], &[1, 2, 3]);
+        assert_eq!(can.read_mb(1, 0), (CODE_RX_EMPTY << CS_CODE_SHIFT) | CS_IDE);
+        assert_eq!(can.registers.read(IFLAG1), 0);
+        assert!(matches!(can.receive_frame(), Err(CanError::RxFifoEmpty)));
+    }
+
+    #[test]
+    fn fifo_filter_table_format_a() {
+        let mut can = device();
+        let filters = [
+            IdFilter::exact(StandardId::new(0x667).unwrap()),
+            IdFilter::new(StandardId::new(0x700).unwrap(), 0x700),
+            IdFilter::exact(ExtendedId::new(0x1234_5678).unwrap()),
+        ];
+        can.configure_rx(&RxConfig::Fifo { format: FifoFormat::A, filters: &filters }).unwrap();
+
+        assert!(can.is_rx_fifo_enabled());
+        assert_eq!(element(&can, 0), 0x667 << 19);
+        assert_eq!(can.registers.read(RXIMR), FILTER_A_RTR | FILTER_A_IDE | (0x7FF << 19));
+        assert_eq!(element(&can, 1), 0x700 << 19);
+        assert_eq!(can.registers.read(RXIMR + 4), FILTER_A_RTR | FILTER_A_IDE | (0x700 << 19));
+        assert_eq!(element(&can, 2), FILTER_A_IDE | (0x1234_5678 << 1));
+        // The rest of the eight elements repeat the last filter
+        for index in 3..8 {
+            assert_eq!(element(&can, index), element(&can, 2));
+        }
+
+        let mcr = can.registers.read(MCR);
+        assert_ne!(mcr & MCR_RFEN, 0);
+        assert_eq!(mcr & MCR_IDAM_MASK, 0);
+        assert_eq!(mcr & MCR_MAXMB_MASK, 8);
+        assert_eq!(can.registers.read(CTRL2) & CTRL2_RFFN_MASK, 0);
+    }
+
+    #[test]
+    fn fifo_filter_table_format_b() {
+        let mut can = device();
+        let filters = [
+            IdFilter::exact(StandardId::new(0x667).unwrap()),
+            IdFilter::exact(StandardId::new(0x7E0).unwrap()),
+            IdFilter::accept_all(ExtendedId::ZERO),
+        ];
+        can.configure_rx(&RxConfig::Fifo { format: FifoFormat::B, filters: &filters }).unwrap();
+
+        assert_eq!(element(&can, 0), (0x667 << 19) | (0x7E0 << 3));
+        assert_eq!(element(&can, 1), (FILTER_B_IDE << 16) | FILTER_B_IDE);
+        assert_eq!(
+            can.registers.read(RXIMR + 4),
+            ((FILTER_B_RTR | FILTER_B_IDE) << 16) | FILTER_B_RTR | FILTER_B_IDE
+        );
+        assert_eq!(can.registers.read(MCR) & MCR_IDAM_MASK, 1 << MCR_IDAM_SHIFT);
+    }
+
+    #[test]
+    fn fifo_elements_beyond_the_individual_masks_share_one() {
+        let mut can = device();
+
+        // 40 elements: RFFN 4, the FIFO and table take message buffers
+        // 0-15, elements 16-39 use RXFGMASK
+        let mut filters = [IdFilter::exact(StandardId::ZERO); 40];
+        for (index, filter) in filters.iter_mut().enumerate() {
+            filter.id = Id::Standard(StandardId::new(index as u16).unwrap());
+        }
+        can.configure_rx(&RxConfig::Fifo { format: FifoFormat::A, filters: &filters }).unwrap();
+        assert_eq!(can.registers.read(CTRL2) & CTRL2_RFFN_MASK, 4 << CTRL2_RFFN_SHIFT);
+        assert_eq!(can.registers.read(RXFGMASK), FILTER_A_RTR | FILTER_A_IDE | (0x7FF << 19));
+        assert_eq!(element(&can, 39), 39 << 19);
+
+        filters[30].mask = 0x700;
+        assert!(matches!(
+            can.configure_rx(&RxConfig::Fifo { format: FifoFormat::A, filters: &filters }),
+            Err(CanError::InvalidFilter)
+        ));
+        // The previous configuration stays
+        assert_eq!(element(&can, 30), 30 << 19);
+        assert!(can.is_rx_fifo_enabled());
+    }
+
+    #[test]
+    fn filters_that_do_not_fit_are_refused() {
+        let mut can = device();
+        let filters = [IdFilter::exact(StandardId::ZERO); 32];
+        assert!(matches!(can.configure_rx(&RxConfig::Mailboxes(&filters)), Err(CanError::InvalidFilter)));
+        assert!(matches!(can.configure_rx(&RxConfig::Mailboxes(&[])), Err(CanError::InvalidFilter)));
+
+        let filters = [IdFilter::exact(StandardId::ZERO); 97];
+        let fifo = RxConfig::Fifo { format: FifoFormat::A, filters: &filters };
+        assert!(matches!(can.configure_rx(&fifo), Err(CanError::InvalidFilter)));
+
+        // No RX FIFO with CAN FD
+        let timing = FdBitTiming::calculate(40_000_000, 500_000, 2_000_000, 800).unwrap();
+        can.init(&CanConfig::default().with_fd(timing)).unwrap();
+        let fifo = RxConfig::Fifo { format: FifoFormat::A, filters: &filters[..1] };
+        assert!(matches!(can.configure_rx(&fifo), Err(CanError::InvalidFilter)));
+    }
+
+    #[test]
+    fn fifo_frames_are_received() {
+        let mut can = device();
+        let filters = [IdFilter::accept_all(StandardId::ZERO)];
+        can.configure_rx(&RxConfig::Fifo { format: FifoFormat::A, filters: &filters }).unwrap();
+
+        let id = Id::Standard(StandardId::new(0x667).unwrap());
+        let flags = IFLAG_FIFO_AVAILABLE | IFLAG_FIFO_OVERFLOW;
+        deliver(&mut can, 0, flags, 2 << CS_DLC_SHIFT, id, [0xFF, 0x00, 0, 0, 0, 0, 0, 0]);
 
-        Ok((id, data, len))
+        let (received, data, len) = can.receive_frame().unwrap();
+        assert_eq!(received, id);
+        assert_eq!(&data[..len as usize], &[0xFF, 0x00]);
+        assert_eq!(can.registers.read(IFLAG1), 0);
+        assert!(matches!(can.receive_frame(), Err(CanError::RxFifoEmpty)));
     }
 }
@@ -0,0 +1,431 @@
+use core::sync::atomic::{fence, Ordering};
+use vcell::VolatileCell;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use crate::clock::Clock;
+
+pub const ENET_BASE: u32 = 0x4007_9000;
+
+/// Number of receive and transmit buffer descriptors
+pub const ENET_RX_RING_LEN: usize = 4;
+pub const ENET_TX_RING_LEN: usize = 4;
+
+// Receive buffer size, a multiple of 16 that holds a full frame
+const ENET_BUFFER_LEN: usize = 1536;
+
+// Largest frame including the FCS
+const ENET_MAX_FRAME_LEN: u32 = 1518;
+
+// MTU handed to smoltcp: the frame without FCS
+const ENET_MTU: usize = 1514;
+
+// Poll iterations before a reset or MDIO transfer counts as failed
+const ENET_TIMEOUT_LOOPS: u32 = 100_000;
+
+// ECR
+const ECR_RESET: u32 = 1 << 0;
+const ECR_ETHEREN: u32 = 1 << 1;
+const ECR_DBSWP: u32 = 1 << 8;
+
+// EIR/EIMR
+const EIR_MII: u32 = 1 << 23;
+
+// RCR
+const RCR_MII_MODE: u32 = 1 << 2;
+const RCR_RMII_MODE: u32 = 1 << 8;
+const RCR_MAX_FL_SHIFT: u32 = 16;
+
+// TCR
+const TCR_FDEN: u32 = 1 << 2;
+
+// TFWR
+const TFWR_STRFWD: u32 = 1 << 8;
+
+// RDAR/TDAR
+const DESCRIPTOR_ACTIVE: u32 = 1 << 24;
+
+// MMFR: clause 22 frame with read/write opcode and turnaround
+const MMFR_ST: u32 = 0b01 << 30;
+const MMFR_OP_WRITE: u32 = 0b01 << 28;
+const MMFR_OP_READ: u32 = 0b10 << 28;
+const MMFR_PA_SHIFT: u32 = 23;
+const MMFR_RA_SHIFT: u32 = 18;
+const MMFR_TA: u32 = 0b10 << 16;
+
+// MDC must stay below 2.5 MHz
+const MDC_MAX_HZ: u32 = 2_500_000;
+
+// Receive buffer descriptor control bits
+const RX_EMPTY: u16 = 1 << 15;
+const RX_WRAP: u16 = 1 << 13;
+const RX_LAST: u16 = 1 << 11;
+// Length violation, non-octet, CRC error, overrun and truncated
+const RX_ERRORS: u16 = (1 << 5) | (1 << 4) | (1 << 2) | (1 << 1) | (1 << 0);
+
+// Transmit buffer descriptor control bits
+const TX_READY: u16 = 1 << 15;
+const TX_WRAP: u16 = 1 << 13;
+const TX_LAST: u16 = 1 << 11;
+const TX_CRC: u16 = 1 << 10;
+
+#[derive(Debug)]
+pub enum EnetError {
+    ResetTimeout,
+    MdioTimeout,
+}
+
+#[repr(C)]
+pub struct EnetRegisters {
+    _reserved0: u32,
+    eir: VolatileCell<u32>,
+    eimr: VolatileCell<u32>,
+    _reserved1: u32,
+    rdar: VolatileCell<u32>,
+    tdar: VolatileCell<u32>,
+    _reserved2: [u32; 3],
+    ecr: VolatileCell<u32>,
+    _reserved3: [u32; 6],
+    mmfr: VolatileCell<u32>,
+    mscr: VolatileCell<u32>,
+    _reserved4: [u32; 7],
+    mibc: VolatileCell<u32>,
+    _reserved5: [u32; 7],
+    rcr: VolatileCell<u32>,
+    _reserved6: [u32; 15],
+    tcr: VolatileCell<u32>,
+    _reserved7: [u32; 7],
+    palr: VolatileCell<u32>,
+    paur: VolatileCell<u32>,
+    opd: VolatileCell<u32>,
+    _reserved8: [u32; 10],
+    iaur: VolatileCell<u32>,
+    ialr: VolatileCell<u32>,
+    gaur: VolatileCell<u32>,
+    galr: VolatileCell<u32>,
+    _reserved9: [u32; 7],
+    tfwr: VolatileCell<u32>,
+    _reserved10: [u32; 14],
+    rdsr: VolatileCell<u32>,
+    tdsr: VolatileCell<u32>,
+    mrbr: VolatileCell<u32>,
+    _reserved11: u32,
+    rsfl: VolatileCell<u32>,
+    rsem: VolatileCell<u32>,
+    raem: VolatileCell<u32>,
+    rafl: VolatileCell<u32>,
+    tsem: VolatileCell<u32>,
+    taem: VolatileCell<u32>,
+    tafl: VolatileCell<u32>,
+    tipg: VolatileCell<u32>,
+    ftrl: VolatileCell<u32>,
+    _reserved12: [u32; 3],
+    tacc: VolatileCell<u32>,
+    racc: VolatileCell<u32>,
+}
+
+// Legacy buffer descriptor, little-endian with ECR[DBSWP] set
+#[repr(C)]
+struct BufferDescriptor {
+    length: VolatileCell<u16>,
+    control: VolatileCell<u16>,
+    buffer: VolatileCell<u32>,
+}
+
+impl BufferDescriptor {
+    const fn new() -> Self {
+        Self {
+            length: VolatileCell::new(0),
+            control: VolatileCell::new(0),
+            buffer: VolatileCell::new(0),
+        }
+    }
+}
+
+#[repr(C, align(16))]
+struct DescriptorRing<const N: usize>([BufferDescriptor; N]);
+
+#[repr(C, align(16))]
+struct Buffer([u8; ENET_BUFFER_LEN]);
+
+/// Descriptor rings and frame buffers the MAC accesses by DMA. Place it in
+/// a static; it must not move once the device is running.
+pub struct EnetStorage {
+    rx_descriptors: DescriptorRing<ENET_RX_RING_LEN>,
+    tx_descriptors: DescriptorRing<ENET_TX_RING_LEN>,
+    rx_buffers: [Buffer; ENET_RX_RING_LEN],
+    tx_buffers: [Buffer; ENET_TX_RING_LEN],
+}
+
+impl EnetStorage {
+    pub const fn new() -> Self {
+        Self {
+            rx_descriptors: DescriptorRing([const { BufferDescriptor::new() }; ENET_RX_RING_LEN]),
+            tx_descriptors: DescriptorRing([const { BufferDescriptor::new() }; ENET_TX_RING_LEN]),
+            rx_buffers: [const { Buffer([0; ENET_BUFFER_LEN]) }; ENET_RX_RING_LEN],
+            tx_buffers: [const { Buffer([0; ENET_BUFFER_LEN]) }; ENET_TX_RING_LEN],
+        }
+    }
+}
+
+impl Default for EnetStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EnetConfig {
+    pub mac: [u8; 6],
+    /// RMII instead of MII towards the PHY
+    pub rmii: bool,
+    pub full_duplex: bool,
+}
+
+impl EnetConfig {
+    pub fn new(mac: [u8; 6]) -> Self {
+        Self {
+            mac,
+            rmii: true,
+            full_duplex: true,
+        }
+    }
+}
+
+struct RxRing {
+    descriptors: &'static mut DescriptorRing<ENET_RX_RING_LEN>,
+    buffers: &'static mut [Buffer; ENET_RX_RING_LEN],
+    index: usize,
+}
+
+struct TxRing {
+    descriptors: &'static mut DescriptorRing<ENET_TX_RING_LEN>,
+    buffers: &'static mut [Buffer; ENET_TX_RING_LEN],
+    index: usize,
+}
+
+/// ENET MAC driven by polling. The ENET interrupts stay masked, so the
+/// default handlers never run.
+pub struct EnetDevice {
+    registers: &'static mut EnetRegisters,
+    rx: RxRing,
+    tx: TxRing,
+}
+
+impl EnetDevice {
+    pub fn new(registers: &'static mut EnetRegisters, storage: &'static mut EnetStorage) -> Self {
+        let EnetStorage {
+            rx_descriptors,
+            tx_descriptors,
+            rx_buffers,
+            tx_buffers,
+        } = storage;
+
+        Self {
+            registers,
+            rx: RxRing {
+                descriptors: rx_descriptors,
+                buffers: rx_buffers,
+                index: 0,
+            },
+            tx: TxRing {
+                descriptors: tx_descriptors,
+                buffers: tx_buffers,
+                index: 0,
+            },
+        }
+    }
+
+    pub fn init(&mut self, config: &EnetConfig) -> Result<(), EnetError> {
+        let registers = &*self.registers;
+
+        registers.ecr.set(ECR_RESET);
+        let mut loops = 0;
+        while registers.ecr.get() & ECR_RESET != 0 {
+            loops += 1;
+            if loops > ENET_TIMEOUT_LOOPS {
+                return Err(EnetError::ResetTimeout);
+            }
+        }
+
+        // Polled operation
+        registers.eimr.set(0);
+        registers.eir.set(0xFFFF_FFFF);
+
+        let mii_speed = Clock::new().get_peripheral_clock().div_ceil(2 * MDC_MAX_HZ);
+        registers.mscr.set((mii_speed & 0x3F) << 1);
+
+        let mac = config.mac;
+        registers.palr.set(u32::from_be_bytes([mac[0], mac[1], mac[2], mac[3]]));
+        registers.paur.set(u32::from_be_bytes([mac[4], mac[5], 0x88, 0x08]));
+        registers.iaur.set(0);
+        registers.ialr.set(0);
+        registers.gaur.set(0);
+        registers.galr.set(0);
+
+        let mut rcr = (ENET_MAX_FRAME_LEN << RCR_MAX_FL_SHIFT) | RCR_MII_MODE;
+        if config.rmii {
+            rcr |= RCR_RMII_MODE;
+        }
+        registers.rcr.set(rcr);
+        registers.tcr.set(if config.full_duplex { TCR_FDEN } else { 0 });
+        registers.tfwr.set(TFWR_STRFWD);
+        registers.mrbr.set(ENET_BUFFER_LEN as u32);
+
+        for (i, descriptor) in self.rx.descriptors.0.iter().enumerate() {
+            let wrap = if i == ENET_RX_RING_LEN - 1 { RX_WRAP } else { 0 };
+            descriptor.buffer.set(self.rx.buffers[i].0.as_ptr() as u32);
+            descriptor.length.set(0);
+            descriptor.control.set(RX_EMPTY | wrap);
+        }
+        for (i, descriptor) in self.tx.descriptors.0.iter().enumerate() {
+            let wrap = if i == ENET_TX_RING_LEN - 1 { TX_WRAP } else { 0 };
+            descriptor.buffer.set(self.tx.buffers[i].0.as_ptr() as u32);
+            descriptor.length.set(0);
+            descriptor.control.set(wrap);
+        }
+        self.rx.index = 0;
+        self.tx.index = 0;
+        registers.rdsr.set(self.rx.descriptors.0.as_ptr() as u32);
+        registers.tdsr.set(self.tx.descriptors.0.as_ptr() as u32);
+
+        fence(Ordering::SeqCst);
+        registers.ecr.set(ECR_ETHEREN | ECR_DBSWP);
+        registers.rdar.set(DESCRIPTOR_ACTIVE);
+        Ok(())
+    }
+
+    /// Reads a PHY register over MDIO
+    pub fn phy_read(&mut self, phy: u8, register: u8) -> Result<u16, EnetError> {
+        self.mdio_transfer(MMFR_OP_READ | mdio_address(phy, register))?;
+        Ok(self.registers.mmfr.get() as u16)
+    }
+
+    /// Writes a PHY register over MDIO
+    pub fn phy_write(&mut self, phy: u8, register: u8, value: u16) -> Result<(), EnetError> {
+        self.mdio_transfer(MMFR_OP_WRITE | mdio_address(phy, register) | value as u32)
+    }
+
+    fn mdio_transfer(&mut self, frame: u32) -> Result<(), EnetError> {
+        self.registers.eir.set(EIR_MII);
+        self.registers.mmfr.set(frame);
+
+        let mut loops = 0;
+        while self.registers.eir.get() & EIR_MII == 0 {
+            loops += 1;
+            if loops > ENET_TIMEOUT_LOOPS {
+                return Err(EnetError::MdioTimeout);
+            }
+        }
+        self.registers.eir.set(EIR_MII);
+        Ok(())
+    }
+}
+
+fn mdio_address(phy: u8, register: u8) -> u32 {
+    MMFR_ST
+        | MMFR_TA
+        | ((phy as u32 & 0x1F) << MMFR_PA_SHIFT)
+        | ((register as u32 & 0x1F) << MMFR_RA_SHIFT)
+}
+
+pub struct EnetRxToken<'a> {
+    ring: &'a mut RxRing,
+    registers: &'a EnetRegisters,
+}
+
+impl phy::RxToken for EnetRxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let index = self.ring.index;
+        let len = self.ring.descriptors.0[index].length.get() as usize;
+
+        // The MAC stores the FCS behind the frame
+        let result = f(&self.ring.buffers[index].0[..len.saturating_sub(4)]);
+        release_rx(self.ring, self.registers);
+        result
+    }
+}
+
+pub struct EnetTxToken<'a> {
+    ring: &'a mut TxRing,
+    registers: &'a EnetRegisters,
+}
+
+impl phy::TxToken for EnetTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let index = self.ring.index;
+        let len = len.min(ENET_MTU);
+        let result = f(&mut self.ring.buffers[index].0[..len]);
+
+        let descriptor = &self.ring.descriptors.0[index];
+        let wrap = if index == ENET_TX_RING_LEN - 1 { TX_WRAP } else { 0 };
+        descriptor.length.set(len as u16);
+        fence(Ordering::SeqCst);
+        descriptor.control.set(TX_READY | TX_LAST | TX_CRC | wrap);
+        fence(Ordering::SeqCst);
+        self.registers.tdar.set(DESCRIPTOR_ACTIVE);
+
+        self.ring.index = (index + 1) % ENET_TX_RING_LEN;
+        result
+    }
+}
+
+// Hands the current receive descriptor back to the MAC
+fn release_rx(ring: &mut RxRing, registers: &EnetRegisters) {
+    let index = ring.index;
+    let wrap = if index == ENET_RX_RING_LEN - 1 { RX_WRAP } else { 0 };
+    fence(Ordering::SeqCst);
+    ring.descriptors.0[index].control.set(RX_EMPTY | wrap);
+    fence(Ordering::SeqCst);
+    registers.rdar.set(DESCRIPTOR_ACTIVE);
+    ring.index = (index + 1) % ENET_RX_RING_LEN;
+}
+
+impl Device for EnetDevice {
+    type RxToken<'a> = EnetRxToken<'a> where Self: 'a;
+    type TxToken<'a> = EnetTxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        // Drop frames received with errors
+        loop {
+            let control = self.rx.descriptors.0[self.rx.index].control.get();
+            if control & RX_EMPTY != 0 {
+                return None;
+            }
+            if control & RX_LAST != 0 && control & RX_ERRORS == 0 {
+                break;
+            }
+            release_rx(&mut self.rx, self.registers);
+        }
+
+        if self.tx.descriptors.0[self.tx.index].control.get() & TX_READY != 0 {
+            return None;
+        }
+
+        let registers = &*self.registers;
+        Some((
+            EnetRxToken { ring: &mut self.rx, registers },
+            EnetTxToken { ring: &mut self.tx, registers },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        // Every descriptor still belongs to the MAC
+        if self.tx.descriptors.0[self.tx.index].control.get() & TX_READY != 0 {
+            return None;
+        }
+        Some(EnetTxToken { ring: &mut self.tx, registers: self.registers })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = ENET_MTU;
+        capabilities
+    }
+}
@@ -1,95 +1,438 @@
-#![no_std]
+use vcell::VolatileCell;
 
-use core::ptr::{read_volatile, write_volatile};
-
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum FlashError {
     Busy,
+    // ACCERR: illegal command, parameter or alignment
     CommandSequence,
+    // FPVIOL: the address lies in a protected region
+    ProtectionViolation,
+    // MGSTAT0 after a program or erase command
     Write,
     Erase,
+    // MGSTAT0 after a margin read: the contents differ from what was expected
+    Verify,
+    Timeout,
+}
+
+const FTFC_BASE: usize = 0x4002_0000;
+
+#[repr(C)]
+pub struct FtfcRegisters {
+    fstat: VolatileCell<u8>,
+    fcnfg: VolatileCell<u8>,
+    fsec: VolatileCell<u8>,
+    fopt: VolatileCell<u8>,
+    fccob: [VolatileCell<u8>; FCCOB_COUNT],
+    fprot: [VolatileCell<u8>; 4],
+}
+
+// Register offsets
+pub const FSTAT: usize = 0x00;
+pub const FCNFG: usize = 0x01;
+pub const FSEC: usize = 0x02;
+pub const FOPT: usize = 0x03;
+pub const FCCOB: usize = 0x04;
+pub const FPROT: usize = 0x10;
+
+const FCCOB_COUNT: usize = 12;
+
+// FSTAT. CCIF launches a command when written with 1, the error flags are
+// cleared by writing 1. MGSTAT0 is cleared by the next launch.
+pub const FSTAT_CCIF: u8 = 1 << 7;
+pub const FSTAT_RDCOLERR: u8 = 1 << 6;
+pub const FSTAT_ACCERR: u8 = 1 << 5;
+pub const FSTAT_FPVIOL: u8 = 1 << 4;
+pub const FSTAT_MGSTAT0: u8 = 1 << 0;
+
+// FTFC commands, written to FCCOB0
+pub const CMD_READ_1S_SECTION: u8 = 0x01;
+pub const CMD_PROGRAM_CHECK: u8 = 0x02;
+pub const CMD_PROGRAM_PHRASE: u8 = 0x07;
+pub const CMD_ERASE_SECTOR: u8 = 0x09;
+
+/// Bytes written by one Program Phrase command
+pub const PHRASE_SIZE: usize = 8;
+/// Bytes compared by one Program Check command
+pub const PROGRAM_CHECK_SIZE: usize = 4;
+
+// Polls of FSTAT before a command counts as hung. A sector erase takes up
+// to about 130 ms.
+const COMMAND_TIMEOUT_LOOPS: u32 = 20_000_000;
+
+/// Register interface of the FTFC. `FtfcRegisters` is the memory mapped
+/// module; tests provide a simulated one.
+pub trait FlashRegisterAccess {
+    fn read(&self, offset: usize) -> u8;
+    fn write(&mut self, offset: usize, value: u8);
+
+    /// Starts the command loaded into FCCOB by writing CCIF and polls FSTAT
+    /// until CCIF is set again, at most `timeout_loops` times. Returns the
+    /// last FSTAT read.
+    fn launch_and_wait(&mut self, timeout_loops: u32) -> u8 {
+        self.write(FSTAT, FSTAT_CCIF);
+        let mut loops = 0;
+        while self.read(FSTAT) & FSTAT_CCIF == 0 && loops < timeout_loops {
+            loops += 1;
+        }
+        self.read(FSTAT)
+    }
+}
+
+impl FtfcRegisters {
+    fn cell(&self, offset: usize) -> &VolatileCell<u8> {
+        match offset {
+            FSTAT => &self.fstat,
+            FCNFG => &self.fcnfg,
+            FSEC => &self.fsec,
+            FOPT => &self.fopt,
+            FCCOB..=0x0F => &self.fccob[offset - FCCOB],
+            FPROT..=0x13 => &self.fprot[offset - FPROT],
+            _ => panic!("FTFC register offset {:#x} out of range", offset),
+        }
+    }
+}
+
+impl FlashRegisterAccess for FtfcRegisters {
+    fn read(&self, offset: usize) -> u8 {
+        self.cell(offset).get()
+    }
+
+    fn write(&mut self, offset: usize, value: u8) {
+        self.cell(offset).set(value)
+    }
+
+    // A P-Flash block cannot be read while a command erases or programs it,
+    // and the bootloader runs from the block it may be erasing. Launch and
+    // wait therefore run from RAM, with interrupts masked so no handler in
+    // flash gets fetched meanwhile.
+    fn launch_and_wait(&mut self, timeout_loops: u32) -> u8 {
+        let fstat = self.fstat.as_ptr();
+        cortex_m::interrupt::free(|_| unsafe { launch_from_ram(fstat, timeout_loops) })
+    }
 }
 
-const FLASH_BASE: *mut u32 = 0x0000_0000 as *mut u32;
-const FLASH_CMD_REG: *mut u32 = 0x4002_0000 as *mut u32; // Flash command register
-const FLASH_STAT_REG: *mut u32 = 0x4002_0004 as *mut u32; // Flash status register
+// Writes CCIF and polls FSTAT without a single fetch from flash. The code
+// is written in assembly so that no call into flash can sneak in, and is
+// linked into .data.ramfunc, which the startup code copies to RAM along
+// with .data (see S32K148_256_flash.ld).
+#[cfg(target_arch = "arm")]
+#[inline(never)]
+#[link_section = ".data.ramfunc"]
+unsafe fn launch_from_ram(fstat: *mut u8, timeout_loops: u32) -> u8 {
+    let status: u32;
+    core::arch::asm!(
+        "strb {ccif}, [{fstat}]",
+        "2:",
+        "ldrb {status}, [{fstat}]",
+        "tst {status}, {ccif}",
+        "bne 3f",
+        "subs {loops}, {loops}, #1",
+        "bne 2b",
+        "3:",
+        fstat = in(reg) fstat,
+        ccif = in(reg) FSTAT_CCIF as u32,
+        loops = inout(reg) timeout_loops.max(1) => _,
+        status = out(reg) status,
+        options(nostack),
+    );
+    status as u8
+}
+
+// Host builds never reach the real FTFC; this keeps them compiling
+#[cfg(not(target_arch = "arm"))]
+unsafe fn launch_from_ram(fstat: *mut u8, timeout_loops: u32) -> u8 {
+    core::ptr::write_volatile(fstat, FSTAT_CCIF);
+    let mut loops = 0;
+    while core::ptr::read_volatile(fstat) & FSTAT_CCIF == 0 && loops < timeout_loops {
+        loops += 1;
+    }
+    core::ptr::read_volatile(fstat)
+}
 
-const CMD_ERASE_SECTOR: u32 = 0x09;
-const CMD_PROGRAM: u32 = 0x20;
-const CMD_READ: u32 = 0x00;
+/// Register offset of FCCOBn. The FCCOB registers are grouped big endian
+/// in words: FCCOB3 comes first, FCCOB0 fourth.
+pub const fn fccob_offset(index: usize) -> usize {
+    FCCOB + (index & !0x3) + (3 - (index & 0x3))
+}
 
-const STAT_BUSY: u32 = 0x01;
-const STAT_ERROR: u32 = 0x02;
-const STAT_READY: u32 = 0x04;
+/// Read level of the margin read commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarginLevel {
+    Normal,
+    User,
+    Factory,
+}
 
-pub struct FlashController {
-    base: *mut u32,
+impl MarginLevel {
+    fn code(self) -> u8 {
+        match self {
+            MarginLevel::Normal => 0x00,
+            MarginLevel::User => 0x01,
+            MarginLevel::Factory => 0x02,
+        }
+    }
+}
+
+/// Runs FTFC commands through the FCCOB registers. Each command waits for
+/// CCIF before it is loaded and again until it has completed. While a
+/// command runs, the flash block it works on cannot be read; the launch and
+/// the wait for completion run from RAM, so this holds for the block the
+/// bootloader executes from as well.
+pub struct FlashController<R: FlashRegisterAccess + 'static = FtfcRegisters> {
+    registers: &'static mut R,
 }
 
 impl FlashController {
     pub fn new() -> Self {
-        Self {
-            base: FLASH_BASE,
-        }
+        Self::with_registers(unsafe { &mut *(FTFC_BASE as *mut FtfcRegisters) })
+    }
+}
+
+impl Default for FlashController {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
+impl<R: FlashRegisterAccess> FlashController<R> {
+    pub fn with_registers(registers: &'static mut R) -> Self {
+        Self { registers }
+    }
+
+    /// Erases the sector holding `sector_addr`, which must be sector aligned
     pub fn erase_sector(&mut self, sector_addr: u32) -> Result<(), FlashError> {
-        // Wait for flash to be ready
+        self.run_command(CMD_ERASE_SECTOR, sector_addr, &[])
+            .map_err(|e| if e == FlashError::Verify { FlashError::Erase } else { e })
+    }
+
+    /// Programs one phrase at the phrase aligned `addr`. The phrase must be
+    /// erased; flash is never programmed twice between erases.
+    pub fn program_phrase(&mut self, addr: u32, data: &[u8; PHRASE_SIZE]) -> Result<(), FlashError> {
+        self.run_command(CMD_PROGRAM_PHRASE, addr, data)
+            .map_err(|e| if e == FlashError::Verify { FlashError::Write } else { e })
+    }
+
+    /// Checks that `phrases` phrases from the phrase aligned `addr` read
+    /// as erased at the given margin level. Returns `Verify` if not.
+    pub fn read_1s_section(&mut self, addr: u32, phrases: u16, margin: MarginLevel) -> Result<(), FlashError> {
+        let [high, low] = phrases.to_be_bytes();
+        self.run_command(CMD_READ_1S_SECTION, addr, &[high, low, margin.code()])
+    }
+
+    /// Compares the four bytes at the word aligned `addr` with `expected`
+    /// at the user or factory margin level. Returns `Verify` on a mismatch.
+    pub fn program_check(
+        &mut self,
+        addr: u32,
+        expected: &[u8; PROGRAM_CHECK_SIZE],
+        margin: MarginLevel,
+    ) -> Result<(), FlashError> {
+        // FCCOB4 holds the margin level, the expected data starts at FCCOB8
+        let mut params = [0u8; 8];
+        params[0] = margin.code();
+        params[4..].copy_from_slice(expected);
+        self.run_command(CMD_PROGRAM_CHECK, addr, &params)
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.registers.read(FSTAT) & FSTAT_CCIF == 0
+    }
+
+    // Loads command, 24-bit address and parameters from FCCOB4 on and
+    // launches the command. MGSTAT0 is reported as `Verify`.
+    fn run_command(&mut self, command: u8, addr: u32, params: &[u8]) -> Result<(), FlashError> {
+        // A previous command may still be running
         self.wait_for_ready()?;
 
-        // Send erase command
-        unsafe {
-            write_volatile(FLASH_CMD_REG, CMD_ERASE_SECTOR);
-        }
+        // Errors of the last command would block the launch
+        self.registers.write(FSTAT, FSTAT_ACCERR | FSTAT_FPVIOL | FSTAT_RDCOLERR);
 
-        // Write to any address in the sector to trigger erase
-        unsafe {
-            write_volatile(self.base.add((sector_addr / 4) as usize), 0xFFFFFFFF);
+        let [_, addr_high, addr_mid, addr_low] = addr.to_be_bytes();
+        self.registers.write(fccob_offset(0), command);
+        self.registers.write(fccob_offset(1), addr_high);
+        self.registers.write(fccob_offset(2), addr_mid);
+        self.registers.write(fccob_offset(3), addr_low);
+        for (i, &byte) in params.iter().enumerate() {
+            self.registers.write(fccob_offset(4 + i), byte);
         }
 
-        // Wait for completion
-        self.wait_for_ready()?;
+        // Writing 1 to CCIF starts the command. CCIF still clear means it
+        // never completed.
+        let status = self.registers.launch_and_wait(COMMAND_TIMEOUT_LOOPS);
+        if status & FSTAT_CCIF == 0 {
+            return Err(FlashError::Timeout);
+        }
+        if status & FSTAT_ACCERR != 0 {
+            return Err(FlashError::CommandSequence);
+        }
+        if status & FSTAT_FPVIOL != 0 {
+            return Err(FlashError::ProtectionViolation);
+        }
+        if status & FSTAT_MGSTAT0 != 0 {
+            return Err(FlashError::Verify);
+        }
+        Ok(())
+    }
 
+    fn wait_for_ready(&self) -> Result<(), FlashError> {
+        let mut loops = 0;
+        while self.is_busy() {
+            loops += 1;
+            if loops > COMMAND_TIMEOUT_LOOPS {
+                return Err(FlashError::Timeout);
+            }
+        }
         Ok(())
     }
+}
 
-    pub fn program_word(&mut self, addr: u32, data: u32) -> Result<(), FlashError> {
-        // Wait for flash to be ready
-        self.wait_for_ready()?;
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    pub(crate) const SIM_FLASH_SIZE: usize = 0x10000;
+
+    /// FTFC with 64 KB of flash at address 0. Commands run as soon as they
+    /// are launched.
+    pub(crate) struct SimFtfc {
+        registers: [u8; 0x14],
+        pub(crate) memory: Vec<u8>,
+        // Commands in the order they were launched
+        pub(crate) commands: Vec<u8>,
+        // Sector answering every command with FPVIOL
+        pub(crate) protected: Option<u32>,
+        // Launched commands never complete
+        pub(crate) hung: bool,
+    }
 
-        // Send program command
-        unsafe {
-            write_volatile(FLASH_CMD_REG, CMD_PROGRAM);
+    impl SimFtfc {
+        pub(crate) fn new() -> &'static mut Self {
+            let mut registers = [0u8; 0x14];
+            registers[FSTAT] = FSTAT_CCIF;
+            Box::leak(Box::new(Self {
+                registers,
+                memory: std::vec![0xFF; SIM_FLASH_SIZE],
+                commands: Vec::new(),
+                protected: None,
+                hung: false,
+            }))
         }
 
-        // Write data
-        unsafe {
-            write_volatile(self.base.add((addr / 4) as usize), data);
+        fn fccob(&self, index: usize) -> u8 {
+            self.registers[fccob_offset(index)]
         }
 
-        // Wait for completion
-        self.wait_for_ready()?;
+        fn execute(&mut self) -> u8 {
+            let command = self.fccob(0);
+            self.commands.push(command);
+            let addr = u32::from_be_bytes([0, self.fccob(1), self.fccob(2), self.fccob(3)]) as usize;
+            if self.protected == Some(addr as u32 & !0xFFF) {
+                return FSTAT_FPVIOL;
+            }
 
-        Ok(())
+            match command {
+                CMD_ERASE_SECTOR if addr.is_multiple_of(4096) => {
+                    self.memory[addr..addr + 4096].fill(0xFF);
+                    0
+                }
+                CMD_PROGRAM_PHRASE if addr.is_multiple_of(PHRASE_SIZE) => {
+                    for i in 0..PHRASE_SIZE {
+                        self.memory[addr + i] &= self.fccob(4 + i);
+                    }
+                    0
+                }
+                CMD_READ_1S_SECTION => {
+                    let len = u16::from_be_bytes([self.fccob(4), self.fccob(5)]) as usize * PHRASE_SIZE;
+                    if self.memory[addr..addr + len].iter().all(|&byte| byte == 0xFF) { 0 } else { FSTAT_MGSTAT0 }
+                }
+                CMD_PROGRAM_CHECK if addr.is_multiple_of(PROGRAM_CHECK_SIZE) => {
+                    let expected = [self.fccob(8), self.fccob(9), self.fccob(10), self.fccob(11)];
+                    if self.memory[addr..addr + 4] == expected { 0 } else { FSTAT_MGSTAT0 }
+                }
+                _ => FSTAT_ACCERR,
+            }
+        }
     }
 
-    pub fn read_word(&self, addr: u32) -> u32 {
-        unsafe {
-            read_volatile(self.base.add((addr / 4) as usize))
+    impl FlashRegisterAccess for SimFtfc {
+        fn read(&self, offset: usize) -> u8 {
+            self.registers[offset]
         }
-    }
 
-    fn wait_for_ready(&self) -> Result<(), FlashError> {
-        loop {
-            let status = unsafe { read_volatile(FLASH_STAT_REG) };
-            
-            if (status & STAT_ERROR) != 0 {
-                return Err(FlashError::CommandSequence);
+        fn write(&mut self, offset: usize, value: u8) {
+            assert!(self.registers[FSTAT] & FSTAT_CCIF != 0, "register written while a command runs");
+            if offset != FSTAT {
+                self.registers[offset] = value;
+                return;
+            }
+
+            self.registers[FSTAT] &= !(value & (FSTAT_ACCERR | FSTAT_FPVIOL | FSTAT_RDCOLERR));
+            // Pending errors block the launch
+            if value & FSTAT_CCIF == 0 || self.registers[FSTAT] & (FSTAT_ACCERR | FSTAT_FPVIOL) != 0 {
+                return;
             }
-            
-            if (status & STAT_BUSY) == 0 {
-                return Ok(());
+            self.registers[FSTAT] &= !FSTAT_MGSTAT0;
+            if self.hung {
+                self.registers[FSTAT] &= !FSTAT_CCIF;
+                return;
             }
+            let status = self.execute();
+            self.registers[FSTAT] |= status;
         }
     }
-} 
+
+    #[test]
+    fn fccob_offsets() {
+        assert_eq!(fccob_offset(0), 0x07);
+        assert_eq!(fccob_offset(3), 0x04);
+        assert_eq!(fccob_offset(4), 0x0B);
+        assert_eq!(fccob_offset(7), 0x08);
+        assert_eq!(fccob_offset(8), 0x0F);
+        assert_eq!(fccob_offset(11), 0x0C);
+    }
+
+    #[test]
+    fn commands_reach_the_flash() {
+        let sim = SimFtfc::new();
+        let sim_ptr = sim as *const SimFtfc;
+        let mut controller = FlashController::with_registers(sim);
+
+        controller.program_phrase(0x1008, &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        controller.program_check(0x100C, &[4, 5, 6, 7], MarginLevel::User).unwrap();
+        assert_eq!(controller.program_check(0x1008, &[1, 1, 2, 3], MarginLevel::User), Err(FlashError::Verify));
+        assert_eq!(controller.read_1s_section(0x1000, 512, MarginLevel::Normal), Err(FlashError::Verify));
+        controller.erase_sector(0x1000).unwrap();
+        controller.read_1s_section(0x1000, 512, MarginLevel::Normal).unwrap();
+
+        let sim = unsafe { &*sim_ptr };
+        assert_eq!(sim.commands, [CMD_PROGRAM_PHRASE, CMD_PROGRAM_CHECK, CMD_PROGRAM_CHECK,
+            CMD_READ_1S_SECTION, CMD_ERASE_SECTOR, CMD_READ_1S_SECTION]);
+        assert!(sim.memory[0x1000..0x2000].iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn errors_do_not_block_the_next_command() {
+        let sim = SimFtfc::new();
+        sim.protected = Some(0x2000);
+        let mut controller = FlashController::with_registers(sim);
+
+        assert_eq!(controller.erase_sector(0x2000), Err(FlashError::ProtectionViolation));
+        controller.erase_sector(0x3000).unwrap();
+        assert_eq!(controller.erase_sector(0x3004), Err(FlashError::CommandSequence));
+        controller.erase_sector(0x3000).unwrap();
+    }
+
+    #[test]
+    fn hung_command_times_out() {
+        let sim = SimFtfc::new();
+        sim.hung = true;
+        let mut controller = FlashController::with_registers(sim);
+
+        assert_eq!(controller.erase_sector(0x1000), Err(FlashError::Timeout));
+        assert!(controller.is_busy());
+        assert_eq!(controller.erase_sector(0x1000), Err(FlashError::Timeout));
+    }
+}
@@ -0,0 +1,161 @@
+use super::Error;
+
+/// Flash Configuration Field: backdoor key, FPROT, FSEC, FOPT, FEPROT and
+/// FDPROT, loaded into the FTFC registers at reset
+pub const FCF_START: u32 = 0x0000_0400;
+pub const FCF_SIZE: u32 = 0x10;
+pub const FPROT_ADDRESS: u32 = 0x0000_0408;
+pub const FSEC_ADDRESS: u32 = 0x0000_040C;
+pub const FOPT_ADDRESS: u32 = 0x0000_040D;
+pub const FEPROT_ADDRESS: u32 = 0x0000_040E;
+pub const FDPROT_ADDRESS: u32 = 0x0000_040F;
+
+// FSEC. SEC is 0b10 for an unsecured device, every other value secures it,
+// including the erased 0b11. MEEN 0b10 disables mass erase, the only way
+// back from a secured device without the backdoor key.
+const FSEC_SEC_MASK: u8 = 0x03;
+const FSEC_SEC_UNSECURE: u8 = 0x02;
+const FSEC_MEEN_MASK: u8 = 0x30;
+const FSEC_MEEN_DISABLED: u8 = 0x20;
+
+// FOPT. A cleared RESET_PIN_CFG turns the reset pin into a GPIO, leaving
+// only the debugger to reset a device whose application hangs.
+const FOPT_RESET_PIN_CFG: u8 = 0x08;
+
+// FPROT0-3, FEPROT and FDPROT have one bit per region, cleared for a
+// protected one. Protected program flash or D-Flash can no longer be erased
+// or programmed by the bootloader.
+const PROTECTION_NONE: u8 = 0xFF;
+
+/// What happens to flash operations that would leave the device secured,
+/// with mass erase disabled, with protected regions or without its reset
+/// pin
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FcfPolicy {
+    /// Refuse them
+    #[default]
+    Reject,
+    /// Program the FCF with the offending bits made safe
+    Rewrite,
+    /// Allow everything, for the production line that secures devices on
+    /// purpose
+    Provisioning,
+}
+
+/// True if `fsec` leaves the device unsecured and mass erase enabled
+pub fn is_safe_fsec(fsec: u8) -> bool {
+    fsec & FSEC_SEC_MASK == FSEC_SEC_UNSECURE && fsec & FSEC_MEEN_MASK != FSEC_MEEN_DISABLED
+}
+
+/// `fsec` with SEC set to unsecured and MEEN to enabled if it was disabled
+pub fn make_safe_fsec(fsec: u8) -> u8 {
+    let fsec = (fsec & !FSEC_SEC_MASK) | FSEC_SEC_UNSECURE;
+    if fsec & FSEC_MEEN_MASK == FSEC_MEEN_DISABLED {
+        fsec | FSEC_MEEN_MASK
+    } else {
+        fsec
+    }
+}
+
+/// True if `value` is safe to program at `address`. Bytes outside the FCF
+/// and the backdoor key are always safe.
+pub fn is_safe_byte(address: u32, value: u8) -> bool {
+    match address {
+        FSEC_ADDRESS => is_safe_fsec(value),
+        FOPT_ADDRESS => value & FOPT_RESET_PIN_CFG != 0,
+        FPROT_ADDRESS..FSEC_ADDRESS | FEPROT_ADDRESS | FDPROT_ADDRESS => value == PROTECTION_NONE,
+        _ => true,
+    }
+}
+
+/// `value` for `address` with the bits `is_safe_byte` objects to made safe
+pub fn make_safe_byte(address: u32, value: u8) -> u8 {
+    match address {
+        FSEC_ADDRESS => make_safe_fsec(value),
+        FOPT_ADDRESS => value | FOPT_RESET_PIN_CFG,
+        FPROT_ADDRESS..FSEC_ADDRESS | FEPROT_ADDRESS | FDPROT_ADDRESS => PROTECTION_NONE,
+        _ => value,
+    }
+}
+
+/// The checked FCF bytes in data programmed at `address`, as their index
+/// in the data and their address
+pub fn fcf_bytes(address: u32, length: usize) -> impl Iterator<Item = (usize, u32)> {
+    (FPROT_ADDRESS..FCF_START + FCF_SIZE)
+        .filter(move |&byte| address <= byte && ((byte - address) as usize) < length)
+        .map(move |byte| ((byte - address) as usize, byte))
+}
+
+impl FcfPolicy {
+    /// The value to program at `address` in place of `value`
+    pub fn check_byte(self, address: u32, value: u8) -> Result<u8, Error> {
+        match self {
+            _ if is_safe_byte(address, value) => Ok(value),
+            FcfPolicy::Reject => Err(Error::UnsafeFcf),
+            FcfPolicy::Rewrite => Ok(make_safe_byte(address, value)),
+            FcfPolicy::Provisioning => Ok(value),
+        }
+    }
+
+    /// An erased FSEC secures the device at the next reset, so the sector
+    /// holding it is only erased while provisioning
+    pub fn check_erase(self, address: u32, length: u32) -> Result<(), Error> {
+        let covers_fsec = address <= FSEC_ADDRESS && (FSEC_ADDRESS as u64) < address as u64 + length as u64;
+        if covers_fsec && self != FcfPolicy::Provisioning {
+            return Err(Error::UnsafeFcf);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Backdoor key, FPROT3-0 with the first sectors protected, FSEC
+    // securing the device, FOPT without the reset pin, FEPROT and FDPROT
+    // protecting everything
+    const UNSAFE_FCF: [u8; FCF_SIZE as usize] = [
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+        0xFF, 0xFF, 0xFF, 0xFE, 0xFF, 0x77, 0x00, 0x00,
+    ];
+
+    fn apply(policy: FcfPolicy, fcf: &[u8]) -> Result<[u8; FCF_SIZE as usize], Error> {
+        let mut result = [0; FCF_SIZE as usize];
+        result.copy_from_slice(fcf);
+        for (offset, address) in fcf_bytes(FCF_START, fcf.len()) {
+            result[offset] = policy.check_byte(address, fcf[offset])?;
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn every_protection_byte_is_checked() {
+        assert!(matches!(apply(FcfPolicy::Reject, &UNSAFE_FCF), Err(Error::UnsafeFcf)));
+
+        let rewritten = apply(FcfPolicy::Rewrite, &UNSAFE_FCF).unwrap();
+        assert_eq!(&rewritten[..8], &UNSAFE_FCF[..8]);
+        assert_eq!(&rewritten[8..], &[0xFF, 0xFF, 0xFF, 0xFF, 0xFE, 0x7F, 0xFF, 0xFF]);
+        assert!(rewritten[8..].iter().enumerate().all(|(i, &value)| is_safe_byte(FPROT_ADDRESS + i as u32, value)));
+
+        assert_eq!(apply(FcfPolicy::Provisioning, &UNSAFE_FCF).unwrap(), UNSAFE_FCF);
+        assert_eq!(apply(FcfPolicy::Reject, &rewritten).unwrap(), rewritten);
+    }
+
+    #[test]
+    fn fcf_bytes_in_partial_writes() {
+        let bytes: [(usize, u32); 2] = [(0, FOPT_ADDRESS), (1, FEPROT_ADDRESS)];
+        assert!(fcf_bytes(FOPT_ADDRESS, 2).eq(bytes));
+        assert_eq!(fcf_bytes(FCF_START + 4, 8).count(), 4);
+        assert_eq!(fcf_bytes(FCF_START, 8).count(), 0);
+        assert_eq!(fcf_bytes(FCF_START + FCF_SIZE, 8).count(), 0);
+        assert_eq!(fcf_bytes(0, 0x1000).count(), 8);
+    }
+
+    #[test]
+    fn erasing_fsec_needs_provisioning() {
+        assert!(matches!(FcfPolicy::Rewrite.check_erase(0, 0x1000), Err(Error::UnsafeFcf)));
+        assert!(FcfPolicy::Reject.check_erase(0x1000, 0x1000).is_ok());
+        assert!(FcfPolicy::Provisioning.check_erase(0, 0x1000).is_ok());
+    }
+}
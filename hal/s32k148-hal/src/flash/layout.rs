@@ -0,0 +1,103 @@
+/// What a flash region is used for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlashKind {
+    /// Program flash (P-Flash), holding the bootloader and the application
+    Program,
+    /// FlexNVM used as data flash (D-Flash)
+    FlexNvm,
+}
+
+/// A flash block made of equally sized sectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashRegion {
+    pub start: u32,
+    pub size: u32,
+    /// Smallest erasable unit
+    pub sector_size: u32,
+    /// Smallest programmable unit, the FTFC phrase
+    pub program_size: u32,
+    pub kind: FlashKind,
+}
+
+impl FlashRegion {
+    /// First address after the region
+    pub const fn end(&self) -> u32 {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, address: u32, length: u32) -> bool {
+        address >= self.start && address as u64 + length as u64 <= self.end() as u64
+    }
+
+    pub fn get_sector_count(&self) -> u32 {
+        self.size / self.sector_size
+    }
+}
+
+/// The flash of a device: its regions and the part of program flash the
+/// bootloader occupies. Everything deciding where flash may be erased or
+/// written goes by this description.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlashLayout {
+    pub regions: &'static [FlashRegion],
+    /// Program flash reserved for the bootloader, from its vector table up
+    /// to the first address after it. It has to agree with the linker script.
+    pub bootloader_start: u32,
+    pub bootloader_end: u32,
+}
+
+impl FlashLayout {
+    /// The region holding all of `address..address + length`
+    pub fn find_region(&self, address: u32, length: u32) -> Option<&'static FlashRegion> {
+        self.regions.iter().find(|region| region.contains(address, length))
+    }
+
+    /// The first region of the given kind
+    pub fn get_region(&self, kind: FlashKind) -> Option<&'static FlashRegion> {
+        self.regions.iter().find(|region| region.kind == kind)
+    }
+
+    /// True if any of `address..address + length` belongs to the bootloader
+    pub fn is_reserved(&self, address: u32, length: u32) -> bool {
+        (address as u64) < self.bootloader_end as u64
+            && address as u64 + length as u64 > self.bootloader_start as u64
+    }
+
+    /// Start of the application: the program flash following the bootloader
+    pub fn get_app_start(&self) -> u32 {
+        self.bootloader_end
+    }
+
+    /// First address after the application, the end of the program flash
+    /// region the bootloader lives in
+    pub fn get_app_end(&self) -> u32 {
+        self.find_region(self.bootloader_start, 0)
+            .map_or(self.bootloader_end, |region| region.end())
+    }
+}
+
+/// S32K148 program flash: 1.5 MB with 4 KB sectors
+pub const S32K148_PFLASH: FlashRegion = FlashRegion {
+    start: 0x0000_0000,
+    size: 0x0018_0000,
+    sector_size: 4096,
+    program_size: 8,
+    kind: FlashKind::Program,
+};
+
+/// S32K148 FlexNVM used as data flash: 512 KB with 4 KB sectors
+pub const S32K148_FLEXNVM: FlashRegion = FlashRegion {
+    start: 0x1000_0000,
+    size: 0x0008_0000,
+    sector_size: 4096,
+    program_size: 8,
+    kind: FlashKind::FlexNvm,
+};
+
+/// S32K148 flash, programmed a phrase at a time. The bootloader takes the
+/// first 256 KB of program flash, see `S32K148_256_flash.ld`.
+pub const S32K148_FLASH_LAYOUT: FlashLayout = FlashLayout {
+    regions: &[S32K148_PFLASH, S32K148_FLEXNVM],
+    bootloader_start: 0x0000_0000,
+    bootloader_end: 0x0004_0000,
+};
@@ -1,21 +1,21 @@
-#![no_std]
-
-use controller::{FlashError, FlashController};
+pub use controller::{FlashController, FlashError, FlashRegisterAccess, FtfcRegisters, MarginLevel};
+pub use fcf::FcfPolicy;
+pub use layout::{FlashKind, FlashLayout, FlashRegion, S32K148_FLASH_LAYOUT, S32K148_FLEXNVM, S32K148_PFLASH};
+use controller::{PHRASE_SIZE, PROGRAM_CHECK_SIZE};
 use core::fmt;
 use core::convert::TryInto;
 
 pub mod controller;
-
-const FLASH_BASE: u32 = 0x0000_0000;
-const FLASH_SIZE: u32 = 0x1000_0000; // 16MB
-const FLASH_PAGE_SIZE: u32 = 4096; // 4KB
-const FLASH_SECTOR_SIZE: u32 = 65536; // 64KB
+pub mod fcf;
+pub mod layout;
 
 #[derive(Debug)]
 pub enum Error {
     Controller(FlashError),
     InvalidAddress,
     InvalidLength,
+    // Would secure, protect or lock up the device, see `FcfPolicy`
+    UnsafeFcf,
 }
 
 impl fmt::Display for Error {
@@ -24,35 +24,61 @@ impl fmt::Display for Error {
             Error::Controller(e) => write!(f, "Flash controller error: {:?}", e),
             Error::InvalidAddress => write!(f, "Invalid address"),
             Error::InvalidLength => write!(f, "Invalid length"),
+            Error::UnsafeFcf => write!(f, "Flash configuration would secure or protect the device"),
         }
     }
 }
 
-pub struct Flash {
-    base_address: u32,
-    size: u32,
-    controller: FlashController,
+pub struct Flash<R: FlashRegisterAccess + 'static = FtfcRegisters> {
+    layout: &'static FlashLayout,
+    controller: FlashController<R>,
+    fcf_policy: FcfPolicy,
 }
 
 impl Flash {
     pub fn new() -> Self {
+        Self::with_controller(FlashController::new(), &S32K148_FLASH_LAYOUT)
+    }
+}
+
+impl Default for Flash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: FlashRegisterAccess> Flash<R> {
+    pub fn with_controller(controller: FlashController<R>, layout: &'static FlashLayout) -> Self {
         Flash {
-            base_address: FLASH_BASE,
-            size: FLASH_SIZE,
-            controller: FlashController::new(),
+            layout,
+            controller,
+            fcf_policy: FcfPolicy::default(),
         }
     }
 
+    pub fn get_layout(&self) -> &'static FlashLayout {
+        self.layout
+    }
+
+    /// Decides how writes to the Flash Configuration Field are handled,
+    /// `FcfPolicy::Reject` unless set otherwise
+    pub fn set_fcf_policy(&mut self, policy: FcfPolicy) {
+        self.fcf_policy = policy;
+    }
+
+    pub fn get_fcf_policy(&self) -> FcfPolicy {
+        self.fcf_policy
+    }
+
     pub fn erase(&mut self, address: u32, length: u32) -> Result<(), Error> {
         // Validate address and length
-        if address < self.base_address || address + length > self.base_address + self.size {
-            return Err(Error::InvalidAddress);
-        }
+        let region = self.find_region(address, length)?;
 
-        // Ensure length is a multiple of sector size
-        if length % FLASH_SECTOR_SIZE != 0 {
+        // Only whole sectors can be erased
+        if !address.is_multiple_of(region.sector_size) || !length.is_multiple_of(region.sector_size) {
             return Err(Error::InvalidLength);
         }
+        self.fcf_policy.check_erase(address, length)?;
 
         // Erase each sector
         let mut current_addr = address;
@@ -60,7 +86,7 @@ impl Flash {
             self.controller
                 .erase_sector(current_addr)
                 .map_err(Error::Controller)?;
-            current_addr += FLASH_SECTOR_SIZE;
+            current_addr += region.sector_size;
         }
 
         Ok(())
@@ -68,20 +94,73 @@ impl Flash {
 
     pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
         // Validate address and length
-        if address < self.base_address || address + data.len() as u32 > self.base_address + self.size {
+        let region = self.find_region(address, data.len() as u32)?;
+
+        // Flash is programmed a phrase at a time
+        if !address.is_multiple_of(region.program_size) {
             return Err(Error::InvalidAddress);
         }
+        if !(data.len() as u32).is_multiple_of(region.program_size) {
+            return Err(Error::InvalidLength);
+        }
+
+        // Checked before anything is programmed
+        let mut fcf = [(0, 0); fcf::FCF_SIZE as usize];
+        let mut fcf_len = 0;
+        for (offset, byte_address) in fcf::fcf_bytes(address, data.len()) {
+            fcf[fcf_len] = (offset, self.fcf_policy.check_byte(byte_address, data[offset])?);
+            fcf_len += 1;
+        }
 
-        // Ensure data length is a multiple of 4 (word size)
-        if data.len() % 4 != 0 {
+        for (i, chunk) in data.chunks(PHRASE_SIZE).enumerate() {
+            let mut phrase: [u8; PHRASE_SIZE] = chunk.try_into().unwrap();
+            for &(offset, value) in &fcf[..fcf_len] {
+                if offset / PHRASE_SIZE == i {
+                    phrase[offset % PHRASE_SIZE] = value;
+                }
+            }
+            self.controller
+                .program_phrase(address + (i * PHRASE_SIZE) as u32, &phrase)
+                .map_err(Error::Controller)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks with Read 1s Section that the range is erased. Both address
+    /// and length must be phrase aligned.
+    pub fn blank_check(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        self.find_region(address, length)?;
+        if !address.is_multiple_of(PHRASE_SIZE as u32) || !length.is_multiple_of(PHRASE_SIZE as u32) {
             return Err(Error::InvalidLength);
         }
 
-        // Write data in 4-byte chunks
-        for (i, chunk) in data.chunks(4).enumerate() {
-            let word = u32::from_le_bytes(chunk.try_into().unwrap());
+        // The phrase count is 16 bits wide, so long ranges take several commands
+        let max_chunk = u16::MAX as u32 * PHRASE_SIZE as u32;
+        let mut current_addr = address;
+        while current_addr < address + length {
+            let chunk = (address + length - current_addr).min(max_chunk);
+            self.controller
+                .read_1s_section(current_addr, (chunk / PHRASE_SIZE as u32) as u16, MarginLevel::Normal)
+                .map_err(Error::Controller)?;
+            current_addr += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Checks with Program Check that freshly programmed data reads back
+    /// as `data` at the user margin level, i.e. that it was programmed
+    /// with enough charge to keep
+    pub fn verify(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.find_region(address, data.len() as u32)?;
+        if !address.is_multiple_of(PROGRAM_CHECK_SIZE as u32) || !data.len().is_multiple_of(PROGRAM_CHECK_SIZE) {
+            return Err(Error::InvalidLength);
+        }
+
+        for (i, chunk) in data.chunks(PROGRAM_CHECK_SIZE).enumerate() {
             self.controller
-                .program_word(address + (i * 4) as u32, word)
+                .program_check(address + (i * PROGRAM_CHECK_SIZE) as u32, chunk.try_into().unwrap(), MarginLevel::User)
                 .map_err(Error::Controller)?;
         }
 
@@ -90,9 +169,7 @@ impl Flash {
 
     pub fn read(&self, address: u32, length: u32) -> Result<&[u8], Error> {
         // Validate address and length
-        if address < self.base_address || address + length > self.base_address + self.size {
-            return Err(Error::InvalidAddress);
-        }
+        self.find_region(address, length)?;
 
         // Return a slice to the flash memory
         let start = address as usize;
@@ -102,9 +179,7 @@ impl Flash {
 
     pub fn calculate_checksum(&self, address: u32, length: u32) -> Result<u32, Error> {
         // Validate address and length
-        if address < self.base_address || address + length > self.base_address + self.size {
-            return Err(Error::InvalidAddress);
-        }
+        self.find_region(address, length)?;
 
         // Read memory and calculate checksum
         let data = self.read(address, length)?;
@@ -118,6 +193,63 @@ impl Flash {
         
         Ok(checksum)
     }
-} 
 
+    // Ranges have to lie within one region of the layout
+    fn find_region(&self, address: u32, length: u32) -> Result<&'static FlashRegion, Error> {
+        self.layout.find_region(address, length).ok_or(Error::InvalidAddress)
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::tests::{SimFtfc, SIM_FLASH_SIZE};
+
+    static LAYOUT: FlashLayout = FlashLayout {
+        regions: &[FlashRegion {
+            start: 0,
+            size: SIM_FLASH_SIZE as u32,
+            sector_size: 4096,
+            program_size: 8,
+            kind: FlashKind::Program,
+        }],
+        bootloader_start: 0,
+        bootloader_end: 0x1000,
+    };
+
+    fn flash() -> (Flash<SimFtfc>, *const SimFtfc) {
+        let sim = SimFtfc::new();
+        let sim_ptr = sim as *const SimFtfc;
+        (Flash::with_controller(FlashController::with_registers(sim), &LAYOUT), sim_ptr)
+    }
+
+    #[test]
+    fn write_verify_and_erase() {
+        let (mut flash, sim) = flash();
+        let sim = unsafe { &*sim };
+        let data: [u8; 16] = core::array::from_fn(|i| i as u8);
+
+        flash.blank_check(0x1000, 0x1000).unwrap();
+        flash.write(0x1008, &data).unwrap();
+        assert_eq!(&sim.memory[0x1008..0x1018], &data);
+        flash.verify(0x1008, &data).unwrap();
+        assert!(matches!(flash.verify(0x1008, &[1, 1, 2, 3]), Err(Error::Controller(FlashError::Verify))));
+        assert!(matches!(flash.blank_check(0x1000, 0x1000), Err(Error::Controller(FlashError::Verify))));
+
+        flash.erase(0x1000, 0x1000).unwrap();
+        flash.blank_check(0x1000, 0x1000).unwrap();
+    }
+
+    #[test]
+    fn ranges_follow_the_layout() {
+        let (mut flash, sim) = flash();
+        let sim = unsafe { &*sim };
+
+        assert!(matches!(flash.write(0x1004, &[0; 8]), Err(Error::InvalidAddress)));
+        assert!(matches!(flash.write(0x1000, &[0; 4]), Err(Error::InvalidLength)));
+        assert!(matches!(flash.erase(0x1800, 0x1000), Err(Error::InvalidLength)));
+        assert!(matches!(flash.erase(0xF000, 0x2000), Err(Error::InvalidAddress)));
+        assert!(matches!(flash.write(SIM_FLASH_SIZE as u32, &[0; 8]), Err(Error::InvalidAddress)));
+        assert!(sim.commands.is_empty());
+    }
+}
@@ -1,8 +1,12 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 use core::marker::PhantomData;
 
 pub mod can;
+pub mod enet;
 pub mod flash;
 pub mod hal;
 pub mod uart;
@@ -11,8 +15,13 @@ pub mod gpio;
 pub mod peripheral;
 pub mod reg;
 
-pub use can::{CanDevice, CanError, CanRegisters};
-pub use flash::{Flash, Error as FlashError};
+pub use can::{
+    dlc_to_len, fd_frame_len, len_to_dlc, BitTiming, BitTimingError, BusOffRecovery, CanConfig, CanDevice,
+    CanError, CanFrame, CanRegisters, ClockSource, ErrorState, FdBitTiming, FifoFormat, IdFilter, RxConfig,
+    CANFD_MAX_DATA_LEN, CAN_MAX_DATA_LEN,
+};
+pub use enet::{EnetConfig, EnetDevice, EnetError, EnetRegisters, EnetStorage};
+pub use flash::{FcfPolicy, Flash, FlashKind, FlashLayout, FlashRegion, Error as FlashError, S32K148_FLASH_LAYOUT};
 pub use hal::S32KHal;
 pub use uart::{debug_println, init_debug_uart};
 pub use clock::Clock;
@@ -34,6 +43,11 @@ impl S32K148 {
             _phantom: PhantomData,
         }
     }
+
+    /// Hands the CAN controller over, e.g. to a protocol transport
+    pub fn into_can(self) -> CanDevice {
+        self.can
+    }
 }
 
 impl S32KHal for S32K148 {
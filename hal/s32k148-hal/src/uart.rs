@@ -7,7 +7,7 @@ pub struct Uart<const N: u8> {
     _peripheral: PeripheralRef<UartPeripheral<N>>,
 }
 
-struct UartPeripheral<const N: u8>;
+pub struct UartPeripheral<const N: u8>;
 
 impl<const N: u8> Uart<N> {
     pub fn new(
@@ -3,7 +3,12 @@ use std::path::PathBuf;
 
 fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-    
+
+    // The startup code is ARM assembly; host builds have nothing to link it into
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("none") {
+        return;
+    }
+
     // Configure cc build for startup code
     cc::Build::new()
         .file("startup/startup_S32K148.S")
@@ -1,24 +1,71 @@
 use s32k148_hal::{
     S32K148,
     S32KHal,
+    CanConfig,
     CanDevice,
     Flash,
+    IdFilter,
+    RxConfig,
     can::CanError,
     flash::Error as FlashError,
 };
+use embedded_can::{Id, StandardId};
+
+// Default identifier of XCP command frames (CRO), the OpenBLT default
+pub const DEFAULT_CRO_ID: Id = Id::Standard(StandardId::new(0x667).unwrap());
+
+// Default identifier of physically addressed UDS requests
+pub const DEFAULT_UDS_REQUEST_ID: Id = Id::Standard(StandardId::new(0x7E0).unwrap());
 
 pub struct Board {
     hal: S32K148,
+    cro_id: Id,
+    uds_request_id: Id,
 }
 
 impl Board {
     pub fn new(hal: S32K148) -> Self {
-        Self { hal }
+        Self {
+            hal,
+            cro_id: DEFAULT_CRO_ID,
+            uds_request_id: DEFAULT_UDS_REQUEST_ID,
+        }
+    }
+
+    /// Takes a `StandardId` or, for 29-bit identifiers, an `ExtendedId`.
+    /// Applies from the next `init_can`.
+    pub fn set_cro_id(&mut self, id: impl Into<Id>) {
+        self.cro_id = id.into();
+    }
+
+    /// Like `set_cro_id`, for UDS requests. Must differ from the CRO
+    /// identifier or `init_can` fails.
+    pub fn set_uds_request_id(&mut self, id: impl Into<Id>) {
+        self.uds_request_id = id.into();
+    }
+
+    pub fn init_can(&mut self) -> Result<(), CanError> {
+        // One identifier cannot tell XCP commands from UDS requests
+        if self.cro_id == self.uds_request_id {
+            return Err(CanError::InvalidFilter);
+        }
+
+        // 500 kbit/s from the oscillator clock
+        let can = self.hal.get_can_mut();
+        can.init(&CanConfig::default())?;
+
+        // Other traffic on the bus never reaches the bootloader
+        let filters = [IdFilter::exact(self.cro_id), IdFilter::exact(self.uds_request_id)];
+        can.configure_rx(&RxConfig::Mailboxes(&filters))
+    }
+
+    pub fn get_cro_id(&self) -> Id {
+        self.cro_id
     }
 
-    pub fn init_can(&mut self) {
-        // Initialize CAN
-        self.hal.get_can_mut().init();
+    /// Gives up the board for its CAN controller, as set up by `init_can`
+    pub fn into_can(self) -> CanDevice {
+        self.hal.into_can()
     }
 
     pub fn init_flash(&mut self) {
@@ -33,7 +80,7 @@ impl Board {
 
         // Check for CAN programming request
         if let Ok((id, _, _)) = self.hal.get_can_mut().receive_frame() {
-            if id == 0x7E0 {
+            if id == self.cro_id || id == self.uds_request_id {
                 return true;
             }
         }
@@ -3,9 +3,14 @@
 
 use cortex_m_rt::entry;
 use panic_halt as _;
-use s32k148_board::Board;
+use s32k148_board::{Board, StateMachine, BootloaderState};
 use s32k148_hal::{S32K148, CanDevice, Flash, CanRegisters, debug_println};
-use crate::state::{StateMachine, BootloaderState};
+use openblt::core::memory::MemoryManager;
+use openblt::hal::s32k148::S32K148 as BootloaderHal;
+use openblt::hal::S32KHal as _;
+use openblt::protocol::can::{CanTransport, XcpCanConfig};
+use openblt::protocol::Protocol;
+use openblt_rust::protocol::xcp::XcpProtocol;
 
 #[entry]
 fn main() -> ! {
@@ -18,6 +23,12 @@ fn main() -> ! {
     // Initialize the board and state machine
     let mut board = Board::new(hal);
     let mut state_machine = StateMachine::new();
+
+    // Bit rate and acceptance filters for XCP and UDS
+    if board.init_can().is_err() {
+        state_machine.transition_to(BootloaderState::Error);
+        debug_println("CAN initialization failed");
+    }
     
     // Print bootloader startup message
     debug_println("S32K148 Bootloader Starting...");
@@ -59,13 +70,7 @@ fn main() -> ! {
                 match board.enter_programming_mode() {
                     Ok(_) => {
                         debug_println("Entered programming mode");
-                        // TODO: Implement XCP protocol handling
-                        // This should handle:
-                        // 1. XCP CONNECT
-                        // 2. XCP SET_MTA
-                        // 3. XCP DOWNLOAD
-                        // 4. XCP PROGRAM_START
-                        // 5. XCP PROGRAM_RESET
+                        serve_xcp(board);
                     }
                     Err(_) => {
                         state_machine.transition_to(BootloaderState::Error);
@@ -92,4 +97,28 @@ fn main() -> ! {
         
         cortex_m::asm::nop();
     }
-} 
+}
+
+// Answers XCP commands from the tester until a PROGRAM_RESET starts the
+// new application
+fn serve_xcp(board: Board) -> ! {
+    let cro_id = board.get_cro_id();
+    let can = board.into_can();
+
+    // The flash is programmed through the bootloader HAL, whose timer also
+    // runs the transport's timeouts. Its own CAN controller stays unused;
+    // the board's has the acceptance filters set up.
+    let hal = unsafe { BootloaderHal::new() };
+    let timer = hal.get_timer().clone();
+    let mut memory = MemoryManager::new(hal).expect("unsupported flash layout");
+
+    let config = XcpCanConfig { cro_id, ..XcpCanConfig::default() };
+    let mut protocol = Protocol::new(CanTransport::with_config(can, timer, config));
+    let mut xcp = XcpProtocol::new();
+    xcp.set_max_cto(protocol.max_packet_size());
+
+    loop {
+        // After a timeout or a bus-off the tester repeats its command
+        let _ = xcp.serve(&mut protocol, &mut memory);
+    }
+}
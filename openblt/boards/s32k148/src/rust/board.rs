@@ -1,5 +1,6 @@
 #![no_std]
 
+use embedded_can::{Id, StandardId};
 use s32k148_hal::{S32K148, S32KHal};
 
 #[derive(Debug)]
@@ -7,13 +8,22 @@ pub enum BoardError {
     HalError(<S32K148 as S32KHal>::Error),
 }
 
+// Default identifier of XCP command frames (CRO)
+pub const DEFAULT_CRO_ID: Id = Id::Standard(StandardId::new(0x667).unwrap());
+
 pub struct Board {
     hal: S32K148,
+    cro_id: Id,
 }
 
 impl Board {
     pub fn new(hal: S32K148) -> Self {
-        Self { hal }
+        Self { hal, cro_id: DEFAULT_CRO_ID }
+    }
+
+    /// Takes a `StandardId` or, for 29-bit identifiers, an `ExtendedId`
+    pub fn set_cro_id(&mut self, id: impl Into<Id>) {
+        self.cro_id = id.into();
     }
 
     pub fn init(&mut self) -> Result<(), BoardError> {
@@ -56,9 +66,9 @@ impl Board {
             return true;
         }
 
-        // Check for CAN programming request (ID 0x7E0)
+        // Check for CAN programming request
         if let Ok((id, _, _)) = self.hal.get_can_mut().receive_frame() {
-            if id == 0x7E0 {
+            if id == self.cro_id {
                 return true;
             }
         }
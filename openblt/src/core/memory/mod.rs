@@ -2,6 +2,9 @@
 
 use core::fmt;
 use crate::hal::S32KHal;
+use s32k148_hal::flash::{fcf, FcfPolicy, FlashLayout, FlashRegion, S32K148_FLASH_LAYOUT};
+
+pub mod record;
 
 #[derive(Debug)]
 pub enum MemoryManagementError {
@@ -12,6 +15,10 @@ pub enum MemoryManagementError {
     ReadError,
     AlignmentError,
     OutOfBounds,
+    // Would secure or protect the device, see `FcfPolicy`
+    UnsafeConfiguration,
+    // Overlaps a phrase that was already programmed with other data
+    AlreadyProgrammed,
 }
 
 impl fmt::Display for MemoryManagementError {
@@ -24,78 +31,477 @@ impl fmt::Display for MemoryManagementError {
             MemoryManagementError::ReadError => write!(f, "Memory read error"),
             MemoryManagementError::AlignmentError => write!(f, "Memory alignment error"),
             MemoryManagementError::OutOfBounds => write!(f, "Memory access out of bounds"),
+            MemoryManagementError::UnsafeConfiguration => write!(f, "Flash configuration would secure or protect the device"),
+            MemoryManagementError::AlreadyProgrammed => write!(f, "Memory already programmed"),
+        }
+    }
+}
+
+// Written data is collected in a RAM copy of one sector of the application
+// region and programmed a phrase at a time. Regions with larger sectors or
+// smaller phrases than these are not supported.
+const MAX_SECTOR_SIZE: usize = 4096;
+const MIN_PHRASE_SIZE: usize = 8;
+const MAX_PHRASES: usize = MAX_SECTOR_SIZE / MIN_PHRASE_SIZE;
+
+// One bit per phrase of a sector
+#[derive(Clone, Copy)]
+struct PhraseSet([u32; MAX_PHRASES / 32]);
+
+impl PhraseSet {
+    const EMPTY: Self = Self([0; MAX_PHRASES / 32]);
+
+    fn insert(&mut self, phrase: usize) {
+        self.0[phrase / 32] |= 1 << (phrase % 32);
+    }
+
+    fn contains(&self, phrase: usize) -> bool {
+        self.0[phrase / 32] & (1 << (phrase % 32)) != 0
+    }
+}
+
+// RAM copy of one flash sector collecting written data until it is
+// programmed, like OpenBLT's FlashWriteBlock
+struct WriteBlock {
+    base: Option<u32>,
+    data: [u8; MAX_SECTOR_SIZE],
+    // Phrases holding written data
+    dirty: PhraseSet,
+    // Phrases the flash already holds data in. A phrase must not be
+    // programmed twice without an erase in between.
+    programmed: PhraseSet,
+}
+
+impl WriteBlock {
+    fn new() -> Self {
+        Self {
+            base: None,
+            data: [0xFF; MAX_SECTOR_SIZE],
+            dirty: PhraseSet::EMPTY,
+            programmed: PhraseSet::EMPTY,
         }
     }
 }
 
 pub struct MemoryManager<H: S32KHal> {
     hal: H,
+    layout: &'static FlashLayout,
     app_start: u32,
     app_end: u32,
+    fcf_policy: FcfPolicy,
+    sector_size: u32,
+    phrase_size: usize,
+    block: WriteBlock,
 }
 
 impl<H: S32KHal> MemoryManager<H> {
     pub fn new(hal: H) -> Result<Self, MemoryManagementError> {
+        Self::with_layout(hal, &S32K148_FLASH_LAYOUT)
+    }
+
+    /// Programs the application area `layout` describes: the program flash
+    /// after the bootloader
+    pub fn with_layout(hal: H, layout: &'static FlashLayout) -> Result<Self, MemoryManagementError> {
+        let app_start = layout.get_app_start();
+        let app_end = layout.get_app_end();
+        let region = layout.find_region(app_start, app_end - app_start)
+            .ok_or(MemoryManagementError::InvalidAddress)?;
+        let (sector_size, phrase_size) = (region.sector_size as usize, region.program_size as usize);
+        if sector_size > MAX_SECTOR_SIZE || phrase_size < MIN_PHRASE_SIZE || !sector_size.is_multiple_of(phrase_size) {
+            return Err(MemoryManagementError::AlignmentError);
+        }
+
         Ok(Self {
             hal,
-            app_start: 0x00004000, // Application start address
-            app_end: 0x0007FFFF,   // Application end address
+            layout,
+            app_start,
+            app_end,
+            fcf_policy: FcfPolicy::default(),
+            sector_size: region.sector_size,
+            phrase_size,
+            block: WriteBlock::new(),
         })
     }
 
     pub fn erase(&mut self, address: u32, length: u32) -> Result<(), MemoryManagementError> {
         // Verify address and length
-        if address < self.app_start || address + length > self.app_end {
-            return Err(MemoryManagementError::OutOfBounds);
-        }
+        self.check_bounds(address, length as usize)?;
 
         // Verify alignment
-        if address % 4096 != 0 || length % 4096 != 0 {
+        let sector_size = self.get_sector_size();
+        if !address.is_multiple_of(sector_size) || !length.is_multiple_of(sector_size) {
             return Err(MemoryManagementError::AlignmentError);
         }
+        self.fcf_policy.check_erase(address, length)
+            .map_err(|_| MemoryManagementError::UnsafeConfiguration)?;
+
+        // Data not yet programmed into an erased sector is dropped with it
+        if let Some(base) = self.block.base {
+            if base >= address && base < address + length {
+                self.block.base = None;
+                self.block.dirty = PhraseSet::EMPTY;
+            }
+        }
 
         // Erase flash
         self.hal.erase_flash(address, length)
             .map_err(|_| MemoryManagementError::EraseError)
     }
 
+    /// Writes `data` of any length to any address. It is collected in a
+    /// sector-sized block in RAM and programmed a phrase at a time once a
+    /// write moves on to another sector, or on `flush`. Flash Configuration
+    /// Field bytes in `data` go through the FCF policy first.
+    ///
+    /// Writes may come in any order, but a phrase is programmed only once:
+    /// data for a phrase already programmed fails with `AlreadyProgrammed`
+    /// unless the flash holds it already.
     pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), MemoryManagementError> {
         // Verify address and length
-        if address < self.app_start || address + data.len() as u32 > self.app_end {
-            return Err(MemoryManagementError::OutOfBounds);
+        self.check_bounds(address, data.len())?;
+
+        // Images have to leave the device unsecured, mass erasable and
+        // unprotected
+        let mut fcf = [(0, 0); fcf::FCF_SIZE as usize];
+        let mut fcf_len = 0;
+        for (offset, byte_address) in fcf::fcf_bytes(address, data.len()) {
+            let value = self.fcf_policy.check_byte(byte_address, data[offset])
+                .map_err(|_| MemoryManagementError::UnsafeConfiguration)?;
+            fcf[fcf_len] = (byte_address, value);
+            fcf_len += 1;
         }
 
-        // Verify alignment
-        if address % 4 != 0 {
-            return Err(MemoryManagementError::AlignmentError);
+        let mut offset = 0;
+        while offset < data.len() {
+            let current = address + offset as u32;
+            let base = current - current % self.sector_size;
+            if self.block.base != Some(base) {
+                self.flush()?;
+                self.load_block(base)?;
+            }
+
+            let start = (current - base) as usize;
+            let count = (self.sector_size as usize - start).min(data.len() - offset);
+            let (end, chunk) = (start + count, &data[offset..offset + count]);
+            let phrases = start / self.phrase_size..=(end - 1) / self.phrase_size;
+            for phrase in phrases.clone() {
+                let from = start.max(phrase * self.phrase_size);
+                let to = end.min((phrase + 1) * self.phrase_size);
+                if self.block.programmed.contains(phrase)
+                    && self.block.data[from..to] != chunk[from - start..to - start]
+                {
+                    return Err(MemoryManagementError::AlreadyProgrammed);
+                }
+            }
+
+            self.block.data[start..end].copy_from_slice(chunk);
+            for &(byte_address, value) in &fcf[..fcf_len] {
+                if (current..current + count as u32).contains(&byte_address) {
+                    self.block.data[(byte_address - base) as usize] = value;
+                }
+            }
+            for phrase in phrases {
+                if !self.block.programmed.contains(phrase) {
+                    self.block.dirty.insert(phrase);
+                }
+            }
+            offset += count;
+        }
+        Ok(())
+    }
+
+    // Loads the sector at `base` into the write block. Bytes not written
+    // keep what the flash holds.
+    fn load_block(&mut self, base: u32) -> Result<(), MemoryManagementError> {
+        let data = &mut self.block.data[..self.sector_size as usize];
+        read_flash(&self.hal, base, data)?;
+
+        // Erased phrases read as 0xFF, and flush never programs a phrase of
+        // 0xFF, so every other phrase was programmed before
+        self.block.programmed = PhraseSet::EMPTY;
+        for (phrase, bytes) in data.chunks(self.phrase_size).enumerate() {
+            if bytes.iter().any(|&byte| byte != 0xFF) {
+                self.block.programmed.insert(phrase);
+            }
+        }
+        self.block.dirty = PhraseSet::EMPTY;
+        self.block.base = Some(base);
+        Ok(())
+    }
+
+    /// Programs the data still held in the write block
+    pub fn flush(&mut self) -> Result<(), MemoryManagementError> {
+        let base = match self.block.base.take() {
+            Some(base) => base,
+            None => return Ok(()),
+        };
+
+        // Each run of adjacent written phrases is programmed in one go.
+        // Phrases left at 0xFF stay erased, so they can still be written.
+        let phrase_count = self.sector_size as usize / self.phrase_size;
+        let needs_programming = |block: &WriteBlock, phrase: usize| {
+            let bytes = &block.data[phrase * self.phrase_size..(phrase + 1) * self.phrase_size];
+            block.dirty.contains(phrase) && bytes.iter().any(|&byte| byte != 0xFF)
+        };
+        let mut phrase = 0;
+        let mut result = Ok(());
+        while phrase < phrase_count && result.is_ok() {
+            if !needs_programming(&self.block, phrase) {
+                phrase += 1;
+                continue;
+            }
+            let first = phrase;
+            while phrase < phrase_count && needs_programming(&self.block, phrase) {
+                phrase += 1;
+            }
+
+            let (start, end) = (first * self.phrase_size, phrase * self.phrase_size);
+            result = self.hal.write_flash(base + start as u32, &self.block.data[start..end])
+                .map_err(|_| MemoryManagementError::WriteError);
         }
 
-        // Write data
-        self.hal.write_flash(address, data)
-            .map_err(|_| MemoryManagementError::WriteError)
+        self.block.dirty = PhraseSet::EMPTY;
+        result
     }
 
+    /// Reads flash, including data still waiting in the write block
     pub fn read(&self, address: u32, data: &mut [u8]) -> Result<(), MemoryManagementError> {
         // Verify address and length
-        if address < self.app_start || address + data.len() as u32 > self.app_end {
-            return Err(MemoryManagementError::OutOfBounds);
+        self.check_bounds(address, data.len())?;
+
+        read_flash(&self.hal, address, data)?;
+
+        if let Some(base) = self.block.base {
+            let start = address.max(base);
+            let end = (address + data.len() as u32).min(base + self.sector_size);
+            if start < end {
+                data[(start - address) as usize..(end - address) as usize]
+                    .copy_from_slice(&self.block.data[(start - base) as usize..(end - base) as usize]);
+            }
         }
+        Ok(())
+    }
 
-        // Verify alignment
-        if address % 4 != 0 {
-            return Err(MemoryManagementError::AlignmentError);
+    // Fails unless `length` bytes at `address` lie in the application area,
+    // without wrapping past the end of the address space
+    fn check_bounds(&self, address: u32, length: usize) -> Result<(), MemoryManagementError> {
+        let end = u32::try_from(length).ok().and_then(|length| address.checked_add(length));
+        if address < self.app_start || end.is_none_or(|end| end > self.app_end) {
+            return Err(MemoryManagementError::OutOfBounds);
         }
+        Ok(())
+    }
+
+    pub fn get_hal(&self) -> &H {
+        &self.hal
+    }
+
+    pub fn get_layout(&self) -> &'static FlashLayout {
+        self.layout
+    }
 
-        // Read data
-        self.hal.read_flash(address, data)
-            .map_err(|_| MemoryManagementError::ReadError)
+    /// Only layouts placing the application over the Flash Configuration
+    /// Field at 0x400 let images reach it; the S32K148 layout reserves it
+    /// for the bootloader
+    pub fn set_fcf_policy(&mut self, policy: FcfPolicy) {
+        self.fcf_policy = policy;
+    }
+
+    pub fn get_fcf_policy(&self) -> FcfPolicy {
+        self.fcf_policy
     }
 
     pub fn get_app_start(&self) -> u32 {
         self.app_start
     }
 
+    /// First address after the application area
     pub fn get_app_end(&self) -> u32 {
         self.app_end
     }
-} 
+
+    /// The parts of the layout's regions that belong to the application
+    /// area, in layout order
+    pub fn app_regions(&self) -> impl Iterator<Item = FlashRegion> + '_ {
+        self.layout.regions.iter().filter_map(move |region| {
+            let start = region.start.max(self.app_start);
+            let end = region.end().min(self.app_end);
+            (start < end).then(|| FlashRegion { start, size: end - start, ..*region })
+        })
+    }
+
+    /// Erase granularity of the application area
+    pub fn get_sector_size(&self) -> u32 {
+        self.sector_size
+    }
+}
+
+// Reads straight from flash, bypassing the write block
+fn read_flash<H: S32KHal>(hal: &H, address: u32, data: &mut [u8]) -> Result<(), MemoryManagementError> {
+    // Aligned reads go straight to the flash
+    if address.is_multiple_of(4) && data.len().is_multiple_of(4) {
+        return hal.read_flash(address, data)
+            .map_err(|_| MemoryManagementError::ReadError);
+    }
+
+    // Flash is read a word at a time, so serve unaligned reads word by word
+    let mut offset = 0;
+    while offset < data.len() {
+        let current = address + offset as u32;
+        let aligned = current & !0x3;
+        let mut word = [0u8; 4];
+        hal.read_flash(aligned, &mut word)
+            .map_err(|_| MemoryManagementError::ReadError)?;
+
+        let start = (current - aligned) as usize;
+        let count = (4 - start).min(data.len() - offset);
+        data[offset..offset + count].copy_from_slice(&word[start..start + count]);
+        offset += count;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::loopback::LoopbackBus;
+    use crate::hal::sim::{SimDevice, SimFlash, SimHal};
+    use s32k148_hal::flash::S32K148_PFLASH;
+    use s32k148_hal::CanFrame;
+    use std::boxed::Box;
+    use std::vec;
+
+    const APP_START: u32 = 0x0004_0000;
+
+    // Memory manager on an erased program flash. The device is leaked, so
+    // every test gets its own.
+    fn setup() -> (&'static SimFlash<'static>, MemoryManager<SimHal<'static, CanFrame, 4>>) {
+        let program = vec![0u8; S32K148_PFLASH.size as usize].leak();
+        let blocks = Box::leak(Box::new([SimFlash::from_region(&S32K148_PFLASH, program)]));
+        let device = Box::leak(Box::new(SimDevice::new(blocks)));
+        let bus = Box::leak(Box::new(LoopbackBus::<CanFrame, 4>::new()));
+        let (can, _) = bus.split();
+        (&blocks[0], MemoryManager::new(SimHal::new(device, can)).unwrap())
+    }
+
+    #[test]
+    fn returning_to_a_sector_keeps_programmed_phrases() {
+        let (flash, mut memory) = setup();
+        let next_sector = APP_START + S32K148_PFLASH.sector_size;
+
+        // Moving on to the next sector programs the first phrase
+        memory.write(APP_START, &[0x11; 4]).unwrap();
+        memory.write(next_sector, &[0x22; 8]).unwrap();
+
+        // Back in the first sector, its programmed phrase takes no new data,
+        // but the data it holds and the erased phrases are fine
+        assert!(matches!(
+            memory.write(APP_START + 4, &[0x33; 4]),
+            Err(MemoryManagementError::AlreadyProgrammed)
+        ));
+        memory.write(APP_START, &[0x11; 4]).unwrap();
+        memory.write(APP_START + 8, &[0x44; 8]).unwrap();
+        memory.flush().unwrap();
+
+        let contents = flash.contents();
+        let start = APP_START as usize;
+        assert_eq!(&contents[start..start + 8], &[0x11, 0x11, 0x11, 0x11, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&contents[start + 8..start + 16], &[0x44; 8]);
+        assert_eq!(&contents[next_sector as usize..next_sector as usize + 8], &[0x22; 8]);
+    }
+
+    #[test]
+    fn lengths_wrapping_the_address_space_are_out_of_bounds() {
+        let (_, mut memory) = setup();
+
+        // APP_START + length wraps around to an address inside the area
+        let length = u32::MAX - APP_START + 1 + S32K148_PFLASH.sector_size;
+        assert!(matches!(memory.erase(APP_START, length), Err(MemoryManagementError::OutOfBounds)));
+        assert!(matches!(memory.write(u32::MAX - 3, &[0; 8]), Err(MemoryManagementError::OutOfBounds)));
+        let mut data = [0u8; 8];
+        assert!(matches!(memory.read(u32::MAX - 3, &mut data), Err(MemoryManagementError::OutOfBounds)));
+    }
+
+    #[test]
+    fn write_block_is_flushed_at_a_sector_boundary() {
+        let (flash, mut memory) = setup();
+        let boundary = APP_START + S32K148_PFLASH.sector_size;
+        let start = (boundary - 8) as usize;
+
+        // One write across the boundary programs the first sector as soon
+        // as it reaches the second
+        memory.write(boundary - 8, &[0x5A; 16]).unwrap();
+        assert_eq!(&flash.contents()[start..start + 16], &[[0x5A; 8], [0xFF; 8]].concat()[..]);
+
+        memory.flush().unwrap();
+        assert_eq!(&flash.contents()[start..start + 16], &[0x5A; 16]);
+    }
+
+    #[test]
+    fn reads_see_data_waiting_in_the_write_block() {
+        let (flash, mut memory) = setup();
+        let start = APP_START as usize;
+
+        memory.write(APP_START + 2, &[0x12, 0x34, 0x56]).unwrap();
+        let mut data = [0u8; 8];
+        memory.read(APP_START, &mut data).unwrap();
+        assert_eq!(data, [0xFF, 0xFF, 0x12, 0x34, 0x56, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&flash.contents()[start..start + 8], &[0xFF; 8]);
+
+        // Unaligned reads reaching past the block come from both
+        let mut data = [0u8; 5];
+        memory.read(APP_START + 3, &mut data).unwrap();
+        assert_eq!(data, [0x34, 0x56, 0xFF, 0xFF, 0xFF]);
+
+        memory.flush().unwrap();
+        memory.read(APP_START + 3, &mut data).unwrap();
+        assert_eq!(data, [0x34, 0x56, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(&flash.contents()[start + 2..start + 5], &[0x12, 0x34, 0x56]);
+    }
+
+    #[test]
+    fn erase_drops_the_buffered_data_of_its_sectors() {
+        let (flash, mut memory) = setup();
+        let sector_size = S32K148_PFLASH.sector_size;
+        let start = APP_START as usize;
+
+        memory.write(APP_START, &[0x77; 8]).unwrap();
+        memory.erase(APP_START, sector_size).unwrap();
+        memory.flush().unwrap();
+        assert_eq!(&flash.contents()[start..start + 8], &[0xFF; 8]);
+        let mut data = [0u8; 8];
+        memory.read(APP_START, &mut data).unwrap();
+        assert_eq!(data, [0xFF; 8]);
+
+        // Erasing another sector keeps it
+        memory.write(APP_START, &[0x77; 8]).unwrap();
+        memory.erase(APP_START + sector_size, sector_size).unwrap();
+        memory.flush().unwrap();
+        assert_eq!(&flash.contents()[start..start + 8], &[0x77; 8]);
+    }
+
+    #[test]
+    fn partly_written_phrase_is_programmed_only_once() {
+        let (flash, mut memory) = setup();
+        let start = APP_START as usize;
+
+        // The rest of the phrase is programmed as 0xFF
+        memory.write(APP_START, &[0xAA; 4]).unwrap();
+        memory.flush().unwrap();
+        assert_eq!(&flash.contents()[start..start + 8], &[0xAA, 0xAA, 0xAA, 0xAA, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        assert!(matches!(
+            memory.write(APP_START + 4, &[0xBB; 4]),
+            Err(MemoryManagementError::AlreadyProgrammed)
+        ));
+        // Data the phrase holds already is accepted
+        memory.write(APP_START, &[0xAA, 0xAA, 0xAA, 0xAA, 0xFF]).unwrap();
+        memory.flush().unwrap();
+
+        // Until the sector is erased
+        memory.erase(APP_START, S32K148_PFLASH.sector_size).unwrap();
+        memory.write(APP_START + 4, &[0xBB; 4]).unwrap();
+        memory.flush().unwrap();
+        assert_eq!(&flash.contents()[start..start + 8], &[0xFF, 0xFF, 0xFF, 0xFF, 0xBB, 0xBB, 0xBB, 0xBB]);
+    }
+}
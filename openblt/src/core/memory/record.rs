@@ -0,0 +1,287 @@
+// Persistent data record for values written by the tester, such as the
+// programming fingerprint. It lives in two flash sectors of its own outside
+// the application area so erasing the application leaves it intact. Each
+// update goes to the sector not holding the current copy, so a reset during
+// the update leaves the previous copy readable.
+
+use super::MemoryManagementError;
+use crate::hal::S32KHal;
+use s32k148_hal::flash::S32K148_FLEXNVM;
+
+/// Largest amount of entry data the record holds
+pub const DATA_RECORD_MAX_LEN: usize = 256;
+
+// Header: magic, sequence number, entry data length, CRC-16 of the sequence
+// number and entry data, and padding to a whole number of phrases
+const DATA_RECORD_MAGIC: u32 = 0x5244_4944; // "DIDR"
+const DATA_RECORD_HEADER_LEN: usize = 16;
+
+// Every entry starts with its identifier and length
+const ENTRY_HEADER_LEN: usize = 3;
+
+pub struct DataRecord<H: S32KHal> {
+    hal: H,
+    address: u32,
+    sector_size: u32,
+    data: [u8; DATA_RECORD_MAX_LEN],
+    len: usize,
+    // Sector holding the current copy and its sequence number
+    current: Option<(u32, u32)>,
+}
+
+impl<H: S32KHal> DataRecord<H> {
+    /// Keeps the record in the first two sectors of the FlexNVM (D-Flash)
+    pub fn new(hal: H) -> Self {
+        Self::with_location(hal, S32K148_FLEXNVM.start, S32K148_FLEXNVM.sector_size)
+    }
+
+    /// Places the record in the two flash sectors starting at `address`
+    pub fn with_location(hal: H, address: u32, sector_size: u32) -> Self {
+        Self {
+            hal,
+            address,
+            sector_size,
+            data: [0xFF; DATA_RECORD_MAX_LEN],
+            len: 0,
+            current: None,
+        }
+    }
+
+    /// Reads the newest valid copy of the record from flash. Without one
+    /// the record reads as empty.
+    pub fn load(&mut self) -> Result<(), MemoryManagementError> {
+        self.len = 0;
+        self.current = None;
+
+        for sector in [self.address, self.address + self.sector_size] {
+            let (sequence, len) = match self.read_copy(sector)? {
+                Some(copy) => copy,
+                None => continue,
+            };
+            // Sequence numbers wrap, so the newer copy is the one ahead by
+            // less than half the range
+            let newer = match self.current {
+                Some((_, current)) => (sequence.wrapping_sub(current) as i32) > 0,
+                None => true,
+            };
+            if newer {
+                self.current = Some((sector, sequence));
+                self.len = len;
+            }
+        }
+
+        // The data buffer holds whichever copy was read last
+        if let Some((sector, _)) = self.current {
+            self.read_copy(sector)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the stored value of `id` into `buffer` and returns its length
+    pub fn read(&self, id: u16, buffer: &mut [u8]) -> Option<usize> {
+        let (offset, len) = self.find(id)?;
+        let len = len.min(buffer.len());
+        buffer[..len].copy_from_slice(&self.data[offset..offset + len]);
+        Some(len)
+    }
+
+    /// Stores `value` under `id`, replacing an earlier value, and rewrites
+    /// the record in flash.
+    pub fn write(&mut self, id: u16, value: &[u8]) -> Result<(), MemoryManagementError> {
+        if value.len() > u8::MAX as usize {
+            return Err(MemoryManagementError::InvalidLength);
+        }
+
+        // Drop the old entry so the new one goes at the end
+        if let Some((offset, len)) = self.find(id) {
+            let start = offset - ENTRY_HEADER_LEN;
+            self.data.copy_within(offset + len..self.len, start);
+            self.len -= ENTRY_HEADER_LEN + len;
+        }
+
+        if self.len + ENTRY_HEADER_LEN + value.len() > DATA_RECORD_MAX_LEN {
+            return Err(MemoryManagementError::InvalidLength);
+        }
+
+        let id = id.to_be_bytes();
+        let entry = self.len;
+        self.data[entry..entry + ENTRY_HEADER_LEN].copy_from_slice(&[id[0], id[1], value.len() as u8]);
+        self.data[entry + ENTRY_HEADER_LEN..entry + ENTRY_HEADER_LEN + value.len()].copy_from_slice(value);
+        self.len += ENTRY_HEADER_LEN + value.len();
+
+        self.commit()
+    }
+
+    /// Address of the first of the two sectors
+    pub fn get_address(&self) -> u32 {
+        self.address
+    }
+
+    // Reads the copy in `sector` into the data buffer. Returns its sequence
+    // number and length if it is valid.
+    fn read_copy(&mut self, sector: u32) -> Result<Option<(u32, usize)>, MemoryManagementError> {
+        let mut header = [0u8; DATA_RECORD_HEADER_LEN];
+        self.hal.read_flash(sector, &mut header)
+            .map_err(|_| MemoryManagementError::ReadError)?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let len = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let crc = u16::from_le_bytes([header[10], header[11]]);
+        if magic != DATA_RECORD_MAGIC || len > DATA_RECORD_MAX_LEN {
+            return Ok(None);
+        }
+
+        // Flash is read in whole words
+        let read_len = (len + 3) & !0x3;
+        self.hal.read_flash(sector + DATA_RECORD_HEADER_LEN as u32, &mut self.data[..read_len])
+            .map_err(|_| MemoryManagementError::ReadError)?;
+
+        let valid = copy_crc(sequence, &self.data[..len]) == crc;
+        Ok(valid.then_some((sequence, len)))
+    }
+
+    // Returns the offset and length of the value stored under id
+    fn find(&self, id: u16) -> Option<(usize, usize)> {
+        let mut offset = 0;
+        while offset + ENTRY_HEADER_LEN <= self.len {
+            let entry_id = u16::from_be_bytes([self.data[offset], self.data[offset + 1]]);
+            let len = self.data[offset + 2] as usize;
+            if entry_id == id {
+                return Some((offset + ENTRY_HEADER_LEN, len));
+            }
+            offset += ENTRY_HEADER_LEN + len;
+        }
+        None
+    }
+
+    // Writes the record to the sector not holding the current copy and
+    // erases the old copy once the new one is complete
+    fn commit(&mut self) -> Result<(), MemoryManagementError> {
+        let (old, sequence) = match self.current {
+            Some((sector, sequence)) => (Some(sector), sequence.wrapping_add(1)),
+            None => (None, 0),
+        };
+        let sector = if old == Some(self.address) { self.address + self.sector_size } else { self.address };
+
+        self.hal.erase_flash(sector, self.sector_size)
+            .map_err(|_| MemoryManagementError::EraseError)?;
+
+        // Program the entries first so a reset in between leaves no valid header
+        let write_len = (self.len + 7) & !0x7;
+        self.data[self.len..write_len].fill(0xFF);
+        if write_len > 0 {
+            self.hal.write_flash(sector + DATA_RECORD_HEADER_LEN as u32, &self.data[..write_len])
+                .map_err(|_| MemoryManagementError::WriteError)?;
+        }
+
+        let mut header = [0xFF; DATA_RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&DATA_RECORD_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..10].copy_from_slice(&(self.len as u16).to_le_bytes());
+        header[10..12].copy_from_slice(&copy_crc(sequence, &self.data[..self.len]).to_le_bytes());
+        self.hal.write_flash(sector, &header)
+            .map_err(|_| MemoryManagementError::WriteError)?;
+        self.current = Some((sector, sequence));
+
+        // Should this fail, the new copy still wins on its sequence number
+        if let Some(old) = old {
+            self.hal.erase_flash(old, self.sector_size)
+                .map_err(|_| MemoryManagementError::EraseError)?;
+        }
+        Ok(())
+    }
+}
+
+// CRC over the sequence number and entry data of a copy
+fn copy_crc(sequence: u32, data: &[u8]) -> u16 {
+    crc16(crc16(0xFFFF, &sequence.to_le_bytes()), data)
+}
+
+// CRC-16/CCITT (polynomial 0x1021), continuing from `crc`. Start with
+// 0xFFFF.
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::loopback::LoopbackBus;
+    use crate::hal::sim::{SimDevice, SimFlash, SimHal};
+    use s32k148_hal::CanFrame;
+    use std::boxed::Box;
+    use std::vec;
+
+    const FINGERPRINT: u16 = 0xF15A;
+
+    type Hal = SimHal<'static, CanFrame, 4>;
+
+    // HAL of a device with an erased FlexNVM. The device is leaked, so
+    // every test gets its own.
+    fn setup() -> (&'static SimDevice<'static>, Hal) {
+        let data = vec![0u8; S32K148_FLEXNVM.size as usize].leak();
+        let blocks = Box::leak(Box::new([SimFlash::from_region(&S32K148_FLEXNVM, data)]));
+        let device = Box::leak(Box::new(SimDevice::new(blocks)));
+        let bus = Box::leak(Box::new(LoopbackBus::<CanFrame, 4>::new()));
+        let (can, _) = bus.split();
+        (device, SimHal::new(device, can))
+    }
+
+    fn load(hal: &Hal) -> DataRecord<Hal> {
+        let mut record = DataRecord::new(hal.clone());
+        record.load().unwrap();
+        record
+    }
+
+    #[test]
+    fn update_survives_power_loss() {
+        let (device, hal) = setup();
+        let mut value = [0u8; 4];
+
+        // An update erases the other sector, programs the entry and the
+        // two header phrases, then erases the old copy. Until the header
+        // is complete the old value stays.
+        for (cut, expected) in [(0, 1), (1, 1), (2, 1), (3, 2), (4, 2)] {
+            let mut record = load(&hal);
+            record.write(FINGERPRINT, &[1; 4]).unwrap();
+
+            device.cut_power_after(cut);
+            assert!(record.write(FINGERPRINT, &[2; 4]).is_err());
+            device.power_cycle();
+
+            let record = load(&hal);
+            assert_eq!(record.read(FINGERPRINT, &mut value), Some(4));
+            assert_eq!(value, [expected; 4], "power lost after {cut} operations");
+        }
+    }
+
+    #[test]
+    fn updates_alternate_between_the_two_sectors() {
+        let (_, hal) = setup();
+        let mut value = [0u8; 4];
+
+        let mut record = load(&hal);
+        assert_eq!(record.read(FINGERPRINT, &mut value), None);
+        let mut addresses = vec![];
+        for round in 0..3u8 {
+            record.write(FINGERPRINT, &[round; 4]).unwrap();
+            addresses.push(load(&hal).current.unwrap().0);
+            assert_eq!(load(&hal).read(FINGERPRINT, &mut value), Some(4));
+            assert_eq!(value, [round; 4]);
+        }
+        let second = S32K148_FLEXNVM.start + S32K148_FLEXNVM.sector_size;
+        assert_eq!(addresses[0], addresses[2]);
+        assert!(addresses.contains(&S32K148_FLEXNVM.start) && addresses.contains(&second));
+    }
+}
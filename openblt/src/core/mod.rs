@@ -1,11 +1,13 @@
 #![no_std]
 
 use crate::hal::S32KHal;
-use crate::protocol::Protocol;
+use crate::protocol::{Protocol, Transport};
+use crate::protocol::can::CanTransport;
 use core::fmt;
 
 pub mod memory;
 use memory::{MemoryManager, MemoryManagementError};
+use memory::record::DataRecord;
 
 #[derive(Debug)]
 pub enum BootloaderError {
@@ -26,27 +28,40 @@ impl fmt::Display for BootloaderError {
     }
 }
 
-pub struct Bootloader<H: S32KHal + Clone> {
+pub struct Bootloader<H: S32KHal + Clone, T: Transport> {
     hal: H,
-    protocol: Protocol<H::Can>,
+    protocol: Protocol<T>,
     memory_manager: MemoryManager<H>,
+    data_record: DataRecord<H>,
 }
 
-impl<H: S32KHal + Clone> Bootloader<H> {
-    pub fn new(hal: H) -> Result<Self, BootloaderError> {
+impl<H: S32KHal<Timer: Clone> + Clone> Bootloader<H, CanTransport<H::Can, H::Timer>> {
+    /// Creates a bootloader talking XCP-on-CAN through the HAL's CAN controller
+    pub fn with_can(hal: H) -> Result<Self, BootloaderError> {
+        let timer = hal.get_timer().clone();
         let can = hal.clone().get_can();
+        Self::new(hal, CanTransport::new(can, timer))
+    }
+}
+
+impl<H: S32KHal + Clone, T: Transport> Bootloader<H, T> {
+    pub fn new(hal: H, transport: T) -> Result<Self, BootloaderError> {
         Ok(Self {
             hal: hal.clone(),
-            protocol: Protocol::new(can),
-            memory_manager: MemoryManager::new(hal)
+            protocol: Protocol::new(transport),
+            memory_manager: MemoryManager::new(hal.clone())
                 .map_err(BootloaderError::MemoryError)?,
+            data_record: DataRecord::new(hal),
         })
     }
 
     pub fn init(&mut self) -> Result<(), BootloaderError> {
         // Initialize protocol
         self.protocol.init().map_err(|_| BootloaderError::ProtocolError)?;
-        
+
+        // Bring in the values the tester stored earlier
+        self.data_record.load().map_err(BootloaderError::MemoryError)?;
+
         Ok(())
     }
 
@@ -54,8 +69,15 @@ impl<H: S32KHal + Clone> Bootloader<H> {
         &self.memory_manager
     }
 
-    pub fn process(&mut self) -> Result<(), BootloaderError> {
-        // TODO: Implement main bootloader loop
-        Ok(())
+    pub fn get_memory_manager_mut(&mut self) -> &mut MemoryManager<H> {
+        &mut self.memory_manager
+    }
+
+    pub fn get_data_record_mut(&mut self) -> &mut DataRecord<H> {
+        &mut self.data_record
+    }
+
+    pub fn get_protocol_mut(&mut self) -> &mut Protocol<T> {
+        &mut self.protocol
     }
 }
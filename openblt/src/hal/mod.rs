@@ -4,7 +4,10 @@
 
 use core::fmt;
 pub use embedded_can::blocking::Can as EmbeddedCan;
-use embedded_can::ErrorKind;
+// The transports poll the non-blocking interface, so their timeouts keep
+// running while no frame arrives
+pub use embedded_can::nb::Can as NbCan;
+use embedded_can::{ErrorKind, Frame, Id};
 
 // Common error types
 #[derive(Debug)]
@@ -51,13 +54,80 @@ impl fmt::Display for FlashError {
     }
 }
 
+// CAN and CAN FD payload lengths, defined by the S32K148 HAL's FlexCAN driver
+pub use s32k148_hal::can::{dlc_to_len, fd_frame_len, len_to_dlc, CAN_MAX_DATA_LEN, CANFD_MAX_DATA_LEN};
+
+/// CAN frames that can also be CAN FD frames. Types of controllers without
+/// FD support return None from `new_fd`.
+pub trait FdFrame: Frame {
+    /// Creates a CAN FD data frame. `data` must have a length a DLC
+    /// expresses, see `fd_frame_len`.
+    fn new_fd(id: impl Into<Id>, data: &[u8], bit_rate_switch: bool) -> Option<Self>;
+
+    fn is_fd(&self) -> bool;
+
+    /// The data phase is sent with the faster data bit rate (BRS)
+    fn is_bit_rate_switch(&self) -> bool;
+
+    /// The transmitter was error passive (ESI)
+    fn is_error_state_indicator(&self) -> bool;
+}
+
+/// Fault confinement state of a CAN node (ISO 11898-1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CanBusState {
+    ErrorActive,
+    ErrorPassive,
+    BusOff,
+}
+
+/// Fault confinement state with the error counters behind it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CanBusStatus {
+    pub state: CanBusState,
+    /// Transmit error counter (TEC)
+    pub tx_errors: u8,
+    /// Receive error counter (REC)
+    pub rx_errors: u8,
+}
+
+impl CanBusStatus {
+    pub fn is_bus_off(&self) -> bool {
+        self.state == CanBusState::BusOff
+    }
+}
+
+/// Error state of a CAN controller
+pub trait CanErrorState {
+    fn get_bus_status(&self) -> CanBusStatus;
+
+    /// Starts or continues the return to the bus after bus-off. Returns
+    /// `WouldBlock` until the controller takes part in bus traffic again.
+    fn recover_bus_off(&mut self) -> nb::Result<(), HalError>;
+}
+
+// Millisecond time source used for protocol timeouts
+pub trait Timer {
+    /// Free running millisecond counter. It wraps around, so compare
+    /// timestamps with `wrapping_sub`.
+    fn now_ms(&self) -> u32;
+
+    /// Returns true once `timeout_ms` have passed since `start_ms`
+    fn has_elapsed(&self, start_ms: u32, timeout_ms: u32) -> bool {
+        self.now_ms().wrapping_sub(start_ms) >= timeout_ms
+    }
+}
+
 // Hardware Abstraction Layer trait
 pub trait S32KHal {
-    type Can: EmbeddedCan;
+    type Can: EmbeddedCan + NbCan<Frame: FdFrame> + CanErrorState;
+    type Timer: Timer;
     type Error: core::fmt::Debug;
 
     fn init() -> Result<Self, Self::Error> where Self: Sized;
     fn get_can(self) -> Self::Can;
+    /// The HAL's one timer, so that its readings keep counting up
+    fn get_timer(&self) -> &Self::Timer;
     fn get_can_mut(&mut self) -> &mut Self::Can;
     fn is_programming_pin_active(&self) -> bool;
     fn enter_programming_mode(&mut self) -> Result<(), Self::Error>;
@@ -71,3 +141,9 @@ pub trait S32KHal {
 // Platform-specific implementations
 pub mod s32k118;
 pub mod s32k148;
+
+// Host-side bus for exercising the protocol stacks
+pub mod loopback;
+
+// Simulated device for running the bootloader on the host
+pub mod sim;
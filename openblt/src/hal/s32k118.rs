@@ -1,5 +1,4 @@
-use crate::hal::{S32KHal, EmbeddedCan, FlashError};
-use embedded_can::blocking::Can as EmbeddedCanTrait;
+use crate::hal::{S32KHal, CanBusState, CanBusStatus, CanErrorState, EmbeddedCan, FdFrame, FlashError, HalError, NbCan, Timer};
 use embedded_can::ErrorKind;
 use embedded_can::{Frame, Id, StandardId};
 
@@ -59,42 +58,106 @@ impl Frame for S32K118Frame {
     }
 }
 
+// The S32K118 port drives its FlexCAN in classic mode only
+impl FdFrame for S32K118Frame {
+    fn new_fd(_id: impl Into<Id>, _data: &[u8], _bit_rate_switch: bool) -> Option<Self> {
+        None
+    }
+
+    fn is_fd(&self) -> bool {
+        false
+    }
+
+    fn is_bit_rate_switch(&self) -> bool {
+        false
+    }
+
+    fn is_error_state_indicator(&self) -> bool {
+        false
+    }
+}
+
 pub struct S32K118Can {
     // TODO: Add CAN registers
 }
 
-impl EmbeddedCanTrait for S32K118Can {
+impl NbCan for S32K118Can {
     type Error = ErrorKind;
     type Frame = S32K118Frame;
 
-    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+    fn transmit(&mut self, _frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
         // TODO: Implement CAN transmission
-        Ok(())
+        Ok(None)
     }
 
-    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+    fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
         // TODO: Implement CAN reception
         Ok(S32K118Frame::new(Id::Standard(StandardId::new(0).unwrap()), &[]).unwrap())
     }
 }
 
+impl EmbeddedCan for S32K118Can {
+    type Error = ErrorKind;
+    type Frame = S32K118Frame;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        nb::block!(NbCan::transmit(self, frame)).map(|_| ())
+    }
+
+    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
+        nb::block!(NbCan::receive(self))
+    }
+}
+
+impl CanErrorState for S32K118Can {
+    fn get_bus_status(&self) -> CanBusStatus {
+        // TODO: Read ESR1 and ECR
+        CanBusStatus {
+            state: CanBusState::ErrorActive,
+            tx_errors: 0,
+            rx_errors: 0,
+        }
+    }
+
+    fn recover_bus_off(&mut self) -> nb::Result<(), HalError> {
+        Ok(())
+    }
+}
+
+pub struct S32K118Timer {
+    // TODO: Add timer registers
+}
+
+impl Timer for S32K118Timer {
+    fn now_ms(&self) -> u32 {
+        // TODO: Implement millisecond timer
+        0
+    }
+}
+
 pub struct S32K118Hal {
     can: S32K118Can,
+    timer: S32K118Timer,
 }
 
 impl S32KHal for S32K118Hal {
     type Can = S32K118Can;
+    type Timer = S32K118Timer;
     type Error = FlashError;
 
     fn init() -> Result<Self, Self::Error> {
         // TODO: Initialize S32K118 hardware
-        Ok(Self { can: S32K118Can {} })
+        Ok(Self { can: S32K118Can {}, timer: S32K118Timer {} })
     }
 
     fn get_can(self) -> Self::Can {
         self.can
     }
 
+    fn get_timer(&self) -> &Self::Timer {
+        &self.timer
+    }
+
     fn get_can_mut(&mut self) -> &mut Self::Can {
         &mut self.can
     }
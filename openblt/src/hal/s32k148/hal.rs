@@ -1,128 +0,0 @@
-#![no_std]
-
-use core::ptr::NonNull;
-use core::cell::UnsafeCell;
-use crate::hal::{S32KHal, EmbeddedCan, FlashError, HalError};
-use super::Flash;
-
-// CAN register structure
-#[repr(C)]
-pub struct CanRegisters {
-    mcr: UnsafeCell<u32>,
-    ctrl1: UnsafeCell<u32>,
-    timer: UnsafeCell<u32>,
-    rxmgmask: UnsafeCell<u32>,
-    rx14mask: UnsafeCell<u32>,
-    rx15mask: UnsafeCell<u32>,
-    ecr: UnsafeCell<u32>,
-    esr1: UnsafeCell<u32>,
-    imask2: UnsafeCell<u32>,
-    imask1: UnsafeCell<u32>,
-    iflag2: UnsafeCell<u32>,
-    iflag1: UnsafeCell<u32>,
-    ctrl2: UnsafeCell<u32>,
-    esr2: UnsafeCell<u32>,
-    crcr: UnsafeCell<u32>,
-    rxfgmask: UnsafeCell<u32>,
-    rxfir: UnsafeCell<u32>,
-    rximr: [UnsafeCell<u32>; 16],
-}
-
-// CAN peripheral implementation
-pub struct S32K148Can {
-    registers: NonNull<CanRegisters>,
-}
-
-impl S32K148Can {
-    pub unsafe fn new(addr: *mut CanRegisters) -> Self {
-        Self {
-            registers: NonNull::new_unchecked(addr),
-        }
-    }
-
-    unsafe fn registers(&mut self) -> &mut CanRegisters {
-        self.registers.as_mut()
-    }
-}
-
-impl EmbeddedCan for S32K148Can {
-    type Frame = [u8; 8];
-    type Error = HalError;
-
-    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
-        // TODO: Implement CAN transmission
-        Ok(())
-    }
-
-    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
-        // TODO: Implement CAN reception
-        Ok([0; 8])
-    }
-}
-
-// HAL implementation
-pub struct S32K148 {
-    can: S32K148Can,
-    flash: Flash,
-}
-
-impl S32K148 {
-    pub unsafe fn new(can_addr: *mut CanRegisters) -> Self {
-        Self {
-            can: S32K148Can::new(can_addr),
-            flash: Flash::new(),
-        }
-    }
-
-    pub fn get_can(&self) -> &S32K148Can {
-        &self.can
-    }
-
-    pub fn get_can_mut(&mut self) -> &mut S32K148Can {
-        &mut self.can
-    }
-}
-
-impl S32KHal for S32K148 {
-    type Can = S32K148Can;
-    type Error = HalError;
-
-    fn init() -> Result<Self, Self::Error> {
-        // TODO: Implement hardware initialization
-        unsafe {
-            Ok(Self::new(0x40024000 as *mut CanRegisters))
-        }
-    }
-
-    fn is_programming_pin_active(&self) -> bool {
-        // TODO: Implement programming pin check
-        true
-    }
-
-    fn enter_programming_mode(&mut self) -> Result<(), Self::Error> {
-        // TODO: Implement programming mode entry
-        Ok(())
-    }
-
-    fn exit_programming_mode(&mut self) -> Result<(), Self::Error> {
-        // TODO: Implement programming mode exit
-        Ok(())
-    }
-
-    fn erase_flash(&mut self, address: u32, length: u32) -> Result<(), Self::Error> {
-        self.flash.erase(address, length)
-    }
-
-    fn write_flash(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error> {
-        self.flash.write(address, data)
-    }
-
-    fn read_flash(&self, address: u32, data: &mut [u8]) -> Result<(), Self::Error> {
-        self.flash.read(address, data)
-    }
-
-    fn jump_to_application(&self, entry_point: u32) -> Result<(), Self::Error> {
-        // TODO: Implement application jump
-        Ok(())
-    }
-} 
@@ -1,202 +1,137 @@
 #![no_std]
 
-use core::ptr::NonNull;
-use core::cell::UnsafeCell;
-use core::fmt;
-use super::{S32KHal, HalError, FlashError};
-use crate::hal::EmbeddedCan;
-use embedded_can::{Frame, Id, StandardId};
+use core::cell::Cell;
+use cortex_m::peripheral::DWT;
+use super::{S32KHal, HalError, CanBusState, CanBusStatus, CanErrorState, FdFrame, Timer};
+use embedded_can::Id;
+use s32k148_hal::can::{CanRegisterAccess, ErrorState};
+use s32k148_hal::{CanConfig, CanDevice, CanRegisters, Flash};
 
-// Register definitions
-#[repr(C)]
-pub struct CanRegisters {
-    mcr: VolatileCell<u32>,
-    ctrl1: VolatileCell<u32>,
-    timer: VolatileCell<u32>,
-    rxmgmask: VolatileCell<u32>,
-    rx14mask: VolatileCell<u32>,
-    rx15mask: VolatileCell<u32>,
-    ecr: VolatileCell<u32>,
-    esr1: VolatileCell<u32>,
-    imask2: VolatileCell<u32>,
-    imask1: VolatileCell<u32>,
-    iflag2: VolatileCell<u32>,
-    iflag1: VolatileCell<u32>,
-    ctrl2: VolatileCell<u32>,
-    esr2: VolatileCell<u32>,
-    crcr: VolatileCell<u32>,
-    rxfgmask: VolatileCell<u32>,
-    rxfir: VolatileCell<u32>,
-    rximr: [VolatileCell<u32>; 16],
-}
-
-// CAN controller implementation
-pub struct S32K148Can {
-    registers: NonNull<CanRegisters>,
-}
-
-impl S32K148Can {
-    pub unsafe fn new(base: *mut CanRegisters) -> Self {
-        Self {
-            registers: NonNull::new_unchecked(base),
-        }
+// The register-level driver of the s32k148-hal crate serves the protocol
+// stacks as well
+impl FdFrame for s32k148_hal::CanFrame {
+    fn new_fd(id: impl Into<Id>, data: &[u8], bit_rate_switch: bool) -> Option<Self> {
+        s32k148_hal::CanFrame::new_fd(id, data, bit_rate_switch)
     }
 
-    unsafe fn registers(&mut self) -> &mut CanRegisters {
-        self.registers.as_mut()
+    fn is_fd(&self) -> bool {
+        s32k148_hal::CanFrame::is_fd(self)
     }
-}
 
-impl EmbeddedCan for S32K148Can {
-    type Frame = CanFrame;
-    type Error = HalError;
-
-    fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
-        // TODO: Implement CAN transmission
-        Ok(())
+    fn is_bit_rate_switch(&self) -> bool {
+        s32k148_hal::CanFrame::is_bit_rate_switch(self)
     }
 
-    fn receive(&mut self) -> Result<Self::Frame, Self::Error> {
-        // TODO: Implement CAN reception
-        Err(HalError::CanError)
+    fn is_error_state_indicator(&self) -> bool {
+        s32k148_hal::CanFrame::is_error_state_indicator(self)
     }
 }
 
-// CAN frame implementation
-#[derive(Debug, Clone, Copy)]
-pub struct CanFrame {
-    id: StandardId,
-    data: [u8; 8],
-    dlc: u8,
-    is_extended: bool,
-    is_remote: bool,
-}
-
-impl Frame for CanFrame {
-    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
-        if data.len() > 8 {
-            return None;
-        }
-        
-        let mut frame_data = [0u8; 8];
-        frame_data[..data.len()].copy_from_slice(data);
-        
-        let id = id.into();
-        let standard_id = match id {
-            Id::Standard(id) => id,
-            Id::Extended(_) => return None,
-        };
-        
-        Some(CanFrame {
-            id: standard_id,
-            data: frame_data,
-            dlc: data.len() as u8,
-            is_extended: false,
-            is_remote: false,
-        })
-    }
-
-    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
-        if dlc > 8 {
-            return None;
-        }
-        
-        let id = id.into();
-        let standard_id = match id {
-            Id::Standard(id) => id,
-            Id::Extended(_) => return None,
+impl<R: CanRegisterAccess> CanErrorState for s32k148_hal::CanDevice<R> {
+    fn get_bus_status(&self) -> CanBusStatus {
+        let state = match self.get_error_state() {
+            ErrorState::Active => CanBusState::ErrorActive,
+            ErrorState::Passive => CanBusState::ErrorPassive,
+            ErrorState::BusOff => CanBusState::BusOff,
         };
-        
-        Some(CanFrame {
-            id: standard_id,
-            data: [0u8; 8],
-            dlc: dlc as u8,
-            is_extended: false,
-            is_remote: true,
-        })
-    }
-
-    fn id(&self) -> Id {
-        Id::Standard(self.id)
+        let (tx_errors, rx_errors) = self.get_error_counters();
+        CanBusStatus { state, tx_errors, rx_errors }
     }
 
-    fn dlc(&self) -> usize {
-        self.dlc as usize
-    }
-
-    fn data(&self) -> &[u8] {
-        &self.data[..self.dlc as usize]
+    fn recover_bus_off(&mut self) -> nb::Result<(), HalError> {
+        s32k148_hal::CanDevice::recover_bus_off(self).map_err(|e| e.map(|_| HalError::CanError))
     }
+}
 
-    fn is_extended(&self) -> bool {
-        self.is_extended
-    }
+// Core clock after the default clock configuration (RUN mode)
+const CORE_CLOCK_HZ: u32 = 80_000_000;
 
-    fn is_remote_frame(&self) -> bool {
-        self.is_remote
-    }
+// Millisecond timer derived from the DWT cycle counter
+#[derive(Clone)]
+pub struct S32K148Timer {
+    cycles_per_ms: u32,
+    last_cycles: Cell<u32>,
+    pending_cycles: Cell<u32>,
+    ms: Cell<u32>,
 }
 
-// Flash controller implementation
-pub struct Flash {
-    controller: NonNull<FlashController>,
-}
+impl S32K148Timer {
+    pub fn new(core_clock_hz: u32) -> Self {
+        // The cycle counter is disabled out of reset
+        unsafe {
+            let mut core = cortex_m::Peripherals::steal();
+            core.DCB.enable_trace();
+            core.DWT.enable_cycle_counter();
+        }
 
-impl Flash {
-    pub unsafe fn new(base: *mut FlashController) -> Self {
         Self {
-            controller: NonNull::new_unchecked(base),
+            cycles_per_ms: core_clock_hz / 1000,
+            last_cycles: Cell::new(DWT::cycle_count()),
+            pending_cycles: Cell::new(0),
+            ms: Cell::new(0),
         }
     }
+}
 
-    unsafe fn controller(&mut self) -> &mut FlashController {
-        self.controller.as_mut()
+impl Timer for S32K148Timer {
+    fn now_ms(&self) -> u32 {
+        // Fold the cycles since the last call into the millisecond count. This
+        // must run at least once per counter wrap (~53 s at 80 MHz).
+        let now = DWT::cycle_count();
+        let elapsed = now
+            .wrapping_sub(self.last_cycles.get())
+            .wrapping_add(self.pending_cycles.get());
+        self.last_cycles.set(now);
+        self.pending_cycles.set(elapsed % self.cycles_per_ms);
+        self.ms.set(self.ms.get().wrapping_add(elapsed / self.cycles_per_ms));
+        self.ms.get()
     }
 }
 
+// FlexCAN0, wired to the transceiver on the evaluation board
+const CAN0_BASE: usize = 0x4002_4000;
+
 // Main HAL implementation
 pub struct S32K148 {
-    can: S32K148Can,
+    can: CanDevice,
     flash: Flash,
+    timer: S32K148Timer,
     programming_pin_active: bool,
 }
 
 impl S32K148 {
     pub unsafe fn new() -> Self {
         Self {
-            can: S32K148Can::new(0x40024000 as *mut CanRegisters),
-            flash: Flash::new(0x40020000 as *mut FlashController),
+            can: CanDevice::new(&mut *(CAN0_BASE as *mut CanRegisters)),
+            flash: Flash::new(),
+            timer: S32K148Timer::new(CORE_CLOCK_HZ),
             programming_pin_active: false,
         }
     }
 }
 
 impl S32KHal for S32K148 {
-    type Can = S32K148Can;
+    type Can = CanDevice;
+    type Timer = S32K148Timer;
     type Error = HalError;
 
     fn init() -> Result<Self, Self::Error> {
-        unsafe {
-            let mut hal = Self::new();
-            
-            // Initialize CAN controller
-            let can_regs = hal.can.registers();
-            can_regs.mcr.set(0x00000001); // Enter initialization mode
-            can_regs.ctrl1.set(0x00000000); // Configure for normal operation
-            can_regs.mcr.set(0x00000000); // Exit initialization mode
-            
-            // Initialize flash controller
-            let flash_ctrl = hal.flash.controller();
-            flash_ctrl.fstat.set(0x00000000); // Clear status register
-            
-            Ok(hal)
-        }
+        let mut hal = unsafe { Self::new() };
+
+        // 500 kbit/s classic CAN; acceptance filters are left to the board
+        hal.can.init(&CanConfig::default()).map_err(|_| HalError::CanError)?;
+
+        Ok(hal)
     }
 
     fn get_can(self) -> Self::Can {
         self.can
     }
 
+    fn get_timer(&self) -> &Self::Timer {
+        &self.timer
+    }
+
     fn get_can_mut(&mut self) -> &mut Self::Can {
         &mut self.can
     }
@@ -216,83 +151,17 @@ impl S32KHal for S32K148 {
     }
 
     fn erase_flash(&mut self, address: u32, length: u32) -> Result<(), Self::Error> {
-        unsafe {
-            let flash_ctrl = self.flash.controller();
-            
-            // Verify address and length alignment
-            if address % 4096 != 0 || length % 4096 != 0 {
-                return Err(HalError::FlashError);
-            }
-            
-            // Erase flash pages
-            let mut current_addr = address;
-            while current_addr < address + length {
-                flash_ctrl.fccobix.set(0);
-                flash_ctrl.fccobhi.set(0x0A); // Erase command
-                flash_ctrl.fccoblo.set(current_addr);
-                flash_ctrl.fstat.set(0x80); // Start command
-                
-                // Wait for completion
-                while flash_ctrl.fstat.get() & 0x80 != 0 {}
-                
-                if flash_ctrl.fstat.get() & 0x30 != 0 {
-                    return Err(HalError::FlashError);
-                }
-                
-                current_addr += 4096;
-            }
-        }
-        Ok(())
+        self.flash.erase(address, length).map_err(|_| HalError::FlashError)
     }
 
     fn write_flash(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error> {
-        unsafe {
-            let flash_ctrl = self.flash.controller();
-            
-            // Verify address alignment
-            if address % 4 != 0 {
-                return Err(HalError::FlashError);
-            }
-            
-            // Write data in 4-byte chunks
-            for (i, chunk) in data.chunks(4).enumerate() {
-                let mut word = 0u32;
-                for (j, &byte) in chunk.iter().enumerate() {
-                    word |= (byte as u32) << (j * 8);
-                }
-                
-                flash_ctrl.fccobix.set(0);
-                flash_ctrl.fccobhi.set(0x06); // Program command
-                flash_ctrl.fccoblo.set(address + (i * 4) as u32);
-                flash_ctrl.fccobhi.set(word);
-                flash_ctrl.fstat.set(0x80); // Start command
-                
-                // Wait for completion
-                while flash_ctrl.fstat.get() & 0x80 != 0 {}
-                
-                if flash_ctrl.fstat.get() & 0x30 != 0 {
-                    return Err(HalError::FlashError);
-                }
-            }
-        }
-        Ok(())
+        self.flash.write(address, data).map_err(|_| HalError::FlashError)
     }
 
     fn read_flash(&self, address: u32, data: &mut [u8]) -> Result<(), Self::Error> {
-        unsafe {
-            // Verify address alignment
-            if address % 4 != 0 {
-                return Err(HalError::FlashError);
-            }
-            
-            // Read data in 4-byte chunks
-            for (i, chunk) in data.chunks_mut(4).enumerate() {
-                let word = *(address as *const u32).add(i);
-                for (j, byte) in chunk.iter_mut().enumerate() {
-                    *byte = ((word >> (j * 8)) & 0xFF) as u8;
-                }
-            }
-        }
+        let flash_data = self.flash.read(address, data.len() as u32)
+            .map_err(|_| HalError::FlashError)?;
+        data.copy_from_slice(flash_data);
         Ok(())
     }
 
@@ -315,29 +184,3 @@ impl S32KHal for S32K148 {
         Err(HalError::InvalidState)
     }
 }
-
-// Helper types
-#[repr(transparent)]
-struct VolatileCell<T> {
-    value: UnsafeCell<T>,
-}
-
-impl<T> VolatileCell<T> {
-    fn get(&self) -> T {
-        unsafe { core::ptr::read_volatile(self.value.get()) }
-    }
-
-    fn set(&self, value: T) {
-        unsafe { core::ptr::write_volatile(self.value.get(), value) }
-    }
-}
-
-#[repr(C)]
-struct FlashController {
-    fstat: VolatileCell<u32>,
-    fcnfg: VolatileCell<u32>,
-    fsec: VolatileCell<u32>,
-    fccobix: VolatileCell<u32>,
-    fccobhi: VolatileCell<u32>,
-    fccoblo: VolatileCell<u32>,
-} 
@@ -0,0 +1,511 @@
+// Simulated device for running the bootloader on the host. Flash blocks
+// behave like NOR flash: erasing sets whole sectors to 0xFF, programming
+// writes whole phrases and can only clear bits. Faults can be injected to
+// test how the bootloader copes with a failing or unpowered device.
+//
+// The flash contents live in buffers owned by the caller. To back a block
+// with a file, read the file into its buffer before the test and write
+// `SimFlash::contents` back afterwards.
+
+use core::cell::{Cell, Ref, RefCell};
+use embedded_can::Frame;
+use super::loopback::LoopbackCan;
+use super::{FdFrame, HalError, S32KHal, Timer};
+use s32k148_hal::flash::FlashRegion;
+
+/// Bytes written by one program operation unless the block is made from a
+/// layout region, as with the FTFC's Program Phrase command
+pub const SIM_PHRASE_SIZE: u32 = 8;
+
+/// One flash block of the simulated device
+pub struct SimFlash<'a> {
+    memory: RefCell<&'a mut [u8]>,
+    base: u32,
+    sector_size: u32,
+    program_size: u32,
+    // Sector whose erase fails and program unit whose programming fails
+    failing_erase: Cell<Option<u32>>,
+    failing_program: Cell<Option<u32>>,
+}
+
+impl<'a> SimFlash<'a> {
+    /// Maps `memory` to `base`. Its length must be a multiple of
+    /// `sector_size`, which must be a multiple of the phrase size.
+    pub fn new(base: u32, sector_size: u32, memory: &'a mut [u8]) -> Self {
+        Self::with_program_size(base, sector_size, SIM_PHRASE_SIZE, memory)
+    }
+
+    /// Simulates `region` of a `FlashLayout`, erased. `memory` has to be
+    /// as large as the region.
+    pub fn from_region(region: &FlashRegion, memory: &'a mut [u8]) -> Self {
+        assert!(memory.len() == region.size as usize);
+        memory.fill(0xFF);
+        Self::with_program_size(region.start, region.sector_size, region.program_size, memory)
+    }
+
+    fn with_program_size(base: u32, sector_size: u32, program_size: u32, memory: &'a mut [u8]) -> Self {
+        assert!(sector_size.is_multiple_of(program_size) && memory.len().is_multiple_of(sector_size as usize));
+        Self {
+            memory: RefCell::new(memory),
+            base,
+            sector_size,
+            program_size,
+            failing_erase: Cell::new(None),
+            failing_program: Cell::new(None),
+        }
+    }
+
+    /// Like `new`, with the block erased first
+    pub fn erased(base: u32, sector_size: u32, memory: &'a mut [u8]) -> Self {
+        memory.fill(0xFF);
+        Self::new(base, sector_size, memory)
+    }
+
+    pub fn contents(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.memory.borrow(), |memory| &**memory)
+    }
+
+    pub fn get_base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn get_sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    pub fn get_size(&self) -> u32 {
+        self.memory.borrow().len() as u32
+    }
+
+    /// Every erase of the sector holding `address` fails until
+    /// `clear_faults`, leaving its contents untouched
+    pub fn fail_erase(&self, address: u32) {
+        self.failing_erase.set(Some(address - address % self.sector_size));
+    }
+
+    /// Every program operation on the unit holding `address` fails until
+    /// `clear_faults`, without changing a bit
+    pub fn fail_program(&self, address: u32) {
+        self.failing_program.set(Some(address - address % self.program_size));
+    }
+
+    /// Toggles one bit of the stored data, like a cell losing or gaining
+    /// charge
+    pub fn flip_bit(&self, address: u32, bit: u8) {
+        let offset = (address - self.base) as usize;
+        self.memory.borrow_mut()[offset] ^= 1 << (bit & 0x7);
+    }
+
+    pub fn clear_faults(&self) {
+        self.failing_erase.set(None);
+        self.failing_program.set(None);
+    }
+
+    fn contains(&self, address: u32, length: u32) -> bool {
+        address >= self.base && (address - self.base) as u64 + length as u64 <= self.get_size() as u64
+    }
+
+    // Erases one sector. An interrupted erase only gets through the first
+    // half of it.
+    fn erase_sector(&self, address: u32, interrupted: bool) -> Result<(), HalError> {
+        if self.failing_erase.get() == Some(address) {
+            return Err(HalError::FlashError);
+        }
+        let offset = (address - self.base) as usize;
+        let len = if interrupted { self.sector_size / 2 } else { self.sector_size } as usize;
+        self.memory.borrow_mut()[offset..offset + len].fill(0xFF);
+        Ok(())
+    }
+
+    // Programs one program unit. An interrupted operation only gets through the
+    // first half of it.
+    fn program_unit(&self, address: u32, data: &[u8], interrupted: bool) -> Result<(), HalError> {
+        if self.failing_program.get() == Some(address) {
+            return Err(HalError::FlashError);
+        }
+        let offset = (address - self.base) as usize;
+        let len = if interrupted { data.len() / 2 } else { data.len() };
+        let mut memory = self.memory.borrow_mut();
+        for (cell, &byte) in memory[offset..offset + len].iter_mut().zip(data) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+
+    fn read(&self, address: u32, data: &mut [u8]) {
+        let offset = (address - self.base) as usize;
+        data.copy_from_slice(&self.memory.borrow()[offset..offset + data.len()]);
+    }
+}
+
+/// State of the simulated device shared by all clones of its `SimHal`: the
+/// flash blocks, a millisecond clock, the programming pin and the power
+/// supply.
+pub struct SimDevice<'a> {
+    blocks: &'a [SimFlash<'a>],
+    now_ms: Cell<u32>,
+    programming_pin: Cell<bool>,
+    programming_mode: Cell<bool>,
+    // Erase and program operations so far, and the one that loses power
+    operations: Cell<u32>,
+    power_loss_at: Cell<Option<u32>>,
+    powered: Cell<bool>,
+    entry_point: Cell<Option<u32>>,
+}
+
+impl<'a> SimDevice<'a> {
+    pub fn new(blocks: &'a [SimFlash<'a>]) -> Self {
+        Self {
+            blocks,
+            now_ms: Cell::new(0),
+            programming_pin: Cell::new(false),
+            programming_mode: Cell::new(false),
+            operations: Cell::new(0),
+            power_loss_at: Cell::new(None),
+            powered: Cell::new(true),
+            entry_point: Cell::new(None),
+        }
+    }
+
+    /// Moves the clock read by `SimTimer` forward
+    pub fn advance_ms(&self, ms: u32) {
+        self.now_ms.set(self.now_ms.get().wrapping_add(ms));
+    }
+
+    pub fn set_programming_pin(&self, active: bool) {
+        self.programming_pin.set(active);
+    }
+
+    pub fn is_programming_mode(&self) -> bool {
+        self.programming_mode.get()
+    }
+
+    /// Lets `count` more erase or program operations complete and cuts the
+    /// power in the middle of the one after. From then on every flash
+    /// access fails until `power_cycle`.
+    pub fn cut_power_after(&self, count: u32) {
+        self.power_loss_at.set(Some(self.operations.get() + count));
+    }
+
+    /// Restores the power. Flash keeps what was written before the loss;
+    /// the rest of the device starts over.
+    pub fn power_cycle(&self) {
+        self.power_loss_at.set(None);
+        self.powered.set(true);
+        self.programming_mode.set(false);
+        self.entry_point.set(None);
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered.get()
+    }
+
+    /// Erase and program operations carried out or started so far. Each
+    /// sector and each program unit counts as one.
+    pub fn get_operation_count(&self) -> u32 {
+        self.operations.get()
+    }
+
+    /// Where `jump_to_application` went, if it was called
+    pub fn get_entry_point(&self) -> Option<u32> {
+        self.entry_point.get()
+    }
+
+    pub fn get_blocks(&self) -> &'a [SimFlash<'a>] {
+        self.blocks
+    }
+
+    fn find_block(&self, address: u32, length: u32) -> Result<&'a SimFlash<'a>, HalError> {
+        if !self.powered.get() {
+            return Err(HalError::FlashError);
+        }
+        self.blocks
+            .iter()
+            .find(|block| block.contains(address, length))
+            .ok_or(HalError::FlashError)
+    }
+
+    // Counts an operation and tells whether it is the one the power fails in
+    fn start_operation(&self) -> bool {
+        let interrupted = self.power_loss_at.get() == Some(self.operations.get());
+        self.operations.set(self.operations.get() + 1);
+        if interrupted {
+            self.powered.set(false);
+        }
+        interrupted
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), HalError> {
+        let block = self.find_block(address, length)?;
+        if !address.is_multiple_of(block.sector_size) || !length.is_multiple_of(block.sector_size) {
+            return Err(HalError::FlashError);
+        }
+
+        let mut current_addr = address;
+        while current_addr < address + length {
+            let interrupted = self.start_operation();
+            block.erase_sector(current_addr, interrupted)?;
+            if interrupted {
+                return Err(HalError::FlashError);
+            }
+            current_addr += block.sector_size;
+        }
+        Ok(())
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), HalError> {
+        let block = self.find_block(address, data.len() as u32)?;
+        if !address.is_multiple_of(block.program_size) || !(data.len() as u32).is_multiple_of(block.program_size) {
+            return Err(HalError::FlashError);
+        }
+
+        for (i, unit) in data.chunks(block.program_size as usize).enumerate() {
+            let interrupted = self.start_operation();
+            block.program_unit(address + i as u32 * block.program_size, unit, interrupted)?;
+            if interrupted {
+                return Err(HalError::FlashError);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, address: u32, data: &mut [u8]) -> Result<(), HalError> {
+        self.find_block(address, data.len() as u32)?.read(address, data);
+        Ok(())
+    }
+}
+
+/// Millisecond counter of a `SimDevice`. It only moves on
+/// `SimDevice::advance_ms`.
+#[derive(Clone)]
+pub struct SimTimer<'a> {
+    device: &'a SimDevice<'a>,
+}
+
+impl Timer for SimTimer<'_> {
+    fn now_ms(&self) -> u32 {
+        self.device.now_ms.get()
+    }
+}
+
+/// HAL of a `SimDevice`, talking CAN through one end of a loopback bus
+#[derive(Clone)]
+pub struct SimHal<'a, F: Frame + Clone, const N: usize> {
+    device: &'a SimDevice<'a>,
+    can: LoopbackCan<'a, F, N>,
+    timer: SimTimer<'a>,
+}
+
+impl<'a, F: Frame + Clone, const N: usize> SimHal<'a, F, N> {
+    pub fn new(device: &'a SimDevice<'a>, can: LoopbackCan<'a, F, N>) -> Self {
+        Self { device, can, timer: SimTimer { device } }
+    }
+
+    pub fn get_device(&self) -> &'a SimDevice<'a> {
+        self.device
+    }
+}
+
+impl<'a, F: FdFrame + Clone, const N: usize> S32KHal for SimHal<'a, F, N> {
+    type Can = LoopbackCan<'a, F, N>;
+    type Timer = SimTimer<'a>;
+    type Error = HalError;
+
+    // There is no hardware to bring up; build the HAL with `SimHal::new`
+    fn init() -> Result<Self, Self::Error> {
+        Err(HalError::InvalidState)
+    }
+
+    fn get_can(self) -> Self::Can {
+        self.can
+    }
+
+    fn get_timer(&self) -> &Self::Timer {
+        &self.timer
+    }
+
+    fn get_can_mut(&mut self) -> &mut Self::Can {
+        &mut self.can
+    }
+
+    fn is_programming_pin_active(&self) -> bool {
+        self.device.programming_pin.get()
+    }
+
+    fn enter_programming_mode(&mut self) -> Result<(), Self::Error> {
+        self.device.programming_mode.set(true);
+        Ok(())
+    }
+
+    fn exit_programming_mode(&mut self) -> Result<(), Self::Error> {
+        self.device.programming_mode.set(false);
+        Ok(())
+    }
+
+    fn erase_flash(&mut self, address: u32, length: u32) -> Result<(), Self::Error> {
+        self.device.erase(address, length)
+    }
+
+    fn write_flash(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error> {
+        self.device.write(address, data)
+    }
+
+    fn read_flash(&self, address: u32, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.device.read(address, data)
+    }
+
+    fn jump_to_application(&self, entry_point: u32) -> Result<(), Self::Error> {
+        self.device.entry_point.set(Some(entry_point));
+        Ok(())
+    }
+}
+
+impl<'a, F: Frame + Clone, const N: usize> s32k148_hal::S32KHal for SimHal<'a, F, N> {
+    type Can = LoopbackCan<'a, F, N>;
+    type Error = HalError;
+
+    fn init() -> Result<Self, Self::Error> {
+        Err(HalError::InvalidState)
+    }
+
+    fn get_can(&self) -> &Self::Can {
+        &self.can
+    }
+
+    fn get_can_mut(&mut self) -> &mut Self::Can {
+        &mut self.can
+    }
+
+    fn enter_programming_mode(&mut self) -> Result<(), Self::Error> {
+        self.device.programming_mode.set(true);
+        Ok(())
+    }
+
+    fn exit_programming_mode(&mut self) -> Result<(), Self::Error> {
+        self.device.programming_mode.set(false);
+        Ok(())
+    }
+
+    fn erase_flash(&mut self, address: u32, length: u32) -> Result<(), Self::Error> {
+        self.device.erase(address, length)
+    }
+
+    fn write_flash(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error> {
+        self.device.write(address, data)
+    }
+
+    fn read_flash(&self, address: u32, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.device.read(address, data)
+    }
+
+    fn is_programming_pin_active(&self) -> bool {
+        self.device.programming_pin.get()
+    }
+
+    fn jump_to_application(&self, entry_point: u32) -> Result<(), Self::Error> {
+        self.device.entry_point.set(Some(entry_point));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::memory::{MemoryManagementError, MemoryManager};
+    use crate::hal::loopback::LoopbackBus;
+    use s32k148_hal::flash::S32K148_PFLASH;
+    use s32k148_hal::CanFrame;
+    use std::boxed::Box;
+    use std::vec;
+
+    const APP_START: u32 = 0x0004_0000;
+
+    type Hal = SimHal<'static, CanFrame, 4>;
+
+    // Memory manager on an erased program flash. The device is leaked, so
+    // every test gets its own.
+    fn setup() -> (&'static SimFlash<'static>, &'static SimDevice<'static>, MemoryManager<Hal>) {
+        let program = vec![0u8; S32K148_PFLASH.size as usize].leak();
+        let blocks = Box::leak(Box::new([SimFlash::from_region(&S32K148_PFLASH, program)]));
+        let device = Box::leak(Box::new(SimDevice::new(blocks)));
+        let bus = Box::leak(Box::new(LoopbackBus::<CanFrame, 4>::new()));
+        let (can, _) = bus.split();
+        (&blocks[0], device, MemoryManager::new(SimHal::new(device, can)).unwrap())
+    }
+
+    #[test]
+    fn failed_program_keeps_cell_contents() {
+        let (flash, _, mut memory) = setup();
+
+        flash.fail_program(APP_START + 8);
+        memory.write(APP_START, &[0x5A; 16]).unwrap();
+        assert!(matches!(memory.flush(), Err(MemoryManagementError::WriteError)));
+
+        // The phrase before the failing one got through
+        let contents = flash.contents();
+        let start = APP_START as usize;
+        assert_eq!(&contents[start..start + 8], &[0x5A; 8]);
+        assert_eq!(&contents[start + 8..start + 16], &[0xFF; 8]);
+
+        // The cell takes data again once the fault is cleared
+        drop(contents);
+        flash.clear_faults();
+        memory.write(APP_START + 8, &[0x5A; 8]).unwrap();
+        memory.flush().unwrap();
+        assert_eq!(&flash.contents()[start + 8..start + 16], &[0x5A; 8]);
+    }
+
+    #[test]
+    fn power_loss_interrupts_programming() {
+        let (_, device, mut memory) = setup();
+        let hal = memory.get_hal().clone();
+
+        let operations = device.get_operation_count();
+        device.cut_power_after(2);
+        memory.write(APP_START, &[0x00; 32]).unwrap();
+        assert!(memory.flush().is_err());
+        assert!(!device.is_powered());
+        assert_eq!(device.get_operation_count(), operations + 3);
+
+        // Nothing can be read until the power returns
+        let mut data = [0u8; 32];
+        assert!(matches!(memory.read(APP_START, &mut data), Err(MemoryManagementError::ReadError)));
+
+        // After the restart two phrases are programmed and the third one
+        // only half
+        device.power_cycle();
+        let memory = MemoryManager::new(hal).unwrap();
+        memory.read(APP_START, &mut data).unwrap();
+        assert_eq!(&data[..20], &[0x00; 20]);
+        assert_eq!(&data[20..], &[0xFF; 12]);
+    }
+
+    #[test]
+    fn bit_flip_shows_in_read_back() {
+        let (flash, _, mut memory) = setup();
+
+        memory.write(APP_START, &[0x0F; 8]).unwrap();
+        memory.flush().unwrap();
+        flash.flip_bit(APP_START + 3, 7);
+
+        let mut data = [0u8; 8];
+        memory.read(APP_START, &mut data).unwrap();
+        assert_eq!(data, [0x0F, 0x0F, 0x0F, 0x8F, 0x0F, 0x0F, 0x0F, 0x0F]);
+    }
+
+    #[test]
+    fn timer_and_entry_point_are_kept_on_the_device() {
+        let (_, device, memory) = setup();
+        let hal = memory.get_hal();
+
+        // Every clone of the timer reads the device's clock
+        let timer = hal.get_timer().clone();
+        device.advance_ms(250);
+        assert_eq!(timer.now_ms(), 250);
+        assert_eq!(hal.get_timer().now_ms(), 250);
+
+        assert_eq!(device.get_entry_point(), None);
+        hal.jump_to_application(APP_START + 0x101).unwrap();
+        assert_eq!(device.get_entry_point(), Some(APP_START + 0x101));
+    }
+}
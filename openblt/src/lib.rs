@@ -1,9 +1,13 @@
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 // Core bootloader functionality
 pub mod core;
 pub mod protocol;
 pub mod hal;
+pub mod net;
 pub mod boards;
 
 // Re-export commonly used types
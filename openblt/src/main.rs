@@ -15,7 +15,7 @@ mod utils;
 fn main() -> ! {
     // Initialize hardware
     let mut hal = S32K148Hal::init().expect("Failed to initialize hardware");
-    let mut bootloader = Bootloader::new(hal).expect("Failed to create bootloader");
+    let mut bootloader = Bootloader::with_can(hal).expect("Failed to create bootloader");
 
     // Test entry conditions
     let is_programming_pin_active = bootloader.get_hal().is_programming_pin_active();
@@ -0,0 +1,143 @@
+// In-memory Ethernet link connecting two smoltcp interfaces, for running
+// the network transports on the host without a TAP device
+
+use core::cell::RefCell;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use super::ETHERNET_MAX_FRAME_LEN;
+
+// Fixed capacity FIFO of frames
+struct FrameQueue<const N: usize> {
+    frames: [[u8; ETHERNET_MAX_FRAME_LEN]; N],
+    lengths: [usize; N],
+    head: usize,
+    count: usize,
+}
+
+impl<const N: usize> FrameQueue<N> {
+    fn new() -> Self {
+        Self {
+            frames: [[0; ETHERNET_MAX_FRAME_LEN]; N],
+            lengths: [0; N],
+            head: 0,
+            count: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.count == N
+    }
+
+    fn push(&mut self, frame: &[u8]) {
+        if self.is_full() {
+            return;
+        }
+        let slot = (self.head + self.count) % N;
+        self.frames[slot][..frame.len()].copy_from_slice(frame);
+        self.lengths[slot] = frame.len();
+        self.count += 1;
+    }
+
+    fn pop(&mut self, frame: &mut [u8; ETHERNET_MAX_FRAME_LEN]) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+        let len = self.lengths[self.head];
+        frame[..len].copy_from_slice(&self.frames[self.head][..len]);
+        self.head = (self.head + 1) % N;
+        self.count -= 1;
+        Some(len)
+    }
+}
+
+/// A point-to-point Ethernet link holding up to `N` frames in flight per
+/// direction. Frames sent by one end are received by the other in order.
+pub struct MemoryLink<const N: usize> {
+    a_to_b: RefCell<FrameQueue<N>>,
+    b_to_a: RefCell<FrameQueue<N>>,
+}
+
+impl<const N: usize> MemoryLink<N> {
+    pub fn new() -> Self {
+        Self {
+            a_to_b: RefCell::new(FrameQueue::new()),
+            b_to_a: RefCell::new(FrameQueue::new()),
+        }
+    }
+
+    /// Returns the two ends of the link
+    pub fn split(&self) -> (MemoryDevice<'_, N>, MemoryDevice<'_, N>) {
+        (
+            MemoryDevice { tx: &self.a_to_b, rx: &self.b_to_a },
+            MemoryDevice { tx: &self.b_to_a, rx: &self.a_to_b },
+        )
+    }
+}
+
+impl<const N: usize> Default for MemoryLink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct MemoryDevice<'l, const N: usize> {
+    tx: &'l RefCell<FrameQueue<N>>,
+    rx: &'l RefCell<FrameQueue<N>>,
+}
+
+pub struct MemoryRxToken {
+    frame: [u8; ETHERNET_MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl phy::RxToken for MemoryRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.frame[..self.len])
+    }
+}
+
+pub struct MemoryTxToken<'a, const N: usize> {
+    queue: &'a RefCell<FrameQueue<N>>,
+}
+
+impl<const N: usize> phy::TxToken for MemoryTxToken<'_, N> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = [0u8; ETHERNET_MAX_FRAME_LEN];
+        let len = len.min(ETHERNET_MAX_FRAME_LEN);
+        let result = f(&mut frame[..len]);
+        self.queue.borrow_mut().push(&frame[..len]);
+        result
+    }
+}
+
+impl<'l, const N: usize> Device for MemoryDevice<'l, N> {
+    type RxToken<'a> = MemoryRxToken where Self: 'a;
+    type TxToken<'a> = MemoryTxToken<'a, N> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let mut frame = [0u8; ETHERNET_MAX_FRAME_LEN];
+        let len = self.rx.borrow_mut().pop(&mut frame)?;
+        Some((MemoryRxToken { frame, len }, MemoryTxToken { queue: self.tx }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        // A full queue behaves like a MAC with no free descriptor
+        if self.tx.borrow().is_full() {
+            return None;
+        }
+        Some(MemoryTxToken { queue: self.tx })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = ETHERNET_MAX_FRAME_LEN;
+        capabilities
+    }
+}
@@ -0,0 +1,122 @@
+// IP networking for the Ethernet based transports, built on smoltcp. Any
+// smoltcp `Device` carries the frames: the ENET MAC on the target or the
+// in-memory link on the host.
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::Device;
+use smoltcp::socket::dhcpv4;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+use crate::hal::Timer;
+
+pub mod memory;
+
+/// Largest Ethernet frame exchanged with a device, without the FCS
+pub const ETHERNET_MAX_FRAME_LEN: usize = 1514;
+
+/// How the interface gets its IPv4 address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpConfig {
+    Static {
+        address: Ipv4Address,
+        prefix_len: u8,
+        gateway: Option<Ipv4Address>,
+    },
+    /// Address, prefix and gateway are leased from a DHCP server
+    Dhcp,
+}
+
+/// IPv4 settings of the bootloader's interface
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkConfig {
+    pub mac: [u8; 6],
+    pub ip: IpConfig,
+}
+
+impl NetworkConfig {
+    /// Uses a fixed address
+    pub fn new(mac: [u8; 6], address: Ipv4Address, prefix_len: u8) -> Self {
+        Self {
+            mac,
+            ip: IpConfig::Static {
+                address,
+                prefix_len,
+                gateway: None,
+            },
+        }
+    }
+
+    /// Requests the address from a DHCP server
+    pub fn dhcp(mac: [u8; 6]) -> Self {
+        Self {
+            mac,
+            ip: IpConfig::Dhcp,
+        }
+    }
+
+    /// Sets the default gateway of a fixed address
+    pub fn with_gateway(mut self, gateway: Ipv4Address) -> Self {
+        if let IpConfig::Static { gateway: ref mut current, .. } = self.ip {
+            *current = Some(gateway);
+        }
+        self
+    }
+}
+
+/// Creates an interface on `device` configured from `config`. A DHCP
+/// configured interface starts without an address.
+pub fn create_interface<D: Device>(config: &NetworkConfig, device: &mut D, now: Instant) -> Interface {
+    let hardware_addr = HardwareAddress::Ethernet(EthernetAddress(config.mac));
+    let mut iface = Interface::new(Config::new(hardware_addr), device, now);
+
+    if let IpConfig::Static { address, prefix_len, gateway } = config.ip {
+        set_address(&mut iface, Ipv4Cidr::new(address, prefix_len), gateway);
+    }
+    iface
+}
+
+fn set_address(iface: &mut Interface, cidr: Ipv4Cidr, gateway: Option<Ipv4Address>) {
+    iface.update_ip_addrs(|addrs| {
+        addrs.clear();
+        let _ = addrs.push(IpCidr::Ipv4(cidr));
+    });
+    iface.routes_mut().remove_default_ipv4_route();
+    if let Some(gateway) = gateway {
+        let _ = iface.routes_mut().add_default_ipv4_route(gateway);
+    }
+}
+
+/// DHCP client applying leased addresses to an interface
+pub struct DhcpClient {
+    handle: SocketHandle,
+}
+
+impl DhcpClient {
+    /// Adds the client's socket to `sockets` when `config` asks for DHCP
+    pub fn start(config: &NetworkConfig, sockets: &mut SocketSet<'_>) -> Option<Self> {
+        if config.ip != IpConfig::Dhcp {
+            return None;
+        }
+        let handle = sockets.add(dhcpv4::Socket::new());
+        Some(Self { handle })
+    }
+
+    /// Applies a new or lost lease. Call it after every interface poll.
+    pub fn poll(&self, iface: &mut Interface, sockets: &mut SocketSet<'_>) {
+        match sockets.get_mut::<dhcpv4::Socket>(self.handle).poll() {
+            Some(dhcpv4::Event::Configured(lease)) => {
+                set_address(iface, lease.address, lease.router);
+            }
+            Some(dhcpv4::Event::Deconfigured) => {
+                iface.update_ip_addrs(|addrs| addrs.clear());
+                iface.routes_mut().remove_default_ipv4_route();
+            }
+            None => {}
+        }
+    }
+}
+
+/// smoltcp timestamp of the timer's current time
+pub fn now<T: Timer>(timer: &T) -> Instant {
+    Instant::from_millis(timer.now_ms() as i64)
+}
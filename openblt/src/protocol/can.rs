@@ -0,0 +1,353 @@
+// XCP-on-CAN transport
+
+use embedded_can::{Frame, Id, StandardId};
+use crate::hal::{fd_frame_len, CanBusStatus, CanErrorState, FdFrame, NbCan, Timer};
+use super::{ProtocolError, Transport};
+
+/// Largest packet carried by a classic CAN frame
+pub const CAN_MAX_PACKET_LEN: usize = 8;
+/// Largest packet carried by a CAN FD frame
+pub const CANFD_MAX_PACKET_LEN: usize = 64;
+
+// Time allowed for a transmit buffer to become free, which includes the
+// return from a bus-off: 128 x 11 bit times, under 3 ms at 500 kbit/s
+const CAN_TX_TIMEOUT_MS: u32 = 50;
+
+/// Who brings the controller back onto the bus after bus-off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusOffPolicy {
+    /// The transport, as soon as it notices
+    Automatic,
+    /// The application, with `CanTransport::recover_bus_off`
+    Manual,
+}
+
+/// XCP-on-CAN transport settings
+#[derive(Debug, Clone, Copy)]
+pub struct XcpCanConfig {
+    /// Identifier of the command frames sent by the master (CRO)
+    pub cro_id: Id,
+    /// Identifier of the response frames sent by the bootloader (DTO)
+    pub dto_id: Id,
+    /// Pad every response to the largest frame, 8 bytes or 64 on CAN FD
+    pub dlc_padding: bool,
+    /// Value of the padding bytes
+    pub fill_byte: u8,
+    /// Send responses as CAN FD frames of up to 64 bytes
+    pub fd: bool,
+    /// Send the data phase of FD frames at the data bit rate
+    pub bit_rate_switch: bool,
+    pub bus_off_policy: BusOffPolicy,
+}
+
+impl XcpCanConfig {
+    pub fn new(cro_id: impl Into<Id>, dto_id: impl Into<Id>) -> Self {
+        Self {
+            cro_id: cro_id.into(),
+            dto_id: dto_id.into(),
+            dlc_padding: false,
+            fill_byte: 0x00,
+            fd: false,
+            bit_rate_switch: false,
+            bus_off_policy: BusOffPolicy::Automatic,
+        }
+    }
+
+    /// Pads responses to the largest frame with `fill_byte`
+    pub fn with_padding(mut self, fill_byte: u8) -> Self {
+        self.dlc_padding = true;
+        self.fill_byte = fill_byte;
+        self
+    }
+
+    /// Uses CAN FD frames, optionally switching to the data bit rate
+    pub fn with_fd(mut self, bit_rate_switch: bool) -> Self {
+        self.fd = true;
+        self.bit_rate_switch = bit_rate_switch;
+        self
+    }
+
+    pub fn with_bus_off_policy(mut self, policy: BusOffPolicy) -> Self {
+        self.bus_off_policy = policy;
+        self
+    }
+
+    /// Largest packet the configured frames carry
+    pub fn max_packet_len(&self) -> usize {
+        if self.fd { CANFD_MAX_PACKET_LEN } else { CAN_MAX_PACKET_LEN }
+    }
+}
+
+impl Default for XcpCanConfig {
+    fn default() -> Self {
+        // 11-bit identifiers, matching the OpenBLT defaults
+        Self::new(
+            StandardId::new(0x667).unwrap(),
+            StandardId::new(0x7E1).unwrap(),
+        )
+    }
+}
+
+pub struct CanTransport<C: NbCan, T: Timer> {
+    can: C,
+    timer: T,
+    config: XcpCanConfig,
+}
+
+impl<C: NbCan<Frame: FdFrame> + CanErrorState, T: Timer> CanTransport<C, T> {
+    pub fn new(can: C, timer: T) -> Self {
+        Self::with_config(can, timer, XcpCanConfig::default())
+    }
+
+    pub fn with_config(can: C, timer: T, config: XcpCanConfig) -> Self {
+        Self { can, timer, config }
+    }
+
+    pub fn set_config(&mut self, config: XcpCanConfig) {
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> &XcpCanConfig {
+        &self.config
+    }
+
+    pub fn get_bus_status(&self) -> CanBusStatus {
+        self.can.get_bus_status()
+    }
+
+    /// Brings the controller back onto the bus under the manual bus-off
+    /// policy. Returns `WouldBlock` until it is back.
+    pub fn recover_bus_off(&mut self) -> nb::Result<(), ProtocolError> {
+        self.can
+            .recover_bus_off()
+            .map_err(|e| e.map(|_| ProtocolError::CanError))
+    }
+
+    // Ok while the controller is on the bus. Under the automatic policy a
+    // bus-off starts the recovery; it is reported until that completes.
+    fn check_bus(&mut self) -> Result<(), ProtocolError> {
+        if !self.can.get_bus_status().is_bus_off() {
+            return Ok(());
+        }
+        match self.config.bus_off_policy {
+            BusOffPolicy::Automatic => match self.can.recover_bus_off() {
+                Ok(()) => Ok(()),
+                Err(nb::Error::WouldBlock) => Err(ProtocolError::BusOff),
+                Err(nb::Error::Other(_)) => Err(ProtocolError::CanError),
+            },
+            BusOffPolicy::Manual => Err(ProtocolError::BusOff),
+        }
+    }
+
+    // Keeps waiting through a bus-off the transport recovers from itself
+    fn may_wait(&self, error: &ProtocolError) -> bool {
+        matches!(error, ProtocolError::BusOff) && self.config.bus_off_policy == BusOffPolicy::Automatic
+    }
+
+    fn timeout_error(&self) -> ProtocolError {
+        if self.can.get_bus_status().is_bus_off() {
+            ProtocolError::BusOff
+        } else {
+            ProtocolError::Timeout
+        }
+    }
+}
+
+impl<C: NbCan<Frame: FdFrame> + CanErrorState, T: Timer> Transport for CanTransport<C, T> {
+    fn max_packet_size(&self) -> usize {
+        self.config.max_packet_len()
+    }
+
+    fn get_bus_status(&self) -> Option<CanBusStatus> {
+        Some(self.can.get_bus_status())
+    }
+
+    // Frames with other identifiers are dropped
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, ProtocolError> {
+        let start = self.timer.now_ms();
+        loop {
+            if let Err(e) = self.check_bus() {
+                if !self.may_wait(&e) {
+                    return Err(e);
+                }
+            }
+
+            match self.can.receive() {
+                Ok(frame) if frame.id() == self.config.cro_id && !frame.is_remote_frame() => {
+                    let data = frame.data();
+                    let len = data.len().min(buffer.len());
+                    buffer[..len].copy_from_slice(&data[..len]);
+                    return Ok(len);
+                }
+                // A busy bus of other traffic must not hold off the timeout
+                Ok(_) | Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(_)) => return Err(ProtocolError::CanError),
+            }
+
+            if self.timer.has_elapsed(start, timeout_ms) {
+                return Err(self.timeout_error());
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        let max_len = self.config.max_packet_len();
+        if packet.len() > max_len {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+
+        let mut payload = [self.config.fill_byte; CANFD_MAX_PACKET_LEN];
+        payload[..packet.len()].copy_from_slice(packet);
+        let frame = if self.config.fd {
+            // FD lengths between the DLC steps are always padded
+            let len = if self.config.dlc_padding {
+                max_len
+            } else {
+                fd_frame_len(packet.len()).ok_or(ProtocolError::InvalidDataLength)?
+            };
+            C::Frame::new_fd(self.config.dto_id, &payload[..len], self.config.bit_rate_switch)
+        } else {
+            let len = if self.config.dlc_padding { max_len } else { packet.len() };
+            C::Frame::new(self.config.dto_id, &payload[..len])
+        };
+        let frame = frame.ok_or(ProtocolError::InvalidAddress)?;
+
+        // Wait for a free transmit buffer, and for the controller to
+        // return after a bus-off
+        let start = self.timer.now_ms();
+        loop {
+            match self.check_bus() {
+                Ok(()) => match self.can.transmit(&frame) {
+                    Ok(_) => return Ok(()),
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(nb::Error::Other(_)) => {
+                        if !self.can.get_bus_status().is_bus_off() {
+                            return Err(ProtocolError::CanError);
+                        }
+                    }
+                },
+                Err(e) => {
+                    if !self.may_wait(&e) {
+                        return Err(e);
+                    }
+                }
+            }
+
+            if self.timer.has_elapsed(start, CAN_TX_TIMEOUT_MS) {
+                return Err(self.timeout_error());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use embedded_can::nb::Can as _;
+    use crate::hal::CanBusState;
+    use crate::hal::loopback::{LoopbackBus, LoopbackCan};
+    use s32k148_hal::CanFrame;
+
+    type Bus = LoopbackBus<CanFrame, 16>;
+    type Can<'a> = LoopbackCan<'a, CanFrame, 16>;
+
+    // Advances by a millisecond on every reading, so that waiting loops
+    // end. The bus comes back from bus-off at `recover_at`.
+    struct TestClock<'a> {
+        now: &'a Cell<u32>,
+        bus: &'a Bus,
+        recover_at: Option<u32>,
+    }
+
+    impl Timer for TestClock<'_> {
+        fn now_ms(&self) -> u32 {
+            let now = self.now.get();
+            self.now.set(now + 1);
+            if self.recover_at.is_some_and(|at| now >= at) {
+                self.bus.set_state(CanBusState::ErrorActive);
+            }
+            now
+        }
+    }
+
+    fn setup<'a>(
+        bus: &'a Bus,
+        now: &'a Cell<u32>,
+        recover_at: Option<u32>,
+        policy: BusOffPolicy,
+    ) -> (CanTransport<Can<'a>, TestClock<'a>>, Can<'a>) {
+        let (slave_can, master_can) = bus.split();
+        let clock = TestClock { now, bus, recover_at };
+        let config = XcpCanConfig::default().with_bus_off_policy(policy);
+        (CanTransport::with_config(slave_can, clock, config), master_can)
+    }
+
+    fn command(data: &[u8]) -> CanFrame {
+        CanFrame::new(StandardId::new(0x667).unwrap(), data).unwrap()
+    }
+
+    #[test]
+    fn automatic_policy_resumes_after_bus_off() {
+        let bus = Bus::new();
+        let now = Cell::new(0);
+        let (mut transport, mut master) = setup(&bus, &now, Some(10), BusOffPolicy::Automatic);
+
+        bus.set_state(CanBusState::BusOff);
+        transport.send(&[0xFF, 0x00]).unwrap();
+        assert!(now.get() > 10);
+
+        let response = master.receive().unwrap();
+        assert_eq!(response.id(), Id::Standard(StandardId::new(0x7E1).unwrap()));
+        assert_eq!(response.data(), &[0xFF, 0x00]);
+    }
+
+    #[test]
+    fn lasting_bus_off_is_reported() {
+        let bus = Bus::new();
+        let now = Cell::new(0);
+        let (mut transport, _master) = setup(&bus, &now, None, BusOffPolicy::Automatic);
+
+        bus.set_state(CanBusState::BusOff);
+        assert!(matches!(transport.send(&[0xFF]), Err(ProtocolError::BusOff)));
+        let mut buffer = [0u8; CAN_MAX_PACKET_LEN];
+        assert!(matches!(transport.receive(&mut buffer, 20), Err(ProtocolError::BusOff)));
+        assert!(Transport::get_bus_status(&transport).unwrap().is_bus_off());
+    }
+
+    #[test]
+    fn manual_policy_leaves_recovery_to_the_application() {
+        let bus = Bus::new();
+        let now = Cell::new(0);
+        let (mut transport, mut master) = setup(&bus, &now, None, BusOffPolicy::Manual);
+
+        bus.set_state(CanBusState::BusOff);
+        let mut buffer = [0u8; CAN_MAX_PACKET_LEN];
+        assert!(matches!(transport.receive(&mut buffer, 1000), Err(ProtocolError::BusOff)));
+        assert!(matches!(transport.send(&[0xFF]), Err(ProtocolError::BusOff)));
+        // Neither waited for the bus to come back
+        assert!(now.get() < 10);
+        assert!(matches!(transport.recover_bus_off(), Err(nb::Error::WouldBlock)));
+
+        bus.set_state(CanBusState::ErrorActive);
+        assert!(transport.recover_bus_off().is_ok());
+        master.transmit(&command(&[0xFF, 0x00])).unwrap();
+        assert_eq!(transport.receive(&mut buffer, 10).unwrap(), 2);
+        transport.send(&[0xFF]).unwrap();
+        assert_eq!(master.receive().unwrap().data(), &[0xFF]);
+    }
+
+    #[test]
+    fn error_passive_node_keeps_working() {
+        let bus = Bus::new();
+        let now = Cell::new(0);
+        let (mut transport, mut master) = setup(&bus, &now, None, BusOffPolicy::Manual);
+
+        bus.set_state(CanBusState::ErrorPassive);
+        assert_eq!(transport.get_bus_status().state, CanBusState::ErrorPassive);
+        master.transmit(&command(&[0xFD])).unwrap();
+        let mut buffer = [0u8; CAN_MAX_PACKET_LEN];
+        assert_eq!(transport.receive(&mut buffer, 10).unwrap(), 1);
+        transport.send(&[0xFF]).unwrap();
+    }
+}
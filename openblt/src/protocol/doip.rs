@@ -0,0 +1,848 @@
+// DoIP (ISO 13400-2) entity carrying diagnostic messages over TCP/IP
+
+use smoltcp::iface::{Interface, SocketHandle, SocketSet, SocketStorage};
+use smoltcp::phy::Device;
+use smoltcp::socket::{tcp, udp};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use crate::hal::Timer;
+use crate::net::{self, DhcpClient, NetworkConfig};
+use super::{ProtocolError, Transport};
+
+/// UDP and TCP port of DoIP entities
+pub const DOIP_PORT: u16 = 13400;
+
+/// Largest diagnostic message (UDS payload) exchanged with the tester
+pub const DOIP_MAX_DIAGNOSTIC_LEN: usize = 4095;
+
+// ISO 13400-2:2012, plus the default version testers use for identification
+const DOIP_PROTOCOL_VERSION: u8 = 0x02;
+const DOIP_PROTOCOL_VERSION_2019: u8 = 0x03;
+const DOIP_PROTOCOL_VERSION_DEFAULT: u8 = 0xFF;
+
+// Protocol version, inverse version, payload type and payload length
+const DOIP_HEADER_LEN: usize = 8;
+
+// Source and target address in front of the diagnostic data
+const DOIP_ADDRESS_LEN: usize = 4;
+
+const DOIP_MAX_MESSAGE_LEN: usize = DOIP_HEADER_LEN + DOIP_ADDRESS_LEN + DOIP_MAX_DIAGNOSTIC_LEN;
+
+// Payload types
+const PT_GENERIC_NACK: u16 = 0x0000;
+const PT_VEHICLE_ID_REQUEST: u16 = 0x0001;
+const PT_VEHICLE_ID_REQUEST_EID: u16 = 0x0002;
+const PT_VEHICLE_ID_REQUEST_VIN: u16 = 0x0003;
+const PT_VEHICLE_ANNOUNCEMENT: u16 = 0x0004;
+const PT_ROUTING_ACTIVATION_REQUEST: u16 = 0x0005;
+const PT_ROUTING_ACTIVATION_RESPONSE: u16 = 0x0006;
+const PT_ALIVE_CHECK_REQUEST: u16 = 0x0007;
+const PT_ALIVE_CHECK_RESPONSE: u16 = 0x0008;
+const PT_ENTITY_STATUS_REQUEST: u16 = 0x4001;
+const PT_ENTITY_STATUS_RESPONSE: u16 = 0x4002;
+const PT_POWER_MODE_REQUEST: u16 = 0x4003;
+const PT_POWER_MODE_RESPONSE: u16 = 0x4004;
+const PT_DIAGNOSTIC_MESSAGE: u16 = 0x8001;
+const PT_DIAGNOSTIC_ACK: u16 = 0x8002;
+const PT_DIAGNOSTIC_NACK: u16 = 0x8003;
+
+// Generic header negative acknowledge codes
+const NACK_INCORRECT_PATTERN: u8 = 0x00;
+const NACK_UNKNOWN_PAYLOAD_TYPE: u8 = 0x01;
+const NACK_MESSAGE_TOO_LARGE: u8 = 0x02;
+const NACK_INVALID_PAYLOAD_LENGTH: u8 = 0x04;
+
+// Routing activation response codes
+const RA_UNKNOWN_SOURCE_ADDRESS: u8 = 0x00;
+const RA_SOURCE_ADDRESS_MISMATCH: u8 = 0x02;
+const RA_UNSUPPORTED_ACTIVATION_TYPE: u8 = 0x06;
+const RA_SUCCESS: u8 = 0x10;
+
+// Routing activation types
+const ACTIVATION_DEFAULT: u8 = 0x00;
+const ACTIVATION_WWH_OBD: u8 = 0x01;
+
+// Diagnostic message acknowledge and negative acknowledge codes
+const DIAG_ACK: u8 = 0x00;
+const DIAG_NACK_INVALID_SOURCE_ADDRESS: u8 = 0x02;
+const DIAG_NACK_UNKNOWN_TARGET_ADDRESS: u8 = 0x03;
+
+// Vehicle announcement: VIN, logical address, EID, GID, further action
+const VIN_LEN: usize = 17;
+const ANNOUNCEMENT_LEN: usize = VIN_LEN + 2 + 6 + 6 + 1;
+const FURTHER_ACTION_NONE: u8 = 0x00;
+
+// Entity status: this entity is a node with a single TCP data socket
+const NODE_TYPE_NODE: u8 = 0x01;
+const MAX_TCP_SOCKETS: u8 = 1;
+
+// Power mode status
+const POWER_MODE_READY: u8 = 0x01;
+
+// A_DoIP_Announce_Num and A_DoIP_Announce_Interval
+const ANNOUNCE_COUNT: u8 = 3;
+const ANNOUNCE_INTERVAL_MS: u32 = 500;
+
+// T_TCP_Initial_Inactivity and T_TCP_General_Inactivity
+const TCP_INITIAL_INACTIVITY_MS: u32 = 2_000;
+const TCP_GENERAL_INACTIVITY_MS: u32 = 300_000;
+
+// Time allowed for a diagnostic message to fit into the TCP send buffer
+const TCP_SEND_TIMEOUT_MS: u32 = 1000;
+
+// Tester addresses of external test equipment
+const TESTER_ADDRESS_MIN: u16 = 0x0E00;
+const TESTER_ADDRESS_MAX: u16 = 0x0FFF;
+
+const UDP_BUFFER_LEN: usize = 512;
+const UDP_PACKET_COUNT: usize = 4;
+const TCP_BUFFER_LEN: usize = 4352;
+
+/// Identity of the DoIP entity
+#[derive(Debug, Clone, Copy)]
+pub struct DoipConfig {
+    pub vin: [u8; VIN_LEN],
+    pub logical_address: u16,
+    /// Entity identification, usually the MAC address
+    pub eid: [u8; 6],
+    /// Group identification
+    pub gid: [u8; 6],
+}
+
+impl DoipConfig {
+    pub fn new(vin: [u8; VIN_LEN], logical_address: u16, eid: [u8; 6]) -> Self {
+        Self {
+            vin,
+            logical_address,
+            eid,
+            gid: eid,
+        }
+    }
+}
+
+/// Socket storage and buffers of a DoIP entity. Allocate it statically and
+/// hand it to `DoipEntity::new`.
+pub struct DoipBuffers<'a> {
+    // UDP, TCP and the DHCP client
+    sockets: [SocketStorage<'a>; 3],
+    udp_rx_metadata: [udp::PacketMetadata; UDP_PACKET_COUNT],
+    udp_rx: [u8; UDP_BUFFER_LEN],
+    udp_tx_metadata: [udp::PacketMetadata; UDP_PACKET_COUNT],
+    udp_tx: [u8; UDP_BUFFER_LEN],
+    tcp_rx: [u8; TCP_BUFFER_LEN],
+    tcp_tx: [u8; TCP_BUFFER_LEN],
+}
+
+impl<'a> DoipBuffers<'a> {
+    pub const fn new() -> Self {
+        Self {
+            sockets: [SocketStorage::EMPTY; 3],
+            udp_rx_metadata: [udp::PacketMetadata::EMPTY; UDP_PACKET_COUNT],
+            udp_rx: [0; UDP_BUFFER_LEN],
+            udp_tx_metadata: [udp::PacketMetadata::EMPTY; UDP_PACKET_COUNT],
+            udp_tx: [0; UDP_BUFFER_LEN],
+            tcp_rx: [0; TCP_BUFFER_LEN],
+            tcp_tx: [0; TCP_BUFFER_LEN],
+        }
+    }
+}
+
+impl Default for DoipBuffers<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct DoipEntity<'a, D: Device, T: Timer> {
+    device: D,
+    timer: T,
+    config: DoipConfig,
+    iface: Interface,
+    sockets: SocketSet<'a>,
+    udp: SocketHandle,
+    tcp: SocketHandle,
+    dhcp: Option<DhcpClient>,
+    announcements_left: u8,
+    last_announcement_ms: u32,
+    tester_address: Option<u16>,
+    tcp_connected: bool,
+    last_activity_ms: u32,
+    rx_message: [u8; DOIP_MAX_MESSAGE_LEN],
+    rx_len: usize,
+    rx_discard: usize,
+    diagnostic: [u8; DOIP_MAX_DIAGNOSTIC_LEN],
+    diagnostic_len: Option<usize>,
+}
+
+impl<'a, D: Device, T: Timer> DoipEntity<'a, D, T> {
+    pub fn new(
+        mut device: D,
+        timer: T,
+        network: &NetworkConfig,
+        config: DoipConfig,
+        buffers: &'a mut DoipBuffers<'a>,
+    ) -> Self {
+        let iface = net::create_interface(network, &mut device, net::now(&timer));
+
+        let DoipBuffers {
+            sockets: socket_storage,
+            udp_rx_metadata,
+            udp_rx,
+            udp_tx_metadata,
+            udp_tx,
+            tcp_rx,
+            tcp_tx,
+        } = buffers;
+
+        let mut sockets = SocketSet::new(&mut socket_storage[..]);
+        let mut udp_socket = udp::Socket::new(
+            udp::PacketBuffer::new(&mut udp_rx_metadata[..], &mut udp_rx[..]),
+            udp::PacketBuffer::new(&mut udp_tx_metadata[..], &mut udp_tx[..]),
+        );
+        let _ = udp_socket.bind(DOIP_PORT);
+        let udp = sockets.add(udp_socket);
+        let mut tcp_socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(&mut tcp_rx[..]),
+            tcp::SocketBuffer::new(&mut tcp_tx[..]),
+        );
+        // The diagnostic response follows the ACK without waiting
+        tcp_socket.set_nagle_enabled(false);
+        let tcp = sockets.add(tcp_socket);
+        let dhcp = DhcpClient::start(network, &mut sockets);
+
+        Self {
+            device,
+            timer,
+            config,
+            iface,
+            sockets,
+            udp,
+            tcp,
+            dhcp,
+            announcements_left: ANNOUNCE_COUNT,
+            last_announcement_ms: 0,
+            tester_address: None,
+            tcp_connected: false,
+            last_activity_ms: 0,
+            rx_message: [0; DOIP_MAX_MESSAGE_LEN],
+            rx_len: 0,
+            rx_discard: 0,
+            diagnostic: [0; DOIP_MAX_DIAGNOSTIC_LEN],
+            diagnostic_len: None,
+        }
+    }
+
+    /// Logical address of the tester whose routing is active
+    pub fn get_tester_address(&self) -> Option<u16> {
+        self.tester_address
+    }
+
+    /// Runs the IP stack, answers vehicle identification and routing
+    /// activation, and sends the start-up announcements. Call it regularly.
+    pub fn poll(&mut self) {
+        self.iface.poll(net::now(&self.timer), &mut self.device, &mut self.sockets);
+        if let Some(dhcp) = &self.dhcp {
+            dhcp.poll(&mut self.iface, &mut self.sockets);
+        }
+
+        self.process_udp();
+        self.announce();
+        self.process_tcp();
+
+        // Push out whatever the handlers queued
+        self.iface.poll(net::now(&self.timer), &mut self.device, &mut self.sockets);
+    }
+
+    /// Takes the last diagnostic message addressed to this entity
+    pub fn take_diagnostic(&mut self) -> Option<&[u8]> {
+        let len = self.diagnostic_len.take()?;
+        Some(&self.diagnostic[..len])
+    }
+
+    fn process_udp(&mut self) {
+        loop {
+            let mut packet = [0u8; UDP_BUFFER_LEN];
+            let socket = self.sockets.get_mut::<udp::Socket>(self.udp);
+            let (len, metadata) = match socket.recv_slice(&mut packet) {
+                Ok(received) => received,
+                Err(_) => break,
+            };
+            self.handle_udp(&packet[..len], metadata.endpoint);
+        }
+    }
+
+    fn handle_udp(&mut self, packet: &[u8], remote: IpEndpoint) {
+        let (payload_type, payload) = match parse_header(packet) {
+            Ok(message) => message,
+            Err(code) => {
+                self.send_udp(remote, PT_GENERIC_NACK, &[code]);
+                return;
+            }
+        };
+
+        match payload_type {
+            PT_VEHICLE_ID_REQUEST if payload.is_empty() => self.send_announcement(remote),
+            PT_VEHICLE_ID_REQUEST_EID if payload.len() == 6 => {
+                if payload == self.config.eid {
+                    self.send_announcement(remote);
+                }
+            }
+            PT_VEHICLE_ID_REQUEST_VIN if payload.len() == VIN_LEN => {
+                if payload == self.config.vin {
+                    self.send_announcement(remote);
+                }
+            }
+            PT_ENTITY_STATUS_REQUEST if payload.is_empty() => {
+                let max_data_size = (DOIP_MAX_MESSAGE_LEN as u32).to_be_bytes();
+                let status = [
+                    NODE_TYPE_NODE,
+                    MAX_TCP_SOCKETS,
+                    self.tcp_connected as u8,
+                    max_data_size[0],
+                    max_data_size[1],
+                    max_data_size[2],
+                    max_data_size[3],
+                ];
+                self.send_udp(remote, PT_ENTITY_STATUS_RESPONSE, &status);
+            }
+            PT_POWER_MODE_REQUEST if payload.is_empty() => {
+                self.send_udp(remote, PT_POWER_MODE_RESPONSE, &[POWER_MODE_READY]);
+            }
+            PT_VEHICLE_ID_REQUEST
+            | PT_VEHICLE_ID_REQUEST_EID
+            | PT_VEHICLE_ID_REQUEST_VIN
+            | PT_ENTITY_STATUS_REQUEST
+            | PT_POWER_MODE_REQUEST => {
+                self.send_udp(remote, PT_GENERIC_NACK, &[NACK_INVALID_PAYLOAD_LENGTH]);
+            }
+            _ => self.send_udp(remote, PT_GENERIC_NACK, &[NACK_UNKNOWN_PAYLOAD_TYPE]),
+        }
+    }
+
+    // Announces the entity a few times after start-up so testers find it
+    // without asking
+    fn announce(&mut self) {
+        // Wait for the DHCP lease
+        if self.announcements_left == 0 || self.iface.ipv4_addr().is_none() {
+            return;
+        }
+        let now = self.timer.now_ms();
+        if self.announcements_left < ANNOUNCE_COUNT
+            && now.wrapping_sub(self.last_announcement_ms) < ANNOUNCE_INTERVAL_MS
+        {
+            return;
+        }
+
+        let broadcast = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), DOIP_PORT);
+        self.send_announcement(broadcast);
+        self.announcements_left -= 1;
+        self.last_announcement_ms = now;
+    }
+
+    fn send_announcement(&mut self, remote: IpEndpoint) {
+        let mut payload = [0u8; ANNOUNCEMENT_LEN];
+        payload[..VIN_LEN].copy_from_slice(&self.config.vin);
+        payload[VIN_LEN..VIN_LEN + 2].copy_from_slice(&self.config.logical_address.to_be_bytes());
+        payload[VIN_LEN + 2..VIN_LEN + 8].copy_from_slice(&self.config.eid);
+        payload[VIN_LEN + 8..VIN_LEN + 14].copy_from_slice(&self.config.gid);
+        payload[VIN_LEN + 14] = FURTHER_ACTION_NONE;
+        self.send_udp(remote, PT_VEHICLE_ANNOUNCEMENT, &payload);
+    }
+
+    fn send_udp(&mut self, remote: IpEndpoint, payload_type: u16, payload: &[u8]) {
+        let mut packet = [0u8; DOIP_HEADER_LEN + ANNOUNCEMENT_LEN];
+        let len = DOIP_HEADER_LEN + payload.len();
+        packet[..DOIP_HEADER_LEN].copy_from_slice(&header(payload_type, payload.len()));
+        packet[DOIP_HEADER_LEN..len].copy_from_slice(payload);
+
+        // Nothing is retried; the tester repeats unanswered requests
+        let socket = self.sockets.get_mut::<udp::Socket>(self.udp);
+        let _ = socket.send_slice(&packet[..len], remote);
+    }
+
+    fn process_tcp(&mut self) {
+        let now = self.timer.now_ms();
+        let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp);
+
+        // A closed connection makes room for the next tester
+        if !socket.is_open() {
+            let _ = socket.listen(DOIP_PORT);
+            self.tcp_connected = false;
+            self.tester_address = None;
+            self.rx_len = 0;
+            self.rx_discard = 0;
+            return;
+        }
+        if !socket.may_recv() && socket.may_send() {
+            socket.close();
+        }
+        if socket.is_active() && !self.tcp_connected {
+            self.tcp_connected = true;
+            self.last_activity_ms = now;
+        }
+
+        // Drop testers that never activate routing or stay silent too long
+        let inactivity = if self.tester_address.is_some() {
+            TCP_GENERAL_INACTIVITY_MS
+        } else {
+            TCP_INITIAL_INACTIVITY_MS
+        };
+        if self.tcp_connected && now.wrapping_sub(self.last_activity_ms) >= inactivity {
+            socket.abort();
+            return;
+        }
+
+        loop {
+            let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp);
+            if !socket.can_recv() {
+                break;
+            }
+            self.last_activity_ms = now;
+
+            // Skip the payload of a message that was too large
+            if self.rx_discard > 0 {
+                let discard = &mut self.rx_discard;
+                let _ = socket.recv(|data| {
+                    let count = data.len().min(*discard);
+                    *discard -= count;
+                    (count, ())
+                });
+                continue;
+            }
+
+            let wanted = if self.rx_len < DOIP_HEADER_LEN {
+                DOIP_HEADER_LEN
+            } else {
+                DOIP_HEADER_LEN + payload_length(&self.rx_message)
+            };
+            match socket.recv_slice(&mut self.rx_message[self.rx_len..wanted]) {
+                Ok(count) => self.rx_len += count,
+                Err(_) => break,
+            }
+
+            if self.rx_len == DOIP_HEADER_LEN && wanted == DOIP_HEADER_LEN {
+                let payload_len = payload_length(&self.rx_message);
+                if let Err(code) = check_header(&self.rx_message) {
+                    // An unreadable stream cannot be resynchronised
+                    self.send_tcp(PT_GENERIC_NACK, &[&[code]]);
+                    self.sockets.get_mut::<tcp::Socket>(self.tcp).close();
+                    self.rx_len = 0;
+                    break;
+                }
+                // The length comes off the wire: compare before adding so
+                // values near u32::MAX cannot wrap
+                if payload_len > DOIP_MAX_MESSAGE_LEN - DOIP_HEADER_LEN {
+                    self.send_tcp(PT_GENERIC_NACK, &[&[NACK_MESSAGE_TOO_LARGE]]);
+                    self.rx_discard = payload_len;
+                    self.rx_len = 0;
+                    continue;
+                }
+            }
+
+            if self.rx_len >= DOIP_HEADER_LEN
+                && self.rx_len == DOIP_HEADER_LEN + payload_length(&self.rx_message)
+            {
+                let message = self.rx_message;
+                let len = self.rx_len;
+                self.rx_len = 0;
+                self.handle_tcp(&message[..len]);
+            }
+        }
+    }
+
+    fn handle_tcp(&mut self, message: &[u8]) {
+        let payload_type = u16::from_be_bytes([message[2], message[3]]);
+        let payload = &message[DOIP_HEADER_LEN..];
+
+        match payload_type {
+            PT_ROUTING_ACTIVATION_REQUEST => self.routing_activation(payload),
+            PT_DIAGNOSTIC_MESSAGE => self.diagnostic_message(payload),
+            // The entity never asks, so answers need no handling
+            PT_ALIVE_CHECK_RESPONSE => {}
+            PT_ALIVE_CHECK_REQUEST => {
+                let address = self.config.logical_address.to_be_bytes();
+                self.send_tcp(PT_ALIVE_CHECK_RESPONSE, &[&address]);
+            }
+            _ => {
+                self.send_tcp(PT_GENERIC_NACK, &[&[NACK_UNKNOWN_PAYLOAD_TYPE]]);
+            }
+        }
+    }
+
+    // Source address, activation type, reserved and optional OEM data
+    fn routing_activation(&mut self, payload: &[u8]) {
+        if payload.len() != 7 && payload.len() != 11 {
+            self.send_tcp(PT_GENERIC_NACK, &[&[NACK_INVALID_PAYLOAD_LENGTH]]);
+            return;
+        }
+
+        let source = u16::from_be_bytes([payload[0], payload[1]]);
+        let code = if !(TESTER_ADDRESS_MIN..=TESTER_ADDRESS_MAX).contains(&source) {
+            RA_UNKNOWN_SOURCE_ADDRESS
+        } else if self.tester_address.is_some_and(|tester| tester != source) {
+            RA_SOURCE_ADDRESS_MISMATCH
+        } else if payload[2] != ACTIVATION_DEFAULT && payload[2] != ACTIVATION_WWH_OBD {
+            RA_UNSUPPORTED_ACTIVATION_TYPE
+        } else {
+            RA_SUCCESS
+        };
+
+        // Tester address, entity address, response code and reserved bytes
+        let tester = source.to_be_bytes();
+        let entity = self.config.logical_address.to_be_bytes();
+        let response = [tester[0], tester[1], entity[0], entity[1], code, 0, 0, 0, 0];
+        self.send_tcp(PT_ROUTING_ACTIVATION_RESPONSE, &[&response]);
+
+        if code == RA_SUCCESS {
+            self.tester_address = Some(source);
+        } else {
+            self.sockets.get_mut::<tcp::Socket>(self.tcp).close();
+        }
+    }
+
+    // Source address, target address and the diagnostic data
+    fn diagnostic_message(&mut self, payload: &[u8]) {
+        if payload.len() <= DOIP_ADDRESS_LEN {
+            self.send_tcp(PT_GENERIC_NACK, &[&[NACK_INVALID_PAYLOAD_LENGTH]]);
+            return;
+        }
+
+        let source = u16::from_be_bytes([payload[0], payload[1]]);
+        let target = u16::from_be_bytes([payload[2], payload[3]]);
+        let entity = self.config.logical_address.to_be_bytes();
+        let tester = source.to_be_bytes();
+
+        if self.tester_address != Some(source) {
+            let nack = [entity[0], entity[1], tester[0], tester[1], DIAG_NACK_INVALID_SOURCE_ADDRESS];
+            self.send_tcp(PT_DIAGNOSTIC_NACK, &[&nack]);
+            self.sockets.get_mut::<tcp::Socket>(self.tcp).close();
+            return;
+        }
+        if target != self.config.logical_address {
+            let nack = [entity[0], entity[1], tester[0], tester[1], DIAG_NACK_UNKNOWN_TARGET_ADDRESS];
+            self.send_tcp(PT_DIAGNOSTIC_NACK, &[&nack]);
+            return;
+        }
+
+        let data = &payload[DOIP_ADDRESS_LEN..];
+        self.diagnostic[..data.len()].copy_from_slice(data);
+        self.diagnostic_len = Some(data.len());
+
+        let ack = [entity[0], entity[1], tester[0], tester[1], DIAG_ACK];
+        self.send_tcp(PT_DIAGNOSTIC_ACK, &[&ack]);
+    }
+
+    // Queues a message made of `parts` on the TCP connection. Returns false
+    // when the send buffer has no room for all of it.
+    fn send_tcp(&mut self, payload_type: u16, parts: &[&[u8]]) -> bool {
+        let len = parts.iter().map(|part| part.len()).sum();
+        let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp);
+        if socket.send_capacity() - socket.send_queue() < DOIP_HEADER_LEN + len {
+            return false;
+        }
+
+        let _ = socket.send_slice(&header(payload_type, len));
+        for part in parts {
+            let _ = socket.send_slice(part);
+        }
+        true
+    }
+}
+
+impl<D: Device, T: Timer> Transport for DoipEntity<'_, D, T> {
+    fn max_packet_size(&self) -> usize {
+        DOIP_MAX_DIAGNOSTIC_LEN
+    }
+
+    // Sends a diagnostic message to the tester with active routing
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        if packet.len() > DOIP_MAX_DIAGNOSTIC_LEN {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+        let tester = self.tester_address.ok_or(ProtocolError::NetworkError)?;
+        let entity = self.config.logical_address.to_be_bytes();
+        let tester = tester.to_be_bytes();
+        let addresses = [entity[0], entity[1], tester[0], tester[1]];
+
+        // Wait for the send buffer to drain far enough for the whole message
+        let start = self.timer.now_ms();
+        while !self.send_tcp(PT_DIAGNOSTIC_MESSAGE, &[&addresses, packet]) {
+            if !self.sockets.get::<tcp::Socket>(self.tcp).may_send() {
+                return Err(ProtocolError::NetworkError);
+            }
+            if self.timer.has_elapsed(start, TCP_SEND_TIMEOUT_MS) {
+                return Err(ProtocolError::Timeout);
+            }
+            self.poll();
+        }
+
+        self.poll();
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, ProtocolError> {
+        let start = self.timer.now_ms();
+        loop {
+            self.poll();
+
+            if let Some(message) = self.take_diagnostic() {
+                let len = message.len();
+                if len > buffer.len() {
+                    return Err(ProtocolError::InvalidDataLength);
+                }
+                buffer[..len].copy_from_slice(message);
+                return Ok(len);
+            }
+
+            if self.timer.has_elapsed(start, timeout_ms) {
+                return Err(ProtocolError::Timeout);
+            }
+        }
+    }
+}
+
+fn header(payload_type: u16, payload_len: usize) -> [u8; DOIP_HEADER_LEN] {
+    let payload_type = payload_type.to_be_bytes();
+    let len = (payload_len as u32).to_be_bytes();
+    [
+        DOIP_PROTOCOL_VERSION,
+        !DOIP_PROTOCOL_VERSION,
+        payload_type[0],
+        payload_type[1],
+        len[0],
+        len[1],
+        len[2],
+        len[3],
+    ]
+}
+
+fn payload_length(header: &[u8]) -> usize {
+    u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize
+}
+
+// Checks the version pattern and returns the generic NACK code on failure
+fn check_header(header: &[u8]) -> Result<(), u8> {
+    let version = header[0];
+    let supported = matches!(
+        version,
+        DOIP_PROTOCOL_VERSION | DOIP_PROTOCOL_VERSION_2019 | DOIP_PROTOCOL_VERSION_DEFAULT
+    );
+    if !supported || header[1] != !version {
+        return Err(NACK_INCORRECT_PATTERN);
+    }
+    Ok(())
+}
+
+// Splits a complete datagram into payload type and payload
+fn parse_header(packet: &[u8]) -> Result<(u16, &[u8]), u8> {
+    if packet.len() < DOIP_HEADER_LEN {
+        return Err(NACK_INCORRECT_PATTERN);
+    }
+    check_header(packet)?;
+    if payload_length(packet) != packet.len() - DOIP_HEADER_LEN {
+        return Err(NACK_INVALID_PAYLOAD_LENGTH);
+    }
+    Ok((u16::from_be_bytes([packet[2], packet[3]]), &packet[DOIP_HEADER_LEN..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use crate::net::memory::{MemoryDevice, MemoryLink};
+
+    const ENTITY_IP: Ipv4Address = Ipv4Address::new(192, 168, 0, 10);
+    const TESTER_IP: Ipv4Address = Ipv4Address::new(192, 168, 0, 20);
+    const ENTITY_ADDRESS: u16 = 0x0010;
+    const TESTER_ADDRESS: u16 = 0x0E00;
+    const VIN: [u8; VIN_LEN] = *b"WVWZZZ1JZ3W386752";
+    const EID: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x10];
+
+    type Link = MemoryLink<16>;
+    type Device = MemoryDevice<'static, 16>;
+
+    #[derive(Clone, Copy)]
+    struct TestClock(&'static Cell<u32>);
+
+    impl Timer for TestClock {
+        fn now_ms(&self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    // The other end of the link: a smoltcp interface with one UDP and one
+    // TCP socket
+    struct Tester {
+        device: Device,
+        clock: TestClock,
+        iface: Interface,
+        sockets: SocketSet<'static>,
+        udp: SocketHandle,
+        tcp: SocketHandle,
+    }
+
+    impl Tester {
+        fn new(mut device: Device, clock: TestClock) -> Self {
+            let network = NetworkConfig::new([0x02, 0, 0, 0, 0, 0x20], TESTER_IP, 24);
+            let iface = net::create_interface(&network, &mut device, net::now(&clock));
+            let storage = Box::leak(Box::new([SocketStorage::EMPTY; 2]));
+            let mut sockets = SocketSet::new(&mut storage[..]);
+            let mut udp_socket = udp::Socket::new(
+                udp::PacketBuffer::new(leak([udp::PacketMetadata::EMPTY; 4]), leak([0u8; 512])),
+                udp::PacketBuffer::new(leak([udp::PacketMetadata::EMPTY; 4]), leak([0u8; 512])),
+            );
+            udp_socket.bind(50_000).unwrap();
+            let udp = sockets.add(udp_socket);
+            let tcp = sockets.add(tcp::Socket::new(
+                tcp::SocketBuffer::new(leak([0u8; 8192])),
+                tcp::SocketBuffer::new(leak([0u8; 8192])),
+            ));
+            Self { device, clock, iface, sockets, udp, tcp }
+        }
+
+        fn connect(&mut self) {
+            let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp);
+            socket.connect(self.iface.context(), (IpAddress::Ipv4(ENTITY_IP), DOIP_PORT), 50_001).unwrap();
+        }
+
+        fn send_udp(&mut self, message: &[u8]) {
+            let socket = self.sockets.get_mut::<udp::Socket>(self.udp);
+            socket.send_slice(message, (IpAddress::Ipv4(ENTITY_IP), DOIP_PORT)).unwrap();
+        }
+
+        fn receive_udp(&mut self) -> Vec<u8> {
+            let mut packet = [0u8; 512];
+            let socket = self.sockets.get_mut::<udp::Socket>(self.udp);
+            let (len, _) = socket.recv_slice(&mut packet).unwrap();
+            packet[..len].to_vec()
+        }
+
+        fn send_tcp(&mut self, message: &[u8]) {
+            let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp);
+            assert_eq!(socket.send_slice(message).unwrap(), message.len());
+        }
+
+        // Everything the entity sent since the last call
+        fn receive_tcp(&mut self) -> Vec<u8> {
+            let mut data = [0u8; 8192];
+            let socket = self.sockets.get_mut::<tcp::Socket>(self.tcp);
+            let len = socket.recv_slice(&mut data).unwrap();
+            data[..len].to_vec()
+        }
+    }
+
+    fn leak<T: 'static, const N: usize>(array: [T; N]) -> &'static mut [T] {
+        &mut Box::leak(Box::new(array))[..]
+    }
+
+    fn setup() -> (DoipEntity<'static, Device, TestClock>, Tester) {
+        let link: &'static Link = Box::leak(Box::new(Link::new()));
+        let (entity_end, tester_end) = link.split();
+        let clock = TestClock(Box::leak(Box::new(Cell::new(0))));
+        let network = NetworkConfig::new(EID, ENTITY_IP, 24);
+        let buffers = Box::leak(Box::new(DoipBuffers::new()));
+        let entity = DoipEntity::new(
+            entity_end,
+            clock,
+            &network,
+            DoipConfig::new(VIN, ENTITY_ADDRESS, EID),
+            buffers,
+        );
+        (entity, Tester::new(tester_end, clock))
+    }
+
+    // Runs both ends for `ms` milliseconds
+    fn run(entity: &mut DoipEntity<'static, Device, TestClock>, tester: &mut Tester, ms: u32) {
+        for _ in 0..ms {
+            entity.poll();
+            tester.iface.poll(net::now(&tester.clock), &mut tester.device, &mut tester.sockets);
+            tester.clock.0.set(tester.clock.0.get() + 1);
+        }
+    }
+
+    fn message(payload_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut message = header(payload_type, payload.len()).to_vec();
+        message.extend_from_slice(payload);
+        message
+    }
+
+    fn activate_routing(entity: &mut DoipEntity<'static, Device, TestClock>, tester: &mut Tester) {
+        tester.connect();
+        run(entity, tester, 20);
+        let source = TESTER_ADDRESS.to_be_bytes();
+        tester.send_tcp(&message(
+            PT_ROUTING_ACTIVATION_REQUEST,
+            &[source[0], source[1], ACTIVATION_DEFAULT, 0, 0, 0, 0],
+        ));
+        run(entity, tester, 20);
+
+        let response = tester.receive_tcp();
+        assert_eq!(&response[..DOIP_HEADER_LEN], &header(PT_ROUTING_ACTIVATION_RESPONSE, 9));
+        assert_eq!(&response[DOIP_HEADER_LEN..DOIP_HEADER_LEN + 4], &[0x0E, 0x00, 0x00, 0x10]);
+        assert_eq!(response[DOIP_HEADER_LEN + 4], RA_SUCCESS);
+        assert_eq!(entity.get_tester_address(), Some(TESTER_ADDRESS));
+    }
+
+    #[test]
+    fn vehicle_identification() {
+        let (mut entity, mut tester) = setup();
+        run(&mut entity, &mut tester, 10);
+
+        tester.send_udp(&message(PT_VEHICLE_ID_REQUEST, &[]));
+        run(&mut entity, &mut tester, 20);
+
+        let announcement = tester.receive_udp();
+        assert_eq!(&announcement[..DOIP_HEADER_LEN], &header(PT_VEHICLE_ANNOUNCEMENT, ANNOUNCEMENT_LEN));
+        let payload = &announcement[DOIP_HEADER_LEN..];
+        assert_eq!(&payload[..VIN_LEN], &VIN);
+        assert_eq!(&payload[VIN_LEN..VIN_LEN + 2], &ENTITY_ADDRESS.to_be_bytes());
+        assert_eq!(&payload[VIN_LEN + 2..VIN_LEN + 8], &EID);
+
+        // A request for another vehicle goes unanswered
+        tester.send_udp(&message(PT_VEHICLE_ID_REQUEST_VIN, b"WVWZZZ1JZ3W000000"));
+        run(&mut entity, &mut tester, 20);
+        assert!(!tester.sockets.get::<udp::Socket>(tester.udp).can_recv());
+    }
+
+    #[test]
+    fn diagnostic_round_trip() {
+        let (mut entity, mut tester) = setup();
+        activate_routing(&mut entity, &mut tester);
+
+        let source = TESTER_ADDRESS.to_be_bytes();
+        let target = ENTITY_ADDRESS.to_be_bytes();
+        tester.send_tcp(&message(
+            PT_DIAGNOSTIC_MESSAGE,
+            &[source[0], source[1], target[0], target[1], 0x10, 0x02],
+        ));
+        run(&mut entity, &mut tester, 20);
+
+        assert_eq!(entity.take_diagnostic(), Some(&[0x10, 0x02][..]));
+        let ack = tester.receive_tcp();
+        assert_eq!(&ack[..DOIP_HEADER_LEN], &header(PT_DIAGNOSTIC_ACK, 5));
+        assert_eq!(&ack[DOIP_HEADER_LEN..], &[target[0], target[1], source[0], source[1], DIAG_ACK]);
+
+        entity.send(&[0x50, 0x02]).unwrap();
+        run(&mut entity, &mut tester, 20);
+        assert_eq!(
+            tester.receive_tcp(),
+            message(PT_DIAGNOSTIC_MESSAGE, &[target[0], target[1], source[0], source[1], 0x50, 0x02])
+        );
+    }
+
+    #[test]
+    fn huge_payload_length_is_refused() {
+        let (mut entity, mut tester) = setup();
+        activate_routing(&mut entity, &mut tester);
+
+        let mut request = header(PT_DIAGNOSTIC_MESSAGE, 0).to_vec();
+        request[4..8].copy_from_slice(&0xFFFF_FFFFu32.to_be_bytes());
+        request.extend_from_slice(&[0x0E, 0x00, 0x00, 0x10, 0x10, 0x02]);
+        tester.send_tcp(&request);
+        run(&mut entity, &mut tester, 20);
+
+        assert_eq!(tester.receive_tcp(), message(PT_GENERIC_NACK, &[NACK_MESSAGE_TOO_LARGE]));
+        assert_eq!(entity.take_diagnostic(), None);
+    }
+}
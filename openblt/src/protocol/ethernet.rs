@@ -0,0 +1,554 @@
+// XCP on Ethernet: every packet carries a header with its length and a
+// counter, over a TCP connection or in UDP datagrams
+
+use smoltcp::iface::{Interface, SocketHandle, SocketSet, SocketStorage};
+use smoltcp::phy::Device;
+use smoltcp::socket::{tcp, udp};
+use smoltcp::wire::IpEndpoint;
+use crate::hal::Timer;
+use crate::net::{self, DhcpClient, NetworkConfig};
+use super::{ProtocolError, Transport, MAX_PACKET_LEN};
+
+/// Port XCP masters connect to by default
+pub const XCP_ETH_DEFAULT_PORT: u16 = 5555;
+
+// LEN and CTR, both Intel byte order
+const XCP_ETH_HEADER_LEN: usize = 4;
+
+const XCP_ETH_MAX_FRAME_LEN: usize = XCP_ETH_HEADER_LEN + MAX_PACKET_LEN;
+
+// Time allowed for a packet to fit into the send buffer
+const XCP_ETH_TX_TIMEOUT_MS: u32 = 50;
+
+const SOCKET_BUFFER_LEN: usize = 1024;
+const UDP_PACKET_COUNT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XcpEthMode {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct XcpEthConfig {
+    pub mode: XcpEthMode,
+    pub port: u16,
+}
+
+impl XcpEthConfig {
+    pub fn new(mode: XcpEthMode) -> Self {
+        Self {
+            mode,
+            port: XCP_ETH_DEFAULT_PORT,
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+}
+
+impl Default for XcpEthConfig {
+    fn default() -> Self {
+        Self::new(XcpEthMode::Tcp)
+    }
+}
+
+/// Socket storage and buffers of the transport. Allocate it statically and
+/// hand it to `EthernetTransport::new`.
+pub struct EthernetBuffers<'a> {
+    // The XCP socket and the DHCP client
+    sockets: [SocketStorage<'a>; 2],
+    rx_metadata: [udp::PacketMetadata; UDP_PACKET_COUNT],
+    rx: [u8; SOCKET_BUFFER_LEN],
+    tx_metadata: [udp::PacketMetadata; UDP_PACKET_COUNT],
+    tx: [u8; SOCKET_BUFFER_LEN],
+}
+
+impl<'a> EthernetBuffers<'a> {
+    pub const fn new() -> Self {
+        Self {
+            sockets: [SocketStorage::EMPTY; 2],
+            rx_metadata: [udp::PacketMetadata::EMPTY; UDP_PACKET_COUNT],
+            rx: [0; SOCKET_BUFFER_LEN],
+            tx_metadata: [udp::PacketMetadata::EMPTY; UDP_PACKET_COUNT],
+            tx: [0; SOCKET_BUFFER_LEN],
+        }
+    }
+}
+
+impl Default for EthernetBuffers<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct EthernetTransport<'a, D: Device, T: Timer> {
+    device: D,
+    timer: T,
+    config: XcpEthConfig,
+    iface: Interface,
+    sockets: SocketSet<'a>,
+    socket: SocketHandle,
+    dhcp: Option<DhcpClient>,
+    // Slave counter, incremented with every packet sent
+    ctr: u16,
+    // Where UDP responses go: the sender of the last command
+    master: Option<IpEndpoint>,
+    // TCP stream reassembly
+    rx_frame: [u8; XCP_ETH_MAX_FRAME_LEN],
+    rx_len: usize,
+    rx_discard: usize,
+    // UDP datagram holding further packets
+    datagram: [u8; SOCKET_BUFFER_LEN],
+    datagram_len: usize,
+    datagram_offset: usize,
+}
+
+impl<'a, D: Device, T: Timer> EthernetTransport<'a, D, T> {
+    pub fn new(
+        mut device: D,
+        timer: T,
+        network: &NetworkConfig,
+        config: XcpEthConfig,
+        buffers: &'a mut EthernetBuffers<'a>,
+    ) -> Self {
+        let iface = net::create_interface(network, &mut device, net::now(&timer));
+
+        let EthernetBuffers {
+            sockets: socket_storage,
+            rx_metadata,
+            rx,
+            tx_metadata,
+            tx,
+        } = buffers;
+
+        let mut sockets = SocketSet::new(&mut socket_storage[..]);
+        let socket = match config.mode {
+            XcpEthMode::Tcp => {
+                let mut socket = tcp::Socket::new(
+                    tcp::SocketBuffer::new(&mut rx[..]),
+                    tcp::SocketBuffer::new(&mut tx[..]),
+                );
+                // Responses go out at once instead of waiting for an ACK
+                socket.set_nagle_enabled(false);
+                sockets.add(socket)
+            }
+            XcpEthMode::Udp => {
+                let mut socket = udp::Socket::new(
+                    udp::PacketBuffer::new(&mut rx_metadata[..], &mut rx[..]),
+                    udp::PacketBuffer::new(&mut tx_metadata[..], &mut tx[..]),
+                );
+                let _ = socket.bind(config.port);
+                sockets.add(socket)
+            }
+        };
+        let dhcp = DhcpClient::start(network, &mut sockets);
+
+        Self {
+            device,
+            timer,
+            config,
+            iface,
+            sockets,
+            socket,
+            dhcp,
+            ctr: 0,
+            master: None,
+            rx_frame: [0; XCP_ETH_MAX_FRAME_LEN],
+            rx_len: 0,
+            rx_discard: 0,
+            datagram: [0; SOCKET_BUFFER_LEN],
+            datagram_len: 0,
+            datagram_offset: 0,
+        }
+    }
+
+    pub fn get_config(&self) -> &XcpEthConfig {
+        &self.config
+    }
+
+    /// Runs the IP stack. `receive` and `send` do this themselves; call it
+    /// while neither runs for a longer time.
+    pub fn poll(&mut self) {
+        self.iface.poll(net::now(&self.timer), &mut self.device, &mut self.sockets);
+        if let Some(dhcp) = &self.dhcp {
+            dhcp.poll(&mut self.iface, &mut self.sockets);
+        }
+
+        if self.config.mode == XcpEthMode::Tcp {
+            let socket = self.sockets.get_mut::<tcp::Socket>(self.socket);
+            if !socket.is_open() {
+                // Ready for the next master with a fresh counter
+                let _ = socket.listen(self.config.port);
+                self.ctr = 0;
+                self.rx_len = 0;
+                self.rx_discard = 0;
+            } else if !socket.may_recv() && socket.may_send() {
+                socket.close();
+            }
+        }
+    }
+
+    // Returns the length of the next complete packet in rx_frame
+    fn receive_tcp(&mut self) -> Option<usize> {
+        let socket = self.sockets.get_mut::<tcp::Socket>(self.socket);
+        while socket.can_recv() {
+            // Skip the rest of a packet too long for the command buffer
+            if self.rx_discard > 0 {
+                let discard = &mut self.rx_discard;
+                let _ = socket.recv(|data| {
+                    let count = data.len().min(*discard);
+                    *discard -= count;
+                    (count, ())
+                });
+                continue;
+            }
+
+            let wanted = if self.rx_len < XCP_ETH_HEADER_LEN {
+                XCP_ETH_HEADER_LEN
+            } else {
+                XCP_ETH_HEADER_LEN + packet_length(&self.rx_frame)
+            };
+            match socket.recv_slice(&mut self.rx_frame[self.rx_len..wanted]) {
+                Ok(count) => self.rx_len += count,
+                Err(_) => return None,
+            }
+
+            if self.rx_len == XCP_ETH_HEADER_LEN {
+                let len = packet_length(&self.rx_frame);
+                if len > MAX_PACKET_LEN {
+                    self.rx_discard = len;
+                    self.rx_len = 0;
+                    continue;
+                }
+            }
+            if self.rx_len >= XCP_ETH_HEADER_LEN
+                && self.rx_len == XCP_ETH_HEADER_LEN + packet_length(&self.rx_frame)
+            {
+                let len = self.rx_len - XCP_ETH_HEADER_LEN;
+                self.rx_len = 0;
+                if len > 0 {
+                    return Some(len);
+                }
+            }
+        }
+        None
+    }
+
+    // Copies the next packet of the current or a new datagram into
+    // rx_frame and returns its length
+    fn receive_udp(&mut self) -> Option<usize> {
+        loop {
+            if self.datagram_offset + XCP_ETH_HEADER_LEN <= self.datagram_len {
+                let frame = &self.datagram[self.datagram_offset..self.datagram_len];
+                let len = packet_length(frame);
+                if len == 0 || len > MAX_PACKET_LEN || XCP_ETH_HEADER_LEN + len > frame.len() {
+                    // The rest of the datagram cannot be trusted
+                    self.datagram_len = 0;
+                    continue;
+                }
+                self.rx_frame[..XCP_ETH_HEADER_LEN + len]
+                    .copy_from_slice(&frame[..XCP_ETH_HEADER_LEN + len]);
+                self.datagram_offset += XCP_ETH_HEADER_LEN + len;
+                return Some(len);
+            }
+
+            let socket = self.sockets.get_mut::<udp::Socket>(self.socket);
+            let (len, metadata) = socket.recv_slice(&mut self.datagram).ok()?;
+            self.master = Some(metadata.endpoint);
+            self.datagram_len = len;
+            self.datagram_offset = 0;
+        }
+    }
+}
+
+impl<D: Device, T: Timer> Transport for EthernetTransport<'_, D, T> {
+    fn max_packet_size(&self) -> usize {
+        MAX_PACKET_LEN
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        if packet.len() > MAX_PACKET_LEN {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+
+        let mut frame = [0u8; XCP_ETH_MAX_FRAME_LEN];
+        let len = XCP_ETH_HEADER_LEN + packet.len();
+        frame[..2].copy_from_slice(&(packet.len() as u16).to_le_bytes());
+        frame[2..4].copy_from_slice(&self.ctr.to_le_bytes());
+        frame[XCP_ETH_HEADER_LEN..len].copy_from_slice(packet);
+
+        let start = self.timer.now_ms();
+        loop {
+            let sent = match self.config.mode {
+                XcpEthMode::Tcp => {
+                    let socket = self.sockets.get_mut::<tcp::Socket>(self.socket);
+                    if !socket.may_send() {
+                        return Err(ProtocolError::NetworkError);
+                    }
+                    if socket.send_capacity() - socket.send_queue() >= len {
+                        let _ = socket.send_slice(&frame[..len]);
+                        true
+                    } else {
+                        false
+                    }
+                }
+                XcpEthMode::Udp => {
+                    let master = self.master.ok_or(ProtocolError::NetworkError)?;
+                    let socket = self.sockets.get_mut::<udp::Socket>(self.socket);
+                    socket.send_slice(&frame[..len], master).is_ok()
+                }
+            };
+            if sent {
+                break;
+            }
+            if self.timer.has_elapsed(start, XCP_ETH_TX_TIMEOUT_MS) {
+                return Err(ProtocolError::Timeout);
+            }
+            self.poll();
+        }
+
+        self.ctr = self.ctr.wrapping_add(1);
+        self.poll();
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, ProtocolError> {
+        let start = self.timer.now_ms();
+        loop {
+            self.poll();
+
+            let received = match self.config.mode {
+                XcpEthMode::Tcp => self.receive_tcp(),
+                XcpEthMode::Udp => self.receive_udp(),
+            };
+            if let Some(len) = received {
+                if len > buffer.len() {
+                    return Err(ProtocolError::InvalidDataLength);
+                }
+                buffer[..len].copy_from_slice(&self.rx_frame[XCP_ETH_HEADER_LEN..XCP_ETH_HEADER_LEN + len]);
+                return Ok(len);
+            }
+
+            if self.timer.has_elapsed(start, timeout_ms) {
+                return Err(ProtocolError::Timeout);
+            }
+        }
+    }
+}
+
+fn packet_length(frame: &[u8]) -> usize {
+    u16::from_le_bytes([frame[0], frame[1]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use smoltcp::wire::{IpAddress, Ipv4Address};
+    use crate::net::memory::{MemoryDevice, MemoryLink};
+
+    const SLAVE_IP: Ipv4Address = Ipv4Address::new(192, 168, 0, 10);
+    const MASTER_IP: Ipv4Address = Ipv4Address::new(192, 168, 0, 20);
+    const MASTER_PORT: u16 = 50_000;
+
+    const CONNECT: [u8; 2] = [0xFF, 0x00];
+    const GET_STATUS: [u8; 1] = [0xFD];
+
+    type Link = MemoryLink<16>;
+    type Device = MemoryDevice<'static, 16>;
+    type Slave = EthernetTransport<'static, Device, TestClock>;
+
+    #[derive(Clone, Copy)]
+    struct TestClock(&'static Cell<u32>);
+
+    impl Timer for TestClock {
+        fn now_ms(&self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    // An XCP master on the other end of the link
+    struct Master {
+        device: Device,
+        clock: TestClock,
+        iface: Interface,
+        sockets: SocketSet<'static>,
+        socket: SocketHandle,
+        mode: XcpEthMode,
+        ctr: u16,
+    }
+
+    impl Master {
+        fn new(mut device: Device, clock: TestClock, mode: XcpEthMode) -> Self {
+            let network = NetworkConfig::new([0x02, 0, 0, 0, 0, 0x20], MASTER_IP, 24);
+            let mut iface = net::create_interface(&network, &mut device, net::now(&clock));
+            let storage = Box::leak(Box::new([SocketStorage::EMPTY; 1]));
+            let mut sockets = SocketSet::new(&mut storage[..]);
+            let socket = match mode {
+                XcpEthMode::Tcp => {
+                    let mut socket = tcp::Socket::new(
+                        tcp::SocketBuffer::new(leak([0u8; 1024])),
+                        tcp::SocketBuffer::new(leak([0u8; 1024])),
+                    );
+                    socket
+                        .connect(iface.context(), (IpAddress::Ipv4(SLAVE_IP), XCP_ETH_DEFAULT_PORT), MASTER_PORT)
+                        .unwrap();
+                    sockets.add(socket)
+                }
+                XcpEthMode::Udp => {
+                    let mut socket = udp::Socket::new(
+                        udp::PacketBuffer::new(leak([udp::PacketMetadata::EMPTY; 4]), leak([0u8; 1024])),
+                        udp::PacketBuffer::new(leak([udp::PacketMetadata::EMPTY; 4]), leak([0u8; 1024])),
+                    );
+                    socket.bind(MASTER_PORT).unwrap();
+                    sockets.add(socket)
+                }
+            };
+            Self { device, clock, iface, sockets, socket, mode, ctr: 0 }
+        }
+
+        fn poll(&mut self) {
+            self.iface.poll(net::now(&self.clock), &mut self.device, &mut self.sockets);
+        }
+
+        // Sends the frames in one TCP write or one datagram
+        fn send(&mut self, frames: &[u8]) {
+            let slave = (IpAddress::Ipv4(SLAVE_IP), XCP_ETH_DEFAULT_PORT);
+            match self.mode {
+                XcpEthMode::Tcp => {
+                    let socket = self.sockets.get_mut::<tcp::Socket>(self.socket);
+                    assert_eq!(socket.send_slice(frames).unwrap(), frames.len());
+                }
+                XcpEthMode::Udp => {
+                    let socket = self.sockets.get_mut::<udp::Socket>(self.socket);
+                    socket.send_slice(frames, slave).unwrap();
+                }
+            }
+        }
+
+        // Everything the slave sent since the last call
+        fn receive(&mut self) -> Vec<u8> {
+            let mut data = [0u8; 1024];
+            let len = match self.mode {
+                XcpEthMode::Tcp => {
+                    let socket = self.sockets.get_mut::<tcp::Socket>(self.socket);
+                    socket.recv_slice(&mut data).unwrap()
+                }
+                XcpEthMode::Udp => {
+                    let socket = self.sockets.get_mut::<udp::Socket>(self.socket);
+                    let (len, metadata) = socket.recv_slice(&mut data).unwrap();
+                    assert_eq!(metadata.endpoint.port, XCP_ETH_DEFAULT_PORT);
+                    len
+                }
+            };
+            data[..len].to_vec()
+        }
+
+        // A packet with the LEN and CTR header
+        fn frame(&mut self, packet: &[u8]) -> Vec<u8> {
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+            frame.extend_from_slice(&self.ctr.to_le_bytes());
+            frame.extend_from_slice(packet);
+            self.ctr = self.ctr.wrapping_add(1);
+            frame
+        }
+    }
+
+    fn leak<T: 'static, const N: usize>(array: [T; N]) -> &'static mut [T] {
+        &mut Box::leak(Box::new(array))[..]
+    }
+
+    fn setup(mode: XcpEthMode) -> (Slave, Master) {
+        let link: &'static Link = Box::leak(Box::new(Link::new()));
+        let (slave_end, master_end) = link.split();
+        let clock = TestClock(Box::leak(Box::new(Cell::new(0))));
+        let network = NetworkConfig::new([0x02, 0, 0, 0, 0, 0x10], SLAVE_IP, 24);
+        let buffers = Box::leak(Box::new(EthernetBuffers::new()));
+        let slave = EthernetTransport::new(slave_end, clock, &network, XcpEthConfig::new(mode), buffers);
+        (slave, Master::new(master_end, clock, mode))
+    }
+
+    // Runs both ends for `ms` milliseconds
+    fn run(slave: &mut Slave, master: &mut Master, ms: u32) {
+        for _ in 0..ms {
+            slave.poll();
+            master.poll();
+            master.clock.0.set(master.clock.0.get() + 1);
+        }
+    }
+
+    // The next packet the slave received, without waiting for one
+    fn next_packet(slave: &mut Slave) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; MAX_PACKET_LEN];
+        match slave.receive(&mut buffer, 0) {
+            Ok(len) => Some(buffer[..len].to_vec()),
+            Err(ProtocolError::Timeout) => None,
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+
+    // Sends CONNECT and GET_STATUS back to back and checks the slave
+    // answers both with an incrementing counter
+    fn exchange_two_commands(mode: XcpEthMode) {
+        let (mut slave, mut master) = setup(mode);
+        run(&mut slave, &mut master, 100);
+
+        let mut frames = master.frame(&CONNECT);
+        frames.extend(master.frame(&GET_STATUS));
+        master.send(&frames);
+        run(&mut slave, &mut master, 10);
+
+        assert_eq!(next_packet(&mut slave).unwrap(), CONNECT);
+        slave.send(&[0xFF, 0x10]).unwrap();
+        assert_eq!(next_packet(&mut slave).unwrap(), GET_STATUS);
+        slave.send(&[0xFF, 0x00, 0x00]).unwrap();
+        assert_eq!(next_packet(&mut slave), None);
+
+        run(&mut slave, &mut master, 10);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0xFF, 0x10]);
+        if mode == XcpEthMode::Udp {
+            assert_eq!(master.receive(), expected);
+            expected.clear();
+        }
+        expected.extend_from_slice(&[0x03, 0x00, 0x01, 0x00, 0xFF, 0x00, 0x00]);
+        assert_eq!(master.receive(), expected);
+    }
+
+    #[test]
+    fn tcp_packets_carry_length_and_counter() {
+        exchange_two_commands(XcpEthMode::Tcp);
+    }
+
+    #[test]
+    fn udp_packets_carry_length_and_counter() {
+        exchange_two_commands(XcpEthMode::Udp);
+    }
+
+    #[test]
+    fn tcp_packets_too_long_are_skipped() {
+        let (mut slave, mut master) = setup(XcpEthMode::Tcp);
+        run(&mut slave, &mut master, 100);
+
+        let mut frames = master.frame(&[0xF0; MAX_PACKET_LEN + 1]);
+        frames.extend(master.frame(&CONNECT));
+        master.send(&frames);
+        run(&mut slave, &mut master, 10);
+
+        assert_eq!(next_packet(&mut slave).unwrap(), CONNECT);
+        assert_eq!(next_packet(&mut slave), None);
+    }
+
+    #[test]
+    fn udp_response_without_master_fails() {
+        let (mut slave, mut master) = setup(XcpEthMode::Udp);
+        run(&mut slave, &mut master, 10);
+        assert!(matches!(slave.send(&[0xFF]), Err(ProtocolError::NetworkError)));
+    }
+}
@@ -0,0 +1,858 @@
+// ISO 15765-2 (ISO-TP) segmentation over CAN
+
+use core::fmt;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+use crate::hal::{
+    fd_frame_len, CanBusStatus, CanErrorState, FdFrame, NbCan, Timer, CAN_MAX_DATA_LEN, CANFD_MAX_DATA_LEN,
+};
+use super::can::BusOffPolicy;
+use super::{ProtocolError, Transport};
+
+/// Largest message described by a 12-bit first frame length
+pub const ISOTP_MAX_MESSAGE_LEN: usize = 4095;
+
+// Classic CAN frame payload
+const CAN_FRAME_LEN: usize = CAN_MAX_DATA_LEN;
+
+// Protocol control information, upper nibble of the first byte
+const PCI_SINGLE_FRAME: u8 = 0x00;
+const PCI_FIRST_FRAME: u8 = 0x10;
+const PCI_CONSECUTIVE_FRAME: u8 = 0x20;
+const PCI_FLOW_CONTROL: u8 = 0x30;
+
+// Flow status of a flow control frame
+const FS_CONTINUE_TO_SEND: u8 = 0x00;
+const FS_WAIT: u8 = 0x01;
+const FS_OVERFLOW: u8 = 0x02;
+
+// Default network layer timeouts from ISO 15765-2
+const DEFAULT_N_AS_MS: u32 = 1000;
+const DEFAULT_N_BS_MS: u32 = 1000;
+const DEFAULT_N_CR_MS: u32 = 1000;
+
+// Flow control WAIT frames accepted in a row before giving up
+const DEFAULT_MAX_WAIT_FRAMES: u8 = 10;
+
+// Normal fixed addressing: priority 6, PGN 0xDA00 for physically addressed
+// diagnostics, then target and source address
+const NORMAL_FIXED_PHYSICAL_BASE: u32 = 0x18DA_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsoTpError {
+    /// A frame could not be transmitted within N_As
+    TimeoutA,
+    /// No flow control frame arrived within N_Bs
+    TimeoutBs,
+    /// No consecutive frame arrived within N_Cr
+    TimeoutCr,
+    /// No message arrived within the receive timeout
+    Timeout,
+    WrongSequenceNumber,
+    InvalidFlowStatus,
+    WaitFrameOverrun,
+    /// The receiver cannot hold the message
+    BufferOverflow,
+    MessageTooLong,
+    CanError,
+    /// The controller went bus-off, aborting messages in progress
+    BusOff,
+}
+
+impl fmt::Display for IsoTpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsoTpError::TimeoutA => write!(f, "Frame transmission timeout (N_As)"),
+            IsoTpError::TimeoutBs => write!(f, "Flow control timeout (N_Bs)"),
+            IsoTpError::TimeoutCr => write!(f, "Consecutive frame timeout (N_Cr)"),
+            IsoTpError::Timeout => write!(f, "No message received"),
+            IsoTpError::WrongSequenceNumber => write!(f, "Wrong consecutive frame sequence number"),
+            IsoTpError::InvalidFlowStatus => write!(f, "Invalid flow status"),
+            IsoTpError::WaitFrameOverrun => write!(f, "Too many flow control wait frames"),
+            IsoTpError::BufferOverflow => write!(f, "Receiver buffer overflow"),
+            IsoTpError::MessageTooLong => write!(f, "Message too long"),
+            IsoTpError::CanError => write!(f, "CAN communication error"),
+            IsoTpError::BusOff => write!(f, "CAN controller is bus-off"),
+        }
+    }
+}
+
+impl From<IsoTpError> for ProtocolError {
+    fn from(error: IsoTpError) -> Self {
+        match error {
+            IsoTpError::TimeoutA
+            | IsoTpError::TimeoutBs
+            | IsoTpError::TimeoutCr
+            | IsoTpError::Timeout => ProtocolError::Timeout,
+            IsoTpError::BufferOverflow | IsoTpError::MessageTooLong => {
+                ProtocolError::InvalidDataLength
+            }
+            IsoTpError::BusOff => ProtocolError::BusOff,
+            _ => ProtocolError::CanError,
+        }
+    }
+}
+
+/// How the N_AI is carried. Extended addressing puts a target address in
+/// the first data byte of every frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Addressing {
+    Normal,
+    Extended {
+        /// Address placed in transmitted frames
+        tx_address: u8,
+        /// Address that received frames must carry
+        rx_address: u8,
+    },
+}
+
+/// ISO-TP channel settings
+#[derive(Debug, Clone, Copy)]
+pub struct IsoTpConfig {
+    /// Identifier of transmitted frames
+    pub tx_id: Id,
+    /// Identifier of received frames
+    pub rx_id: Id,
+    pub addressing: Addressing,
+    /// Block size announced to the sender, 0 for no further flow control
+    pub block_size: u8,
+    /// STmin announced to the sender, encoded as on the wire
+    pub st_min: u8,
+    pub n_as_ms: u32,
+    pub n_bs_ms: u32,
+    pub n_cr_ms: u32,
+    pub max_wait_frames: u8,
+    /// Payload of transmitted frames (TX_DL): 8 for classic CAN, up to 64
+    /// for CAN FD
+    pub tx_dl: usize,
+    /// Send the data phase of FD frames at the data bit rate
+    pub bit_rate_switch: bool,
+    /// Pad every frame to a DLC of 8
+    pub padding: bool,
+    /// Value of the padding bytes
+    pub fill_byte: u8,
+    pub bus_off_policy: BusOffPolicy,
+}
+
+impl IsoTpConfig {
+    pub fn new(tx_id: impl Into<Id>, rx_id: impl Into<Id>) -> Self {
+        Self {
+            tx_id: tx_id.into(),
+            rx_id: rx_id.into(),
+            addressing: Addressing::Normal,
+            block_size: 0,
+            st_min: 0,
+            n_as_ms: DEFAULT_N_AS_MS,
+            n_bs_ms: DEFAULT_N_BS_MS,
+            n_cr_ms: DEFAULT_N_CR_MS,
+            max_wait_frames: DEFAULT_MAX_WAIT_FRAMES,
+            tx_dl: CAN_FRAME_LEN,
+            bit_rate_switch: false,
+            padding: false,
+            fill_byte: 0xCC,
+            bus_off_policy: BusOffPolicy::Automatic,
+        }
+    }
+
+    /// 29-bit normal fixed addressing as used on J1939 networks: requests to
+    /// `ecu_address` arrive on 0x18DA<ecu><tester>, responses go out on
+    /// 0x18DA<tester><ecu>
+    pub fn normal_fixed(ecu_address: u8, tester_address: u8) -> Self {
+        Self::new(
+            normal_fixed_id(tester_address, ecu_address),
+            normal_fixed_id(ecu_address, tester_address),
+        )
+    }
+
+    /// Pads frames to 8 bytes with `fill_byte`
+    pub fn with_padding(mut self, fill_byte: u8) -> Self {
+        self.padding = true;
+        self.fill_byte = fill_byte;
+        self
+    }
+
+    pub fn with_extended_addressing(mut self, tx_address: u8, rx_address: u8) -> Self {
+        self.addressing = Addressing::Extended { tx_address, rx_address };
+        self
+    }
+
+    /// Sends CAN FD frames with up to `tx_dl` bytes, a valid FD frame length
+    /// between 8 and 64. Frames of any length are received either way.
+    pub fn with_fd(mut self, tx_dl: usize, bit_rate_switch: bool) -> Self {
+        self.tx_dl = match fd_frame_len(tx_dl) {
+            Some(len) => len.max(CAN_FRAME_LEN),
+            None => CANFD_MAX_DATA_LEN,
+        };
+        self.bit_rate_switch = bit_rate_switch;
+        self
+    }
+
+    pub fn with_bus_off_policy(mut self, policy: BusOffPolicy) -> Self {
+        self.bus_off_policy = policy;
+        self
+    }
+
+    /// Sets the block size and STmin announced in our flow control frames
+    pub fn with_flow_control(mut self, block_size: u8, st_min: u8) -> Self {
+        self.block_size = block_size;
+        self.st_min = st_min;
+        self
+    }
+}
+
+impl Default for IsoTpConfig {
+    fn default() -> Self {
+        // Physically addressed diagnostic identifiers of the first ECU
+        Self::new(
+            StandardId::new(0x7E8).unwrap(),
+            StandardId::new(0x7E0).unwrap(),
+        )
+    }
+}
+
+fn normal_fixed_id(target_address: u8, source_address: u8) -> ExtendedId {
+    let raw = NORMAL_FIXED_PHYSICAL_BASE | ((target_address as u32) << 8) | source_address as u32;
+    ExtendedId::new(raw).unwrap()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TxState {
+    Idle,
+    WaitFlowControl,
+    SendConsecutive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RxState {
+    Idle,
+    Receiving,
+}
+
+/// ISO-TP channel on one CAN controller. It buffers a whole message of up
+/// to `ISOTP_MAX_MESSAGE_LEN` bytes each way, as UDS transfers need.
+/// `Protocol` cuts packets down to the XCP `MAX_PACKET_LEN`, so UDS servers
+/// use `IsoTp` directly through its `Transport` implementation.
+pub struct IsoTp<C: NbCan, T: Timer> {
+    can: C,
+    timer: T,
+    config: IsoTpConfig,
+
+    tx_state: TxState,
+    tx_buffer: [u8; ISOTP_MAX_MESSAGE_LEN],
+    tx_len: usize,
+    tx_offset: usize,
+    tx_sequence: u8,
+    // Consecutive frames left in the block, if the receiver limits it
+    tx_block_remaining: u8,
+    tx_block_limited: bool,
+    tx_st_min_ms: u32,
+    tx_wait_frames: u8,
+    tx_timer_start: u32,
+
+    rx_state: RxState,
+    rx_buffer: [u8; ISOTP_MAX_MESSAGE_LEN],
+    rx_len: usize,
+    rx_offset: usize,
+    rx_sequence: u8,
+    // Payload of the first frame, which all consecutive frames but the last match
+    rx_dl: usize,
+    rx_block_count: u8,
+    rx_timer_start: u32,
+    rx_ready: Option<usize>,
+}
+
+impl<C: NbCan<Frame: FdFrame> + CanErrorState, T: Timer> IsoTp<C, T> {
+    pub fn new(can: C, timer: T, config: IsoTpConfig) -> Self {
+        Self {
+            can,
+            timer,
+            config,
+            tx_state: TxState::Idle,
+            tx_buffer: [0; ISOTP_MAX_MESSAGE_LEN],
+            tx_len: 0,
+            tx_offset: 0,
+            tx_sequence: 0,
+            tx_block_remaining: 0,
+            tx_block_limited: false,
+            tx_st_min_ms: 0,
+            tx_wait_frames: 0,
+            tx_timer_start: 0,
+            rx_state: RxState::Idle,
+            rx_buffer: [0; ISOTP_MAX_MESSAGE_LEN],
+            rx_len: 0,
+            rx_offset: 0,
+            rx_sequence: 0,
+            rx_dl: CAN_FRAME_LEN,
+            rx_block_count: 0,
+            rx_timer_start: 0,
+            rx_ready: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: IsoTpConfig) {
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> &IsoTpConfig {
+        &self.config
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.tx_state != TxState::Idle
+    }
+
+    /// Starts sending a message. It goes out as `poll` is called.
+    pub fn start_send(&mut self, data: &[u8]) -> Result<(), IsoTpError> {
+        if data.is_empty() || data.len() > ISOTP_MAX_MESSAGE_LEN {
+            return Err(IsoTpError::MessageTooLong);
+        }
+
+        let capacity = self.frame_capacity(self.config.tx_dl);
+        let mut payload = [0u8; CANFD_MAX_DATA_LEN];
+        if data.len() < self.frame_capacity(CAN_FRAME_LEN) {
+            payload[0] = PCI_SINGLE_FRAME | data.len() as u8;
+            payload[1..1 + data.len()].copy_from_slice(data);
+            return self.transmit(&payload[..1 + data.len()]);
+        }
+        if data.len() < capacity - 1 {
+            // Single frames beyond 8 bytes carry the length in a second byte
+            payload[0] = PCI_SINGLE_FRAME;
+            payload[1] = data.len() as u8;
+            payload[2..2 + data.len()].copy_from_slice(data);
+            return self.transmit(&payload[..2 + data.len()]);
+        }
+
+        self.tx_buffer[..data.len()].copy_from_slice(data);
+        self.tx_len = data.len();
+
+        let first_len = capacity - 2;
+        payload[0] = PCI_FIRST_FRAME | (data.len() >> 8) as u8;
+        payload[1] = data.len() as u8;
+        payload[2..capacity].copy_from_slice(&data[..first_len]);
+        self.transmit(&payload[..capacity])?;
+
+        self.tx_offset = first_len;
+        self.tx_sequence = 1;
+        self.tx_wait_frames = 0;
+        self.tx_timer_start = self.timer.now_ms();
+        self.tx_state = TxState::WaitFlowControl;
+        Ok(())
+    }
+
+    pub fn get_bus_status(&self) -> CanBusStatus {
+        self.can.get_bus_status()
+    }
+
+    /// Brings the controller back onto the bus under the manual bus-off
+    /// policy. Returns `WouldBlock` until it is back.
+    pub fn recover_bus_off(&mut self) -> nb::Result<(), IsoTpError> {
+        self.can
+            .recover_bus_off()
+            .map_err(|e| e.map(|_| IsoTpError::CanError))
+    }
+
+    /// Processes received frames, sends pending consecutive frames and
+    /// checks the network layer timeouts. Call it regularly.
+    pub fn poll(&mut self) -> Result<(), IsoTpError> {
+        if self.can.get_bus_status().is_bus_off() {
+            if self.config.bus_off_policy == BusOffPolicy::Automatic {
+                let _ = self.can.recover_bus_off();
+            }
+            self.tx_state = TxState::Idle;
+            self.rx_state = RxState::Idle;
+            return Err(IsoTpError::BusOff);
+        }
+
+        loop {
+            let frame = match self.can.receive() {
+                Ok(frame) => frame,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => return Err(IsoTpError::CanError),
+            };
+            if frame.id() != self.config.rx_id || frame.is_remote_frame() {
+                continue;
+            }
+
+            let data = frame.data();
+            let payload = match self.config.addressing {
+                Addressing::Normal => data,
+                Addressing::Extended { rx_address, .. } => match data.split_first() {
+                    Some((&address, rest)) if address == rx_address => rest,
+                    _ => continue,
+                },
+            };
+            if payload.is_empty() {
+                continue;
+            }
+
+            self.handle_frame(payload)?;
+        }
+
+        match self.tx_state {
+            TxState::WaitFlowControl => {
+                if self.timer.has_elapsed(self.tx_timer_start, self.config.n_bs_ms) {
+                    self.tx_state = TxState::Idle;
+                    return Err(IsoTpError::TimeoutBs);
+                }
+            }
+            TxState::SendConsecutive => {
+                // The timer may tick right after the previous frame, so a
+                // non-zero STmin waits one extra tick
+                let separation = if self.tx_st_min_ms > 0 { self.tx_st_min_ms + 1 } else { 0 };
+                if self.timer.has_elapsed(self.tx_timer_start, separation) {
+                    self.send_consecutive_frame()?;
+                }
+            }
+            TxState::Idle => {}
+        }
+
+        if self.rx_state == RxState::Receiving
+            && self.timer.has_elapsed(self.rx_timer_start, self.config.n_cr_ms)
+        {
+            self.rx_state = RxState::Idle;
+            return Err(IsoTpError::TimeoutCr);
+        }
+
+        Ok(())
+    }
+
+    /// Takes the last completely received message, if any
+    pub fn take_received(&mut self) -> Option<&[u8]> {
+        let len = self.rx_ready.take()?;
+        Some(&self.rx_buffer[..len])
+    }
+
+    /// Sends a message and waits until its last frame went out.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), IsoTpError> {
+        self.start_send(data)?;
+        while self.is_sending() {
+            if let Err(e) = self.poll() {
+                self.tx_state = TxState::Idle;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout_ms` for the start of a message, receives it into
+    /// `buffer` and returns its length. Once a message has started the
+    /// N_Cr timeout applies instead.
+    pub fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, IsoTpError> {
+        let start = self.timer.now_ms();
+        loop {
+            // Automatic recovery may bring the bus back within the timeout
+            match self.poll() {
+                Err(IsoTpError::BusOff) if self.config.bus_off_policy == BusOffPolicy::Automatic => {
+                    if self.timer.has_elapsed(start, timeout_ms) {
+                        return Err(IsoTpError::BusOff);
+                    }
+                    continue;
+                }
+                result => result?,
+            }
+
+            if let Some(message) = self.take_received() {
+                let len = message.len();
+                if len > buffer.len() {
+                    return Err(IsoTpError::BufferOverflow);
+                }
+                buffer[..len].copy_from_slice(message);
+                return Ok(len);
+            }
+
+            if self.rx_state == RxState::Idle && self.timer.has_elapsed(start, timeout_ms) {
+                return Err(IsoTpError::Timeout);
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, payload: &[u8]) -> Result<(), IsoTpError> {
+        let frame_len = payload.len() + self.address_len();
+
+        match payload[0] & 0xF0 {
+            PCI_SINGLE_FRAME => {
+                let (len, offset) = match payload[0] & 0x0F {
+                    // Escaped length of a CAN FD single frame
+                    0 if frame_len > CAN_FRAME_LEN && payload.len() > 1 => {
+                        (payload[1] as usize, 2)
+                    }
+                    len if frame_len <= CAN_FRAME_LEN => (len as usize, 1),
+                    _ => return Ok(()),
+                };
+                if len == 0 || len > payload.len() - offset {
+                    return Ok(());
+                }
+
+                // A new message replaces one still being received
+                self.rx_state = RxState::Idle;
+                self.rx_buffer[..len].copy_from_slice(&payload[offset..offset + len]);
+                self.rx_ready = Some(len);
+            }
+            PCI_FIRST_FRAME => {
+                // The first frame sets RX_DL for the rest of the message
+                if frame_len < CAN_FRAME_LEN || fd_frame_len(frame_len) != Some(frame_len) {
+                    return Ok(());
+                }
+                let capacity = payload.len();
+
+                let len = (((payload[0] & 0x0F) as usize) << 8) | payload[1] as usize;
+                if len == 0 {
+                    // Escape sequence for messages over 4095 bytes
+                    self.rx_state = RxState::Idle;
+                    return self.send_flow_control(FS_OVERFLOW);
+                }
+                // Messages fitting a single frame must not be segmented
+                let max_single_len = if frame_len > CAN_FRAME_LEN { capacity - 2 } else { capacity - 1 };
+                if len <= max_single_len {
+                    return Ok(());
+                }
+
+                let first_len = capacity - 2;
+                self.rx_buffer[..first_len].copy_from_slice(&payload[2..capacity]);
+                self.rx_len = len;
+                self.rx_offset = first_len;
+                self.rx_sequence = 1;
+                self.rx_dl = frame_len;
+                self.rx_block_count = 0;
+                self.rx_state = RxState::Receiving;
+                self.send_flow_control(FS_CONTINUE_TO_SEND)?;
+                self.rx_timer_start = self.timer.now_ms();
+            }
+            PCI_CONSECUTIVE_FRAME => {
+                if self.rx_state != RxState::Receiving {
+                    return Ok(());
+                }
+                if payload[0] & 0x0F != self.rx_sequence {
+                    self.rx_state = RxState::Idle;
+                    return Err(IsoTpError::WrongSequenceNumber);
+                }
+
+                let len = (self.rx_len - self.rx_offset).min(self.frame_capacity(self.rx_dl) - 1);
+                if payload.len() < 1 + len {
+                    return Ok(());
+                }
+                self.rx_buffer[self.rx_offset..self.rx_offset + len]
+                    .copy_from_slice(&payload[1..1 + len]);
+                self.rx_offset += len;
+                self.rx_sequence = (self.rx_sequence + 1) & 0x0F;
+                self.rx_timer_start = self.timer.now_ms();
+
+                if self.rx_offset == self.rx_len {
+                    self.rx_state = RxState::Idle;
+                    self.rx_ready = Some(self.rx_len);
+                } else if self.config.block_size != 0 {
+                    self.rx_block_count += 1;
+                    if self.rx_block_count == self.config.block_size {
+                        self.rx_block_count = 0;
+                        self.send_flow_control(FS_CONTINUE_TO_SEND)?;
+                        self.rx_timer_start = self.timer.now_ms();
+                    }
+                }
+            }
+            PCI_FLOW_CONTROL => {
+                if self.tx_state != TxState::WaitFlowControl {
+                    return Ok(());
+                }
+
+                match payload[0] & 0x0F {
+                    FS_CONTINUE_TO_SEND => {
+                        if payload.len() < 3 {
+                            return Ok(());
+                        }
+                        self.tx_block_remaining = payload[1];
+                        self.tx_block_limited = payload[1] != 0;
+                        self.tx_st_min_ms = decode_st_min(payload[2]);
+                        self.tx_wait_frames = 0;
+                        // The first consecutive frame may follow right away
+                        self.tx_timer_start = self.timer.now_ms().wrapping_sub(self.tx_st_min_ms + 1);
+                        self.tx_state = TxState::SendConsecutive;
+                    }
+                    FS_WAIT => {
+                        self.tx_wait_frames += 1;
+                        if self.tx_wait_frames > self.config.max_wait_frames {
+                            self.tx_state = TxState::Idle;
+                            return Err(IsoTpError::WaitFrameOverrun);
+                        }
+                        self.tx_timer_start = self.timer.now_ms();
+                    }
+                    FS_OVERFLOW => {
+                        self.tx_state = TxState::Idle;
+                        return Err(IsoTpError::BufferOverflow);
+                    }
+                    _ => {
+                        self.tx_state = TxState::Idle;
+                        return Err(IsoTpError::InvalidFlowStatus);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn send_consecutive_frame(&mut self) -> Result<(), IsoTpError> {
+        let len = (self.tx_len - self.tx_offset).min(self.frame_capacity(self.config.tx_dl) - 1);
+        let mut payload = [0u8; CANFD_MAX_DATA_LEN];
+        payload[0] = PCI_CONSECUTIVE_FRAME | self.tx_sequence;
+        payload[1..1 + len].copy_from_slice(&self.tx_buffer[self.tx_offset..self.tx_offset + len]);
+        self.transmit(&payload[..1 + len])?;
+
+        self.tx_offset += len;
+        self.tx_sequence = (self.tx_sequence + 1) & 0x0F;
+        self.tx_timer_start = self.timer.now_ms();
+
+        if self.tx_offset == self.tx_len {
+            self.tx_state = TxState::Idle;
+        } else if self.tx_block_limited {
+            self.tx_block_remaining -= 1;
+            if self.tx_block_remaining == 0 {
+                self.tx_state = TxState::WaitFlowControl;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_flow_control(&mut self, flow_status: u8) -> Result<(), IsoTpError> {
+        self.transmit(&[PCI_FLOW_CONTROL | flow_status, self.config.block_size, self.config.st_min])
+    }
+
+    // Bytes in front of the protocol control information
+    fn address_len(&self) -> usize {
+        match self.config.addressing {
+            Addressing::Normal => 0,
+            Addressing::Extended { .. } => 1,
+        }
+    }
+
+    // Payload bytes of a frame of `frame_len` bytes after the extended address
+    fn frame_capacity(&self, frame_len: usize) -> usize {
+        frame_len - self.address_len()
+    }
+
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), IsoTpError> {
+        let mut data = [self.config.fill_byte; CANFD_MAX_DATA_LEN];
+        let offset = match self.config.addressing {
+            Addressing::Normal => 0,
+            Addressing::Extended { tx_address, .. } => {
+                data[0] = tx_address;
+                1
+            }
+        };
+        data[offset..offset + payload.len()].copy_from_slice(payload);
+        let mut len = offset + payload.len();
+        if self.config.padding {
+            len = len.max(CAN_FRAME_LEN);
+        }
+
+        // CAN FD frames beyond 8 bytes are always padded to the next DLC
+        let frame = if self.config.tx_dl > CAN_FRAME_LEN {
+            let len = fd_frame_len(len).ok_or(IsoTpError::MessageTooLong)?;
+            C::Frame::new_fd(self.config.tx_id, &data[..len], self.config.bit_rate_switch)
+        } else {
+            C::Frame::new(self.config.tx_id, &data[..len])
+        };
+        let frame = frame.ok_or(IsoTpError::CanError)?;
+
+        let start = self.timer.now_ms();
+        loop {
+            match self.can.transmit(&frame) {
+                Ok(_) => return Ok(()),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(_)) => {
+                    if self.can.get_bus_status().is_bus_off() {
+                        return Err(IsoTpError::BusOff);
+                    }
+                    return Err(IsoTpError::CanError);
+                }
+            }
+
+            if self.timer.has_elapsed(start, self.config.n_as_ms) {
+                return Err(IsoTpError::TimeoutA);
+            }
+        }
+    }
+}
+
+impl<C: NbCan<Frame: FdFrame> + CanErrorState, T: Timer> Transport for IsoTp<C, T> {
+    fn max_packet_size(&self) -> usize {
+        ISOTP_MAX_MESSAGE_LEN
+    }
+
+    fn get_bus_status(&self) -> Option<CanBusStatus> {
+        Some(self.can.get_bus_status())
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        IsoTp::send(self, packet).map_err(ProtocolError::from)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, ProtocolError> {
+        IsoTp::receive(self, buffer, timeout_ms).map_err(ProtocolError::from)
+    }
+}
+
+// STmin in milliseconds. Sub-millisecond values round up to the timer
+// resolution; reserved values mean the maximum of 127 ms.
+fn decode_st_min(st_min: u8) -> u32 {
+    match st_min {
+        0x00..=0x7F => st_min as u32,
+        0xF1..=0xF9 => 1,
+        _ => 0x7F,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::vec::Vec;
+    use crate::hal::loopback::{LoopbackBus, LoopbackCan};
+    use s32k148_hal::CanFrame;
+
+    type Bus = LoopbackBus<CanFrame, 16>;
+    type Can<'a> = LoopbackCan<'a, CanFrame, 16>;
+
+    #[derive(Clone, Copy)]
+    struct TestClock<'a>(&'a Cell<u32>);
+
+    impl Timer for TestClock<'_> {
+        fn now_ms(&self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    fn tester_config() -> IsoTpConfig {
+        IsoTpConfig::new(StandardId::new(0x7E0).unwrap(), StandardId::new(0x7E8).unwrap())
+    }
+
+    // Polls both ends for `ms` milliseconds, failing on any error
+    fn run(a: &mut IsoTp<Can<'_>, TestClock<'_>>, b: &mut IsoTp<Can<'_>, TestClock<'_>>, clock: &Cell<u32>, ms: u32) {
+        for _ in 0..ms {
+            a.poll().unwrap();
+            b.poll().unwrap();
+            clock.set(clock.get() + 1);
+        }
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn single_frame_round_trip() {
+        let bus = Bus::new();
+        let (ecu_can, tester_can) = bus.split();
+        let clock = Cell::new(0);
+        let mut ecu = IsoTp::new(ecu_can, TestClock(&clock), IsoTpConfig::default());
+        let mut tester = IsoTp::new(tester_can, TestClock(&clock), tester_config());
+
+        tester.start_send(&[0x10, 0x02]).unwrap();
+        run(&mut ecu, &mut tester, &clock, 2);
+        assert_eq!(ecu.take_received(), Some(&[0x10, 0x02][..]));
+
+        ecu.start_send(&[0x50, 0x02, 0x00, 0x19, 0x01, 0xF4]).unwrap();
+        run(&mut ecu, &mut tester, &clock, 2);
+        assert_eq!(tester.take_received(), Some(&[0x50, 0x02, 0x00, 0x19, 0x01, 0xF4][..]));
+    }
+
+    #[test]
+    fn segmented_message_follows_flow_control() {
+        let bus = Bus::new();
+        let (ecu_can, tester_can) = bus.split();
+        let clock = Cell::new(0);
+        // Two consecutive frames per block, 5 ms apart, so the 14
+        // consecutive frames take at least 35 ms
+        let config = IsoTpConfig::default().with_flow_control(2, 5);
+        let mut ecu = IsoTp::new(ecu_can, TestClock(&clock), config);
+        let mut tester = IsoTp::new(tester_can, TestClock(&clock), tester_config());
+
+        // A first frame with 6 bytes and 14 consecutive frames with 7
+        let data = message(104);
+        tester.start_send(&data).unwrap();
+        run(&mut ecu, &mut tester, &clock, 20);
+        assert!(tester.is_sending());
+        assert_eq!(ecu.take_received(), None);
+
+        run(&mut ecu, &mut tester, &clock, 50);
+        assert!(!tester.is_sending());
+        assert_eq!(ecu.take_received(), Some(&data[..]));
+    }
+
+    #[test]
+    fn largest_message_with_extended_addressing() {
+        let bus = Bus::new();
+        let (ecu_can, tester_can) = bus.split();
+        let clock = Cell::new(0);
+        let mut ecu = IsoTp::new(
+            ecu_can,
+            TestClock(&clock),
+            IsoTpConfig::default().with_extended_addressing(0xF1, 0x01),
+        );
+        let mut tester = IsoTp::new(
+            tester_can,
+            TestClock(&clock),
+            tester_config().with_extended_addressing(0x01, 0xF1),
+        );
+
+        let data = message(ISOTP_MAX_MESSAGE_LEN);
+        tester.start_send(&data).unwrap();
+        run(&mut ecu, &mut tester, &clock, 1000);
+        assert_eq!(ecu.take_received(), Some(&data[..]));
+        assert_eq!(tester.start_send(&message(ISOTP_MAX_MESSAGE_LEN + 1)), Err(IsoTpError::MessageTooLong));
+    }
+
+    #[test]
+    fn fd_frames_carry_long_single_frames() {
+        let bus = Bus::new();
+        let (ecu_can, tester_can) = bus.split();
+        let clock = Cell::new(0);
+        let mut ecu = IsoTp::new(ecu_can, TestClock(&clock), IsoTpConfig::default().with_fd(64, true));
+        let mut tester = IsoTp::new(tester_can, TestClock(&clock), tester_config().with_fd(64, true));
+
+        let data = message(62);
+        tester.start_send(&data).unwrap();
+        assert!(!tester.is_sending());
+        run(&mut ecu, &mut tester, &clock, 1);
+        assert_eq!(ecu.take_received(), Some(&data[..]));
+
+        let data = message(300);
+        ecu.start_send(&data).unwrap();
+        run(&mut ecu, &mut tester, &clock, 20);
+        assert_eq!(tester.take_received(), Some(&data[..]));
+    }
+
+    #[test]
+    fn missing_flow_control_times_out() {
+        let bus = Bus::new();
+        let (_ecu_can, tester_can) = bus.split();
+        let clock = Cell::new(0);
+        let mut tester = IsoTp::new(tester_can, TestClock(&clock), tester_config());
+
+        tester.start_send(&message(20)).unwrap();
+        clock.set(DEFAULT_N_BS_MS - 1);
+        assert_eq!(tester.poll(), Ok(()));
+        clock.set(DEFAULT_N_BS_MS);
+        assert_eq!(tester.poll(), Err(IsoTpError::TimeoutBs));
+        assert!(!tester.is_sending());
+    }
+
+    #[test]
+    fn wrong_sequence_number_aborts_reception() {
+        let bus = Bus::new();
+        let (ecu_can, mut tester_can) = bus.split();
+        let clock = Cell::new(0);
+        let mut ecu = IsoTp::new(ecu_can, TestClock(&clock), IsoTpConfig::default());
+        let id = StandardId::new(0x7E0).unwrap();
+
+        let first = [0x10, 0x14, 0, 1, 2, 3, 4, 5];
+        NbCan::transmit(&mut tester_can, &CanFrame::new(id, &first).unwrap()).unwrap();
+        ecu.poll().unwrap();
+        let flow_control = NbCan::receive(&mut tester_can).unwrap();
+        assert_eq!(&flow_control.data()[..3], &[PCI_FLOW_CONTROL | FS_CONTINUE_TO_SEND, 0, 0]);
+
+        let second = [PCI_CONSECUTIVE_FRAME | 2, 6, 7, 8, 9, 10, 11, 12];
+        NbCan::transmit(&mut tester_can, &CanFrame::new(id, &second).unwrap()).unwrap();
+        assert_eq!(ecu.poll(), Err(IsoTpError::WrongSequenceNumber));
+        assert_eq!(ecu.take_received(), None);
+    }
+}
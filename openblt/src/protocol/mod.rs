@@ -1,7 +1,16 @@
 #![no_std]
 
 use core::fmt;
-use crate::hal::EmbeddedCan;
+use crate::hal::CanBusStatus;
+
+pub mod can;
+pub mod doip;
+pub mod ethernet;
+pub mod isotp;
+pub mod transport;
+pub mod uart;
+
+pub use transport::Transport;
 
 #[derive(Debug)]
 pub enum ProtocolError {
@@ -9,8 +18,10 @@ pub enum ProtocolError {
     InvalidDataLength,
     InvalidAddress,
     CanError,
+    BusOff,
     Timeout,
     ChecksumError,
+    NetworkError,
 }
 
 impl fmt::Display for ProtocolError {
@@ -20,32 +31,164 @@ impl fmt::Display for ProtocolError {
             ProtocolError::InvalidDataLength => write!(f, "Invalid data length"),
             ProtocolError::InvalidAddress => write!(f, "Invalid address"),
             ProtocolError::CanError => write!(f, "CAN communication error"),
+            ProtocolError::BusOff => write!(f, "CAN controller is bus-off"),
             ProtocolError::Timeout => write!(f, "Protocol timeout"),
             ProtocolError::ChecksumError => write!(f, "Checksum verification failed"),
+            ProtocolError::NetworkError => write!(f, "Network communication error"),
         }
     }
 }
 
+// XCP command codes
+pub const XCP_CMD_CONNECT: u8 = 0xFF;
+pub const XCP_CMD_DISCONNECT: u8 = 0xFE;
+pub const XCP_CMD_GET_STATUS: u8 = 0xFD;
+pub const XCP_CMD_SYNCH: u8 = 0xFC;
+pub const XCP_CMD_GET_COMM_MODE_INFO: u8 = 0xFB;
+pub const XCP_CMD_GET_SEED: u8 = 0xF8;
+pub const XCP_CMD_UNLOCK: u8 = 0xF7;
+pub const XCP_CMD_SET_MTA: u8 = 0xF6;
+pub const XCP_CMD_UPLOAD: u8 = 0xF5;
+pub const XCP_CMD_SHORT_UPLOAD: u8 = 0xF4;
+pub const XCP_CMD_BUILD_CHECKSUM: u8 = 0xF3;
+pub const XCP_CMD_DOWNLOAD: u8 = 0xF0;
+pub const XCP_CMD_DOWNLOAD_NEXT: u8 = 0xEF;
+pub const XCP_CMD_PROGRAM_START: u8 = 0xD2;
+pub const XCP_CMD_PROGRAM_CLEAR: u8 = 0xD1;
+pub const XCP_CMD_PROGRAM: u8 = 0xD0;
+pub const XCP_CMD_PROGRAM_RESET: u8 = 0xCF;
+pub const XCP_CMD_GET_PGM_PROCESSOR_INFO: u8 = 0xCE;
+pub const XCP_CMD_GET_SECTOR_INFO: u8 = 0xCD;
+pub const XCP_CMD_PROGRAM_NEXT: u8 = 0xCA;
+pub const XCP_CMD_PROGRAM_MAX: u8 = 0xC9;
+
+/// A decoded XCP command packet (CRO). Variants carrying data borrow it
+/// from the received packet. Multi-byte fields use Intel byte order.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Command {
-    Program,
-    Verify,
-    Reboot,
-    GetVersion,
-    GetChecksum,
-    Erase,
+pub enum Command<'a> {
+    Connect { mode: u8 },
+    Disconnect,
+    GetStatus,
+    Synch,
+    GetCommModeInfo,
+    GetSeed { mode: u8, resource: u8 },
+    Unlock { length: u8, data: &'a [u8] },
+    SetMta { extension: u8, address: u32 },
+    Upload { size: u8 },
+    ShortUpload { size: u8, extension: u8, address: u32 },
+    BuildChecksum { block_size: u32 },
+    Download { size: u8, data: &'a [u8] },
+    DownloadNext { size: u8, data: &'a [u8] },
+    ProgramStart,
+    ProgramClear { mode: u8, range: u32 },
+    Program { size: u8, data: &'a [u8] },
+    ProgramReset,
+    GetPgmProcessorInfo,
+    GetSectorInfo { mode: u8, number: u8 },
+    ProgramNext { size: u8, data: &'a [u8] },
+    ProgramMax { data: &'a [u8] },
+}
+
+impl<'a> Command<'a> {
+    /// Decodes a raw command packet.
+    pub fn decode(packet: &'a [u8]) -> Result<Self, ProtocolError> {
+        let code = *packet.first().ok_or(ProtocolError::InvalidDataLength)?;
+
+        let command = match code {
+            XCP_CMD_CONNECT => Command::Connect { mode: byte_at(packet, 1)? },
+            XCP_CMD_DISCONNECT => Command::Disconnect,
+            XCP_CMD_GET_STATUS => Command::GetStatus,
+            XCP_CMD_SYNCH => Command::Synch,
+            XCP_CMD_GET_COMM_MODE_INFO => Command::GetCommModeInfo,
+            XCP_CMD_GET_SEED => Command::GetSeed {
+                mode: byte_at(packet, 1)?,
+                resource: byte_at(packet, 2)?,
+            },
+            XCP_CMD_UNLOCK => {
+                let (length, data) = sized_data(packet)?;
+                Command::Unlock { length, data }
+            }
+            XCP_CMD_SET_MTA => Command::SetMta {
+                extension: byte_at(packet, 3)?,
+                address: u32_at(packet, 4)?,
+            },
+            XCP_CMD_UPLOAD => Command::Upload { size: byte_at(packet, 1)? },
+            XCP_CMD_SHORT_UPLOAD => Command::ShortUpload {
+                size: byte_at(packet, 1)?,
+                extension: byte_at(packet, 3)?,
+                address: u32_at(packet, 4)?,
+            },
+            XCP_CMD_BUILD_CHECKSUM => Command::BuildChecksum { block_size: u32_at(packet, 4)? },
+            XCP_CMD_DOWNLOAD => {
+                let (size, data) = sized_data(packet)?;
+                Command::Download { size, data }
+            }
+            XCP_CMD_DOWNLOAD_NEXT => {
+                let (size, data) = sized_data(packet)?;
+                Command::DownloadNext { size, data }
+            }
+            XCP_CMD_PROGRAM_START => Command::ProgramStart,
+            XCP_CMD_PROGRAM_CLEAR => Command::ProgramClear {
+                mode: byte_at(packet, 1)?,
+                range: u32_at(packet, 4)?,
+            },
+            XCP_CMD_PROGRAM => {
+                let (size, data) = sized_data(packet)?;
+                Command::Program { size, data }
+            }
+            XCP_CMD_PROGRAM_RESET => Command::ProgramReset,
+            XCP_CMD_GET_PGM_PROCESSOR_INFO => Command::GetPgmProcessorInfo,
+            XCP_CMD_GET_SECTOR_INFO => Command::GetSectorInfo {
+                mode: byte_at(packet, 1)?,
+                number: byte_at(packet, 2)?,
+            },
+            XCP_CMD_PROGRAM_NEXT => {
+                let (size, data) = sized_data(packet)?;
+                Command::ProgramNext { size, data }
+            }
+            XCP_CMD_PROGRAM_MAX => Command::ProgramMax { data: &packet[1..] },
+            _ => return Err(ProtocolError::InvalidCommand),
+        };
+
+        Ok(command)
+    }
+}
+
+fn byte_at(packet: &[u8], index: usize) -> Result<u8, ProtocolError> {
+    packet.get(index).copied().ok_or(ProtocolError::InvalidDataLength)
+}
+
+fn u32_at(packet: &[u8], index: usize) -> Result<u32, ProtocolError> {
+    let bytes = packet
+        .get(index..index + 4)
+        .ok_or(ProtocolError::InvalidDataLength)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Commands of the form [code, n, data...]. The element count is returned
+// as sent; the data holds whatever of it arrived in this packet, since
+// block transfers spread the elements over several packets.
+fn sized_data(packet: &[u8]) -> Result<(u8, &[u8]), ProtocolError> {
+    let size = byte_at(packet, 1)?;
+    let available = packet.len() - 2;
+    Ok((size, &packet[2..2 + available.min(size as usize)]))
 }
 
-pub struct Protocol<C: EmbeddedCan> {
-    can: C,
+/// Largest packet any transport hands to the protocol
+pub const MAX_PACKET_LEN: usize = 64;
+
+pub struct Protocol<T: Transport> {
+    transport: T,
     timeout_ms: u32,
+    rx_buffer: [u8; MAX_PACKET_LEN],
 }
 
-impl<C: EmbeddedCan> Protocol<C> {
-    pub fn new(can: C) -> Self {
+impl<T: Transport> Protocol<T> {
+    pub fn new(transport: T) -> Self {
         Self {
-            can,
+            transport,
             timeout_ms: 1000, // Default timeout
+            rx_buffer: [0; MAX_PACKET_LEN],
         }
     }
 
@@ -58,17 +201,41 @@ impl<C: EmbeddedCan> Protocol<C> {
         self.timeout_ms = timeout_ms;
     }
 
-    pub fn receive_command(&mut self) -> Result<Command, ProtocolError> {
-        // TODO: Implement command reception with timeout
-        Ok(Command::GetVersion)
+    pub fn get_transport(&self) -> &T {
+        &self.transport
     }
 
-    pub fn send_response(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
-        // TODO: Implement response sending
-        Ok(())
+    pub fn get_transport_mut(&mut self) -> &mut T {
+        &mut self.transport
     }
-}
 
+    /// Error state of the underlying bus, if the transport has one
+    pub fn get_bus_status(&self) -> Option<CanBusStatus> {
+        self.transport.get_bus_status()
+    }
 
+    /// Largest packet that can be sent over the underlying transport
+    pub fn max_packet_size(&self) -> usize {
+        self.transport.max_packet_size().min(MAX_PACKET_LEN)
+    }
 
+    /// Waits up to the configured timeout for a command packet and decodes it.
+    pub fn receive_command(&mut self) -> Result<Command<'_>, ProtocolError> {
+        let len = self.receive_packet()?.len();
+        Command::decode(&self.rx_buffer[..len])
+    }
 
+    /// Waits up to the configured timeout for a command packet and returns
+    /// it undecoded.
+    pub fn receive_packet(&mut self) -> Result<&[u8], ProtocolError> {
+        let len = self.transport.receive(&mut self.rx_buffer, self.timeout_ms)?;
+        Ok(&self.rx_buffer[..len])
+    }
+
+    pub fn send_response(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        if data.len() > self.max_packet_size() {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+        self.transport.send(data)
+    }
+}
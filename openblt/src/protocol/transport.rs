@@ -0,0 +1,173 @@
+// Packet level link between the bootloader and the host
+
+use crate::hal::CanBusStatus;
+use super::{ProtocolError, MAX_PACKET_LEN};
+
+/// A link that moves whole protocol packets, such as CAN frames or framed
+/// UART messages.
+pub trait Transport {
+    /// Largest packet the link can carry
+    fn max_packet_size(&self) -> usize;
+
+    /// Sends a single packet
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError>;
+
+    /// Waits up to `timeout_ms` for a packet, copies it into `buffer` and
+    /// returns its length
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, ProtocolError>;
+
+    /// Error state of the bus, for links running over CAN
+    fn get_bus_status(&self) -> Option<CanBusStatus> {
+        None
+    }
+}
+
+// Fixed capacity FIFO of packets
+struct PacketQueue<const N: usize> {
+    packets: [[u8; MAX_PACKET_LEN]; N],
+    lengths: [usize; N],
+    head: usize,
+    count: usize,
+}
+
+impl<const N: usize> PacketQueue<N> {
+    const fn new() -> Self {
+        Self {
+            packets: [[0; MAX_PACKET_LEN]; N],
+            lengths: [0; N],
+            head: 0,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        if packet.len() > MAX_PACKET_LEN {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+        if self.count == N {
+            return Err(ProtocolError::Timeout);
+        }
+
+        let slot = (self.head + self.count) % N;
+        self.packets[slot][..packet.len()].copy_from_slice(packet);
+        self.lengths[slot] = packet.len();
+        self.count += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let len = self.lengths[self.head].min(buffer.len());
+        buffer[..len].copy_from_slice(&self.packets[self.head][..len]);
+        self.head = (self.head + 1) % N;
+        self.count -= 1;
+        Some(len)
+    }
+}
+
+/// In-memory transport for running protocol logic without hardware. The
+/// host side queues packets with `inject` and collects the bootloader's
+/// packets with `collect`.
+pub struct MemoryTransport<const N: usize> {
+    rx: PacketQueue<N>,
+    tx: PacketQueue<N>,
+    max_packet_size: usize,
+}
+
+impl<const N: usize> MemoryTransport<N> {
+    pub const fn new(max_packet_size: usize) -> Self {
+        Self {
+            rx: PacketQueue::new(),
+            tx: PacketQueue::new(),
+            max_packet_size,
+        }
+    }
+
+    /// Queues a packet for the bootloader to receive
+    pub fn inject(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        self.rx.push(packet)
+    }
+
+    /// Takes the oldest packet sent by the bootloader
+    pub fn collect(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        self.tx.pop(buffer)
+    }
+}
+
+impl<const N: usize> Transport for MemoryTransport<N> {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        if packet.len() > self.max_packet_size {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+        self.tx.push(packet)
+    }
+
+    // Nothing arrives while waiting, so an empty queue times out right away
+    fn receive(&mut self, buffer: &mut [u8], _timeout_ms: u32) -> Result<usize, ProtocolError> {
+        self.rx.pop(buffer).ok_or(ProtocolError::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Command, Protocol, XCP_CMD_CONNECT};
+
+    #[test]
+    fn packets_round_trip_in_order() {
+        let mut transport = MemoryTransport::<4>::new(8);
+        transport.inject(&[0x01]).unwrap();
+        transport.inject(&[0x02, 0x03]).unwrap();
+        transport.send(&[0xFF, 0x00]).unwrap();
+
+        let mut buffer = [0u8; 8];
+        assert_eq!(transport.receive(&mut buffer, 0).unwrap(), 1);
+        assert_eq!(buffer[0], 0x01);
+        assert_eq!(transport.receive(&mut buffer, 0).unwrap(), 2);
+        assert_eq!(&buffer[..2], &[0x02, 0x03]);
+        assert!(matches!(transport.receive(&mut buffer, 0), Err(ProtocolError::Timeout)));
+
+        assert_eq!(transport.collect(&mut buffer), Some(2));
+        assert_eq!(&buffer[..2], &[0xFF, 0x00]);
+        assert_eq!(transport.collect(&mut buffer), None);
+    }
+
+    #[test]
+    fn queues_are_bounded() {
+        let mut transport = MemoryTransport::<2>::new(8);
+        transport.inject(&[0; MAX_PACKET_LEN]).unwrap();
+        transport.inject(&[0]).unwrap();
+        assert!(matches!(transport.inject(&[0]), Err(ProtocolError::Timeout)));
+        assert!(matches!(transport.inject(&[0; MAX_PACKET_LEN + 1]), Err(ProtocolError::InvalidDataLength)));
+        assert!(matches!(transport.send(&[0; 9]), Err(ProtocolError::InvalidDataLength)));
+
+        // Freed slots are reused once the queue wraps around
+        let mut buffer = [0u8; MAX_PACKET_LEN];
+        for round in 0..5u8 {
+            transport.send(&[round]).unwrap();
+            assert_eq!(transport.collect(&mut buffer), Some(1));
+            assert_eq!(buffer[0], round);
+        }
+    }
+
+    #[test]
+    fn protocol_runs_over_memory() {
+        let mut protocol = Protocol::new(MemoryTransport::<4>::new(8));
+        protocol.get_transport_mut().inject(&[XCP_CMD_CONNECT, 0x00]).unwrap();
+        assert!(matches!(protocol.receive_command(), Ok(Command::Connect { mode: 0 })));
+        assert!(matches!(protocol.receive_command(), Err(ProtocolError::Timeout)));
+
+        protocol.send_response(&[0xFF, 0x10]).unwrap();
+        assert!(matches!(protocol.send_response(&[0; 9]), Err(ProtocolError::InvalidDataLength)));
+        let mut buffer = [0u8; 8];
+        assert_eq!(protocol.get_transport_mut().collect(&mut buffer), Some(2));
+        assert_eq!(&buffer[..2], &[0xFF, 0x10]);
+    }
+}
@@ -0,0 +1,96 @@
+// XCP-on-UART (RS232) transport using the OpenBLT framing: every packet is
+// preceded by a single length byte.
+
+use s32k148_hal::uart::Uart;
+use crate::hal::Timer;
+use super::{ProtocolError, Transport};
+
+/// Longest packet accepted over the serial link
+pub const UART_MAX_PACKET_LEN: usize = 64;
+
+// Default time allowed between two bytes of the same packet
+const UART_DEFAULT_INTER_BYTE_TIMEOUT_MS: u32 = 100;
+
+// Byte level access to a serial port
+pub trait SerialPort {
+    /// Returns the next received byte, if one is available
+    fn read_byte(&mut self) -> Option<u8>;
+    /// Writes a byte, waiting for room in the transmitter
+    fn write_byte(&mut self, byte: u8);
+}
+
+impl<const N: u8> SerialPort for Uart<N> {
+    fn read_byte(&mut self) -> Option<u8> {
+        Uart::read_byte(self)
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        Uart::write_byte(self, byte)
+    }
+}
+
+pub struct UartTransport<U: SerialPort, T: Timer> {
+    uart: U,
+    timer: T,
+    inter_byte_timeout_ms: u32,
+}
+
+impl<U: SerialPort, T: Timer> UartTransport<U, T> {
+    pub fn new(uart: U, timer: T) -> Self {
+        Self {
+            uart,
+            timer,
+            inter_byte_timeout_ms: UART_DEFAULT_INTER_BYTE_TIMEOUT_MS,
+        }
+    }
+
+    pub fn set_inter_byte_timeout(&mut self, timeout_ms: u32) {
+        self.inter_byte_timeout_ms = timeout_ms;
+    }
+
+    fn read_byte_within(&mut self, timeout_ms: u32) -> Result<u8, ProtocolError> {
+        let start = self.timer.now_ms();
+        loop {
+            if let Some(byte) = self.uart.read_byte() {
+                return Ok(byte);
+            }
+            if self.timer.has_elapsed(start, timeout_ms) {
+                return Err(ProtocolError::Timeout);
+            }
+        }
+    }
+}
+
+impl<U: SerialPort, T: Timer> Transport for UartTransport<U, T> {
+    fn max_packet_size(&self) -> usize {
+        UART_MAX_PACKET_LEN
+    }
+
+    // A packet whose bytes stop arriving for longer than the inter-byte
+    // timeout is dropped
+    fn receive(&mut self, buffer: &mut [u8], timeout_ms: u32) -> Result<usize, ProtocolError> {
+        let len = self.read_byte_within(timeout_ms)? as usize;
+        if len == 0 || len > UART_MAX_PACKET_LEN || len > buffer.len() {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+
+        for byte in buffer[..len].iter_mut() {
+            *byte = self.read_byte_within(self.inter_byte_timeout_ms)?;
+        }
+
+        Ok(len)
+    }
+
+    // Every packet is preceded by its length byte
+    fn send(&mut self, packet: &[u8]) -> Result<(), ProtocolError> {
+        if packet.is_empty() || packet.len() > UART_MAX_PACKET_LEN {
+            return Err(ProtocolError::InvalidDataLength);
+        }
+
+        self.uart.write_byte(packet.len() as u8);
+        for &byte in packet {
+            self.uart.write_byte(byte);
+        }
+        Ok(())
+    }
+}
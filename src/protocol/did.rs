@@ -0,0 +1,150 @@
+// Data identifiers served by ReadDataByIdentifier/WriteDataByIdentifier.
+// Each identifier either carries a fixed value supplied by the board, a
+// value kept in the persistent data record, or the active session.
+
+// Identification and fingerprint DIDs from ISO 14229-1 Annex C
+pub const DID_PROGRAMMING_FINGERPRINT: u16 = 0xF15A;
+pub const DID_BOOT_SOFTWARE_IDENTIFICATION: u16 = 0xF180;
+pub const DID_APPLICATION_SOFTWARE_IDENTIFICATION: u16 = 0xF181;
+pub const DID_APPLICATION_DATA_IDENTIFICATION: u16 = 0xF182;
+pub const DID_BOOT_SOFTWARE_FINGERPRINT: u16 = 0xF183;
+pub const DID_APPLICATION_SOFTWARE_FINGERPRINT: u16 = 0xF184;
+pub const DID_APPLICATION_DATA_FINGERPRINT: u16 = 0xF185;
+pub const DID_ACTIVE_DIAGNOSTIC_SESSION: u16 = 0xF186;
+pub const DID_SPARE_PART_NUMBER: u16 = 0xF187;
+pub const DID_ECU_SOFTWARE_NUMBER: u16 = 0xF188;
+pub const DID_ECU_SOFTWARE_VERSION_NUMBER: u16 = 0xF189;
+pub const DID_SYSTEM_SUPPLIER_IDENTIFIER: u16 = 0xF18A;
+pub const DID_ECU_MANUFACTURING_DATE: u16 = 0xF18B;
+pub const DID_ECU_SERIAL_NUMBER: u16 = 0xF18C;
+
+/// Number of identifiers a registry holds
+pub const DID_REGISTRY_SIZE: usize = 24;
+
+// Longest value of the standard identifiers kept in the data record
+const DID_STANDARD_MAX_LEN: usize = 16;
+
+/// Where the value of an identifier comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DidData {
+    /// Fixed value compiled into the bootloader
+    Static(&'static [u8]),
+    /// Value kept in the data record, at most `max_len` bytes
+    Stored { max_len: usize },
+    /// The diagnostic session the server is in
+    ActiveSession,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Did {
+    pub id: u16,
+    pub data: DidData,
+    /// WriteDataByIdentifier may change the value. Only stored values can
+    /// be written.
+    pub writable: bool,
+}
+
+pub struct DidRegistry {
+    entries: [Option<Did>; DID_REGISTRY_SIZE],
+}
+
+impl DidRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self {
+            entries: [None; DID_REGISTRY_SIZE],
+        }
+    }
+
+    /// Creates a registry with the standard identification and fingerprint
+    /// identifiers. The identification values are read from the data record
+    /// until the board registers fixed values for them.
+    pub fn standard() -> Self {
+        let mut registry = Self::new();
+        for id in DID_BOOT_SOFTWARE_IDENTIFICATION..=DID_ECU_SERIAL_NUMBER {
+            let data = if id == DID_ACTIVE_DIAGNOSTIC_SESSION {
+                DidData::ActiveSession
+            } else {
+                DidData::Stored { max_len: DID_STANDARD_MAX_LEN }
+            };
+            registry.register(Did { id, data, writable: false });
+        }
+
+        // The tester writes the fingerprint before it downloads
+        registry.register(Did {
+            id: DID_PROGRAMMING_FINGERPRINT,
+            data: DidData::Stored { max_len: DID_STANDARD_MAX_LEN },
+            writable: true,
+        });
+        registry
+    }
+
+    /// Adds an identifier, replacing an earlier entry with the same id.
+    /// Returns false when the registry is full.
+    pub fn register(&mut self, did: Did) -> bool {
+        let slot = self
+            .entries
+            .iter()
+            .position(|entry| matches!(entry, Some(entry) if entry.id == did.id))
+            .or_else(|| self.entries.iter().position(|entry| entry.is_none()));
+
+        match slot {
+            Some(slot) => {
+                self.entries[slot] = Some(did);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gives an identifier a fixed, read-only value.
+    pub fn register_static(&mut self, id: u16, value: &'static [u8]) -> bool {
+        self.register(Did { id, data: DidData::Static(value), writable: false })
+    }
+
+    pub fn find(&self, id: u16) -> Option<&Did> {
+        self.entries.iter().flatten().find(|did| did.id == id)
+    }
+}
+
+impl Default for DidRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_registry_only_lets_the_programming_fingerprint_be_written() {
+        let registry = DidRegistry::standard();
+        let writable = registry.find(DID_PROGRAMMING_FINGERPRINT).unwrap();
+        assert_eq!(writable.data, DidData::Stored { max_len: DID_STANDARD_MAX_LEN });
+        assert!(writable.writable);
+
+        for id in DID_BOOT_SOFTWARE_IDENTIFICATION..=DID_ECU_SERIAL_NUMBER {
+            assert!(!registry.find(id).unwrap().writable, "{id:#06X} is writable");
+        }
+        assert_eq!(registry.find(DID_ACTIVE_DIAGNOSTIC_SESSION).unwrap().data, DidData::ActiveSession);
+        assert_eq!(registry.find(0xF17F), None);
+    }
+
+    #[test]
+    fn register_replaces_entries_until_the_registry_is_full() {
+        let mut registry = DidRegistry::standard();
+        assert!(registry.register_static(DID_ECU_SERIAL_NUMBER, b"SN42"));
+        let did = registry.find(DID_ECU_SERIAL_NUMBER).unwrap();
+        assert_eq!(did.data, DidData::Static(b"SN42"));
+        assert!(!did.writable);
+
+        let mut registry = DidRegistry::new();
+        for id in 0..DID_REGISTRY_SIZE as u16 {
+            assert!(registry.register_static(id, &[]));
+        }
+        assert!(!registry.register_static(0x1000, &[]));
+        assert!(registry.register_static(0, b"replaced"));
+        assert_eq!(registry.find(0).unwrap().data, DidData::Static(b"replaced"));
+    }
+}
@@ -1,4 +1,7 @@
 #![no_std]
 
 pub mod can;
+pub mod did;
+pub mod security;
+pub mod uds;
 pub mod xcp; 
@@ -0,0 +1,26 @@
+/// Seed/key algorithm used to unlock protected resources.
+///
+/// The protocol asks for a seed, hands it to the tester and later checks
+/// the key the tester computed from it. `level` identifies what is being
+/// unlocked: the XCP resource bit or the UDS security access level.
+pub trait SeedKeyAlgorithm {
+    /// Writes a fresh seed for `level` into `seed` and returns its length.
+    fn generate_seed(&mut self, level: u8, seed: &mut [u8]) -> usize;
+
+    /// Returns true when `key` is the correct answer to `seed`.
+    fn verify_key(&mut self, level: u8, seed: &[u8], key: &[u8]) -> bool;
+}
+
+/// Algorithm for targets that do not protect anything. It hands out empty
+/// seeds and accepts any key.
+pub struct Unprotected;
+
+impl SeedKeyAlgorithm for Unprotected {
+    fn generate_seed(&mut self, _level: u8, _seed: &mut [u8]) -> usize {
+        0
+    }
+
+    fn verify_key(&mut self, _level: u8, _seed: &[u8], _key: &[u8]) -> bool {
+        true
+    }
+}
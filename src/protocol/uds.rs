@@ -0,0 +1,1087 @@
+use super::did::{DidData, DidRegistry};
+use super::security::{SeedKeyAlgorithm, Unprotected};
+use crate::{DataRecord, MemoryManagementError, MemoryManager, S32KHal};
+use openblt::hal::Timer;
+use openblt::protocol::{ProtocolError, Transport};
+
+// Service identifiers
+pub const UDS_SID_DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+pub const UDS_SID_ECU_RESET: u8 = 0x11;
+pub const UDS_SID_READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+pub const UDS_SID_SECURITY_ACCESS: u8 = 0x27;
+pub const UDS_SID_WRITE_DATA_BY_IDENTIFIER: u8 = 0x2E;
+pub const UDS_SID_ROUTINE_CONTROL: u8 = 0x31;
+pub const UDS_SID_REQUEST_DOWNLOAD: u8 = 0x34;
+pub const UDS_SID_TRANSFER_DATA: u8 = 0x36;
+pub const UDS_SID_REQUEST_TRANSFER_EXIT: u8 = 0x37;
+pub const UDS_SID_TESTER_PRESENT: u8 = 0x3E;
+
+// Positive responses echo the SID with this bit set
+const UDS_POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const UDS_NEGATIVE_RESPONSE: u8 = 0x7F;
+
+// Set in the sub-function byte when no positive response is wanted
+const UDS_SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+// Negative response codes
+pub const UDS_NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+pub const UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+pub const UDS_NRC_INCORRECT_MESSAGE_LENGTH: u8 = 0x13;
+pub const UDS_NRC_RESPONSE_TOO_LONG: u8 = 0x14;
+pub const UDS_NRC_CONDITIONS_NOT_CORRECT: u8 = 0x22;
+pub const UDS_NRC_REQUEST_SEQUENCE_ERROR: u8 = 0x24;
+pub const UDS_NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+pub const UDS_NRC_SECURITY_ACCESS_DENIED: u8 = 0x33;
+pub const UDS_NRC_INVALID_KEY: u8 = 0x35;
+pub const UDS_NRC_EXCEEDED_NUMBER_OF_ATTEMPTS: u8 = 0x36;
+pub const UDS_NRC_REQUIRED_TIME_DELAY_NOT_EXPIRED: u8 = 0x37;
+pub const UDS_NRC_UPLOAD_DOWNLOAD_NOT_ACCEPTED: u8 = 0x70;
+pub const UDS_NRC_TRANSFER_DATA_SUSPENDED: u8 = 0x71;
+pub const UDS_NRC_GENERAL_PROGRAMMING_FAILURE: u8 = 0x72;
+pub const UDS_NRC_WRONG_BLOCK_SEQUENCE_COUNTER: u8 = 0x73;
+pub const UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED_IN_ACTIVE_SESSION: u8 = 0x7E;
+pub const UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION: u8 = 0x7F;
+
+// ECUReset sub-functions
+const UDS_RESET_HARD: u8 = 0x01;
+const UDS_RESET_SOFT: u8 = 0x03;
+
+// RoutineControl sub-functions
+const UDS_ROUTINE_START: u8 = 0x01;
+
+// Routine identifiers
+pub const UDS_ROUTINE_ERASE_MEMORY: u16 = 0xFF00;
+pub const UDS_ROUTINE_CHECK_PROGRAMMING_DEPENDENCIES: u16 = 0xFF01;
+
+// Routine status reported by the bootloader's routines
+const UDS_ROUTINE_STATUS_CORRECT: u8 = 0x00;
+const UDS_ROUTINE_STATUS_INCORRECT: u8 = 0x01;
+
+// TesterPresent sub-function
+const UDS_TESTER_PRESENT_ZERO: u8 = 0x00;
+
+/// Largest request accepted, sized for a full TransferData block
+pub const UDS_MAX_REQUEST_LEN: usize = 2 + UDS_TRANSFER_BLOCK_LEN;
+/// Largest response produced
+pub const UDS_MAX_RESPONSE_LEN: usize = 64;
+
+// Data bytes per TransferData. Whole flash words keep every block aligned.
+const UDS_TRANSFER_BLOCK_LEN: usize = 1024;
+
+/// Longest seed handed out by SecurityAccess
+pub const UDS_SEED_MAX_LEN: usize = 32;
+// Seed reported for a level that is already unlocked
+const UDS_UNLOCKED_SEED_LEN: usize = 4;
+
+// Session timing reported by DiagnosticSessionControl
+const UDS_P2_SERVER_MAX_MS: u16 = 50;
+const UDS_P2_STAR_SERVER_MAX_MS: u16 = 5000;
+
+// Time a non-default session survives without requests
+const UDS_S3_SERVER_MS: u32 = 5000;
+
+// Default number of wrong keys tolerated before the delay kicks in
+const UDS_DEFAULT_MAX_ATTEMPTS: u8 = 3;
+// Default time seeds are refused for after too many wrong keys
+const UDS_DEFAULT_LOCKOUT_MS: u32 = 10_000;
+// Default security level protecting the programming services
+const UDS_DEFAULT_SECURITY_LEVEL: u8 = 0x01;
+
+// RAM the application's initial stack pointer must point into
+const RAM_START: u32 = 0x1FFE_0000;
+const RAM_END: u32 = 0x2002_0000;
+
+/// Diagnostic sessions the server can be in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Session {
+    Default = 0x01,
+    Programming = 0x02,
+    Extended = 0x03,
+}
+
+// Download set up by RequestDownload
+#[derive(Debug, Clone, Copy)]
+struct Download {
+    address: u32,
+    remaining: u32,
+    sequence: u8,
+    // Set once a block was written, so there is a block to repeat
+    started: bool,
+}
+
+pub struct UdsServer<S: SeedKeyAlgorithm = Unprotected> {
+    session: Session,
+    security_level: u8,
+    protected: bool,
+    unlocked: bool,
+    seed_key: S,
+    seed: [u8; UDS_SEED_MAX_LEN],
+    seed_len: usize,
+    failed_attempts: u8,
+    max_attempts: u8,
+    lockout_ms: u32,
+    locked_until: Option<u32>,
+    download: Option<Download>,
+    dids: DidRegistry,
+    reset_pending: bool,
+    last_request_ms: u32,
+    now_ms: u32,
+}
+
+impl UdsServer<Unprotected> {
+    /// Creates a server whose programming services need no SecurityAccess.
+    pub fn new() -> Self {
+        Self::init(Unprotected, false)
+    }
+}
+
+impl Default for UdsServer<Unprotected> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: SeedKeyAlgorithm> UdsServer<S> {
+    /// Creates a server that keeps the programming services locked until
+    /// the tester passes SecurityAccess with a key accepted by `seed_key`.
+    pub fn with_seed_key(seed_key: S) -> Self {
+        Self::init(seed_key, true)
+    }
+
+    fn init(seed_key: S, protected: bool) -> Self {
+        UdsServer {
+            session: Session::Default,
+            security_level: UDS_DEFAULT_SECURITY_LEVEL,
+            protected,
+            unlocked: !protected,
+            seed_key,
+            seed: [0; UDS_SEED_MAX_LEN],
+            seed_len: 0,
+            failed_attempts: 0,
+            max_attempts: UDS_DEFAULT_MAX_ATTEMPTS,
+            lockout_ms: UDS_DEFAULT_LOCKOUT_MS,
+            locked_until: None,
+            download: None,
+            dids: DidRegistry::standard(),
+            reset_pending: false,
+            last_request_ms: 0,
+            now_ms: 0,
+        }
+    }
+
+    /// Selects the odd SecurityAccess level (requestSeed sub-function)
+    /// that unlocks programming.
+    pub fn set_security_level(&mut self, level: u8) {
+        self.security_level = level | 0x01;
+    }
+
+    /// Configures how many wrong keys are tolerated before seeds are
+    /// refused for `lockout_ms`.
+    pub fn set_lockout(&mut self, max_attempts: u8, lockout_ms: u32) {
+        self.max_attempts = max_attempts;
+        self.lockout_ms = lockout_ms;
+    }
+
+    /// Advances the server's notion of time and falls back to the default
+    /// session once S3 expires. Call this regularly from the main loop with
+    /// a millisecond timestamp.
+    pub fn poll(&mut self, now_ms: u32) {
+        self.now_ms = now_ms;
+        if let Some(until) = self.locked_until {
+            if self.now_ms.wrapping_sub(until) as i32 >= 0 {
+                self.locked_until = None;
+                self.failed_attempts = 0;
+            }
+        }
+
+        if self.session != Session::Default
+            && self.now_ms.wrapping_sub(self.last_request_ms) >= UDS_S3_SERVER_MS
+        {
+            self.enter_session(Session::Default);
+        }
+    }
+
+    /// Identifiers served by ReadDataByIdentifier/WriteDataByIdentifier
+    pub fn get_dids_mut(&mut self) -> &mut DidRegistry {
+        &mut self.dids
+    }
+
+    pub fn get_session(&self) -> Session {
+        self.session
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    /// Returns true once a positive ECUReset response was produced. The
+    /// reset must only be carried out after that response went out.
+    pub fn is_reset_pending(&self) -> bool {
+        self.reset_pending
+    }
+
+    /// Processes a single request and writes the response into `response`.
+    /// Returns the response length, or None when no response is to be sent.
+    /// Programming services are carried out on `memory`, and stored data
+    /// identifiers live in `record`.
+    pub fn process_request<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8; UDS_MAX_RESPONSE_LEN],
+        memory: &mut MemoryManager<H>,
+        record: &mut DataRecord<H>,
+    ) -> Option<usize> {
+        let sid = *request.first()?;
+        self.last_request_ms = self.now_ms;
+
+        let result = match sid {
+            UDS_SID_DIAGNOSTIC_SESSION_CONTROL => self.diagnostic_session_control(request, response),
+            UDS_SID_ECU_RESET => self.ecu_reset(request, response),
+            UDS_SID_READ_DATA_BY_IDENTIFIER => self.read_data_by_identifier(request, response, record),
+            UDS_SID_SECURITY_ACCESS => self.security_access(request, response),
+            UDS_SID_WRITE_DATA_BY_IDENTIFIER => self.write_data_by_identifier(request, response, record),
+            UDS_SID_ROUTINE_CONTROL => self.routine_control(request, response, memory),
+            UDS_SID_REQUEST_DOWNLOAD => self.request_download(request, response, memory),
+            UDS_SID_TRANSFER_DATA => self.transfer_data(request, response, memory),
+            UDS_SID_REQUEST_TRANSFER_EXIT => self.request_transfer_exit(request, response, memory),
+            UDS_SID_TESTER_PRESENT => self.tester_present(request, response),
+            _ => Err(UDS_NRC_SERVICE_NOT_SUPPORTED),
+        };
+
+        match result {
+            Ok(len) => {
+                // Sub-function services may ask for the positive response to be left out
+                let has_sub_function = matches!(
+                    sid,
+                    UDS_SID_DIAGNOSTIC_SESSION_CONTROL
+                        | UDS_SID_ECU_RESET
+                        | UDS_SID_SECURITY_ACCESS
+                        | UDS_SID_ROUTINE_CONTROL
+                        | UDS_SID_TESTER_PRESENT
+                );
+                if has_sub_function && request[1] & UDS_SUPPRESS_POSITIVE_RESPONSE != 0 {
+                    return None;
+                }
+                response[0] = sid + UDS_POSITIVE_RESPONSE_OFFSET;
+                Some(len)
+            }
+            Err(nrc) => {
+                response[..3].copy_from_slice(&[UDS_NEGATIVE_RESPONSE, sid, nrc]);
+                Some(3)
+            }
+        }
+    }
+
+    /// Waits for one request on `transport`, processes it and sends the
+    /// response. The session and lockout timers run on the HAL's clock, so
+    /// S3 expires even while no request arrives. A requested ECU reset
+    /// starts the application afterwards.
+    /// A bus-off keeps the session, so the tester can repeat the request
+    /// once the controller is back on the bus.
+    pub fn serve<T: Transport, H: S32KHal>(
+        &mut self,
+        transport: &mut T,
+        timeout_ms: u32,
+        memory: &mut MemoryManager<H>,
+        record: &mut DataRecord<H>,
+    ) -> Result<(), ProtocolError> {
+        let mut request = [0u8; UDS_MAX_REQUEST_LEN];
+        let mut response = [0u8; UDS_MAX_RESPONSE_LEN];
+
+        let received = transport.receive(&mut request, timeout_ms);
+        self.poll(memory.get_hal().get_timer().now_ms());
+        let len = received?;
+        if let Some(len) = self.process_request(&request[..len], &mut response, memory, record) {
+            transport.send(&response[..len])?;
+        }
+
+        if self.reset_pending {
+            self.reset(memory);
+        }
+        Ok(())
+    }
+
+    fn diagnostic_session_control(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, u8> {
+        if request.len() != 2 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        let session = match request[1] & !UDS_SUPPRESS_POSITIVE_RESPONSE {
+            0x01 => Session::Default,
+            0x02 => Session::Programming,
+            0x03 => Session::Extended,
+            _ => return Err(UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED),
+        };
+        self.enter_session(session);
+
+        // Session type, P2server_max in ms, P2*server_max in 10 ms
+        let p2 = UDS_P2_SERVER_MAX_MS.to_be_bytes();
+        let p2_star = (UDS_P2_STAR_SERVER_MAX_MS / 10).to_be_bytes();
+        response[1..6].copy_from_slice(&[session as u8, p2[0], p2[1], p2_star[0], p2_star[1]]);
+        Ok(6)
+    }
+
+    fn ecu_reset(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        if request.len() != 2 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        let reset_type = request[1] & !UDS_SUPPRESS_POSITIVE_RESPONSE;
+        if reset_type != UDS_RESET_HARD && reset_type != UDS_RESET_SOFT {
+            return Err(UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED);
+        }
+
+        self.reset_pending = true;
+        response[1] = reset_type;
+        Ok(2)
+    }
+
+    fn read_data_by_identifier<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        record: &DataRecord<H>,
+    ) -> Result<usize, u8> {
+        if request.len() < 3 || request.len() % 2 != 1 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        // Identifiers without a value are left out of the response
+        let mut len = 1;
+        for id in request[1..].chunks(2) {
+            let Some(did) = self.dids.find(u16::from_be_bytes([id[0], id[1]])) else {
+                continue;
+            };
+            if len + 2 > response.len() {
+                return Err(UDS_NRC_RESPONSE_TOO_LONG);
+            }
+
+            let value = &mut response[len + 2..];
+            let value_len = match did.data {
+                DidData::Static(data) => {
+                    if data.len() > value.len() {
+                        return Err(UDS_NRC_RESPONSE_TOO_LONG);
+                    }
+                    value[..data.len()].copy_from_slice(data);
+                    data.len()
+                }
+                DidData::Stored { max_len } => {
+                    if max_len > value.len() {
+                        return Err(UDS_NRC_RESPONSE_TOO_LONG);
+                    }
+                    match record.read(did.id, &mut value[..max_len]) {
+                        Some(value_len) => value_len,
+                        None => continue,
+                    }
+                }
+                DidData::ActiveSession => {
+                    *value.first_mut().ok_or(UDS_NRC_RESPONSE_TOO_LONG)? = self.session as u8;
+                    1
+                }
+            };
+
+            response[len..len + 2].copy_from_slice(id);
+            len += 2 + value_len;
+        }
+
+        if len == 1 {
+            return Err(UDS_NRC_REQUEST_OUT_OF_RANGE);
+        }
+        Ok(len)
+    }
+
+    fn write_data_by_identifier<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        record: &mut DataRecord<H>,
+    ) -> Result<usize, u8> {
+        if request.len() < 4 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        let id = u16::from_be_bytes([request[1], request[2]]);
+        let did = self.dids.find(id).ok_or(UDS_NRC_REQUEST_OUT_OF_RANGE)?;
+        let max_len = match did.data {
+            DidData::Stored { max_len } if did.writable => max_len,
+            _ => return Err(UDS_NRC_REQUEST_OUT_OF_RANGE),
+        };
+        if request.len() - 3 > max_len {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+        if !self.unlocked {
+            return Err(UDS_NRC_SECURITY_ACCESS_DENIED);
+        }
+
+        record
+            .write(id, &request[3..])
+            .map_err(|_| UDS_NRC_GENERAL_PROGRAMMING_FAILURE)?;
+
+        response[1..3].copy_from_slice(&request[1..3]);
+        Ok(3)
+    }
+
+    fn security_access(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        if self.session == Session::Default {
+            return Err(UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+        }
+        if request.len() < 2 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        let sub_function = request[1] & !UDS_SUPPRESS_POSITIVE_RESPONSE;
+        if sub_function == self.security_level {
+            self.request_seed(request, response)
+        } else if sub_function == self.security_level + 1 {
+            self.send_key(request, response)
+        } else {
+            Err(UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED)
+        }
+    }
+
+    fn request_seed(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        if request.len() != 2 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+        if self.locked_until.is_some() {
+            return Err(UDS_NRC_REQUIRED_TIME_DELAY_NOT_EXPIRED);
+        }
+
+        response[1] = self.security_level;
+
+        // An unlocked level is reported with an all zero seed
+        if self.unlocked {
+            self.seed_len = 0;
+            response[2..2 + UDS_UNLOCKED_SEED_LEN].fill(0);
+            return Ok(2 + UDS_UNLOCKED_SEED_LEN);
+        }
+
+        let len = self.seed_key.generate_seed(self.security_level, &mut self.seed);
+        self.seed_len = len.min(UDS_SEED_MAX_LEN);
+        response[2..2 + self.seed_len].copy_from_slice(&self.seed[..self.seed_len]);
+        Ok(2 + self.seed_len)
+    }
+
+    fn send_key(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        // A seed must have been handed out first
+        if self.seed_len == 0 {
+            return Err(UDS_NRC_REQUEST_SEQUENCE_ERROR);
+        }
+        if request.len() < 3 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        let seed = &self.seed[..self.seed_len];
+        let accepted = self.seed_key.verify_key(self.security_level, seed, &request[2..]);
+        self.seed_len = 0;
+
+        if !accepted {
+            self.failed_attempts = self.failed_attempts.saturating_add(1);
+            if self.failed_attempts >= self.max_attempts {
+                self.locked_until = Some(self.now_ms.wrapping_add(self.lockout_ms));
+                return Err(UDS_NRC_EXCEEDED_NUMBER_OF_ATTEMPTS);
+            }
+            return Err(UDS_NRC_INVALID_KEY);
+        }
+
+        self.failed_attempts = 0;
+        self.unlocked = true;
+        response[1] = self.security_level + 1;
+        Ok(2)
+    }
+
+    fn routine_control<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Result<usize, u8> {
+        if self.session == Session::Default {
+            return Err(UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+        }
+        if request.len() < 4 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        // The bootloader's routines run to completion when started
+        let sub_function = request[1] & !UDS_SUPPRESS_POSITIVE_RESPONSE;
+        if sub_function != UDS_ROUTINE_START {
+            return Err(UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED);
+        }
+
+        let routine = u16::from_be_bytes([request[2], request[3]]);
+        let status = match routine {
+            UDS_ROUTINE_ERASE_MEMORY => self.erase_memory(&request[4..], memory)?,
+            UDS_ROUTINE_CHECK_PROGRAMMING_DEPENDENCIES => {
+                if request.len() != 4 {
+                    return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+                }
+                check_programming_dependencies(memory)
+            }
+            _ => return Err(UDS_NRC_REQUEST_OUT_OF_RANGE),
+        };
+
+        // Routine control type and identifier, followed by the routine status
+        response[1..5].copy_from_slice(&[sub_function, request[2], request[3], status]);
+        Ok(5)
+    }
+
+    // Option record: addressAndLengthFormatIdentifier, memory address, memory size
+    fn erase_memory<H: S32KHal>(
+        &mut self,
+        record: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Result<u8, u8> {
+        if self.session != Session::Programming {
+            return Err(UDS_NRC_CONDITIONS_NOT_CORRECT);
+        }
+        if !self.unlocked {
+            return Err(UDS_NRC_SECURITY_ACCESS_DENIED);
+        }
+
+        let (address, size) = parse_address_and_length(record)?;
+        match memory.erase(address, size) {
+            Ok(()) => Ok(UDS_ROUTINE_STATUS_CORRECT),
+            Err(e) => Err(memory_error(e)),
+        }
+    }
+
+    fn request_download<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        memory: &MemoryManager<H>,
+    ) -> Result<usize, u8> {
+        if self.session != Session::Programming {
+            return Err(UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+        }
+        if request.len() < 3 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+        if !self.unlocked {
+            return Err(UDS_NRC_SECURITY_ACCESS_DENIED);
+        }
+        if self.download.is_some() {
+            return Err(UDS_NRC_CONDITIONS_NOT_CORRECT);
+        }
+
+        // Neither compression nor encryption is supported
+        if request[1] != 0x00 {
+            return Err(UDS_NRC_REQUEST_OUT_OF_RANGE);
+        }
+
+        let (address, size) = parse_address_and_length(&request[2..])?;
+        let in_range = address >= memory.get_app_start()
+            && size > 0
+            && address.checked_add(size).is_some_and(|end| end <= memory.get_app_end());
+        if !in_range || address % 4 != 0 {
+            return Err(UDS_NRC_REQUEST_OUT_OF_RANGE);
+        }
+
+        self.download = Some(Download { address, remaining: size, sequence: 1, started: false });
+
+        // lengthFormatIdentifier and maxNumberOfBlockLength, which counts the
+        // SID and block sequence counter too
+        let max_block_len = (UDS_TRANSFER_BLOCK_LEN as u16 + 2).to_be_bytes();
+        response[1..4].copy_from_slice(&[0x20, max_block_len[0], max_block_len[1]]);
+        Ok(4)
+    }
+
+    fn transfer_data<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Result<usize, u8> {
+        if self.session != Session::Programming {
+            return Err(UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+        }
+        if request.len() < 3 || request.len() > UDS_MAX_REQUEST_LEN {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+        let mut download = self.download.ok_or(UDS_NRC_REQUEST_SEQUENCE_ERROR)?;
+
+        let sequence = request[1];
+        response[1] = sequence;
+
+        // A repeated block was already written, so it is only acknowledged
+        if download.started && sequence == download.sequence.wrapping_sub(1) {
+            return Ok(2);
+        }
+        if sequence != download.sequence {
+            return Err(UDS_NRC_WRONG_BLOCK_SEQUENCE_COUNTER);
+        }
+
+        let data = &request[2..];
+        if data.len() as u32 > download.remaining {
+            return Err(UDS_NRC_TRANSFER_DATA_SUSPENDED);
+        }
+
+        memory.write(download.address, data).map_err(memory_error)?;
+
+        download.address += data.len() as u32;
+        download.remaining -= data.len() as u32;
+        download.sequence = download.sequence.wrapping_add(1);
+        download.started = true;
+        self.download = Some(download);
+        Ok(2)
+    }
+
+    fn request_transfer_exit<H: S32KHal>(
+        &mut self,
+        request: &[u8],
+        _response: &mut [u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Result<usize, u8> {
+        if self.session != Session::Programming {
+            return Err(UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+        }
+        if request.len() != 1 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+
+        let download = self.download.ok_or(UDS_NRC_REQUEST_SEQUENCE_ERROR)?;
+        if download.remaining != 0 {
+            return Err(UDS_NRC_REQUEST_SEQUENCE_ERROR);
+        }
+
+        // The end of the download is programmed before it is confirmed
+        memory.flush().map_err(memory_error)?;
+
+        self.download = None;
+        Ok(1)
+    }
+
+    fn tester_present(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, u8> {
+        if request.len() != 2 {
+            return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+        }
+        if request[1] & !UDS_SUPPRESS_POSITIVE_RESPONSE != UDS_TESTER_PRESENT_ZERO {
+            return Err(UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED);
+        }
+
+        response[1] = UDS_TESTER_PRESENT_ZERO;
+        Ok(2)
+    }
+
+    // Every session change relocks security and aborts a download
+    fn enter_session(&mut self, session: Session) {
+        self.session = session;
+        self.download = None;
+        self.seed_len = 0;
+        self.unlocked = !self.protected;
+    }
+
+    fn reset<H: S32KHal>(&mut self, memory: &mut MemoryManager<H>) {
+        self.reset_pending = false;
+        self.enter_session(Session::Default);
+
+        // An aborted download may have left data in the write block
+        let _ = memory.flush();
+
+        // The reset vector is the second word of the application's vector table
+        let mut entry_point = [0u8; 4];
+        if memory.read(memory.get_app_start() + 4, &mut entry_point).is_ok() {
+            let _ = memory
+                .get_hal()
+                .jump_to_application(u32::from_le_bytes(entry_point));
+        }
+    }
+}
+
+// Parses addressAndLengthFormatIdentifier followed by the memory address
+// and memory size it describes, both big endian.
+fn parse_address_and_length(record: &[u8]) -> Result<(u32, u32), u8> {
+    let format = *record.first().ok_or(UDS_NRC_INCORRECT_MESSAGE_LENGTH)?;
+    let size_len = (format >> 4) as usize;
+    let address_len = (format & 0x0F) as usize;
+    if !(1..=4).contains(&size_len) || !(1..=4).contains(&address_len) {
+        return Err(UDS_NRC_REQUEST_OUT_OF_RANGE);
+    }
+    if record.len() != 1 + address_len + size_len {
+        return Err(UDS_NRC_INCORRECT_MESSAGE_LENGTH);
+    }
+
+    let be = |bytes: &[u8]| bytes.iter().fold(0u32, |value, &b| (value << 8) | b as u32);
+    let address = be(&record[1..1 + address_len]);
+    let size = be(&record[1 + address_len..]);
+    Ok((address, size))
+}
+
+// The application's vector table must hold a stack pointer into RAM and a
+// Thumb reset handler inside the application area.
+fn check_programming_dependencies<H: S32KHal>(memory: &MemoryManager<H>) -> u8 {
+    let mut vectors = [0u8; 8];
+    if memory.read(memory.get_app_start(), &mut vectors).is_err() {
+        return UDS_ROUTINE_STATUS_INCORRECT;
+    }
+
+    let stack_pointer = u32::from_le_bytes([vectors[0], vectors[1], vectors[2], vectors[3]]);
+    let reset_handler = u32::from_le_bytes([vectors[4], vectors[5], vectors[6], vectors[7]]);
+    let stack_valid = stack_pointer > RAM_START && stack_pointer <= RAM_END;
+    let handler_valid = reset_handler & 0x1 != 0
+        && reset_handler > memory.get_app_start()
+        && reset_handler < memory.get_app_end();
+
+    if stack_valid && handler_valid {
+        UDS_ROUTINE_STATUS_CORRECT
+    } else {
+        UDS_ROUTINE_STATUS_INCORRECT
+    }
+}
+
+fn memory_error(error: MemoryManagementError) -> u8 {
+    match error {
+        MemoryManagementError::InvalidAddress
+        | MemoryManagementError::InvalidLength
+        | MemoryManagementError::AlignmentError
+        | MemoryManagementError::OutOfBounds => UDS_NRC_REQUEST_OUT_OF_RANGE,
+        MemoryManagementError::WriteError
+        | MemoryManagementError::EraseError
+        | MemoryManagementError::ReadError
+        | MemoryManagementError::AlreadyProgrammed => UDS_NRC_GENERAL_PROGRAMMING_FAILURE,
+        MemoryManagementError::UnsafeConfiguration => UDS_NRC_CONDITIONS_NOT_CORRECT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::did::{
+        DID_ACTIVE_DIAGNOSTIC_SESSION, DID_APPLICATION_DATA_FINGERPRINT,
+        DID_APPLICATION_SOFTWARE_FINGERPRINT, DID_BOOT_SOFTWARE_FINGERPRINT, DID_ECU_SERIAL_NUMBER,
+        DID_PROGRAMMING_FINGERPRINT,
+    };
+    use openblt::hal::loopback::LoopbackBus;
+    use openblt::hal::sim::{SimDevice, SimFlash, SimHal};
+    use openblt::protocol::transport::MemoryTransport;
+    use s32k148_hal::flash::{S32K148_FLEXNVM, S32K148_PFLASH};
+    use s32k148_hal::CanFrame;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    type Hal = SimHal<'static, CanFrame, 4>;
+
+    // Accepts the seed with every bit inverted
+    struct InvertedSeed;
+
+    impl SeedKeyAlgorithm for InvertedSeed {
+        fn generate_seed(&mut self, _level: u8, seed: &mut [u8]) -> usize {
+            seed[..4].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+            4
+        }
+
+        fn verify_key(&mut self, _level: u8, seed: &[u8], key: &[u8]) -> bool {
+            seed.len() == key.len() && seed.iter().zip(key).all(|(&s, &k)| s == !k)
+        }
+    }
+
+    // Memory manager and data record on erased flash. The device is leaked,
+    // so every test gets its own.
+    fn setup() -> (&'static SimDevice<'static>, MemoryManager<Hal>, DataRecord<Hal>) {
+        let program = vec![0u8; S32K148_PFLASH.size as usize].leak();
+        let data = vec![0u8; S32K148_FLEXNVM.size as usize].leak();
+        let blocks = Box::leak(Box::new([
+            SimFlash::from_region(&S32K148_PFLASH, program),
+            SimFlash::from_region(&S32K148_FLEXNVM, data),
+        ]));
+        let device = Box::leak(Box::new(SimDevice::new(blocks)));
+        let bus = Box::leak(Box::new(LoopbackBus::<CanFrame, 4>::new()));
+        let (can, _) = bus.split();
+        let hal = SimHal::new(device, can);
+        let mut record = DataRecord::new(hal.clone());
+        record.load().unwrap();
+        (device, MemoryManager::new(hal).unwrap(), record)
+    }
+
+    fn request<S: SeedKeyAlgorithm>(
+        uds: &mut UdsServer<S>,
+        memory: &mut MemoryManager<Hal>,
+        record: &mut DataRecord<Hal>,
+        request: &[u8],
+    ) -> Vec<u8> {
+        let mut response = [0u8; UDS_MAX_RESPONSE_LEN];
+        let len = uds
+            .process_request(request, &mut response, memory, record)
+            .expect("no response");
+        response[..len].to_vec()
+    }
+
+    fn negative(sid: u8, nrc: u8) -> Vec<u8> {
+        vec![UDS_NEGATIVE_RESPONSE, sid, nrc]
+    }
+
+    // RequestDownload with 4 byte address and size
+    fn request_download(address: u32, size: u32) -> Vec<u8> {
+        let mut request = vec![UDS_SID_REQUEST_DOWNLOAD, 0x00, 0x44];
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&size.to_be_bytes());
+        request
+    }
+
+    #[test]
+    fn session_control_switches_sessions() {
+        let (_, mut memory, mut record) = setup();
+        let mut uds = UdsServer::new();
+        let dsc = UDS_SID_DIAGNOSTIC_SESSION_CONTROL;
+
+        // P2 of 50 ms and P2* of 5000 ms in units of 10 ms
+        let response = request(&mut uds, &mut memory, &mut record, &[dsc, 0x02]);
+        assert_eq!(response, [0x50, 0x02, 0x00, 0x32, 0x01, 0xF4]);
+        assert_eq!(uds.get_session(), Session::Programming);
+
+        let mut response = [0u8; UDS_MAX_RESPONSE_LEN];
+        let suppressed = uds.process_request(&[dsc, 0x83], &mut response, &mut memory, &mut record);
+        assert_eq!(suppressed, None);
+        assert_eq!(uds.get_session(), Session::Extended);
+
+        let response = request(&mut uds, &mut memory, &mut record, &[dsc, 0x04]);
+        assert_eq!(response, negative(dsc, UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED));
+        let response = request(&mut uds, &mut memory, &mut record, &[dsc, 0x01, 0x00]);
+        assert_eq!(response, negative(dsc, UDS_NRC_INCORRECT_MESSAGE_LENGTH));
+        assert_eq!(uds.get_session(), Session::Extended);
+
+        let response = request(&mut uds, &mut memory, &mut record, &[0x85, 0x01]);
+        assert_eq!(response, negative(0x85, UDS_NRC_SERVICE_NOT_SUPPORTED));
+    }
+
+    #[test]
+    fn serve_runs_the_session_timeout_on_the_hal_timer() {
+        let (device, mut memory, mut record) = setup();
+        let mut uds = UdsServer::new();
+        let mut transport = MemoryTransport::<4>::new(64);
+        let mut response = [0u8; UDS_MAX_RESPONSE_LEN];
+        let read_session = [UDS_SID_READ_DATA_BY_IDENTIFIER, 0xF1, 0x86];
+
+        transport.inject(&[UDS_SID_DIAGNOSTIC_SESSION_CONTROL, 0x02]).unwrap();
+        uds.serve(&mut transport, 0, &mut memory, &mut record).unwrap();
+        assert_eq!(transport.collect(&mut response), Some(6));
+
+        // Every request restarts S3
+        device.advance_ms(4999);
+        transport.inject(&read_session).unwrap();
+        uds.serve(&mut transport, 0, &mut memory, &mut record).unwrap();
+        let len = transport.collect(&mut response).unwrap();
+        assert_eq!(&response[..len], &[0x62, 0xF1, 0x86, 0x02]);
+
+        // S3 also runs out while no request arrives
+        device.advance_ms(5000);
+        assert!(uds.serve(&mut transport, 0, &mut memory, &mut record).is_err());
+        assert_eq!(uds.get_session(), Session::Default);
+    }
+
+    #[test]
+    fn ecu_reset_starts_the_application_after_the_response() {
+        let (device, mut memory, mut record) = setup();
+        let mut uds = UdsServer::new();
+        let mut transport = MemoryTransport::<4>::new(64);
+        let mut response = [0u8; UDS_MAX_RESPONSE_LEN];
+        let app_start = memory.get_app_start();
+        memory.write(app_start, &[0x00, 0x80, 0x00, 0x20, 0x01, 0x01, 0x04, 0x00]).unwrap();
+        memory.flush().unwrap();
+
+        transport.inject(&[UDS_SID_ECU_RESET, UDS_RESET_HARD]).unwrap();
+        uds.serve(&mut transport, 0, &mut memory, &mut record).unwrap();
+        let len = transport.collect(&mut response).unwrap();
+        assert_eq!(&response[..len], &[0x51, UDS_RESET_HARD]);
+        assert_eq!(device.get_entry_point(), Some(0x0004_0101));
+        assert!(!uds.is_reset_pending());
+    }
+
+    #[test]
+    fn security_access_unlocks_with_the_right_key() {
+        let (device, mut memory, mut record) = setup();
+        let mut uds = UdsServer::with_seed_key(InvertedSeed);
+        uds.set_lockout(2, 1000);
+        let sa = UDS_SID_SECURITY_ACCESS;
+
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x01]);
+        assert_eq!(response, negative(sa, UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION));
+        request(&mut uds, &mut memory, &mut record, &[UDS_SID_DIAGNOSTIC_SESSION_CONTROL, 0x02]);
+
+        // sendKey needs a seed first
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x02, 0xED, 0xCB, 0xA9, 0x87]);
+        assert_eq!(response, negative(sa, UDS_NRC_REQUEST_SEQUENCE_ERROR));
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x03]);
+        assert_eq!(response, negative(sa, UDS_NRC_SUB_FUNCTION_NOT_SUPPORTED));
+
+        // Wrong keys until the delay starts
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x01]);
+        assert_eq!(response, [0x67, 0x01, 0x12, 0x34, 0x56, 0x78]);
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x02, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(response, negative(sa, UDS_NRC_INVALID_KEY));
+        request(&mut uds, &mut memory, &mut record, &[sa, 0x01]);
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x02, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(response, negative(sa, UDS_NRC_EXCEEDED_NUMBER_OF_ATTEMPTS));
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x01]);
+        assert_eq!(response, negative(sa, UDS_NRC_REQUIRED_TIME_DELAY_NOT_EXPIRED));
+
+        device.advance_ms(1000);
+        uds.poll(memory.get_hal().get_timer().now_ms());
+        request(&mut uds, &mut memory, &mut record, &[sa, 0x01]);
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x02, 0xED, 0xCB, 0xA9, 0x87]);
+        assert_eq!(response, [0x67, 0x02]);
+        assert!(uds.is_unlocked());
+
+        // An unlocked level hands out a zero seed, and a session change locks it again
+        let response = request(&mut uds, &mut memory, &mut record, &[sa, 0x01]);
+        assert_eq!(response, [0x67, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        request(&mut uds, &mut memory, &mut record, &[UDS_SID_DIAGNOSTIC_SESSION_CONTROL, 0x02]);
+        assert!(!uds.is_unlocked());
+    }
+
+    #[test]
+    fn download_is_programmed_block_by_block() {
+        let (_, mut memory, mut record) = setup();
+        let mut uds = UdsServer::new();
+        let app_start = memory.get_app_start();
+        let image: [u8; 16] = core::array::from_fn(|i| i as u8);
+
+        request(&mut uds, &mut memory, &mut record, &[UDS_SID_DIAGNOSTIC_SESSION_CONTROL, 0x02]);
+        let response = request(&mut uds, &mut memory, &mut record, &request_download(app_start, 16));
+        assert_eq!(response, [0x74, 0x20, 0x04, 0x02]);
+
+        let mut block = vec![UDS_SID_TRANSFER_DATA, 0x01];
+        block.extend_from_slice(&image[..8]);
+        assert_eq!(request(&mut uds, &mut memory, &mut record, &block), [0x76, 0x01]);
+        // A repeated block is only acknowledged
+        assert_eq!(request(&mut uds, &mut memory, &mut record, &block), [0x76, 0x01]);
+
+        let mut block = vec![UDS_SID_TRANSFER_DATA, 0x02];
+        block.extend_from_slice(&image[8..]);
+        assert_eq!(request(&mut uds, &mut memory, &mut record, &block), [0x76, 0x02]);
+        let response = request(&mut uds, &mut memory, &mut record, &[UDS_SID_REQUEST_TRANSFER_EXIT]);
+        assert_eq!(response, [0x77]);
+
+        let mut data = [0u8; 16];
+        memory.read(app_start, &mut data).unwrap();
+        assert_eq!(data, image);
+    }
+
+    #[test]
+    fn download_errors_are_reported() {
+        let (_, mut memory, mut record) = setup();
+        let mut uds = UdsServer::with_seed_key(InvertedSeed);
+        let app_start = memory.get_app_start();
+        let (rd, td, rte) =
+            (UDS_SID_REQUEST_DOWNLOAD, UDS_SID_TRANSFER_DATA, UDS_SID_REQUEST_TRANSFER_EXIT);
+
+        let response = request(&mut uds, &mut memory, &mut record, &request_download(app_start, 8));
+        assert_eq!(response, negative(rd, UDS_NRC_SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION));
+        request(&mut uds, &mut memory, &mut record, &[UDS_SID_DIAGNOSTIC_SESSION_CONTROL, 0x02]);
+        let response = request(&mut uds, &mut memory, &mut record, &request_download(app_start, 8));
+        assert_eq!(response, negative(rd, UDS_NRC_SECURITY_ACCESS_DENIED));
+
+        request(&mut uds, &mut memory, &mut record, &[UDS_SID_SECURITY_ACCESS, 0x01]);
+        request(&mut uds, &mut memory, &mut record, &[UDS_SID_SECURITY_ACCESS, 0x02, 0xED, 0xCB, 0xA9, 0x87]);
+        let response = request(&mut uds, &mut memory, &mut record, &[td, 0x01, 0x00]);
+        assert_eq!(response, negative(td, UDS_NRC_REQUEST_SEQUENCE_ERROR));
+        let response = request(&mut uds, &mut memory, &mut record, &request_download(0x0000_1000, 8));
+        assert_eq!(response, negative(rd, UDS_NRC_REQUEST_OUT_OF_RANGE));
+        let response = request(&mut uds, &mut memory, &mut record, &request_download(app_start + 2, 8));
+        assert_eq!(response, negative(rd, UDS_NRC_REQUEST_OUT_OF_RANGE));
+
+        request(&mut uds, &mut memory, &mut record, &request_download(app_start, 8));
+        let response = request(&mut uds, &mut memory, &mut record, &request_download(app_start, 8));
+        assert_eq!(response, negative(rd, UDS_NRC_CONDITIONS_NOT_CORRECT));
+        let response = request(&mut uds, &mut memory, &mut record, &[td, 0x02, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(response, negative(td, UDS_NRC_WRONG_BLOCK_SEQUENCE_COUNTER));
+        let response = request(&mut uds, &mut memory, &mut record, &[td, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(response, negative(td, UDS_NRC_TRANSFER_DATA_SUSPENDED));
+        request(&mut uds, &mut memory, &mut record, &[td, 0x01, 0x11, 0x11, 0x11, 0x11]);
+        let response = request(&mut uds, &mut memory, &mut record, &[rte]);
+        assert_eq!(response, negative(rte, UDS_NRC_REQUEST_SEQUENCE_ERROR));
+        request(&mut uds, &mut memory, &mut record, &[td, 0x02, 0x22, 0x22, 0x22, 0x22]);
+        assert_eq!(request(&mut uds, &mut memory, &mut record, &[rte]), [0x77]);
+
+        // Different data for flash that was programmed already
+        request(&mut uds, &mut memory, &mut record, &request_download(app_start, 8));
+        let response = request(&mut uds, &mut memory, &mut record, &[td, 0x01, 0x33, 0x33, 0x33, 0x33]);
+        assert_eq!(response, negative(td, UDS_NRC_GENERAL_PROGRAMMING_FAILURE));
+    }
+
+    #[test]
+    fn fingerprints_are_read_and_written_by_identifier() {
+        let (_, mut memory, mut record) = setup();
+        let mut uds = UdsServer::new();
+        let (rdbi, wdbi) = (UDS_SID_READ_DATA_BY_IDENTIFIER, UDS_SID_WRITE_DATA_BY_IDENTIFIER);
+
+        // Nothing stored yet
+        let response = request(&mut uds, &mut memory, &mut record, &[rdbi, 0xF1, 0x5A]);
+        assert_eq!(response, negative(rdbi, UDS_NRC_REQUEST_OUT_OF_RANGE));
+
+        let response = request(&mut uds, &mut memory, &mut record, &[wdbi, 0xF1, 0x5A, 0x01, 0x02, 0x03]);
+        assert_eq!(response, [0x6E, 0xF1, 0x5A]);
+        let response = request(&mut uds, &mut memory, &mut record, &[rdbi, 0xF1, 0x5A]);
+        assert_eq!(response, [0x62, 0xF1, 0x5A, 0x01, 0x02, 0x03]);
+
+        // The software fingerprints are kept in the record by the bootloader
+        for (id, value) in [
+            (DID_BOOT_SOFTWARE_FINGERPRINT, 0x83),
+            (DID_APPLICATION_SOFTWARE_FINGERPRINT, 0x84),
+            (DID_APPLICATION_DATA_FINGERPRINT, 0x85),
+        ] {
+            record.write(id, &[value; 2]).unwrap();
+            let [high, low] = id.to_be_bytes();
+            let response = request(&mut uds, &mut memory, &mut record, &[rdbi, high, low]);
+            assert_eq!(response, [0x62, high, low, value, value]);
+        }
+
+        // Several identifiers in one request, the active session included
+        let read = [rdbi, 0xF1, 0x5A, 0xF1, 0x86, 0xF1, 0x85];
+        let response = request(&mut uds, &mut memory, &mut record, &read);
+        assert_eq!(response, [0x62, 0xF1, 0x5A, 1, 2, 3, 0xF1, 0x86, 0x01, 0xF1, 0x85, 0x85, 0x85]);
+    }
+
+    #[test]
+    fn read_only_and_unknown_identifiers_are_refused() {
+        let (_, mut memory, mut record) = setup();
+        let mut uds = UdsServer::new();
+        let (rdbi, wdbi) = (UDS_SID_READ_DATA_BY_IDENTIFIER, UDS_SID_WRITE_DATA_BY_IDENTIFIER);
+        assert!(uds.get_dids_mut().register_static(DID_ECU_SERIAL_NUMBER, b"SN42"));
+
+        for id in [
+            DID_BOOT_SOFTWARE_FINGERPRINT,
+            DID_APPLICATION_SOFTWARE_FINGERPRINT,
+            DID_APPLICATION_DATA_FINGERPRINT,
+            DID_ACTIVE_DIAGNOSTIC_SESSION,
+            DID_ECU_SERIAL_NUMBER,
+        ] {
+            let [high, low] = id.to_be_bytes();
+            let response = request(&mut uds, &mut memory, &mut record, &[wdbi, high, low, 0x00]);
+            assert_eq!(response, negative(wdbi, UDS_NRC_REQUEST_OUT_OF_RANGE));
+        }
+        let response = request(&mut uds, &mut memory, &mut record, &[rdbi, 0xF1, 0x8C]);
+        assert_eq!(response, [0x62, 0xF1, 0x8C, b'S', b'N', b'4', b'2']);
+
+        // Unknown identifiers
+        let response = request(&mut uds, &mut memory, &mut record, &[rdbi, 0x12, 0x34]);
+        assert_eq!(response, negative(rdbi, UDS_NRC_REQUEST_OUT_OF_RANGE));
+        let response = request(&mut uds, &mut memory, &mut record, &[wdbi, 0x12, 0x34, 0x00]);
+        assert_eq!(response, negative(wdbi, UDS_NRC_REQUEST_OUT_OF_RANGE));
+
+        // Values longer than the identifier holds, and odd length reads
+        let mut write = vec![wdbi, 0xF1, 0x5A];
+        write.extend_from_slice(&[0; 17]);
+        let response = request(&mut uds, &mut memory, &mut record, &write);
+        assert_eq!(response, negative(wdbi, UDS_NRC_INCORRECT_MESSAGE_LENGTH));
+        let response = request(&mut uds, &mut memory, &mut record, &[rdbi, 0xF1, 0x5A, 0xF1]);
+        assert_eq!(response, negative(rdbi, UDS_NRC_INCORRECT_MESSAGE_LENGTH));
+    }
+
+    #[test]
+    fn fingerprint_write_needs_security_access() {
+        let (_, mut memory, mut record) = setup();
+        let mut uds = UdsServer::with_seed_key(InvertedSeed);
+        let wdbi = UDS_SID_WRITE_DATA_BY_IDENTIFIER;
+
+        let response = request(&mut uds, &mut memory, &mut record, &[wdbi, 0xF1, 0x5A, 0x01]);
+        assert_eq!(response, negative(wdbi, UDS_NRC_SECURITY_ACCESS_DENIED));
+        assert_eq!(record.read(DID_PROGRAMMING_FINGERPRINT, &mut [0u8; 16]), None);
+    }
+}
@@ -1,16 +1,1458 @@
 #![no_std]
 
-pub struct XcpProtocol {
-    // TODO: Implement XCP protocol
+use super::security::{SeedKeyAlgorithm, Unprotected};
+use crate::utils::crc::{update_crc16_ccitt, update_crc32};
+use crate::{MemoryManagementError, MemoryManager, S32KHal};
+use openblt::hal::Timer;
+use openblt::protocol::{Command, Protocol, ProtocolError, Transport, XCP_CMD_CONNECT};
+
+// XCP packet identifiers
+pub const XCP_PID_RES: u8 = 0xFF;
+pub const XCP_PID_ERR: u8 = 0xFE;
+
+// XCP error codes
+pub const XCP_ERR_CMD_SYNCH: u8 = 0x00;
+pub const XCP_ERR_CMD_BUSY: u8 = 0x10;
+pub const XCP_ERR_CMD_UNKNOWN: u8 = 0x20;
+pub const XCP_ERR_CMD_SYNTAX: u8 = 0x21;
+pub const XCP_ERR_OUT_OF_RANGE: u8 = 0x22;
+pub const XCP_ERR_WRITE_PROTECTED: u8 = 0x23;
+pub const XCP_ERR_ACCESS_DENIED: u8 = 0x24;
+pub const XCP_ERR_ACCESS_LOCKED: u8 = 0x25;
+pub const XCP_ERR_PAGE_NOT_VALID: u8 = 0x26;
+pub const XCP_ERR_SEQUENCE: u8 = 0x29;
+pub const XCP_ERR_GENERIC: u8 = 0x31;
+pub const XCP_ERR_VERIFY: u8 = 0x32;
+pub const XCP_ERR_RESOURCE_TEMPORARY_NOT_ACCESSIBLE: u8 = 0x33;
+
+// Resource bits reported by CONNECT and GET_STATUS
+pub const XCP_RES_CALPAG: u8 = 0x01;
+pub const XCP_RES_DAQ: u8 = 0x04;
+pub const XCP_RES_STIM: u8 = 0x08;
+pub const XCP_RES_PGM: u8 = 0x10;
+
+// COMM_MODE_BASIC bits reported by CONNECT
+const XCP_COMM_MODE_BYTE_ORDER_INTEL: u8 = 0x00;
+const XCP_COMM_MODE_SLAVE_BLOCK: u8 = 0x40;
+const XCP_COMM_MODE_OPTIONAL: u8 = 0x80;
+
+// COMM_MODE_OPTIONAL and COMM_MODE_PGM bits
+const XCP_COMM_MODE_MASTER_BLOCK: u8 = 0x01;
+const XCP_COMM_MODE_PGM_SLAVE_BLOCK: u8 = 0x40;
+
+// Protocol and transport layer versions (XCP 1.x)
+const XCP_PROTOCOL_LAYER_VERSION: u8 = 0x01;
+const XCP_TRANSPORT_LAYER_VERSION: u8 = 0x01;
+
+// Version of this XCP slave driver, reported by GET_COMM_MODE_INFO
+const XCP_DRIVER_VERSION: u8 = 0x10;
+
+/// Maximum size of a command/response packet (CTO) on classic CAN
+pub const XCP_MAX_CTO: usize = 8;
+/// Maximum size of a command/response packet on CAN FD
+pub const XCP_MAX_CTO_FD: usize = 64;
+
+/// Largest block moved by a single block transfer (the element count is a byte)
+pub const XCP_BLOCK_MAX_LEN: usize = 255;
+/// Number of packets needed to complete the largest block in master block mode
+pub const XCP_MAX_BS: u8 = max_block_size(XCP_MAX_CTO);
+
+/// Longest seed handed out by GET_SEED
+pub const XCP_SEED_MAX_LEN: usize = 32;
+/// Longest key accepted by UNLOCK
+pub const XCP_KEY_MAX_LEN: usize = 32;
+
+// GET_SEED modes
+const XCP_GET_SEED_MODE_FIRST: u8 = 0x00;
+const XCP_GET_SEED_MODE_REMAINING: u8 = 0x01;
+
+// Default number of wrong keys tolerated before the lockout delay kicks in
+const XCP_DEFAULT_MAX_UNLOCK_ATTEMPTS: u8 = 3;
+// Default time GET_SEED is refused for after too many wrong keys
+const XCP_DEFAULT_LOCKOUT_MS: u32 = 10_000;
+
+// PROGRAM_CLEAR access modes
+const XCP_PGM_CLEAR_MODE_ABSOLUTE: u8 = 0x00;
+
+// PGM_PROPERTIES reported by GET_PGM_PROCESSOR_INFO
+const XCP_PGM_ABSOLUTE_MODE: u8 = 0x01;
+const XCP_PGM_NON_SEQ_SUPPORTED: u8 = 0x40;
+
+// GET_SECTOR_INFO modes
+const XCP_SECTOR_INFO_START: u8 = 0x00;
+const XCP_SECTOR_INFO_LENGTH: u8 = 0x01;
+const XCP_SECTOR_INFO_NAME_LENGTH: u8 = 0x02;
+
+// PROGRAM_METHOD reported by GET_SECTOR_INFO
+const XCP_PGM_METHOD_SEQUENTIAL: u8 = 0x00;
+
+// Chunk size used to read memory while building a checksum
+const XCP_CHECKSUM_CHUNK_SIZE: usize = 64;
+
+/// Checksum algorithms the slave can use to answer BUILD_CHECKSUM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumType {
+    /// Bytes added into a byte
+    Add11 = 0x01,
+    /// Intel byte order 32-bit words added into a 32-bit word
+    Add44 = 0x06,
+    /// CRC-16/CCITT
+    Crc16Ccitt = 0x08,
+    /// CRC-32 (IEEE 802.3)
+    Crc32 = 0x09,
+}
+
+// Master block transfer being reassembled
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockTransfer {
+    Idle,
+    Download,
+    Program,
+}
+
+/// A single XCP response packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct XcpPacket {
+    data: [u8; XCP_MAX_CTO_FD],
+    len: usize,
 }
 
-impl XcpProtocol {
+impl XcpPacket {
+    pub fn new(data: &[u8]) -> Self {
+        let len = data.len().min(XCP_MAX_CTO_FD);
+        let mut packet = [0u8; XCP_MAX_CTO_FD];
+        packet[..len].copy_from_slice(&data[..len]);
+        Self { data: packet, len }
+    }
+
+    /// Positive response carrying no data.
+    pub fn positive() -> Self {
+        Self::new(&[XCP_PID_RES])
+    }
+
+    /// Negative response with the given error code.
+    pub fn error(code: u8) -> Self {
+        Self::new(&[XCP_PID_ERR, code])
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.len > 0 && self.data[0] == XCP_PID_ERR
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+pub struct XcpProtocol<S: SeedKeyAlgorithm = Unprotected> {
+    connected: bool,
+    programming: bool,
+    reset_requested: bool,
+    protection: u8,
+    protected_resources: u8,
+    mta: u32,
+    checksum_type: ChecksumType,
+    max_cto: usize,
+    max_bs: u8,
+    min_st: u8,
+    block_transfer: BlockTransfer,
+    block: [u8; XCP_BLOCK_MAX_LEN],
+    block_len: usize,
+    block_received: usize,
+    upload_remaining: usize,
+    seed_key: S,
+    seed: [u8; XCP_SEED_MAX_LEN],
+    seed_len: usize,
+    seed_sent: usize,
+    seed_resource: u8,
+    key: [u8; XCP_KEY_MAX_LEN],
+    key_len: usize,
+    key_received: usize,
+    failed_unlocks: u8,
+    max_unlock_attempts: u8,
+    lockout_ms: u32,
+    locked_until: Option<u32>,
+    now_ms: u32,
+}
+
+impl XcpProtocol<Unprotected> {
+    /// Creates a processor that does not protect any resource.
     pub fn new() -> Self {
-        XcpProtocol {}
+        Self::init(Unprotected, 0)
     }
+}
+
+impl<S: SeedKeyAlgorithm> XcpProtocol<S> {
+    /// Creates a processor that keeps the PGM resource locked until the
+    /// tester unlocks it with a key accepted by `seed_key`.
+    pub fn with_seed_key(seed_key: S) -> Self {
+        Self::init(seed_key, XCP_RES_PGM)
+    }
+
+    fn init(seed_key: S, protected_resources: u8) -> Self {
+        XcpProtocol {
+            connected: false,
+            programming: false,
+            reset_requested: false,
+            protection: protected_resources,
+            protected_resources,
+            mta: 0,
+            checksum_type: ChecksumType::Add44,
+            max_cto: XCP_MAX_CTO,
+            max_bs: XCP_MAX_BS,
+            min_st: 0,
+            block_transfer: BlockTransfer::Idle,
+            block: [0; XCP_BLOCK_MAX_LEN],
+            block_len: 0,
+            block_received: 0,
+            upload_remaining: 0,
+            seed_key,
+            seed: [0; XCP_SEED_MAX_LEN],
+            seed_len: 0,
+            seed_sent: 0,
+            seed_resource: 0,
+            key: [0; XCP_KEY_MAX_LEN],
+            key_len: 0,
+            key_received: 0,
+            failed_unlocks: 0,
+            max_unlock_attempts: XCP_DEFAULT_MAX_UNLOCK_ATTEMPTS,
+            lockout_ms: XCP_DEFAULT_LOCKOUT_MS,
+            locked_until: None,
+            now_ms: 0,
+        }
+    }
+
+    /// Configures how many wrong keys are tolerated before GET_SEED is
+    /// refused for `lockout_ms`.
+    pub fn set_lockout(&mut self, max_attempts: u8, lockout_ms: u32) {
+        self.max_unlock_attempts = max_attempts;
+        self.lockout_ms = lockout_ms;
+    }
+
+    /// Configures the master block mode advertised to the master: at most
+    /// `max_bs` PROGRAM_NEXT/DOWNLOAD_NEXT packets per block, sent at least
+    /// `min_st` * 100 us apart. A `max_bs` of 0 disables master block mode.
+    /// `max_bs` is capped at the packets needed for the largest block with
+    /// the current packet size, whether `set_max_cto` is called before or
+    /// after.
+    pub fn set_block_mode(&mut self, max_bs: u8, min_st: u8) {
+        self.max_bs = max_bs;
+        self.min_st = min_st;
+    }
+
+    /// Sets the packet size reported by CONNECT and used for responses,
+    /// between `XCP_MAX_CTO` and `XCP_MAX_CTO_FD`. Pass the transport's
+    /// packet size, e.g. 64 on CAN FD. The block mode set by
+    /// `set_block_mode` is kept.
+    pub fn set_max_cto(&mut self, max_cto: usize) {
+        self.max_cto = max_cto.clamp(XCP_MAX_CTO, XCP_MAX_CTO_FD);
+    }
+
+    pub fn get_max_cto(&self) -> usize {
+        self.max_cto
+    }
+
+    /// Advances the processor's notion of time and ends an expired unlock
+    /// lockout. `process_command` does this with the HAL's timer before
+    /// every command.
+    pub fn poll(&mut self, now_ms: u32) {
+        self.now_ms = now_ms;
+        if let Some(until) = self.locked_until {
+            if self.now_ms.wrapping_sub(until) as i32 >= 0 {
+                self.locked_until = None;
+                self.failed_unlocks = 0;
+            }
+        }
+    }
+
+    /// Returns the resource bits that are currently locked.
+    pub fn get_protection(&self) -> u8 {
+        self.protection
+    }
+
+    /// Selects the algorithm used to answer BUILD_CHECKSUM.
+    pub fn set_checksum_type(&mut self, checksum_type: ChecksumType) {
+        self.checksum_type = checksum_type;
+    }
+
+    /// Current memory transfer address.
+    pub fn get_mta(&self) -> u32 {
+        self.mta
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn is_programming(&self) -> bool {
+        self.programming
+    }
+
+    /// Processes a single command packet (CRO) and returns the response
+    /// packet to send back, if any. Programming commands are carried out
+    /// on `memory`. The application is only started by
+    /// `finish_program_reset`, after the response to PROGRAM_RESET.
+    pub fn process_command<H: S32KHal>(
+        &mut self,
+        command: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Option<XcpPacket> {
+        let code = *command.first()?;
+
+        // The unlock lockout runs on the HAL's clock
+        self.poll(memory.get_hal().get_timer().now_ms());
+
+        // While disconnected the slave must silently ignore everything but CONNECT
+        if !self.connected && code != XCP_CMD_CONNECT {
+            return None;
+        }
 
-    pub fn process_command(&mut self, command: &[u8]) -> Option<[u8; 8]> {
-        // TODO: Implement command processing
+        let command = match Command::decode(command) {
+            Ok(command) => command,
+            Err(ProtocolError::InvalidCommand) => return Some(XcpPacket::error(XCP_ERR_CMD_UNKNOWN)),
+            Err(_) => return Some(XcpPacket::error(XCP_ERR_CMD_SYNTAX)),
+        };
+
+        // Anything but the next packet of a block aborts the block transfer
+        if !matches!(command, Command::ProgramNext { .. } | Command::DownloadNext { .. }) {
+            self.block_transfer = BlockTransfer::Idle;
+            self.upload_remaining = 0;
+        }
+
+        let response = match command {
+            Command::Connect { .. } => self.cmd_connect(),
+            Command::Disconnect => self.cmd_disconnect(),
+            Command::GetStatus => self.cmd_get_status(),
+            Command::Synch => XcpPacket::error(XCP_ERR_CMD_SYNCH),
+            Command::GetCommModeInfo => self.cmd_get_comm_mode_info(),
+            Command::GetSeed { mode, resource } => self.cmd_get_seed(mode, resource),
+            Command::Unlock { length, data } => self.cmd_unlock(length, data),
+            Command::SetMta { address, .. } => self.cmd_set_mta(address),
+            Command::Upload { size } => self.cmd_upload(size, memory),
+            Command::ShortUpload { size, address, .. } => {
+                // SHORT_UPLOAD never uses slave block mode
+                if size as usize > self.max_cto - 1 {
+                    return Some(XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+                }
+                self.mta = address;
+                self.cmd_upload(size, memory)
+            }
+            Command::BuildChecksum { block_size } => self.cmd_build_checksum(block_size, memory),
+            Command::Download { size, data } => return self.cmd_download(size, data, memory),
+            Command::DownloadNext { size, data } => {
+                return self.cmd_block_next(BlockTransfer::Download, size, data, memory)
+            }
+            Command::ProgramStart => self.cmd_program_start(),
+            Command::ProgramClear { mode, range } => self.cmd_program_clear(mode, range, memory),
+            Command::Program { size, data } => return self.cmd_program(size, data, memory),
+            Command::ProgramNext { size, data } => {
+                return self.cmd_block_next(BlockTransfer::Program, size, data, memory)
+            }
+            Command::ProgramMax { data } => self.cmd_program_max(data, memory),
+            Command::ProgramReset => self.cmd_program_reset(memory),
+            Command::GetPgmProcessorInfo => self.cmd_get_pgm_processor_info(memory),
+            Command::GetSectorInfo { mode, number } => self.cmd_get_sector_info(mode, number, memory),
+        };
+
+        Some(response)
+    }
+
+    /// Returns the next packet of an UPLOAD answered in slave block mode.
+    /// Call this after sending the response to UPLOAD until it returns None,
+    /// keeping the transport's separation time between the packets.
+    pub fn next_block_response<H: S32KHal>(
+        &mut self,
+        memory: &MemoryManager<H>,
+    ) -> Option<XcpPacket> {
+        if self.upload_remaining == 0 {
+            return None;
+        }
+
+        let size = self.upload_remaining.min(self.max_cto - 1);
+        let mut response = [0u8; XCP_MAX_CTO_FD];
+        response[0] = XCP_PID_RES;
+        if let Err(e) = memory.read(self.mta, &mut response[1..1 + size]) {
+            self.upload_remaining = 0;
+            return Some(self.memory_error(e, memory));
+        }
+
+        self.mta += size as u32;
+        self.upload_remaining -= size;
+        Some(XcpPacket::new(&response[..1 + size]))
+    }
+
+    /// Starts the programmed application once PROGRAM_RESET was answered.
+    /// Call this after sending the response to the command. It returns
+    /// when there was no PROGRAM_RESET or the application could not be
+    /// started.
+    pub fn finish_program_reset<H: S32KHal>(&mut self, memory: &MemoryManager<H>) {
+        if !self.reset_requested {
+            return;
+        }
+        self.reset_requested = false;
+
+        // The reset vector is the second word of the application's vector table
+        let mut entry_point = [0u8; 4];
+        if memory.read(memory.get_app_start() + 4, &mut entry_point).is_ok() {
+            let _ = memory
+                .get_hal()
+                .jump_to_application(u32::from_le_bytes(entry_point));
+        }
+    }
+
+    /// Waits for one command on `protocol`, processes it and sends the
+    /// response, followed by any slave block upload packets. After
+    /// PROGRAM_RESET the application is started. A bus-off leaves the
+    /// session as it is, so the master can repeat the command once the
+    /// controller is back on the bus.
+    pub fn serve<T: Transport, H: S32KHal>(
+        &mut self,
+        protocol: &mut Protocol<T>,
+        memory: &mut MemoryManager<H>,
+    ) -> Result<(), ProtocolError> {
+        let response = {
+            let command = protocol.receive_packet()?;
+            self.process_command(command, memory)
+        };
+
+        if let Some(response) = response {
+            protocol.send_response(response.as_slice())?;
+        }
+        while let Some(response) = self.next_block_response(memory) {
+            protocol.send_response(response.as_slice())?;
+        }
+        self.finish_program_reset(memory);
+        Ok(())
+    }
+
+    fn cmd_connect(&mut self) -> XcpPacket {
+        // Both normal (0) and user defined (1) connect modes start a new session
+        self.connected = true;
+        self.programming = false;
+        self.reset_requested = false;
+        self.mta = 0;
+        self.protection = self.protected_resources;
+        self.seed_len = 0;
+        self.key_len = 0;
+
+        // No DAQ, so data transfer packets are no larger than commands
+        let max_dto = (self.max_cto as u16).to_le_bytes();
+        XcpPacket::new(&[
+            XCP_PID_RES,
+            XCP_RES_PGM,
+            XCP_COMM_MODE_BYTE_ORDER_INTEL | XCP_COMM_MODE_SLAVE_BLOCK | XCP_COMM_MODE_OPTIONAL,
+            self.max_cto as u8,
+            max_dto[0],
+            max_dto[1],
+            XCP_PROTOCOL_LAYER_VERSION,
+            XCP_TRANSPORT_LAYER_VERSION,
+        ])
+    }
+
+    fn cmd_disconnect(&mut self) -> XcpPacket {
+        self.connected = false;
+        self.programming = false;
+        XcpPacket::positive()
+    }
+
+    fn cmd_get_status(&self) -> XcpPacket {
+        // Session status, resource protection, reserved, session configuration id
+        XcpPacket::new(&[XCP_PID_RES, 0x00, self.protection, 0x00, 0x00, 0x00])
+    }
+
+    fn cmd_get_comm_mode_info(&self) -> XcpPacket {
+        // Reserved, COMM_MODE_OPTIONAL, reserved, MAX_BS, MIN_ST, QUEUE_SIZE, driver version
+        XcpPacket::new(&[
+            XCP_PID_RES,
+            0x00,
+            self.master_block_mode(),
+            0x00,
+            self.block_size(),
+            self.min_st,
+            0x00,
+            XCP_DRIVER_VERSION,
+        ])
+    }
+
+    fn cmd_get_seed(&mut self, mode: u8, resource: u8) -> XcpPacket {
+        if self.locked_until.is_some() {
+            return XcpPacket::error(XCP_ERR_RESOURCE_TEMPORARY_NOT_ACCESSIBLE);
+        }
+
+        match mode {
+            XCP_GET_SEED_MODE_FIRST => {
+                // Exactly one resource can be unlocked at a time
+                if resource.count_ones() != 1 {
+                    return XcpPacket::error(XCP_ERR_OUT_OF_RANGE);
+                }
+                // An unprotected resource is reported with a zero length seed
+                if self.protection & resource == 0 {
+                    self.seed_len = 0;
+                    return XcpPacket::new(&[XCP_PID_RES, 0x00]);
+                }
+
+                let len = self.seed_key.generate_seed(resource, &mut self.seed);
+                self.seed_len = len.min(XCP_SEED_MAX_LEN);
+                self.seed_sent = 0;
+                self.seed_resource = resource;
+                self.key_len = 0;
+            }
+            XCP_GET_SEED_MODE_REMAINING => {
+                if self.seed_len == 0 || self.seed_sent >= self.seed_len {
+                    return XcpPacket::error(XCP_ERR_SEQUENCE);
+                }
+            }
+            _ => return XcpPacket::error(XCP_ERR_OUT_OF_RANGE),
+        }
+
+        // Length of the remaining seed followed by as much of it as fits
+        let remaining = self.seed_len - self.seed_sent;
+        let count = remaining.min(self.max_cto - 2);
+        let mut response = [0u8; XCP_MAX_CTO_FD];
+        response[0] = XCP_PID_RES;
+        response[1] = remaining as u8;
+        response[2..2 + count].copy_from_slice(&self.seed[self.seed_sent..self.seed_sent + count]);
+        self.seed_sent += count;
+
+        XcpPacket::new(&response[..2 + count])
+    }
+
+    fn cmd_unlock(&mut self, length: u8, data: &[u8]) -> XcpPacket {
+        // A complete seed must have been handed out first
+        if self.seed_len == 0 || self.seed_sent < self.seed_len {
+            return XcpPacket::error(XCP_ERR_SEQUENCE);
+        }
+
+        let length = length as usize;
+        if self.key_len == 0 {
+            // The first part announces the total key length
+            if length == 0 || length > XCP_KEY_MAX_LEN {
+                return XcpPacket::error(XCP_ERR_OUT_OF_RANGE);
+            }
+            self.key_len = length;
+            self.key_received = 0;
+        } else if length != self.key_len - self.key_received {
+            // Every following part announces the remaining length
+            return XcpPacket::error(XCP_ERR_SEQUENCE);
+        }
+
+        let count = data.len().min(self.key_len - self.key_received);
+        self.key[self.key_received..self.key_received + count].copy_from_slice(&data[..count]);
+        self.key_received += count;
+        if self.key_received < self.key_len {
+            return XcpPacket::new(&[XCP_PID_RES, self.protection]);
+        }
+
+        let seed = &self.seed[..self.seed_len];
+        let key = &self.key[..self.key_len];
+        let accepted = self.seed_key.verify_key(self.seed_resource, seed, key);
+        self.seed_len = 0;
+        self.key_len = 0;
+
+        if !accepted {
+            // A wrong key ends the session, and repeated ones lock out GET_SEED
+            self.failed_unlocks = self.failed_unlocks.saturating_add(1);
+            if self.failed_unlocks >= self.max_unlock_attempts {
+                self.locked_until = Some(self.now_ms.wrapping_add(self.lockout_ms));
+            }
+            self.connected = false;
+            self.programming = false;
+            return XcpPacket::error(XCP_ERR_ACCESS_LOCKED);
+        }
+
+        self.failed_unlocks = 0;
+        self.protection &= !self.seed_resource;
+        XcpPacket::new(&[XCP_PID_RES, self.protection])
+    }
+
+    fn cmd_set_mta(&mut self, address: u32) -> XcpPacket {
+        // Only a single, linear address space exists so the extension is ignored
+        self.mta = address;
+        XcpPacket::positive()
+    }
+
+    fn cmd_upload<H: S32KHal>(&mut self, size: u8, memory: &MemoryManager<H>) -> XcpPacket {
+        if size == 0 {
+            return XcpPacket::error(XCP_ERR_OUT_OF_RANGE);
+        }
+
+        // Anything not fitting the first packet follows in slave block mode
+        self.upload_remaining = size as usize;
+        self.next_block_response(memory)
+            .unwrap_or_else(|| XcpPacket::error(XCP_ERR_GENERIC))
+    }
+
+    fn cmd_build_checksum<H: S32KHal>(
+        &mut self,
+        block_size: u32,
+        memory: &MemoryManager<H>,
+    ) -> XcpPacket {
+        // ADD_44 sums whole words
+        if block_size == 0 || (self.checksum_type == ChecksumType::Add44 && !block_size.is_multiple_of(4)) {
+            return XcpPacket::error(XCP_ERR_OUT_OF_RANGE);
+        }
+
+        let mut checksum = match self.checksum_type {
+            ChecksumType::Crc16Ccitt => 0xFFFF,
+            ChecksumType::Crc32 => 0xFFFF_FFFF,
+            ChecksumType::Add11 | ChecksumType::Add44 => 0,
+        };
+
+        let mut chunk = [0u8; XCP_CHECKSUM_CHUNK_SIZE];
+        let mut remaining = block_size;
+        let mut address = self.mta;
+        while remaining > 0 {
+            let count = (remaining as usize).min(XCP_CHECKSUM_CHUNK_SIZE);
+            let data = &mut chunk[..count];
+            if let Err(e) = memory.read(address, data) {
+                return self.memory_error(e, memory);
+            }
+
+            checksum = match self.checksum_type {
+                ChecksumType::Add11 => data
+                    .iter()
+                    .fold(checksum as u8, |sum, &b| sum.wrapping_add(b)) as u32,
+                ChecksumType::Add44 => data.chunks(4).fold(checksum, |sum, word| {
+                    sum.wrapping_add(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                }),
+                ChecksumType::Crc16Ccitt => update_crc16_ccitt(checksum as u16, data) as u32,
+                ChecksumType::Crc32 => update_crc32(checksum, data),
+            };
+
+            address += count as u32;
+            remaining -= count as u32;
+        }
+
+        if self.checksum_type == ChecksumType::Crc32 {
+            checksum = !checksum;
+        }
+        self.mta += block_size;
+
+        let checksum = checksum.to_le_bytes();
+        XcpPacket::new(&[
+            XCP_PID_RES,
+            self.checksum_type as u8,
+            0x00,
+            0x00,
+            checksum[0],
+            checksum[1],
+            checksum[2],
+            checksum[3],
+        ])
+    }
+
+    fn cmd_download<H: S32KHal>(
+        &mut self,
+        size: u8,
+        data: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Option<XcpPacket> {
+        // Downloads end up in flash, which is only writable during a programming session
+        if !self.programming {
+            return Some(XcpPacket::error(XCP_ERR_SEQUENCE));
+        }
+        if size == 0 {
+            return Some(XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+        }
+
+        self.transfer(BlockTransfer::Download, size, data, memory)
+    }
+
+    fn cmd_program_start(&mut self) -> XcpPacket {
+        if self.protection & XCP_RES_PGM != 0 {
+            return XcpPacket::error(XCP_ERR_ACCESS_LOCKED);
+        }
+        self.programming = true;
+
+        // Reserved, COMM_MODE_PGM, MAX_CTO_PGM, MAX_BS_PGM, MIN_ST_PGM, QUEUE_SIZE_PGM
+        XcpPacket::new(&[
+            XCP_PID_RES,
+            0x00,
+            self.master_block_mode() | XCP_COMM_MODE_PGM_SLAVE_BLOCK,
+            self.max_cto as u8,
+            self.block_size(),
+            self.min_st,
+            0x00,
+        ])
+    }
+
+    fn cmd_program_clear<H: S32KHal>(
+        &mut self,
+        mode: u8,
+        range: u32,
+        memory: &mut MemoryManager<H>,
+    ) -> XcpPacket {
+        if !self.programming {
+            return XcpPacket::error(XCP_ERR_SEQUENCE);
+        }
+        // Only the absolute access mode (clear range starting at the MTA) is supported
+        if mode != XCP_PGM_CLEAR_MODE_ABSOLUTE {
+            return XcpPacket::error(XCP_ERR_OUT_OF_RANGE);
+        }
+
+        match memory.erase(self.mta, range) {
+            Ok(()) => XcpPacket::positive(),
+            Err(e) => self.memory_error(e, memory),
+        }
+    }
+
+    fn cmd_program<H: S32KHal>(
+        &mut self,
+        size: u8,
+        data: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Option<XcpPacket> {
+        if !self.programming {
+            return Some(XcpPacket::error(XCP_ERR_SEQUENCE));
+        }
+
+        // A zero length PROGRAM marks the end of the memory segment
+        if size == 0 {
+            return Some(match memory.flush() {
+                Ok(()) => XcpPacket::positive(),
+                Err(e) => self.memory_error(e, memory),
+            });
+        }
+
+        self.transfer(BlockTransfer::Program, size, data, memory)
+    }
+
+    // Writes the data of a DOWNLOAD or PROGRAM, or starts reassembling a
+    // master block when more elements were announced than the packet holds.
+    fn transfer<H: S32KHal>(
+        &mut self,
+        kind: BlockTransfer,
+        size: u8,
+        data: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Option<XcpPacket> {
+        let size = size as usize;
+        if data.len() >= size {
+            return Some(self.program(&data[..size], memory));
+        }
+
+        let packets = (size - data.len() + self.max_cto - 3) / (self.max_cto - 2);
+        if packets > self.block_size() as usize {
+            return Some(XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+        }
+
+        // The slave stays silent until the last packet of the block arrived
+        self.block[..data.len()].copy_from_slice(data);
+        self.block_len = size;
+        self.block_received = data.len();
+        self.block_transfer = kind;
         None
     }
-} 
+
+    fn cmd_block_next<H: S32KHal>(
+        &mut self,
+        kind: BlockTransfer,
+        size: u8,
+        data: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> Option<XcpPacket> {
+        if self.block_transfer != kind {
+            self.block_transfer = BlockTransfer::Idle;
+            return Some(XcpPacket::error(XCP_ERR_SEQUENCE));
+        }
+
+        // Every packet announces the number of elements still to come
+        let remaining = self.block_len - self.block_received;
+        if size as usize != remaining {
+            self.block_transfer = BlockTransfer::Idle;
+            return Some(XcpPacket::new(&[XCP_PID_ERR, XCP_ERR_SEQUENCE, remaining as u8]));
+        }
+
+        let count = data.len().min(remaining);
+        self.block[self.block_received..self.block_received + count]
+            .copy_from_slice(&data[..count]);
+        self.block_received += count;
+        if self.block_received < self.block_len {
+            return None;
+        }
+
+        self.block_transfer = BlockTransfer::Idle;
+        let block = self.block;
+        Some(self.program(&block[..self.block_len], memory))
+    }
+
+    fn cmd_program_max<H: S32KHal>(
+        &mut self,
+        data: &[u8],
+        memory: &mut MemoryManager<H>,
+    ) -> XcpPacket {
+        if !self.programming {
+            return XcpPacket::error(XCP_ERR_SEQUENCE);
+        }
+        if data.len() < self.max_cto - 1 {
+            return XcpPacket::error(XCP_ERR_CMD_SYNTAX);
+        }
+
+        self.program(&data[..self.max_cto - 1], memory)
+    }
+
+    fn cmd_program_reset<H: S32KHal>(&mut self, memory: &mut MemoryManager<H>) -> XcpPacket {
+        if !self.programming {
+            return XcpPacket::error(XCP_ERR_SEQUENCE);
+        }
+
+        // Data still held back by the memory manager goes to flash first
+        if let Err(e) = memory.flush() {
+            return self.memory_error(e, memory);
+        }
+        self.programming = false;
+
+        // The application is started by `finish_program_reset`, once the
+        // response is on its way
+        self.reset_requested = true;
+        XcpPacket::positive()
+    }
+
+    fn cmd_get_pgm_processor_info<H: S32KHal>(&self, memory: &MemoryManager<H>) -> XcpPacket {
+        // The memory manager buffers writes per sector, so they may come in any order
+        let sectors = memory.app_regions().count().min(u8::MAX as usize) as u8;
+        XcpPacket::new(&[
+            XCP_PID_RES,
+            XCP_PGM_ABSOLUTE_MODE | XCP_PGM_NON_SEQ_SUPPORTED,
+            sectors,
+        ])
+    }
+
+    // XCP sectors are the parts of the flash layout's regions open to the
+    // application, cleared and programmed in the order they are numbered
+    fn cmd_get_sector_info<H: S32KHal>(
+        &self,
+        mode: u8,
+        number: u8,
+        memory: &MemoryManager<H>,
+    ) -> XcpPacket {
+        let region = match memory.app_regions().nth(number as usize) {
+            Some(region) => region,
+            None => return XcpPacket::error(XCP_ERR_OUT_OF_RANGE),
+        };
+
+        let info = match mode {
+            XCP_SECTOR_INFO_START => region.start,
+            XCP_SECTOR_INFO_LENGTH => region.size,
+            // Sectors have no names
+            XCP_SECTOR_INFO_NAME_LENGTH => return XcpPacket::new(&[XCP_PID_RES, 0x00]),
+            _ => return XcpPacket::error(XCP_ERR_OUT_OF_RANGE),
+        };
+
+        // CLEAR_SEQUENCE_NUMBER, PROGRAM_SEQUENCE_NUMBER, PROGRAM_METHOD, SECTOR_INFO
+        let info = info.to_le_bytes();
+        XcpPacket::new(&[
+            XCP_PID_RES,
+            number,
+            number,
+            XCP_PGM_METHOD_SEQUENTIAL,
+            info[0],
+            info[1],
+            info[2],
+            info[3],
+        ])
+    }
+
+    fn program<H: S32KHal>(&mut self, data: &[u8], memory: &mut MemoryManager<H>) -> XcpPacket {
+        match memory.write(self.mta, data) {
+            Ok(()) => {
+                self.mta += data.len() as u32;
+                XcpPacket::positive()
+            }
+            Err(e) => self.memory_error(e, memory),
+        }
+    }
+
+    // MAX_BS as advertised, never more than the largest block needs
+    fn block_size(&self) -> u8 {
+        self.max_bs.min(max_block_size(self.max_cto))
+    }
+
+    fn master_block_mode(&self) -> u8 {
+        if self.max_bs > 0 {
+            XCP_COMM_MODE_MASTER_BLOCK
+        } else {
+            0
+        }
+    }
+
+    fn memory_error<H: S32KHal>(
+        &self,
+        error: MemoryManagementError,
+        memory: &MemoryManager<H>,
+    ) -> XcpPacket {
+        match error {
+            // The flash the bootloader itself lives in
+            MemoryManagementError::OutOfBounds if memory.get_layout().is_reserved(self.mta, 1) => {
+                XcpPacket::error(XCP_ERR_ACCESS_DENIED)
+            }
+            MemoryManagementError::InvalidAddress
+            | MemoryManagementError::InvalidLength
+            | MemoryManagementError::AlignmentError
+            | MemoryManagementError::OutOfBounds => XcpPacket::error(XCP_ERR_OUT_OF_RANGE),
+            MemoryManagementError::WriteError
+            | MemoryManagementError::EraseError
+            | MemoryManagementError::ReadError => XcpPacket::error(XCP_ERR_GENERIC),
+            MemoryManagementError::UnsafeConfiguration => XcpPacket::error(XCP_ERR_ACCESS_DENIED),
+            MemoryManagementError::AlreadyProgrammed => XcpPacket::error(XCP_ERR_SEQUENCE),
+        }
+    }
+}
+
+// Number of packets needed to complete the largest block with packets of
+// `max_cto` bytes
+const fn max_block_size(max_cto: usize) -> u8 {
+    ((XCP_BLOCK_MAX_LEN + max_cto - 3) / (max_cto - 2)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openblt::hal::loopback::LoopbackBus;
+    use openblt::hal::sim::{SimDevice, SimFlash, SimHal};
+    use openblt::protocol::transport::MemoryTransport;
+    use openblt::protocol::{
+        XCP_CMD_BUILD_CHECKSUM, XCP_CMD_DISCONNECT, XCP_CMD_DOWNLOAD, XCP_CMD_DOWNLOAD_NEXT,
+        XCP_CMD_GET_COMM_MODE_INFO, XCP_CMD_GET_SEED, XCP_CMD_GET_STATUS, XCP_CMD_PROGRAM,
+        XCP_CMD_PROGRAM_CLEAR, XCP_CMD_PROGRAM_MAX, XCP_CMD_PROGRAM_RESET, XCP_CMD_PROGRAM_START,
+        XCP_CMD_SET_MTA, XCP_CMD_SHORT_UPLOAD, XCP_CMD_SYNCH, XCP_CMD_UNLOCK, XCP_CMD_UPLOAD,
+    };
+    use s32k148_hal::flash::S32K148_PFLASH;
+    use s32k148_hal::CanFrame;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    type Hal = SimHal<'static, CanFrame, 4>;
+
+    // Accepts the seed with every bit inverted
+    struct InvertedSeed;
+
+    impl SeedKeyAlgorithm for InvertedSeed {
+        fn generate_seed(&mut self, _level: u8, seed: &mut [u8]) -> usize {
+            seed[..4].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+            4
+        }
+
+        fn verify_key(&mut self, _level: u8, seed: &[u8], key: &[u8]) -> bool {
+            seed.len() == key.len() && seed.iter().zip(key).all(|(&s, &k)| s == !k)
+        }
+    }
+
+    // Memory manager on an erased program flash. The device is leaked, so
+    // every test gets its own.
+    fn setup() -> (&'static SimDevice<'static>, MemoryManager<Hal>) {
+        let program = vec![0u8; S32K148_PFLASH.size as usize].leak();
+        let blocks = Box::leak(Box::new([SimFlash::from_region(&S32K148_PFLASH, program)]));
+        let device = Box::leak(Box::new(SimDevice::new(blocks)));
+        let bus = Box::leak(Box::new(LoopbackBus::<CanFrame, 4>::new()));
+        let (can, _) = bus.split();
+        (device, MemoryManager::new(SimHal::new(device, can)).unwrap())
+    }
+
+    fn set_mta(address: u32) -> [u8; 8] {
+        let address = address.to_le_bytes();
+        [XCP_CMD_SET_MTA, 0, 0, 0, address[0], address[1], address[2], address[3]]
+    }
+
+    fn short_upload(size: u8, address: u32) -> [u8; 8] {
+        let address = address.to_le_bytes();
+        [XCP_CMD_SHORT_UPLOAD, size, 0, 0, address[0], address[1], address[2], address[3]]
+    }
+
+    fn build_checksum(block_size: u32) -> [u8; 8] {
+        let size = block_size.to_le_bytes();
+        [XCP_CMD_BUILD_CHECKSUM, 0, 0, 0, size[0], size[1], size[2], size[3]]
+    }
+
+    // 0x10, 0x11, ... 0x1F at the start of the application area
+    fn write_pattern(memory: &mut MemoryManager<Hal>) {
+        let pattern: [u8; 16] = core::array::from_fn(|i| 0x10 + i as u8);
+        memory.write(memory.get_app_start(), &pattern).unwrap();
+        memory.flush().unwrap();
+    }
+
+    fn command<S: SeedKeyAlgorithm>(
+        xcp: &mut XcpProtocol<S>,
+        memory: &mut MemoryManager<Hal>,
+        packet: &[u8],
+    ) -> XcpPacket {
+        xcp.process_command(packet, memory).expect("no response")
+    }
+
+    #[test]
+    fn session_starts_with_connect_and_ends_with_disconnect() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+
+        // Nothing but CONNECT is answered outside a session
+        assert_eq!(xcp.process_command(&[XCP_CMD_GET_STATUS], &mut memory), None);
+        assert_eq!(xcp.process_command(&[0xC0], &mut memory), None);
+
+        // PGM resource, slave block mode and optional modes, 8 byte CTO and DTO, XCP 1.x
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, XCP_RES_PGM, 0xC0, 8, 8, 0, 1, 1]);
+        assert!(xcp.is_connected());
+
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_GET_STATUS]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_SYNCH]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_CMD_SYNCH));
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_GET_COMM_MODE_INFO]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x01, 0x00, XCP_MAX_BS, 0x00, 0x00, 0x10]);
+
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_DISCONNECT]);
+        assert_eq!(response, XcpPacket::positive());
+        assert!(!xcp.is_connected());
+        assert_eq!(xcp.process_command(&[XCP_CMD_GET_STATUS], &mut memory), None);
+    }
+
+    #[test]
+    fn connect_reports_protected_resources() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::with_seed_key(InvertedSeed);
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_GET_STATUS]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, XCP_RES_PGM, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn unknown_command_is_refused() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        let response = command(&mut xcp, &mut memory, &[0xC0, 0x01]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_CMD_UNKNOWN));
+        assert_eq!(xcp.process_command(&[], &mut memory), None);
+        assert!(xcp.is_connected());
+    }
+
+    #[test]
+    fn command_and_response_lengths_follow_the_cto() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::with_seed_key(InvertedSeed);
+
+        // Commands missing their parameters
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        for packet in [&[XCP_CMD_GET_SEED, 0x00][..], &[XCP_CMD_SET_MTA, 0x00, 0x00, 0x00, 0x00]] {
+            assert_eq!(command(&mut xcp, &mut memory, packet), XcpPacket::error(XCP_ERR_CMD_SYNTAX));
+        }
+
+        // A SHORT_UPLOAD answer has to fit one packet
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_SHORT_UPLOAD, 8, 0, 0, 0x00, 0x00, 0x04, 0x00]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+
+        // The seed is handed out in parts that fit the CTO
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_GET_SEED, 0x00, XCP_RES_PGM]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 4, 0x12, 0x34, 0x56, 0x78]);
+
+        // The CTO stays between the classic CAN and the CAN FD size
+        xcp.set_max_cto(4);
+        assert_eq!(xcp.get_max_cto(), XCP_MAX_CTO);
+        xcp.set_max_cto(128);
+        assert_eq!(xcp.get_max_cto(), XCP_MAX_CTO_FD);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        assert_eq!(&response.as_slice()[3..6], &[64, 64, 0]);
+    }
+
+    #[test]
+    fn unlock_lockout_expires_on_the_hal_timer() {
+        let (device, mut memory) = setup();
+        let mut xcp = XcpProtocol::with_seed_key(InvertedSeed);
+        xcp.set_lockout(1, 500);
+        let get_seed = [XCP_CMD_GET_SEED, XCP_GET_SEED_MODE_FIRST, XCP_RES_PGM];
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        let seed = command(&mut xcp, &mut memory, &get_seed);
+        assert_eq!(seed.as_slice(), &[XCP_PID_RES, 4, 0x12, 0x34, 0x56, 0x78]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UNLOCK, 4, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_ACCESS_LOCKED));
+        assert!(!xcp.is_connected());
+
+        // A new session gets no seed until the lockout is over
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        device.advance_ms(499);
+        let response = command(&mut xcp, &mut memory, &get_seed);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_RESOURCE_TEMPORARY_NOT_ACCESSIBLE));
+
+        device.advance_ms(1);
+        let seed = command(&mut xcp, &mut memory, &get_seed);
+        assert_eq!(seed.as_slice(), &[XCP_PID_RES, 4, 0x12, 0x34, 0x56, 0x78]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UNLOCK, 4, 0xED, 0xCB, 0xA9, 0x87]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00]);
+        assert_eq!(xcp.get_protection(), 0);
+    }
+
+    #[test]
+    fn application_is_cleared_programmed_and_started() {
+        let (device, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+        let sector_size = memory.get_sector_size().to_le_bytes();
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM_START]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x41, 8, XCP_MAX_BS, 0x00, 0x00]);
+        assert!(xcp.is_programming());
+
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+        let clear = [XCP_CMD_PROGRAM_CLEAR, 0, 0, 0, sector_size[0], sector_size[1], sector_size[2], sector_size[3]];
+        assert_eq!(command(&mut xcp, &mut memory, &clear), XcpPacket::positive());
+
+        // Stack pointer and reset vector, then a full packet of code
+        let packets: [&[u8]; 4] = [
+            &[XCP_CMD_PROGRAM, 6, 0x00, 0xF0, 0x00, 0x20, 0x01, 0x01],
+            &[XCP_CMD_PROGRAM, 2, 0x04, 0x00],
+            &[XCP_CMD_PROGRAM_MAX, 0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6],
+            &[XCP_CMD_PROGRAM, 0],
+        ];
+        for packet in packets {
+            assert_eq!(command(&mut xcp, &mut memory, packet), XcpPacket::positive());
+        }
+        assert_eq!(xcp.get_mta(), app_start + 15);
+
+        let mut flash = [0u8; 16];
+        memory.read(app_start, &mut flash).unwrap();
+        assert_eq!(
+            flash,
+            [0x00, 0xF0, 0x00, 0x20, 0x01, 0x01, 0x04, 0x00, 0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xFF]
+        );
+
+        // The response goes out before the application is started
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM_RESET]);
+        assert_eq!(response, XcpPacket::positive());
+        assert!(!xcp.is_programming());
+        assert_eq!(device.get_entry_point(), None);
+        xcp.finish_program_reset(&memory);
+        assert_eq!(device.get_entry_point(), Some(0x0004_0101));
+    }
+
+    #[test]
+    fn programming_needs_program_start_in_the_current_session() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let program = [XCP_CMD_PROGRAM, 2, 0x12, 0x34];
+
+        let mta = set_mta(memory.get_app_start());
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &mta);
+        for packet in [&program[..], &[XCP_CMD_PROGRAM_CLEAR, 0, 0, 0, 0, 0, 0, 0], &[XCP_CMD_PROGRAM_RESET]] {
+            assert_eq!(command(&mut xcp, &mut memory, packet), XcpPacket::error(XCP_ERR_SEQUENCE));
+        }
+
+        // A new CONNECT ends the programming session of the last one
+        command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM_START]);
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        assert!(!xcp.is_programming());
+        assert_eq!(command(&mut xcp, &mut memory, &program), XcpPacket::error(XCP_ERR_SEQUENCE));
+    }
+
+    #[test]
+    fn program_start_needs_the_pgm_resource_unlocked() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::with_seed_key(InvertedSeed);
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM_START]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_ACCESS_LOCKED));
+        assert!(!xcp.is_programming());
+    }
+
+    #[test]
+    fn uploads_move_the_mta_along() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+        write_pattern(&mut memory);
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 3]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x10, 0x11, 0x12]);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 2]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x13, 0x14]);
+        assert_eq!(xcp.get_mta(), app_start + 5);
+
+        // SHORT_UPLOAD sets the MTA, which then points past the data
+        let response = command(&mut xcp, &mut memory, &short_upload(2, app_start + 8));
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x18, 0x19]);
+        assert_eq!(xcp.get_mta(), app_start + 10);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 1]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x1A]);
+
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 0]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn upload_longer_than_a_packet_continues_in_the_next() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+        write_pattern(&mut memory);
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 16]);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16]);
+        let response = xcp.next_block_response(&memory).unwrap();
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D]);
+        let response = xcp.next_block_response(&memory).unwrap();
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x1E, 0x1F]);
+        assert_eq!(xcp.next_block_response(&memory), None);
+        assert_eq!(xcp.get_mta(), app_start + 16);
+
+        // Another command ends the upload
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+        command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 10]);
+        command(&mut xcp, &mut memory, &[XCP_CMD_GET_STATUS]);
+        assert_eq!(xcp.next_block_response(&memory), None);
+    }
+
+    #[test]
+    fn addresses_outside_the_application_are_refused() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_end = memory.get_app_end();
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &set_mta(0x2000_0000));
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 4]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+        assert_eq!(xcp.get_mta(), 0x2000_0000);
+
+        // The bootloader's own flash
+        let response = command(&mut xcp, &mut memory, &short_upload(4, 0x0000_1000));
+        assert_eq!(response, XcpPacket::error(XCP_ERR_ACCESS_DENIED));
+
+        // A block running past the end of the application area
+        command(&mut xcp, &mut memory, &set_mta(app_end - 4));
+        let response = command(&mut xcp, &mut memory, &build_checksum(8));
+        assert_eq!(response, XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+        assert_eq!(xcp.get_mta(), app_end - 4);
+    }
+
+    #[test]
+    fn add44_checksum_adds_intel_words() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+        memory
+            .write(app_start, &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x00, 0x00, 0x00])
+            .unwrap();
+        memory.flush().unwrap();
+
+        // 0x04030201 + 0x08070605 + 0xFFFFFFFF + 0x00000001
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+        let response = command(&mut xcp, &mut memory, &build_checksum(16));
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x06, 0x00, 0x00, 0x06, 0x08, 0x0A, 0x0C]);
+        assert_eq!(xcp.get_mta(), app_start + 16);
+
+        // Erased words over several read chunks: 64 x 0xFFFFFFFF
+        let response = command(&mut xcp, &mut memory, &build_checksum(256));
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x06, 0x00, 0x00, 0xC0, 0xFF, 0xFF, 0xFF]);
+
+        // Only whole words are summed
+        for block_size in [0, 6] {
+            let response = command(&mut xcp, &mut memory, &build_checksum(block_size));
+            assert_eq!(response, XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+        }
+        xcp.set_checksum_type(ChecksumType::Add11);
+        let response = command(&mut xcp, &mut memory, &build_checksum(6));
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x01, 0x00, 0x00, 0xFA, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn block_mode_and_packet_size_are_set_in_any_order() {
+        let (_, mut memory) = setup();
+        let program_start = |xcp: &mut XcpProtocol, memory: &mut MemoryManager<Hal>| {
+            command(xcp, memory, &[XCP_CMD_CONNECT, 0x00]);
+            command(xcp, memory, &[XCP_CMD_PROGRAM_START])
+        };
+
+        let mut xcp = XcpProtocol::new();
+        xcp.set_block_mode(4, 2);
+        xcp.set_max_cto(64);
+        let response = program_start(&mut xcp, &mut memory);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x41, 64, 4, 2, 0x00]);
+
+        let mut xcp = XcpProtocol::new();
+        xcp.set_max_cto(64);
+        xcp.set_block_mode(4, 2);
+        let response = program_start(&mut xcp, &mut memory);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x41, 64, 4, 2, 0x00]);
+
+        // Capped at the 5 packets of 62 elements a 255 byte block needs...
+        xcp.set_block_mode(20, 0);
+        let response = program_start(&mut xcp, &mut memory);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x41, 64, 5, 0, 0x00]);
+        // ...and back to the requested size with smaller packets
+        xcp.set_max_cto(8);
+        let response = program_start(&mut xcp, &mut memory);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x41, 8, 20, 0, 0x00]);
+
+        // Disabled master block mode stays disabled
+        xcp.set_block_mode(0, 0);
+        xcp.set_max_cto(64);
+        let response = program_start(&mut xcp, &mut memory);
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x00, 0x40, 64, 0, 0, 0x00]);
+    }
+
+    #[test]
+    fn download_next_completes_a_master_block() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM_START]);
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+
+        // 14 elements: 6 in DOWNLOAD, 6 and 2 in DOWNLOAD_NEXT, answered once
+        let download = [XCP_CMD_DOWNLOAD, 14, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05];
+        assert_eq!(xcp.process_command(&download, &mut memory), None);
+        let next = [XCP_CMD_DOWNLOAD_NEXT, 8, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B];
+        assert_eq!(xcp.process_command(&next, &mut memory), None);
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_DOWNLOAD_NEXT, 2, 0x0C, 0x0D]);
+        assert_eq!(response, XcpPacket::positive());
+        assert_eq!(xcp.get_mta(), app_start + 14);
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM, 0]);
+        let mut data = [0u8; 16];
+        memory.read(app_start, &mut data).unwrap();
+        let expected: [u8; 14] = core::array::from_fn(|i| i as u8);
+        assert_eq!(&data[..14], &expected);
+        assert_eq!(&data[14..], &[0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn download_next_out_of_sequence_is_refused() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &[XCP_CMD_PROGRAM_START]);
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+
+        // No block was started
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_DOWNLOAD_NEXT, 2, 0x00, 0x01]);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_SEQUENCE));
+
+        // A packet that does not announce the remaining 8 elements aborts the block
+        let download = [XCP_CMD_DOWNLOAD, 14, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05];
+        assert_eq!(xcp.process_command(&download, &mut memory), None);
+        let next = [XCP_CMD_DOWNLOAD_NEXT, 7, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B];
+        let response = command(&mut xcp, &mut memory, &next);
+        assert_eq!(response.as_slice(), &[XCP_PID_ERR, XCP_ERR_SEQUENCE, 8]);
+        let next = [XCP_CMD_DOWNLOAD_NEXT, 8, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B];
+        let response = command(&mut xcp, &mut memory, &next);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_SEQUENCE));
+        assert_eq!(xcp.get_mta(), app_start);
+
+        // More packets than MAX_BS allows
+        xcp.set_block_mode(1, 0);
+        let response = command(&mut xcp, &mut memory, &download);
+        assert_eq!(response, XcpPacket::error(XCP_ERR_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn slave_block_upload_is_split_at_the_packet_size() {
+        let (_, mut memory) = setup();
+        let mut xcp = XcpProtocol::new();
+        let app_start = memory.get_app_start();
+        write_pattern(&mut memory);
+        xcp.set_max_cto(12);
+
+        command(&mut xcp, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        command(&mut xcp, &mut memory, &set_mta(app_start));
+        let response = command(&mut xcp, &mut memory, &[XCP_CMD_UPLOAD, 16]);
+        assert_eq!(response.len(), 12);
+        assert_eq!(&response.as_slice()[1..], &[0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A]);
+        let response = xcp.next_block_response(&memory).unwrap();
+        assert_eq!(response.as_slice(), &[XCP_PID_RES, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F]);
+        assert_eq!(xcp.next_block_response(&memory), None);
+    }
+
+    // Hands `packet` to the bootloader's transport and collects everything
+    // it answers
+    fn exchange(
+        xcp: &mut XcpProtocol,
+        protocol: &mut Protocol<MemoryTransport<4>>,
+        memory: &mut MemoryManager<Hal>,
+        packet: &[u8],
+    ) -> Vec<Vec<u8>> {
+        protocol.get_transport_mut().inject(packet).unwrap();
+        xcp.serve(protocol, memory).unwrap();
+        let mut responses = vec![];
+        let mut buffer = [0u8; 8];
+        while let Some(len) = protocol.get_transport_mut().collect(&mut buffer) {
+            responses.push(buffer[..len].to_vec());
+        }
+        responses
+    }
+
+    #[test]
+    fn blank_device_is_programmed_over_the_transport() {
+        let (device, mut memory) = setup();
+        let mut protocol = Protocol::new(MemoryTransport::<4>::new(8));
+        let mut xcp = XcpProtocol::new();
+        xcp.set_max_cto(protocol.max_packet_size());
+        let app_start = memory.get_app_start();
+        let positive = vec![vec![XCP_PID_RES]];
+
+        // Stack pointer, reset handler and a few more vectors
+        let image: Vec<u8> = [0x2000_8000u32, app_start + 0x101, 0x1111_1111, 0x2222_2222, 0x3333_3333]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let sum = image
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .fold(0u32, u32::wrapping_add)
+            .to_le_bytes();
+
+        let connect = exchange(&mut xcp, &mut protocol, &mut memory, &[XCP_CMD_CONNECT, 0x00]);
+        assert_eq!(connect[0][..4], [XCP_PID_RES, XCP_RES_PGM, 0xC0, 8]);
+        exchange(&mut xcp, &mut protocol, &mut memory, &[XCP_CMD_PROGRAM_START]);
+        assert_eq!(exchange(&mut xcp, &mut protocol, &mut memory, &set_mta(app_start)), positive);
+        let sector = memory.get_sector_size().to_le_bytes();
+        let clear = [XCP_CMD_PROGRAM_CLEAR, 0, 0, 0, sector[0], sector[1], sector[2], sector[3]];
+        assert_eq!(exchange(&mut xcp, &mut protocol, &mut memory, &clear), positive);
+
+        for chunk in image.chunks(6) {
+            let mut program = vec![XCP_CMD_PROGRAM, chunk.len() as u8];
+            program.extend_from_slice(chunk);
+            assert_eq!(exchange(&mut xcp, &mut protocol, &mut memory, &program), positive);
+        }
+        assert_eq!(exchange(&mut xcp, &mut protocol, &mut memory, &[XCP_CMD_PROGRAM, 0]), positive);
+
+        // The master reads the image back before it resets the target
+        exchange(&mut xcp, &mut protocol, &mut memory, &set_mta(app_start));
+        let checksum = exchange(&mut xcp, &mut protocol, &mut memory, &build_checksum(image.len() as u32));
+        assert_eq!(checksum, [vec![XCP_PID_RES, 0x06, 0x00, 0x00, sum[0], sum[1], sum[2], sum[3]]]);
+        exchange(&mut xcp, &mut protocol, &mut memory, &set_mta(app_start));
+        let upload = exchange(&mut xcp, &mut protocol, &mut memory, &[XCP_CMD_UPLOAD, 8]);
+        assert_eq!(upload[0][1..], image[..7]);
+        assert_eq!(upload[1][1..], image[7..8]);
+
+        assert_eq!(device.get_entry_point(), None);
+        let reset = exchange(&mut xcp, &mut protocol, &mut memory, &[XCP_CMD_PROGRAM_RESET]);
+        assert_eq!(reset, positive);
+        assert_eq!(device.get_entry_point(), Some(app_start + 0x101));
+        let start = app_start as usize;
+        assert_eq!(device.get_blocks()[0].contents()[start..start + image.len()], image[..]);
+    }
+}
@@ -1,6 +1,42 @@
 #![no_std]
 
+/// Calculate the standard CRC-32 (IEEE 802.3) of data
 pub fn calculate_crc32(data: &[u8]) -> u32 {
-    // TODO: Implement CRC32 calculation
-    0
-} 
+    !update_crc32(0xFFFF_FFFF, data)
+}
+
+/// Feed more data into a running CRC-32. Start with 0xFFFFFFFF and invert
+/// the final value.
+pub fn update_crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Calculate the CRC-16/CCITT (polynomial 0x1021, initial value 0xFFFF) of data
+pub fn calculate_crc16_ccitt(data: &[u8]) -> u16 {
+    update_crc16_ccitt(0xFFFF, data)
+}
+
+/// Feed more data into a running CRC-16/CCITT. Start with 0xFFFF.
+pub fn update_crc16_ccitt(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}